@@ -0,0 +1,877 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use chrono::{DateTime, Datelike, Local, Timelike};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zstd::stream::write::Encoder;
+use age::stream::StreamWriter;
+use crate::backup_options::OutputFormat;
+use crate::encryption::{BackupCipher, ENCRYPTED_SUFFIX};
+use crate::fs_compat::{FileSystemKind, part_name};
+use crate::failures::fatal;
+use crate::cpu_governor::throttle;
+use crate::bandwidth::limit;
+
+pub const TAR_ZST_FILE: &str = "backup.tar.zst"; // nome dell'archivio tar.zst creato nella cartella del backup
+pub const ZIP_FILE: &str = "backup.zip";         // nome dell'archivio zip creato nella cartella del backup (se diviso in volumi: backup.001.zip, backup.002.zip, ...)
+pub const FAT_MAX_FILE_SIZE: u64 = u32::MAX as u64; // dimensione massima di un file su FAT32
+
+const ZIP_ENTRY_OVERHEAD: u64 = 512;  // stima per eccesso dello spazio occupato da intestazione locale, descrittore e voce della directory centrale di un file
+const ZIP_END_OVERHEAD: u64 = 1024;   // stima per eccesso dei record finali della directory centrale (ZIP64 compreso)
+const STORED_BLOCK_SIZE: usize = 128 * 1024; // dimensione massima di un blocco zstd
+const ZSTD_FRAME_OVERHEAD: u64 = 16;  // stima per eccesso dei byte scritti quando un file apre un nuovo frame zstd (chiusura del frame precedente e intestazione)
+
+// estensioni dei file già compressi, per cui la compressione non porta vantaggi (valore di default dell'opzione "store_extensions")
+pub const DEFAULT_STORE_EXTENSIONS: [&str; 17] = ["jpg", "jpeg", "png", "gif", "webp", "zip", "rar", "7z", "gz", "bz2", "xz", "zst", "mp3", "mp4", "mkv", "avi", "mov"];
+
+/***
+ArchiveOutput: file dell'archivio tar.zst, in chiaro o cifrato con la chiave del backup
+***/
+enum ArchiveOutput {
+    Plain(SplitFile),
+    Encrypted(StreamWriter<SplitFile>),
+}
+
+impl ArchiveOutput {
+    fn finish(self) -> io::Result<SplitFile> {
+        match self {
+            ArchiveOutput::Plain(file) => Ok(file),
+            ArchiveOutput::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for ArchiveOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveOutput::Plain(file) => file.write(buf),
+            ArchiveOutput::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveOutput::Plain(file) => file.flush(),
+            ArchiveOutput::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/***
+SplitFile: file scritto nel backup (archivio tar.zst o file cifrato), diviso in parti "<nome>.part001", "<nome>.part002", ...
+    (da riunire concatenandole, vedi open_split) quando supera la dimensione massima di un file sulla destinazione (FAT32).
+    Un file che non supera il limite resta un unico file
+    path: percorso del file
+    max_size: dimensione massima di una parte (None: nessun limite)
+    file: parte corrente
+    written: byte scritti nella parte corrente
+    parts: numero di parti create
+***/
+pub struct SplitFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    file: BufWriter<File>,
+    written: u64,
+    parts: usize,
+}
+
+impl SplitFile {
+    pub fn create(path: &Path, max_size: Option<u64>) -> io::Result<Self> {
+        Ok(SplitFile { path: path.to_path_buf(), max_size, file: BufWriter::new(File::create(path)?), written: 0, parts: 1 })
+    }
+
+    fn part_path(&self, n: usize) -> PathBuf {
+        PathBuf::from(part_name(&self.path.to_string_lossy(), n))
+    }
+
+    // chiude la parte corrente e apre la successiva: alla prima divisione il file diventa la prima parte
+    fn next_part(&mut self) -> io::Result<()> {
+        let next = BufWriter::new(File::create(self.part_path(self.parts + 1))?);
+        std::mem::replace(&mut self.file, next).into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        if self.parts == 1 {
+            fs::rename(&self.path, self.part_path(1))?;
+        }
+        self.parts += 1;
+        self.written = 0;
+        Ok(())
+    }
+
+    // scrive su disco l'ultima parte (le precedenti sono già state scritte da next_part)
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()
+    }
+
+    /***
+    finish: chiude il file
+        -> restituisce: i percorsi dei file scritti (il file stesso, o le sue parti in ordine)
+    ***/
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        self.file.flush()?;
+        match self.parts {
+            1 => Ok(vec![self.path]),
+            parts => Ok((1..=parts).map(|n| self.part_path(n)).collect()),
+        }
+    }
+}
+
+impl Write for SplitFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_size.is_some_and(|max| self.written >= max) {
+            self.next_part()?;
+        }
+        let len = match self.max_size {
+            Some(max) => (max - self.written).min(buf.len() as u64) as usize,
+            None => buf.len(),
+        };
+        let n = self.file.write(&buf[..len])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/***
+ZstdFrames: writer che scrive i dati in una sequenza di frame zstd sullo stesso file.
+    Cambiare livello di compressione chiude il frame corrente e ne apre uno nuovo: un file .zst formato da più frame
+    viene decompresso normalmente da zstd e da tar.
+    level: livello di compressione del frame corrente (None: dati memorizzati senza compressione, vedi StoredFrame)
+***/
+struct ZstdFrames {
+    frame: Option<Frame>,
+    level: Option<i32>,
+}
+
+impl ZstdFrames {
+    fn new(output: ArchiveOutput, level: Option<i32>) -> io::Result<Self> {
+        Ok(ZstdFrames { frame: Some(Frame::open(output, level)?), level })
+    }
+
+    fn set_level(&mut self, level: Option<i32>) -> io::Result<()> {
+        if level == self.level {
+            return Ok(());
+        }
+        let file = self.frame.take().unwrap().finish()?;
+        self.frame = Some(Frame::open(file, level)?);
+        self.level = level;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        let mut file = self.frame.take().unwrap().finish()?.finish()?;
+        file.sync_all()?;
+        file.finish()
+    }
+}
+
+impl Write for ZstdFrames {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.frame.as_mut().unwrap() {
+            Frame::Compressed(encoder) => encoder.write(buf),
+            Frame::Stored(frame) => frame.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.frame.as_mut().unwrap() {
+            Frame::Compressed(encoder) => encoder.flush(),
+            Frame::Stored(frame) => frame.flush(),
+        }
+    }
+}
+
+/***
+Frame: frame zstd aperto, compresso dalla libreria zstd o formato da blocchi memorizzati
+***/
+enum Frame {
+    Compressed(Encoder<'static, ArchiveOutput>),
+    Stored(StoredFrame),
+}
+
+impl Frame {
+    fn open(output: ArchiveOutput, level: Option<i32>) -> io::Result<Self> {
+        match level {
+            Some(level) => Ok(Frame::Compressed(Encoder::new(output, level)?)),
+            None => Ok(Frame::Stored(StoredFrame::new(output)?)),
+        }
+    }
+
+    fn finish(self) -> io::Result<ArchiveOutput> {
+        match self {
+            Frame::Compressed(encoder) => encoder.finish(),
+            Frame::Stored(frame) => frame.finish(),
+        }
+    }
+}
+
+/***
+StoredFrame: frame zstd formato da blocchi "raw" (RFC 8878), cioè da dati memorizzati senza compressione: i file già compressi
+    occupano nell'archivio la loro dimensione più 3 byte ogni STORED_BLOCK_SIZE byte, senza passare dal compressore
+    block: dati del blocco corrente, scritto quando è pieno o alla chiusura del frame
+***/
+struct StoredFrame {
+    output: ArchiveOutput,
+    block: Vec<u8>,
+}
+
+impl StoredFrame {
+    fn new(mut output: ArchiveOutput) -> io::Result<Self> {
+        // magic number, descrittore del frame (senza dimensione del contenuto, dizionario e checksum) e finestra di 128 KB
+        output.write_all(&[0x28, 0xB5, 0x2F, 0xFD, 0x00, 0x38])?;
+        Ok(StoredFrame { output, block: Vec::with_capacity(STORED_BLOCK_SIZE) })
+    }
+
+    // scrive il blocco corrente: intestazione di 3 byte (ultimo blocco, tipo "raw" = 0, dimensione) seguita dai dati
+    fn write_block(&mut self, last: bool) -> io::Result<()> {
+        let header = (self.block.len() as u32) << 3 | last as u32;
+        self.output.write_all(&header.to_le_bytes()[..3])?;
+        self.output.write_all(&self.block)?;
+        self.block.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<ArchiveOutput> {
+        self.write_block(true)?;
+        Ok(self.output)
+    }
+}
+
+impl Write for StoredFrame {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.block.len() == STORED_BLOCK_SIZE {
+            self.write_block(false)?;
+        }
+        let n = buf.len().min(STORED_BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/***
+HashingReader: reader che calcola l'hash BLAKE3 dei dati letti (usato per il manifest durante la scrittura dell'archivio)
+***/
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        throttle();
+        limit(n as u64);
+        Ok(n)
+    }
+}
+
+/***
+ZeroPadded: reader che restituisce esattamente 'remaining' byte: se il file sorgente finisce prima (si è accorciato dopo la lettura
+    dei metadati) i byte mancanti sono zeri. Nel tar la dimensione è scritta nell'intestazione prima dei dati: un'entry più corta
+    disallineerebbe tutte le entry successive dell'archivio
+    padded: numero di zeri aggiunti
+***/
+struct ZeroPadded<R: Read> {
+    inner: R,
+    remaining: u64,
+    padded: u64,
+}
+
+impl<R: Read> Read for ZeroPadded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining.try_into().unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            buf[..len].fill(0);
+            self.padded += len as u64;
+            n = len;
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/***
+ArchiveWriter: archivio in cui vengono scritti i file del backup, nei formati "tar.zst" e "zip"
+***/
+pub enum ArchiveWriter {
+    // lo stato dei due writer (encoder, volumi) è grande: lo tengo sullo heap
+    TarZst(Box<TarZstWriter>),
+    Zip(Box<ZipVolumes>),
+}
+
+impl ArchiveWriter {
+    /***
+    append_file: aggiunge un file all'archivio
+        src: percorso del file sorgente
+        name: percorso del file all'interno dell'archivio
+        metadata: metadati del file sorgente
+        -> restituisce: l'hash BLAKE3 del contenuto scritto. Un errore a metà del file lascerebbe l'archivio inutilizzabile ed è
+            quindi segnato come bloccante (vedi failures::fatal), tranne quando l'entry è stata completata (vedi TarZstWriter::append_file)
+    ***/
+    pub fn append_file(&mut self, src: &Path, name: &str, metadata: &fs::Metadata) -> io::Result<String> {
+        match self {
+            ArchiveWriter::TarZst(archive) => archive.append_file(src, name, metadata),
+            ArchiveWriter::Zip(archive) => archive.append_file(src, name, metadata).map_err(fatal),
+        }
+    }
+
+    /***
+    finish: completa l'archivio e lo scrive su disco
+        -> restituisce: i percorsi dei file creati (più di uno se l'archivio zip è stato diviso in volumi o l'archivio tar.zst in parti)
+    ***/
+    pub fn finish(self) -> io::Result<Vec<PathBuf>> {
+        match self {
+            ArchiveWriter::TarZst(archive) => archive.finish(),
+            ArchiveWriter::Zip(archive) => archive.finish(),
+        }
+    }
+}
+
+/***
+TarZstWriter: scrittura dei file selezionati in un unico archivio .tar.zst (diviso in parti sui file system con una dimensione massima dei file, vedi SplitFile)
+    level: livello di compressione zstd configurato
+    store_extensions: estensioni dei file già compressi, memorizzati nell'archivio senza comprimerli (vedi StoredFrame)
+***/
+pub struct TarZstWriter {
+    builder: tar::Builder<ZstdFrames>,
+    level: i32,
+    store_extensions: Vec<String>,
+}
+
+impl TarZstWriter {
+    /***
+    create: crea l'archivio
+        path: percorso del file .tar.zst (se l'archivio è cifrato, al nome viene aggiunto ENCRYPTED_SUFFIX)
+        level: livello di compressione zstd
+        store_extensions: estensioni dei file da non comprimere
+        cipher: chiave del backup, se l'archivio deve essere cifrato
+        max_part_size: dimensione massima di un file sulla destinazione (vedi max_file_size), oltre la quale l'archivio viene diviso in parti
+    ***/
+    pub fn create(path: &Path, level: i32, store_extensions: &[String], cipher: Option<&BackupCipher>, max_part_size: Option<u64>) -> io::Result<Self> {
+        let output = match cipher {
+            Some(cipher) => {
+                let mut name = path.as_os_str().to_os_string();
+                name.push(ENCRYPTED_SUFFIX);
+                ArchiveOutput::Encrypted(cipher.wrap(SplitFile::create(Path::new(&name), max_part_size)?)?)
+            }
+            None => ArchiveOutput::Plain(SplitFile::create(path, max_part_size)?),
+        };
+        let frames = ZstdFrames::new(output, Some(level))?;
+        Ok(TarZstWriter { builder: tar::Builder::new(frames), level, store_extensions: store_extensions.to_vec() })
+    }
+
+    /***
+    append_file: aggiunge un file all'archivio
+        src: percorso del file sorgente
+        name: percorso del file all'interno dell'archivio
+        metadata: metadati del file sorgente
+        -> restituisce: l'hash BLAKE3 del contenuto scritto. Se il file si è accorciato dopo la lettura dei metadati, l'entry viene
+            completata con zeri (vedi ZeroPadded) e viene restituito un errore non bloccante: il file va registrato come non copiato
+    ***/
+    pub fn append_file(&mut self, src: &Path, name: &str, metadata: &fs::Metadata) -> io::Result<String> {
+        let level = if is_stored(src, &self.store_extensions) { None } else { Some(self.level) };
+        self.builder.get_mut().set_level(level).map_err(fatal)?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(metadata);
+        header.set_size(metadata.len());
+
+        let hashing = HashingReader { inner: File::open(src)?.take(metadata.len()), hasher: blake3::Hasher::new() };
+        let mut reader = ZeroPadded { inner: hashing, remaining: metadata.len(), padded: 0 };
+        self.builder.append_data(&mut header, name, &mut reader).map_err(fatal)?;
+        if reader.padded > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("il file si è accorciato durante il backup: {} byte mancanti scritti come zeri nell'archivio", reader.padded)));
+        }
+        Ok(reader.inner.hasher.finalize().to_hex().to_string())
+    }
+
+    /***
+    finish: completa l'archivio (blocchi finali del tar e chiusura del frame zstd) e lo scrive su disco
+    ***/
+    pub fn finish(self) -> io::Result<Vec<PathBuf>> {
+        self.builder.into_inner()?.finish()
+    }
+}
+
+/***
+VolumeFile: file di un volume zip che tiene traccia della dimensione scritta (condivisa con ZipVolumes, perché ZipWriter non dà accesso al file)
+***/
+struct VolumeFile {
+    file: BufWriter<File>,
+    position: u64,
+    size: Rc<Cell<u64>>,
+}
+
+impl Write for VolumeFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.position += n as u64;
+        self.size.set(self.size.get().max(self.position));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for VolumeFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+/***
+ZipVolumes: scrittura dei file selezionati in un archivio zip (ZIP64 per i file e gli archivi oltre 4 GB), apribile senza programmi aggiuntivi su Windows, macOS e Linux.
+    Se è indicata una dimensione massima, l'archivio viene diviso in volumi: ogni volume è un archivio zip completo e indipendente.
+    Un file più grande di un volume viene diviso in parti "<nome>.part001", "<nome>.part002", ... in volumi consecutivi, da riunire concatenandole.
+    dir: cartella in cui vengono creati i volumi
+    max_volume_size: dimensione massima di un volume (None: un solo archivio)
+    store_extensions: estensioni dei file già compressi, inseriti nell'archivio senza comprimerli
+***/
+pub struct ZipVolumes {
+    dir: PathBuf,
+    max_volume_size: Option<u64>,
+    store_extensions: Vec<String>,
+    writer: Option<ZipWriter<VolumeFile>>,
+    written: Rc<Cell<u64>>,        // byte scritti nel volume corrente
+    central_directory: u64,       // stima della dimensione della directory centrale del volume corrente
+    volume_entries: usize,        // numero di file nel volume corrente
+    volumes: Vec<PathBuf>,        // volumi creati
+}
+
+impl ZipVolumes {
+    /***
+    create: crea l'archivio (il primo volume)
+        dir: cartella del backup
+        max_volume_size: dimensione massima di un volume, None se l'archivio non deve essere diviso
+        store_extensions: estensioni dei file da non comprimere
+    ***/
+    pub fn create(dir: &Path, max_volume_size: Option<u64>, store_extensions: &[String]) -> io::Result<Self> {
+        let mut volumes = ZipVolumes {
+            dir: dir.to_path_buf(),
+            max_volume_size,
+            store_extensions: store_extensions.to_vec(),
+            writer: None,
+            written: Rc::new(Cell::new(0)),
+            central_directory: 0,
+            volume_entries: 0,
+            volumes: Vec::new(),
+        };
+        volumes.next_volume()?;
+        Ok(volumes)
+    }
+
+    /***
+    next_volume: completa il volume corrente (se presente) e ne apre uno nuovo.
+        Quando viene creato il secondo volume, il primo viene rinominato da "backup.zip" a "backup.001.zip"
+    ***/
+    fn next_volume(&mut self) -> io::Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.finish()?.flush()?;
+        }
+        if self.volumes.len() == 1 {
+            let first = self.dir.join(volume_name(1));
+            fs::rename(&self.volumes[0], &first)?;
+            self.volumes[0] = first;
+        }
+
+        let path = if self.volumes.is_empty() { self.dir.join(ZIP_FILE) } else { self.dir.join(volume_name(self.volumes.len() + 1)) };
+        self.written = Rc::new(Cell::new(0));
+        let file = VolumeFile { file: BufWriter::new(File::create(&path)?), position: 0, size: Rc::clone(&self.written) };
+        self.writer = Some(ZipWriter::new(file));
+        self.central_directory = ZIP_END_OVERHEAD;
+        self.volume_entries = 0;
+        self.volumes.push(path);
+        Ok(())
+    }
+
+    /***
+    fits: verifica se un file di una certa dimensione può essere aggiunto al volume corrente
+        size: spazio occupato dal file nell'archivio (stima per eccesso)
+    ***/
+    fn fits(&self, size: u64) -> bool {
+        match self.max_volume_size {
+            Some(max) => self.written.get() + size + ZIP_ENTRY_OVERHEAD + self.central_directory <= max,
+            None => true,
+        }
+    }
+
+    /***
+    append_file: aggiunge un file all'archivio, aprendo un nuovo volume se quello corrente non ha spazio sufficiente
+        src: percorso del file sorgente
+        name: percorso del file all'interno dell'archivio (separatore '/')
+        metadata: metadati del file sorgente
+        -> restituisce: l'hash BLAKE3 del contenuto scritto
+    ***/
+    pub fn append_file(&mut self, src: &Path, name: &str, metadata: &fs::Metadata) -> io::Result<String> {
+        let stored = is_stored(src, &self.store_extensions);
+        let size = metadata.len();
+        let mut reader = HashingReader { inner: File::open(src)?.take(size), hasher: blake3::Hasher::new() };
+
+        if !self.fits(zip_size_estimate(size, stored)) && self.volume_entries > 0 {
+            self.next_volume()?;
+        }
+
+        if self.fits(zip_size_estimate(size, stored)) {
+            self.write_entry(name, &mut reader, size, stored, metadata)?;
+        } else {
+            // il file non entra in un volume vuoto: viene diviso in parti, ognuna in un volume diverso
+            let max = self.max_volume_size.unwrap();
+            let part_size = (max.saturating_sub(ZIP_ENTRY_OVERHEAD + ZIP_END_OVERHEAD + 64) * 1000 / 1001).max(1);
+            let mut remaining = size;
+            let mut part = 1;
+            while remaining > 0 {
+                if self.volume_entries > 0 {
+                    self.next_volume()?;
+                }
+                let chunk = remaining.min(part_size);
+                self.write_entry(&format!("{}.part{:03}", name, part), &mut (&mut reader).take(chunk), chunk, stored, metadata)?;
+                remaining -= chunk;
+                part += 1;
+            }
+        }
+
+        Ok(reader.hasher.finalize().to_hex().to_string())
+    }
+
+    /***
+    write_entry: scrive un file nel volume corrente
+        name: nome del file nell'archivio
+        data: contenuto del file
+        size: dimensione del contenuto
+        stored: se true il file non viene compresso
+        metadata: metadati del file sorgente (data di modifica e permessi)
+    ***/
+    fn write_entry(&mut self, name: &str, data: &mut dyn Read, size: u64, stored: bool, metadata: &fs::Metadata) -> io::Result<()> {
+        let mut options = SimpleFileOptions::default()
+            .compression_method(if stored { CompressionMethod::Stored } else { CompressionMethod::Deflated })
+            .large_file(size >= FAT_MAX_FILE_SIZE)
+            .last_modified_time(zip_time(metadata));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            options = options.unix_permissions(metadata.permissions().mode() & 0o7777);
+        }
+
+        let writer = self.writer.as_mut().unwrap();
+        writer.start_file(name, options)?;
+        io::copy(data, writer)?;
+        self.central_directory += ZIP_ENTRY_OVERHEAD / 2 + name.len() as u64;
+        self.volume_entries += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        let mut file = self.writer.take().unwrap().finish()?;
+        file.flush()?;
+        file.file.get_ref().sync_all()?;
+        Ok(self.volumes)
+    }
+}
+
+fn volume_name(n: usize) -> String {
+    format!("backup.{:03}.zip", n)
+}
+
+/***
+zip_time: funzione per convertire la data di modifica di un file nel formato dell'archivio zip (ora locale, precisione di 2 secondi)
+    metadata: metadati del file
+***/
+fn zip_time(metadata: &fs::Metadata) -> zip::DateTime {
+    metadata.modified().ok()
+        .map(DateTime::<Local>::from)
+        .and_then(|t| zip::DateTime::from_date_and_time(t.year() as u16, t.month() as u8, t.day() as u8, t.hour() as u8, t.minute() as u8, t.second() as u8).ok())
+        .unwrap_or_default()
+}
+
+/***
+is_stored: funzione per verificare se un file è già compresso, in base all'estensione
+    path: percorso del file
+    store_extensions: estensioni dei file già compressi
+***/
+fn is_stored(path: &Path, store_extensions: &[String]) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| store_extensions.iter().any(|s| s.eq_ignore_ascii_case(&e)))
+}
+
+/***
+max_file_size: funzione per ottenere la dimensione massima di un file sul disco che contiene un percorso
+    path: percorso sul disco
+    -> restituisce: Some(dimensione) se il file system ha un limite (FAT32), altrimenti None
+***/
+pub fn max_file_size(path: &Path) -> Option<u64> {
+    FileSystemKind::detect(path).max_file_size()
+}
+
+/***
+archive_size_estimate: funzione per stimare lo spazio occupato da un file nel backup, senza considerare la compressione
+    format: formato del backup
+    size: dimensione del file
+    -> restituisce: per il formato tar.zst, la dimensione dell'intestazione più il contenuto arrotondato a blocchi di 512 byte,
+                    con le intestazioni dei blocchi e dei frame zstd (la dimensione esatta, a meno dei frame, per un file memorizzato);
+                    per il formato zip, la stima per eccesso di intestazioni e contenuto
+***/
+pub fn archive_size_estimate(format: OutputFormat, size: u64) -> u64 {
+    match format {
+        OutputFormat::Directory => size,
+        OutputFormat::TarZst => {
+            let tar_size = 512 + size.div_ceil(512) * 512;
+            tar_size + tar_size.div_ceil(STORED_BLOCK_SIZE as u64) * 3 + ZSTD_FRAME_OVERHEAD
+        }
+        OutputFormat::Zip => ZIP_ENTRY_OVERHEAD + zip_size_estimate(size, false),
+    }
+}
+
+/***
+zip_size_estimate: funzione per stimare per eccesso lo spazio occupato dal contenuto di un file all'interno dell'archivio zip
+    size: dimensione del file
+    stored: se false il file viene compresso (nel caso peggiore deflate aumenta leggermente la dimensione dei dati incomprimibili)
+***/
+pub fn zip_size_estimate(size: u64, stored: bool) -> u64 {
+    if stored { size } else { size + size / 1000 + 64 }
+}
+
+/***
+list_zip_volumes: funzione per elencare i file contenuti in un archivio zip, eventualmente diviso in volumi (usata dalla verifica del backup).
+    Le parti "<nome>.part001", "<nome>.part002", ... di un file diviso tra più volumi vengono riunite
+    dir: cartella del backup
+    full: se true calcola anche l'hash BLAKE3 del contenuto di ogni file
+    -> restituisce: mappa percorso -> (dimensione, hash)
+***/
+pub fn list_zip_volumes(dir: &Path, full: bool) -> io::Result<BTreeMap<String, (u64, Option<String>)>> {
+    let mut volumes = vec![dir.join(ZIP_FILE)];
+    if !volumes[0].is_file() {
+        volumes = (1..).map(|n| dir.join(volume_name(n))).take_while(|p| p.is_file()).collect();
+    }
+
+    let mut files = BTreeMap::new();
+    let mut parts: HashMap<String, (u64, blake3::Hasher)> = HashMap::new();
+    for volume in volumes {
+        let mut archive = ZipArchive::new(File::open(volume)?)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            if !entry.is_file() {
+                continue;
+            }
+            let name = entry.name().to_string();
+            let size = entry.size();
+
+            // parte di un file diviso: le parti si trovano in volumi consecutivi, in ordine
+            if let Some((base, number)) = name.rsplit_once(".part") {
+                if number.len() == 3 && number.chars().all(|c| c.is_ascii_digit()) {
+                    let (total, hasher) = parts.entry(base.to_string()).or_insert_with(|| (0, blake3::Hasher::new()));
+                    *total += size;
+                    if full {
+                        io::copy(&mut entry, hasher)?;
+                    }
+                    continue;
+                }
+            }
+
+            let hash = if full {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(&mut entry, &mut hasher)?;
+                Some(hasher.finalize().to_hex().to_string())
+            } else {
+                None
+            };
+            files.insert(name, (size, hash));
+        }
+    }
+
+    for (name, (size, hasher)) in parts {
+        files.insert(name, (size, if full { Some(hasher.finalize().to_hex().to_string()) } else { None }));
+    }
+
+    Ok(files)
+}
+
+/***
+split_parts: funzione per trovare i file che compongono un file del backup, eventualmente diviso in parti (vedi SplitFile)
+    path: percorso del file
+    -> restituisce: il percorso del file se è un unico file, altrimenti i percorsi delle parti in ordine (vuoto se il file non esiste)
+***/
+pub fn split_parts(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        return vec![path.to_path_buf()];
+    }
+    (1..).map(|n| PathBuf::from(part_name(&path.to_string_lossy(), n))).take_while(|p| p.is_file()).collect()
+}
+
+/***
+open_split: funzione per leggere un file del backup (archivio tar.zst o file cifrato), anche se diviso in parti
+    path: percorso del file
+    -> restituisce: il contenuto del file, con le parti concatenate
+***/
+pub fn open_split(path: &Path) -> io::Result<Box<dyn Read>> {
+    let mut parts = split_parts(path).into_iter();
+    let first: Box<dyn Read> = Box::new(File::open(parts.next().unwrap_or_else(|| path.to_path_buf()))?);
+    parts.try_fold(first, |reader, part| Ok(Box::new(reader.chain(File::open(part)?)) as Box<dyn Read>))
+}
+
+/***
+list_tar_zst: funzione per elencare i file contenuti in un archivio .tar.zst (usata dalla verifica del backup)
+    path: percorso dell'archivio (anche se diviso in parti)
+    full: se true calcola anche l'hash BLAKE3 del contenuto di ogni file
+    -> restituisce: mappa percorso -> (dimensione, hash)
+***/
+pub fn list_tar_zst(path: &Path, full: bool) -> io::Result<BTreeMap<String, (u64, Option<String>)>> {
+    let decoder = zstd::stream::read::Decoder::new(open_split(path)?)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut files = BTreeMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().to_string();
+        let size = entry.header().size()?;
+        let hash = if full {
+            let mut hasher = blake3::Hasher::new();
+            io::copy(&mut entry, &mut hasher)?;
+            Some(hasher.finalize().to_hex().to_string())
+        } else {
+            None
+        };
+        files.insert(name, (size, hash));
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_entries_match_the_size_estimate() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Vec<String> = DEFAULT_STORE_EXTENSIONS.iter().map(|e| e.to_string()).collect();
+        // contenuto facilmente comprimibile: se i file venissero compressi l'archivio sarebbe molto più piccolo del tar
+        let files = [("foto.jpg", 300_000), ("video.mp4", 131_072), ("vuoto.zip", 0), ("icona.png", 700)];
+        let mut writer = TarZstWriter::create(&dir.path().join(TAR_ZST_FILE), 19, &store, None, None).unwrap();
+        let (mut tar_size, mut estimate) = (1024, 1024); // blocchi finali del tar, come in SizeEstimator::total_size
+        for (name, size) in files {
+            let path = dir.path().join(name);
+            fs::write(&path, &b"backup ".repeat(size / 7 + 1)[..size]).unwrap();
+            writer.append_file(&path, name, &fs::metadata(&path).unwrap()).unwrap();
+            tar_size += 512 + (size as u64).div_ceil(512) * 512;
+            estimate += archive_size_estimate(OutputFormat::TarZst, size as u64);
+        }
+        let archive = writer.finish().unwrap().remove(0);
+
+        // i file memorizzati occupano il tar più le sole intestazioni dei blocchi e dei frame zstd, entro la stima
+        let written = fs::metadata(&archive).unwrap().len();
+        assert!(tar_size < written && written <= estimate, "tar {} archivio {} stima {}", tar_size, written, estimate);
+        let listed = list_tar_zst(&archive, true).unwrap();
+        for (name, size) in files {
+            assert_eq!(listed[name], (size as u64, Some(blake3::hash(&fs::read(dir.path().join(name)).unwrap()).to_hex().to_string())));
+        }
+
+        // gli altri file vengono compressi
+        let text = dir.path().join("testo.txt");
+        fs::write(&text, b"backup ".repeat(50_000)).unwrap();
+        let mut writer = TarZstWriter::create(&dir.path().join("compresso.tar.zst"), 19, &store, None, None).unwrap();
+        writer.append_file(&text, "testo.txt", &fs::metadata(&text).unwrap()).unwrap();
+        let archive = writer.finish().unwrap().remove(0);
+        assert!(fs::metadata(&archive).unwrap().len() < 10_000);
+    }
+
+    // estrae l'archivio (anche se diviso in parti) nella cartella indicata
+    fn extract(path: &Path, output: &Path) {
+        tar::Archive::new(zstd::stream::read::Decoder::new(open_split(path).unwrap()).unwrap()).unpack(output).unwrap();
+    }
+
+    #[test]
+    fn extracted_files_match_the_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, output) = (dir.path().join("sorgente"), dir.path().join("estratto"));
+        fs::create_dir_all(src.join("foto")).unwrap();
+        let files = [("note.txt", b"backup ".repeat(20_000)), ("foto/mare.jpg", (0..300_000u32).map(|i| (i % 251) as u8).collect()), ("vuoto.txt", Vec::new())];
+        let path = dir.path().join(TAR_ZST_FILE);
+        let mut writer = TarZstWriter::create(&path, 3, &["jpg".to_string()], None, None).unwrap();
+        for (name, data) in &files {
+            fs::write(src.join(name), data).unwrap();
+            let hash = writer.append_file(&src.join(name), name, &fs::metadata(src.join(name)).unwrap()).unwrap();
+            assert_eq!(hash, blake3::hash(data).to_hex().to_string());
+        }
+        writer.finish().unwrap();
+
+        extract(&path, &output);
+        for (name, data) in &files {
+            assert_eq!(&fs::read(output.join(name)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn a_file_that_shrinks_does_not_corrupt_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, output) = (dir.path().join("sorgente"), dir.path().join("estratto"));
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("log.txt"), vec![b'a'; 10_000]).unwrap();
+        fs::write(src.join("dopo.txt"), b"contenuto del file successivo").unwrap();
+
+        // i metadati vengono letti prima che il file si accorci
+        let metadata = fs::metadata(src.join("log.txt")).unwrap();
+        fs::write(src.join("log.txt"), vec![b'a'; 4_000]).unwrap();
+        let path = dir.path().join(TAR_ZST_FILE);
+        let mut writer = TarZstWriter::create(&path, 3, &[], None, None).unwrap();
+        let error = writer.append_file(&src.join("log.txt"), "log.txt", &metadata).unwrap_err();
+        assert!(!crate::failures::is_fatal(&error), "{}", error);
+        writer.append_file(&src.join("dopo.txt"), "dopo.txt", &fs::metadata(src.join("dopo.txt")).unwrap()).unwrap();
+        writer.finish().unwrap();
+
+        // l'entry accorciata è completata con zeri e l'entry successiva resta leggibile
+        extract(&path, &output);
+        let shrunk = fs::read(output.join("log.txt")).unwrap();
+        assert_eq!((shrunk.len(), &shrunk[..4_000], shrunk[4_000..].iter().all(|b| *b == 0)), (10_000, &[b'a'; 4_000][..], true));
+        assert_eq!(fs::read(output.join("dopo.txt")).unwrap(), b"contenuto del file successivo");
+    }
+
+    #[test]
+    fn archive_is_split_into_parts_at_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(TAR_ZST_FILE);
+        let file = dir.path().join("foto.jpg");
+        fs::write(&file, (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>()).unwrap();
+
+        // i file memorizzati non vengono compressi: con parti da 30 KB l'archivio deve essere diviso
+        let mut writer = TarZstWriter::create(&path, 3, &["jpg".to_string()], None, Some(30_000)).unwrap();
+        writer.append_file(&file, "foto.jpg", &fs::metadata(&file).unwrap()).unwrap();
+        let parts = writer.finish().unwrap();
+        assert_eq!(parts.len(), 4);
+        assert!(!path.exists());
+        assert_eq!(parts, split_parts(&path));
+        assert!(parts.iter().all(|part| fs::metadata(part).unwrap().len() <= 30_000));
+        assert_eq!(parts[0], dir.path().join(format!("{}.part001", TAR_ZST_FILE)));
+
+        // le parti concatenate formano l'archivio completo
+        let listed = list_tar_zst(&path, true).unwrap();
+        assert_eq!(listed["foto.jpg"], (100_000, Some(blake3::hash(&fs::read(&file).unwrap()).to_hex().to_string())));
+
+        // un archivio entro il limite resta un unico file
+        let mut writer = TarZstWriter::create(&path, 3, &[], None, Some(1 << 30)).unwrap();
+        writer.append_file(&file, "foto.jpg", &fs::metadata(&file).unwrap()).unwrap();
+        assert_eq!(writer.finish().unwrap(), vec![path.clone()]);
+        assert_eq!(split_parts(&path), vec![path]);
+    }
+}
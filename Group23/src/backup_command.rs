@@ -3,6 +3,7 @@ use device_query::{DeviceQuery, DeviceState}; //device_query: libreria per chied
 use rdev::display_size;            //rdev: libreria per sentire/inviare eventi a tastiera/mouse su Windows, Mac, Linux
 use std::sync::mpsc::{self};
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use crate::cpu_governor::throttle;
 
 pub fn first_command(stop_flag: Arc<AtomicBool>) -> mpsc::Receiver<bool> {
     let (sender, receiver) = mpsc::channel();
@@ -26,6 +27,7 @@ pub fn first_command(stop_flag: Arc<AtomicBool>) -> mpsc::Receiver<bool> {
             }
 
             thread::sleep(std::time::Duration::from_millis(10));
+            throttle(); //pausa più lunga se il tool supera il limite di CPU mentre aspetta le gesture
 
             let mouse = device_state.get_mouse(); //per ottenere le coordinate del mouse e lo stato dei bottoni del mouse
             let coordinates = mouse.coords;
@@ -98,6 +100,7 @@ pub fn second_command() -> mpsc::Receiver<bool> {
         let mut end: (i32, i32);
         loop {
             thread::sleep(std::time::Duration::from_millis(10));
+            throttle();
             let mouse = device_state.get_mouse();
             let coordinates = mouse.coords;
             if mouse.button_pressed[1]{
@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::cpu_governor::throttle;
+use crate::bandwidth::limit;
+
+pub const INDEX_FILE: &str = "backup_index.txt";     // elenco dei file che compongono il backup, scritto al termine del backup
+pub const DELETED_FILE: &str = "backup_deleted.txt"; // elenco dei file presenti nel backup precedente ma non più nella sorgente
+pub const INCOMPLETE_SUFFIX: &str = ".incomplete";   // suffisso delle cartelle in cui viene costruito uno snapshot non ancora completato
+
+/***
+IndexEntry: informazioni su un file del backup
+    size: dimensione del file sorgente in byte
+    mtime: data di ultima modifica del file sorgente (nanosecondi dal 1/1/1970)
+    hash: hash BLAKE3 del contenuto, se calcolato
+    location: percorso (relativo alla radice del disco esterno) del backup che contiene la copia del file
+    stored: percorso della copia nel backup, se diverso dal percorso relativo (nome modificato per il file system della destinazione, vedi fs_compat.rs)
+***/
+#[derive(Clone, Debug)]
+pub struct IndexEntry {
+    pub size: u64,
+    pub mtime: u128,
+    pub hash: Option<String>,
+    pub location: String,
+    pub stored: Option<String>,
+}
+
+impl IndexEntry {
+    /***
+    stored_path: percorso della copia del file all'interno del backup indicato da location
+        relative: percorso relativo del file (chiave dell'indice)
+    ***/
+    pub fn stored_path<'a>(&'a self, relative: &'a str) -> &'a str {
+        self.stored.as_deref().unwrap_or(relative)
+    }
+}
+
+/***
+BackupIndex: indice di un backup, salvato nel file INDEX_FILE all'interno della cartella del backup.
+    Un backup incrementale contiene solo i file nuovi o modificati, ma il suo indice elenca tutti i file della sorgente:
+    per i file invariati, location indica il backup precedente che ne contiene la copia.
+    source: percorso sorgente del backup
+    created: data di creazione del backup (formato %Y%m%d%H%M%S%3f, UTC)
+    format: formato del backup ("directory", "tar.zst" o "zip"); solo i backup "directory" possono essere la base di un backup incrementale o snapshot
+    partial: true se il backup contiene solo una parte dei file della sorgente (backup d'emergenza interrotto dall'opzione "time_limit",
+        o ridotto allo spazio della destinazione dall'opzione "best_fit");
+        un backup parziale non viene contato dalle regole di conservazione (vedi retention.rs)
+    files: mappa percorso relativo -> informazioni sul file
+***/
+pub struct BackupIndex {
+    pub source: String,
+    pub created: String,
+    pub format: String,
+    pub partial: bool,
+    pub files: BTreeMap<String, IndexEntry>,
+}
+
+impl BackupIndex {
+    pub fn new(source: &str, created: &str) -> Self {
+        BackupIndex { source: source.to_string(), created: created.to_string(), format: "directory".to_string(), partial: false, files: BTreeMap::new() }
+    }
+
+    /***
+    load: legge l'indice di un backup
+        dir: cartella del backup
+        -> restituisce: l'indice letto
+    ***/
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let reader = io::BufReader::new(File::open(dir.join(INDEX_FILE))?);
+        let mut index = BackupIndex::new("", "");
+        let mut columns = 5; // gli indici senza "# version=2" non hanno la colonna stored
+
+        for line in reader.lines() {
+            let line = line?;
+            if let Some(header) = line.strip_prefix("# ") { // intestazione: "# chiave=valore"
+                if let Some((key, value)) = header.split_once('=') {
+                    match key {
+                        "source" => index.source = value.to_string(),
+                        "created" => index.created = value.to_string(),
+                        "format" => index.format = value.to_string(),
+                        "partial" => index.partial = value == "true",
+                        "version" => columns = if value == "1" { 5 } else { 6 },
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+
+            // riga di un file: dimensione, mtime, hash, location, (percorso nel backup) e percorso relativo separati da tabulazione
+            let parts: Vec<&str> = line.splitn(columns, '\t').collect();
+            if parts.len() != columns {
+                continue;
+            }
+            let (size, mtime) = match (parts[0].parse(), parts[1].parse()) {
+                (Ok(s), Ok(m)) => (s, m),
+                _ => continue,
+            };
+            let hash = if parts[2] == "-" { None } else { Some(parts[2].to_string()) };
+            let stored = if columns == 6 && parts[4] != "-" { Some(parts[4].to_string()) } else { None };
+            index.files.insert(parts[columns - 1].to_string(), IndexEntry { size, mtime, hash, location: parts[3].to_string(), stored });
+        }
+
+        Ok(index)
+    }
+
+    /***
+    save: scrive l'indice nella cartella del backup
+        dir: cartella del backup
+    ***/
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let mut file = io::BufWriter::new(File::create(dir.join(INDEX_FILE))?);
+        writeln!(file, "# version=2")?;
+        writeln!(file, "# source={}", self.source)?;
+        writeln!(file, "# created={}", self.created)?;
+        writeln!(file, "# format={}", self.format)?;
+        if self.partial {
+            writeln!(file, "# partial=true")?;
+        }
+        for (path, entry) in &self.files {
+            writeln!(file, "{}\t{}\t{}\t{}\t{}\t{}", entry.size, entry.mtime, entry.hash.as_deref().unwrap_or("-"), entry.location, entry.stored.as_deref().unwrap_or("-"), path)?;
+        }
+        file.flush()
+    }
+
+    /***
+    is_unchanged: verifica se un file è invariato rispetto a questo indice e se la sua copia è ancora presente sul disco esterno
+        relative: percorso relativo del file
+        size, mtime: dimensione e data di modifica attuali del file sorgente
+        hash: hash attuale del file sorgente (se calcolato)
+        backup_root: radice del disco esterno
+        -> restituisce: la voce dell'indice se il file è invariato, altrimenti None
+    ***/
+    pub fn is_unchanged(&self, relative: &str, size: u64, mtime: u128, hash: Option<&str>, backup_root: &Path) -> Option<&IndexEntry> {
+        let entry = self.files.get(relative)?;
+        if entry.size != size || entry.mtime != mtime {
+            return None;
+        }
+        if let Some(h) = hash {
+            if entry.hash.as_deref() != Some(h) {
+                return None;
+            }
+        }
+        if !backup_root.join(&entry.location).join(entry.stored_path(relative)).is_file() {
+            return None;
+        }
+        Some(entry)
+    }
+
+    /***
+    known_hash: hash registrato in questo indice per un file invariato (stessa dimensione e data di modifica), anche se la sua copia
+        non è più sul disco esterno: la copia appena scritta di un file invariato ha lo stesso contenuto
+        relative: percorso relativo del file
+        size, mtime: dimensione e data di modifica attuali del file sorgente
+    ***/
+    pub fn known_hash(&self, relative: &str, size: u64, mtime: u128) -> Option<&str> {
+        self.files.get(relative).filter(|e| e.size == size && e.mtime == mtime).and_then(|e| e.hash.as_deref())
+    }
+}
+
+/***
+list_backups: funzione per elencare i backup completi della stessa sorgente presenti sul disco esterno
+    backup_root: radice del disco esterno (cartella che contiene i backup)
+    source: percorso sorgente del backup
+    -> restituisce: i percorsi e gli indici dei backup trovati, dal più recente al meno recente
+***/
+pub fn list_backups(backup_root: &Path, source: &str) -> Vec<(PathBuf, BackupIndex)> {
+    let mut backups = Vec::new();
+
+    let entries = match fs::read_dir(backup_root) {
+        Ok(e) => e,
+        Err(_) => return backups,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.join(INDEX_FILE).is_file() || entry.file_name().to_string_lossy().ends_with(INCOMPLETE_SUFFIX) { // i backup senza indice (o snapshot non completati) non vengono considerati
+            continue;
+        }
+        if let Ok(index) = BackupIndex::load(&path) {
+            if index.source == source {
+                backups.push((path, index));
+            }
+        }
+    }
+
+    backups.sort_by(|(_, a), (_, b)| b.created.cmp(&a.created));
+    backups
+}
+
+/***
+find_previous_backup: funzione per trovare il backup più recente della stessa sorgente sul disco esterno
+    backup_root: radice del disco esterno (cartella che contiene i backup)
+    source: percorso sorgente del backup
+    -> restituisce: il percorso e l'indice del backup più recente, None se non ne esistono
+***/
+pub fn find_previous_backup(backup_root: &Path, source: &str) -> Option<(PathBuf, BackupIndex)> {
+    list_backups(backup_root, source).into_iter().next()
+}
+
+/***
+save_deleted: funzione per registrare i file presenti nel backup precedente ma non più nella sorgente (non vengono eliminati dai backup precedenti)
+    dir: cartella del backup
+    previous: indice del backup precedente
+    current: indice del backup corrente
+    failed: percorsi dei file e delle cartelle che non è stato possibile copiare (non sono stati eliminati dalla sorgente)
+    -> restituisce: il numero di file eliminati dalla sorgente
+***/
+pub fn save_deleted(dir: &Path, previous: &BackupIndex, current: &BackupIndex, failed: &[&str]) -> io::Result<usize> {
+    let is_failed = |p: &str| failed.iter().any(|f| p == *f || p.strip_prefix(*f).is_some_and(|rest| rest.starts_with('/')));
+    let deleted: Vec<&String> = previous.files.keys().filter(|p| !current.files.contains_key(*p) && !is_failed(p)).collect();
+    if deleted.is_empty() {
+        return Ok(0);
+    }
+
+    let mut file = File::create(dir.join(DELETED_FILE))?;
+    for path in &deleted {
+        writeln!(file, "{}", path)?;
+    }
+    Ok(deleted.len())
+}
+
+/***
+relative_key: funzione per ottenere la chiave dell'indice di un file (percorso relativo alla sorgente, con separatore '/')
+    path: percorso del file
+    src_root: percorso della sorgente
+***/
+pub fn relative_key(path: &Path, src_root: &Path) -> String {
+    let relative = path.strip_prefix(src_root).unwrap_or(path);
+    relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/***
+check_relative_key: funzione per verificare un percorso relativo letto da un file del backup (indice, nomi cifrati, metadati) prima di unirlo
+    a una cartella: un backup manomesso non deve poter leggere o scrivere fuori dalla cartella. Sono ammessi solo nomi normali separati
+    da '/' (niente "..", ".", radice o lettera di unità); su Windows, dove sono separatori o indicano un'unità, anche '\' e ':' non sono ammessi
+    key: percorso relativo
+    -> restituisce: Err(InvalidData) se il percorso non è valido
+***/
+pub fn check_relative_key(key: &str) -> io::Result<()> {
+    let valid = key.split('/').all(|c| !c.is_empty() && c != ".")
+        && !(cfg!(windows) && key.contains(['\\', ':']))
+        && Path::new(key).components().all(|c| matches!(c, Component::Normal(_)));
+    match valid {
+        true => Ok(()),
+        false => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Percorso non valido nel backup: '{}'", key))),
+    }
+}
+
+/***
+modified_nanos: funzione per ottenere la data di ultima modifica di un file in nanosecondi dal 1/1/1970
+    metadata: metadati del file
+***/
+pub fn modified_nanos(metadata: &fs::Metadata) -> u128 {
+    metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+/***
+copy_file_hashed: funzione per copiare un file calcolando, durante la copia, l'hash BLAKE3 del contenuto
+    src: percorso del file sorgente
+    dst: percorso del file di destinazione
+    -> restituisce: il numero di byte copiati e l'hash in formato esadecimale
+***/
+pub fn copy_file_hashed(src: &Path, dst: &Path) -> io::Result<(u64, String)> {
+    let mut input = File::open(src)?;
+    let mut output = io::BufWriter::new(File::create(dst)?);
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        output.write_all(&buffer[..n])?;
+        copied += n as u64;
+        throttle();
+        limit(n as u64);
+    }
+    output.flush()?;
+    output.get_ref().set_permissions(input.metadata()?.permissions())?; // come fs::copy, vengono copiati anche i permessi
+    Ok((copied, hasher.finalize().to_hex().to_string()))
+}
+
+/***
+hash_file: funzione per calcolare l'hash BLAKE3 del contenuto di un file
+    path: percorso del file
+    -> restituisce: l'hash in formato esadecimale
+***/
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        throttle(); // il calcolo dell'hash rispetta il limite di CPU del backup (vedi cpu_governor)
+        limit(n as u64);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
@@ -0,0 +1,403 @@
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::time::Duration;
+use crate::retention::RetentionPolicy;
+use crate::archive::DEFAULT_STORE_EXTENSIONS;
+use crate::encryption::EncryptionOptions;
+use crate::special_files::SpecialFilesPolicy;
+use crate::copy_pool::MAX_WORKERS;
+use crate::cpu_governor::{DEFAULT_LISTENING_LIMIT, DEFAULT_BACKUP_LIMIT};
+use crate::priority::{PriorityRule, PriorityRules};
+
+/***
+BackupMode: modalità di esecuzione del backup
+    Full: vengono copiati tutti i file della sorgente
+    Incremental: vengono copiati solo i file nuovi o modificati rispetto all'ultimo backup presente sul disco esterno
+    Snapshot: ogni backup contiene l'intero albero dei file, ma i file invariati sono hard link ai file dello snapshot precedente
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BackupMode {
+    Full,
+    Incremental,
+    Snapshot,
+}
+
+/***
+OutputFormat: formato in cui viene scritto il backup
+    Directory: i file vengono copiati nella cartella del backup mantenendo la struttura della sorgente
+    TarZst: i file vengono scritti in un unico archivio tar compresso con zstd (archive::TAR_ZST_FILE) nella cartella del backup
+    Zip: i file vengono scritti in un archivio zip (archive::ZIP_FILE), eventualmente diviso in volumi, apribile su qualsiasi computer
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputFormat {
+    Directory,
+    TarZst,
+    Zip,
+}
+
+impl OutputFormat {
+    /***
+    name: nome del formato, usato nel file di configurazione, nell'indice e nel manifest del backup
+    ***/
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Directory => "directory",
+            OutputFormat::TarZst => "tar.zst",
+            OutputFormat::Zip => "zip",
+        }
+    }
+}
+
+/***
+BackupOptions: opzioni avanzate del backup, lette dalle righe "chiave=valore" del file di configurazione
+    mode: modalità di backup (chiave "mode", valori "full", "incremental" o "snapshot")
+    hash: se true, in modalità incrementale e snapshot il contenuto dei file viene confrontato anche tramite hash (chiave "hash", valori "true" o "false")
+    name_template: modello del nome della cartella del backup (chiave "name_template"): formato strftime più i segnaposto {source}, {host} e {profile}
+    profile: nome del profilo di backup, usabile nel modello del nome (chiave "profile")
+    retention: politica di conservazione dei backup precedenti (chiavi "keep_last", "keep_daily", "keep_weekly", "keep_monthly", "max_size")
+    format: formato del backup (chiave "format", valori "directory", "tar.zst" o "zip"); un archivio è sempre un backup completo
+    compression_level: livello di compressione zstd dell'archivio (chiave "compression_level", default 3)
+    store_extensions: estensioni dei file già compressi, inseriti nell'archivio senza comprimerli (chiave "store_extensions", separate da spazi o virgole)
+    volume_size: dimensione massima di un volume dell'archivio zip (chiave "volume_size", es. "4G"); su FAT32 l'archivio viene comunque diviso in volumi da 4 GB
+    encryption: opzioni di cifratura del backup (chiavi "passphrase", "recipient", "encrypt_names"); un backup cifrato è sempre completo
+    parity: percentuale di ridondanza dei dati di parità Reed-Solomon scritti nel backup (chiave "parity", da 0 a 100; 0 = nessun dato di parità)
+    preserve_metadata: conserva date, permessi, proprietario, attributi estesi e collegamenti simbolici dei file (chiave "preserve_metadata", solo nel formato "directory")
+    special_files: cosa fare di named pipe, socket e dispositivi presenti nella sorgente (chiave "special_files", valori "skip" o "record")
+    salvage: modalità salvataggio per una sorgente danneggiata: letture ripetute, zone illeggibili riempite di zeri, file piccoli e documenti copiati per primi (chiave "salvage")
+    workers: numero di file copiati in parallelo (chiave "workers", da 1 a copy_pool::MAX_WORKERS); None ("auto") = scelto in base ai dischi di sorgente e destinazione
+    cpu_limit_idle: limite di CPU del tool mentre aspetta le gesture, in % della capacità totale (chiave "cpu_limit_idle", da 1 a 100; 100 = nessun limite)
+    cpu_limit_backup: limite di CPU del tool durante il backup, in % della capacità totale (chiave "cpu_limit_backup", da 1 a 100; 100 = nessun limite)
+    bandwidth_limit: banda massima della copia in byte al secondo (chiave "bandwidth_limit", in MB/s, es. "20" o "512K"; 0 = nessun limite)
+    bandwidth_burst: byte copiati senza pause dopo un periodo di inattività (chiave "bandwidth_burst", in MB, es. "64"); None = quelli di un secondo
+    priority: ordine di copia dei file (chiavi "priority", "priority_paths", "priority_recent_days", "priority_small_size"), vedi priority::PriorityRules;
+        in modalità salvataggio, senza la chiave "priority", vengono copiati per primi i file piccoli e poi i documenti
+    time_limit: tempo a disposizione per un backup d'emergenza (chiave "time_limit", es. "3m", "90s"; senza suffisso in minuti), vedi time_budget::TimeBudget;
+        senza la chiave "priority" i file vengono copiati con le regole "critical,documents,recent,small"
+    best_fit: se lo spazio della destinazione non basta, invece di annullare il backup vengono copiati i file più importanti che ci
+        stanno (chiave "best_fit"), vedi space_budget::SpaceBudget; senza la chiave "priority" valgono le regole di time_limit
+    idle_io: su Linux il backup usa la classe di I/O "idle", cioè il disco solo quando nessun altro processo lo usa (chiave "idle_io")
+***/
+#[derive(Clone, Debug)]
+pub struct BackupOptions {
+    pub mode: BackupMode,
+    pub hash: bool,
+    pub name_template: String,
+    pub profile: String,
+    pub retention: RetentionPolicy,
+    pub format: OutputFormat,
+    pub compression_level: i32,
+    pub store_extensions: Vec<String>,
+    pub volume_size: Option<u64>,
+    pub encryption: EncryptionOptions,
+    pub parity: u8,
+    pub preserve_metadata: bool,
+    pub special_files: SpecialFilesPolicy,
+    pub salvage: bool,
+    pub workers: Option<usize>,
+    pub cpu_limit_idle: u8,
+    pub cpu_limit_backup: u8,
+    pub bandwidth_limit: Option<u64>,
+    pub bandwidth_burst: Option<u64>,
+    pub idle_io: bool,
+    pub priority: PriorityRules,
+    pub time_limit: Option<Duration>,
+    pub best_fit: bool,
+}
+
+pub const DEFAULT_NAME_TEMPLATE: &str = "{source}_backup_%Y%m%d%H%M%S";
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        BackupOptions {
+            mode: BackupMode::Full,
+            hash: false,
+            name_template: DEFAULT_NAME_TEMPLATE.to_string(),
+            profile: "default".to_string(),
+            retention: RetentionPolicy::default(),
+            format: OutputFormat::Directory,
+            compression_level: 3,
+            store_extensions: DEFAULT_STORE_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            volume_size: None,
+            encryption: EncryptionOptions::default(),
+            parity: 0,
+            preserve_metadata: false,
+            special_files: SpecialFilesPolicy::Skip,
+            salvage: false,
+            workers: None,
+            cpu_limit_idle: DEFAULT_LISTENING_LIMIT,
+            cpu_limit_backup: DEFAULT_BACKUP_LIMIT,
+            bandwidth_limit: None,
+            bandwidth_burst: None,
+            idle_io: false,
+            priority: PriorityRules::default(),
+            time_limit: None,
+            best_fit: false,
+        }
+    }
+}
+
+/***
+is_option_line: funzione per riconoscere le righe del file di configurazione che contengono un'opzione
+    line: riga del file di configurazione
+    -> restituisce: true se la riga è nel formato "chiave=valore"
+***/
+pub fn is_option_line(line: &str) -> bool {
+    line.contains('=')
+}
+
+/***
+get_options: funzione per leggere le opzioni avanzate dal file di configurazione (dalla seconda riga in poi)
+    filename: nome del file di configurazione
+    -> restituisce: le opzioni lette; le opzioni assenti o non valide assumono il valore di default
+***/
+pub fn get_options(filename: &str) -> io::Result<BackupOptions> {
+    let file = File::open(filename)?;
+    let reader = io::BufReader::new(file);
+    let mut options = BackupOptions::default();
+
+    for line in reader.lines().skip(1) {  // Salta la prima riga (origine del backup)
+        let line = line?;
+        if !is_option_line(&line) {
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').unwrap();
+        let (key, value) = (key.trim(), value.trim());
+
+        match key {
+            "mode" => match value {
+                "full" => options.mode = BackupMode::Full,
+                "incremental" => options.mode = BackupMode::Incremental,
+                "snapshot" => options.mode = BackupMode::Snapshot,
+                _ => println!("Valore '{}' non valido per l'opzione 'mode', verrà usato il valore di default.", value),
+            },
+            "hash" => match parse_bool(value) {
+                Some(b) => options.hash = b,
+                None => println!("Valore '{}' non valido per l'opzione 'hash', verrà usato il valore di default.", value),
+            },
+            "name_template" if !value.is_empty() => options.name_template = value.to_string(),
+            "profile" if !value.is_empty() => options.profile = value.to_string(),
+            "keep_last" | "keep_daily" | "keep_weekly" | "keep_monthly" => match value.parse::<usize>() {
+                Ok(n) => {
+                    let retention = &mut options.retention;
+                    match key {
+                        "keep_last" => retention.keep_last = Some(n),
+                        "keep_daily" => retention.keep_daily = Some(n),
+                        "keep_weekly" => retention.keep_weekly = Some(n),
+                        _ => retention.keep_monthly = Some(n),
+                    }
+                }
+                Err(_) => println!("Valore '{}' non valido per l'opzione '{}', verrà ignorata.", value, key),
+            },
+            "max_size" => match parse_size(value) {
+                Some(s) => options.retention.max_size = Some(s),
+                None => println!("Valore '{}' non valido per l'opzione 'max_size', verrà ignorata.", value),
+            },
+            "format" => match value {
+                "directory" => options.format = OutputFormat::Directory,
+                "tar.zst" => options.format = OutputFormat::TarZst,
+                "zip" => options.format = OutputFormat::Zip,
+                _ => println!("Valore '{}' non valido per l'opzione 'format', verrà usato il valore di default.", value),
+            },
+            "volume_size" => match parse_size(value) {
+                Some(s) if s > 0 => options.volume_size = Some(s),
+                _ => println!("Valore '{}' non valido per l'opzione 'volume_size', verrà ignorata.", value),
+            },
+            "compression_level" => match value.parse::<i32>() {
+                Ok(level) if zstd::compression_level_range().contains(&level) => options.compression_level = level,
+                _ => println!("Valore '{}' non valido per l'opzione 'compression_level', verrà usato il valore di default.", value),
+            },
+            "passphrase" if !value.is_empty() => options.encryption.passphrase = Some(value.to_string()),
+            "recipient" if !value.is_empty() => options.encryption.recipients.push(value.to_string()),
+            "encrypt_names" => match parse_bool(value) {
+                Some(b) => options.encryption.encrypt_names = b,
+                None => println!("Valore '{}' non valido per l'opzione 'encrypt_names', verrà usato il valore di default.", value),
+            },
+            "parity" => match value.trim_end_matches('%').parse::<u8>() {
+                Ok(p) if p <= 100 => options.parity = p,
+                _ => println!("Valore '{}' non valido per l'opzione 'parity', verrà usato il valore di default.", value),
+            },
+            "preserve_metadata" => match parse_bool(value) {
+                Some(b) => options.preserve_metadata = b,
+                None => println!("Valore '{}' non valido per l'opzione 'preserve_metadata', verrà usato il valore di default.", value),
+            },
+            "special_files" => match value {
+                "skip" => options.special_files = SpecialFilesPolicy::Skip,
+                "record" => options.special_files = SpecialFilesPolicy::Record,
+                _ => println!("Valore '{}' non valido per l'opzione 'special_files', verrà usato il valore di default.", value),
+            },
+            "salvage" => match parse_bool(value) {
+                Some(b) => options.salvage = b,
+                None => println!("Valore '{}' non valido per l'opzione 'salvage', verrà usato il valore di default.", value),
+            },
+            "workers" => match value {
+                "auto" => options.workers = None,
+                _ => match value.parse::<usize>() {
+                    Ok(n) if (1..=MAX_WORKERS).contains(&n) => options.workers = Some(n),
+                    _ => println!("Valore '{}' non valido per l'opzione 'workers', verrà usato il valore di default.", value),
+                },
+            },
+            "cpu_limit_idle" | "cpu_limit_backup" => match value.trim_end_matches('%').parse::<u8>() {
+                Ok(p) if (1..=100).contains(&p) => match key {
+                    "cpu_limit_idle" => options.cpu_limit_idle = p,
+                    _ => options.cpu_limit_backup = p,
+                },
+                _ => println!("Valore '{}' non valido per l'opzione '{}', verrà usato il valore di default.", value, key),
+            },
+            "bandwidth_limit" => match parse_rate(value) {
+                Some(0) => options.bandwidth_limit = None,
+                Some(r) => options.bandwidth_limit = Some(r),
+                None => println!("Valore '{}' non valido per l'opzione 'bandwidth_limit', verrà ignorata.", value),
+            },
+            "bandwidth_burst" => match parse_rate(value) {
+                Some(b) if b > 0 => options.bandwidth_burst = Some(b),
+                _ => println!("Valore '{}' non valido per l'opzione 'bandwidth_burst', verrà usato il valore di default.", value),
+            },
+            "idle_io" => match parse_bool(value) {
+                Some(b) => options.idle_io = b,
+                None => println!("Valore '{}' non valido per l'opzione 'idle_io', verrà usato il valore di default.", value),
+            },
+            "priority" => {
+                let rules: Vec<&str> = value.split(|c: char| c == ',' || c.is_whitespace()).filter(|r| !r.is_empty() && *r != "none").collect();
+                match rules.iter().map(|r| PriorityRule::parse(r)).collect::<Option<Vec<_>>>() {
+                    Some(rules) => options.priority.rules = rules,
+                    None => println!("Valore '{}' non valido per l'opzione 'priority' (regole: critical, documents, recent, small), verrà ignorata.", value),
+                }
+            }
+            "priority_paths" => {
+                options.priority.critical = value.split(',')
+                    .map(|p| p.trim().replace('\\', "/").trim_matches('/').to_string())
+                    .filter(|p| !p.is_empty())
+                    .collect();
+            }
+            "priority_recent_days" => match value.parse::<u64>() {
+                Ok(d) if d > 0 => options.priority.recent_days = d,
+                _ => println!("Valore '{}' non valido per l'opzione 'priority_recent_days', verrà usato il valore di default.", value),
+            },
+            "priority_small_size" => match parse_size(value) {
+                Some(s) => options.priority.small_size = s,
+                None => println!("Valore '{}' non valido per l'opzione 'priority_small_size', verrà usato il valore di default.", value),
+            },
+            "time_limit" => match value {
+                "none" | "0" => options.time_limit = None,
+                _ => match parse_duration(value) {
+                    Some(d) => options.time_limit = Some(d),
+                    None => println!("Valore '{}' non valido per l'opzione 'time_limit', verrà ignorata.", value),
+                },
+            },
+            "best_fit" => match parse_bool(value) {
+                Some(b) => options.best_fit = b,
+                None => println!("Valore '{}' non valido per l'opzione 'best_fit', verrà usato il valore di default.", value),
+            },
+            "store_extensions" => {
+                options.store_extensions = value.split(|c: char| c == ',' || c.is_whitespace())
+                    .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                    .filter(|e| !e.is_empty())
+                    .collect();
+            }
+            _ => println!("Opzione '{}' sconosciuta, verrà ignorata.", key),
+        }
+    }
+
+    if options.encryption.is_enabled() && options.format == OutputFormat::Zip {
+        println!("Il formato 'zip' non supporta la cifratura, verrà usato il formato 'tar.zst'.");
+        options.format = OutputFormat::TarZst;
+    }
+    if options.encryption.is_enabled() && options.mode != BackupMode::Full {
+        println!("Un backup cifrato è sempre completo, l'opzione 'mode' verrà ignorata.");
+        options.mode = BackupMode::Full;
+    }
+    if options.preserve_metadata && options.encryption.is_enabled() {
+        println!("Un backup cifrato non conserva i metadati dei file, l'opzione 'preserve_metadata' verrà ignorata.");
+        options.preserve_metadata = false;
+    }
+    if options.special_files == SpecialFilesPolicy::Record && options.encryption.is_enabled() {
+        println!("Un backup cifrato non registra i file speciali (l'elenco ne rivelerebbe i nomi), verranno ignorati.");
+        options.special_files = SpecialFilesPolicy::Skip;
+    }
+    if options.preserve_metadata && options.format != OutputFormat::Directory {
+        println!("Nel formato '{}' date e permessi dei file sono già salvati nell'archivio, l'opzione 'preserve_metadata' verrà ignorata.", options.format.name());
+        options.preserve_metadata = false;
+    }
+    if options.salvage && (options.format != OutputFormat::Directory || options.encryption.is_enabled()) {
+        println!("Nel formato '{}' o con la cifratura le letture non riuscite non vengono ripetute: della modalità salvataggio resta solo l'ordine di copia.", options.format.name());
+    }
+    if options.salvage && !options.priority.is_enabled() {
+        options.priority = options.priority.salvage();
+    }
+    if (options.time_limit.is_some() || options.best_fit) && !options.priority.is_enabled() {
+        options.priority.rules = vec![PriorityRule::Critical, PriorityRule::Documents, PriorityRule::Recent, PriorityRule::Small];
+    }
+    if options.time_limit.is_some() && options.parity > 0 {
+        println!("Con l'opzione 'time_limit' il tempo a disposizione è riservato alla copia dei file, l'opzione 'parity' verrà ignorata.");
+        options.parity = 0;
+    }
+    if !options.priority.critical.is_empty() && !options.priority.rules.contains(&PriorityRule::Critical) {
+        println!("L'opzione 'priority_paths' viene usata solo se 'priority' contiene la regola 'critical', verrà ignorata.");
+    }
+    if options.workers.is_some_and(|n| n > 1) && (options.format != OutputFormat::Directory || options.encryption.is_enabled() || options.salvage) {
+        println!("Nel formato '{}', con la cifratura o in modalità salvataggio i file vengono copiati uno alla volta, l'opzione 'workers' verrà ignorata.", options.format.name());
+        options.workers = Some(1);
+    }
+    if options.format != OutputFormat::Directory && options.mode != BackupMode::Full {
+        println!("Il formato '{}' crea sempre un backup completo, l'opzione 'mode' verrà ignorata.", options.format.name());
+        options.mode = BackupMode::Full;
+    }
+
+    println!("Opzioni del backup: {:?}", options);
+    Ok(options)
+}
+
+/***
+parse_size: funzione per leggere una dimensione in byte, con suffisso opzionale K, M, G o T (multipli di 1024)
+    value: stringa da leggere (es. "500M", "16G")
+***/
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (number, multiplier) = match value.chars().last()? {
+        'K' => (&value[..value.len() - 1], 1u64 << 10),
+        'M' => (&value[..value.len() - 1], 1u64 << 20),
+        'G' => (&value[..value.len() - 1], 1u64 << 30),
+        'T' => (&value[..value.len() - 1], 1u64 << 40),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/***
+parse_rate: funzione per leggere una banda o una dimensione in MB, con suffisso opzionale K, M, G o T come parse_size
+    value: stringa da leggere (es. "20", "20MB/s", "512K")
+    -> restituisce: il numero di byte (al secondo)
+***/
+fn parse_rate(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let value = value.strip_suffix("/s").unwrap_or(value);
+    match value.parse::<u64>() {
+        Ok(mb) => mb.checked_mul(1 << 20),
+        Err(_) => parse_size(value),
+    }
+}
+
+/***
+parse_duration: funzione per leggere una durata con suffisso opzionale s, m o h (senza suffisso: minuti)
+    value: stringa da leggere (es. "3", "90s", "3m", "1h")
+***/
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim().to_lowercase();
+    let (number, unit) = match value.chars().last()? {
+        's' => (&value[..value.len() - 1], 1),
+        'm' => (&value[..value.len() - 1], 60),
+        'h' => (&value[..value.len() - 1], 3600),
+        _ => (value.as_str(), 60),
+    };
+    number.trim().parse::<u64>().ok().filter(|n| *n > 0).map(|n| Duration::from_secs(n * unit))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
@@ -1,21 +1,131 @@
 use std::fs::{self, File}; // modulo per il filesystem
 use std::io::{self, BufRead, Write}; // modulo per l'I/O
-use std::path::{Path};
+use std::fmt::Write as FmtWrite;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use sysinfo::{System, Disks, get_current_pid};
-use chrono::Utc;
+use chrono::{Local, Utc};
 use std::time::{Instant, Duration};
 use std::{env, thread};
 use crate::notification_window::{start_notify};
+use crate::backup_options::{BackupOptions, BackupMode, OutputFormat, is_option_line, DEFAULT_NAME_TEMPLATE};
+use crate::retention::apply_retention;
+use crate::backup_index::{BackupIndex, IndexEntry, INCOMPLETE_SUFFIX, list_backups, save_deleted, relative_key, modified_nanos, hash_file};
+use crate::manifest::{Manifest, MANIFEST_FILE};
+use crate::encryption::BackupCipher;
+use crate::parity::create_parity;
+use crate::fs_compat::{DestinationNames, FileSystemKind, RENAMED_FILE, allocation_unit, copy_file_split};
+use crate::metadata::{MetadataSidecar, METADATA_FILE, copy_metadata, copy_symlink};
+use crate::special_files::{SpecialFiles, SpecialFilesPolicy, SpecialKind, HardLinks, SPECIAL_FILE, link_id, is_sparse, copy_file_sparse};
+use crate::failures::{CopyFailures, is_space_exhausted, remove_partial};
+use crate::journal::{Journal, CHECKPOINT_SIZE, find_interrupted, copy_file_resumable};
+use crate::salvage::copy_file_salvage;
+use crate::copy_pool::{CopyPool, auto_workers};
+use crate::fast_copy::{CopyStrategy, CopyStrategies, copy_file_fast, reflink_hashed};
+use crate::cpu_governor::{self, throttle};
+use crate::bandwidth::{self, IdleIo};
+use crate::time_budget::TimeBudget;
+use crate::space_budget::SpaceBudget;
+use crate::estimator::{SizeEstimator, InsufficientSpace, ESTIMATE_WAIT, available_space};
+use crate::archive::{ArchiveWriter, TarZstWriter, ZipVolumes, TAR_ZST_FILE, max_file_size};
+
+const EXCLUDED_SHOWN: usize = 5; // file esclusi per lo spazio insufficiente elencati nella notifica finale (l'elenco completo è nel log del backup)
+
+/***
+CopyContext: stato condiviso dalle chiamate ricorsive di copy_dir_recursive
+    src_root: Path della sorgente del backup
+    backup_root: Path della radice del disco esterno (cartella che contiene i backup)
+    location: percorso del backup corrente relativo a backup_root
+    options: opzioni del backup
+    previous: indice del backup precedente (solo in modalità incrementale o snapshot)
+    last_index: indice dell'ultimo backup della stessa sorgente, in qualsiasi modalità: l'hash dei file invariati viene preso da qui
+        invece di rileggere la copia clonata o copiata dal kernel (vedi fast_copy::copy_file_fast)
+    index: indice del backup corrente, aggiornato durante la copia
+    unchanged_files: numero di file invariati rispetto al backup precedente (non copiati in modalità incrementale, collegati tramite hard link in modalità snapshot)
+    manifest: manifest del backup corrente, con i file presenti nella cartella del backup
+    archive: archivio in cui vengono scritti i file (solo nei formati "tar.zst" e "zip"); se None i file vengono copiati nella cartella del backup
+    cipher: chiave del backup, se il backup è cifrato
+    names: nomi con cui i file vengono salvati, adattati al file system della destinazione (solo nel formato "directory")
+    metadata: metadati non rappresentabili sulla destinazione e collegamenti simbolici (solo con l'opzione "preserve_metadata")
+    previous_metadata: metadati registrati nel backup precedente, riportati per i file invariati
+    special: file speciali (named pipe, socket, dispositivi) trovati nella sorgente, non copiati
+    hard_links: file con più hard link nella sorgente, collegati anche nel backup (solo nel formato "directory" senza cifratura)
+    sparse_files, sparse_holes: numero di file sparsi copiati e byte delle zone vuote non scritte sulla destinazione
+    failures: file che non è stato possibile copiare (permessi, file eliminati durante il backup, errori di I/O), elencati nel log
+    journal: giornale dei file copiati, per riprendere il backup se viene interrotto (solo nel formato "directory" senza cifratura)
+    priority_pass: classe di file copiata nel passaggio corrente sulla sorgente (vedi priority::PriorityRules), None se i file vengono copiati nell'ordine delle cartelle
+    pool: worker che copiano i file in parallelo alla visita della sorgente (None se i file vengono copiati uno alla volta)
+    folders: cartelle di cui copiare i metadati al termine della copia (percorso relativo, Path sorgente, Path della copia)
+    strategies: numero di file copiati con ogni strategia (reflink, copy_file_range, copia del tool), riportato nel log
+    estimator: stima della dimensione del backup, calcolata durante la copia, con cui viene verificato lo spazio libero della destinazione
+    budget: tempo a disposizione del backup (opzione "time_limit"), None se non c'è un limite
+    space: spazio della destinazione a disposizione dei file (opzione "best_fit"); se presente lo spazio non viene verificato da estimator
+***/
+struct CopyContext<'a> {
+    src_root: &'a Path,
+    backup_root: &'a Path,
+    location: String,
+    options: &'a BackupOptions,
+    previous: Option<Arc<BackupIndex>>,
+    last_index: Option<Arc<BackupIndex>>,
+    index: BackupIndex,
+    unchanged_files: usize,
+    manifest: Manifest,
+    archive: Option<ArchiveWriter>,
+    cipher: Option<BackupCipher>,
+    names: DestinationNames,
+    metadata: Option<MetadataSidecar>,
+    previous_metadata: MetadataSidecar,
+    special: SpecialFiles,
+    hard_links: HardLinks,
+    sparse_files: usize,
+    sparse_holes: u64,
+    failures: CopyFailures,
+    journal: Option<Journal>,
+    priority_pass: Option<u8>,
+    pool: Option<CopyPool<PendingCopy, CopiedData>>,
+    folders: Vec<(String, PathBuf, PathBuf)>,
+    strategies: CopyStrategies,
+    estimator: SizeEstimator,
+    budget: Option<TimeBudget>,
+    space: Option<SpaceBudget>,
+}
+
+/***
+CopiedData: risultato della scrittura di un file nel backup (vedi copy_file_data): hash del contenuto, true se il file è stato diviso in parti
+    e strategia con cui è stato copiato (None se è stato scritto nell'archivio, cifrato o riutilizzato da un backup interrotto)
+***/
+type CopiedData = (String, bool, Option<CopyStrategy>);
+
+/***
+PendingCopy: file da registrare nell'indice, nel manifest e nel giornale al termine della copia (eseguita da un worker o dal thread del backup)
+    path: Path del file sorgente
+    key: percorso relativo del file
+    dest_path: Path della copia
+    stored: percorso del file nel backup, se diverso da key
+    metadata: metadati del file sorgente
+    link: identificativo (dispositivo, inode) del file, se ha più hard link nella sorgente
+***/
+struct PendingCopy {
+    path: PathBuf,
+    key: String,
+    dest_path: PathBuf,
+    stored: Option<String>,
+    metadata: fs::Metadata,
+    link: Option<(u64, u64)>,
+}
 
 /***
 copy_dir: copia nel percorso di destinazione il contenuto del percorso sorgente (funzione wrapper di copy_dir_recursive)
     src: stringa del percorso sorgente
     dst: stringa del percorso di destinazione (se la destinazione è una cartella non vuota viene creata una cartella)
     extensions: vettore di stringhe delle estensioni dei file da copiare, se è vuoto vengono copiati tutti i file
+    options: opzioni avanzate del backup (modalità incrementale o snapshot, hash, formato)
     -> restituisce: Ok(dimensione) se l'operazione è andata a buon fine
 ***/
-pub fn copy_dir(src: &str, dst: &str, extensions: Vec<String>) -> io::Result<i64> {
+pub fn copy_dir(src: &str, dst: &str, extensions: Vec<String>, options: &BackupOptions) -> io::Result<i64> {
     let start = Instant::now();
+    let budget = options.time_limit.map(TimeBudget::new); //il tempo a disposizione comprende la preparazione del backup
     let src_path = Path::new(src);
     let dst_path = Path::new(dst);
 
@@ -26,15 +136,425 @@ pub fn copy_dir(src: &str, dst: &str, extensions: Vec<String>) -> io::Result<i64
         return Ok(-1);
     }
 
-    let (total_files, total_size) = count_files_and_size(src_path, &extensions)?;
+    let backup_root = dst_path.parent().unwrap_or(dst_path); //cartella che contiene i backup (radice del disco esterno)
+
+    //prima di iniziare il nuovo backup vengono eliminati i backup precedenti che non rispettano la politica di conservazione
+    let removed = apply_retention(backup_root, src, options)?;
+    if !removed.is_empty() {
+        println!("{} backup precedenti eliminati secondo la politica di conservazione.", removed.len());
+    }
+
+    //in modalità incrementale e snapshot si cerca l'ultimo backup completo della stessa sorgente sul disco esterno (gli archivi non possono essere usati come base)
+    let previous = match options.mode {
+        BackupMode::Incremental | BackupMode::Snapshot => list_backups(backup_root, src).into_iter().find(|(_, index)| index.format == OutputFormat::Directory.name()),
+        BackupMode::Full => None,
+    };
+    match &previous {
+        Some((path, _)) if options.mode == BackupMode::Snapshot => println!("Snapshot basato su '{}'.", path.to_string_lossy()),
+        Some((path, _)) => println!("Backup incrementale rispetto a '{}'.", path.to_string_lossy()),
+        None if options.mode != BackupMode::Full => println!("Nessun backup precedente trovato, verranno copiati tutti i file."),
+        None => {}
+    }
+    let previous_metadata = match &previous {
+        Some((path, _)) if options.preserve_metadata => MetadataSidecar::load(path).unwrap_or_default(),
+        _ => MetadataSidecar::default(),
+    };
+    let previous = previous.map(|(_, index)| Arc::new(index));
+    let last_index = match &previous {
+        Some(index) => Some(Arc::clone(index)),
+        None => list_backups(backup_root, src).into_iter().next().map(|(_, index)| Arc::new(index)),
+    };
+
+    //un backup interrotto della stessa sorgente (computer spento, disco esterno rimosso) viene ripreso dal punto in cui si era fermato,
+    //invece di ricominciare da capo in una nuova cartella. Gli archivi e i backup cifrati ricominciano sempre da capo
+    let journaled = options.format == OutputFormat::Directory && !options.encryption.is_enabled();
+    let interrupted = if journaled { find_interrupted(backup_root, src, options) } else { None };
+    let resumed = match interrupted {
+        Some(path) => match Journal::resume(&path) {
+            Ok(journal) => Some((path, journal)),
+            Err(e) => {
+                println!("Impossibile leggere il giornale del backup interrotto '{}', verrà creato un nuovo backup: {}", path.to_string_lossy(), e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    //la banda del backup può essere limitata, e su Linux il backup può usare il disco solo quando nessun altro processo lo usa:
+    //i thread della stima e della copia, creati dopo, ereditano la priorità di I/O del thread del backup
+    bandwidth::configure(options.bandwidth_limit, options.bandwidth_burst);
+    let idle_io = match options.idle_io {
+        true => IdleIo::enter().inspect_err(|e| println!("Impossibile impostare la priorità di I/O \"idle\": {}", e)).ok(),
+        false => None,
+    };
+
+    //la sorgente viene visitata una sola volta dalla copia, che inizia subito: il numero di file e la dimensione del backup vengono stimati
+    //in parallelo da un altro thread, e lo spazio libero della destinazione viene verificato più volte durante la copia (vedi SizeEstimator)
+    let estimator = SizeEstimator::start(src_path, &extensions, previous.clone(), backup_root, options, dst_path);
+    if let Some((path, _)) = &resumed {
+        println!("Ripresa del backup interrotto '{}'.", path.to_string_lossy());
+    }
+
+    //se la stima termina in poco tempo (sorgente piccola), lo spazio viene verificato prima di creare la cartella del backup
+    let streamed = !estimator.wait(ESTIMATE_WAIT);
+    if !streamed {
+        let mut total_size = estimator.total_size();
+        if let Some((_, journal)) = &resumed {
+            total_size = total_size.saturating_sub(journal.resumed_size()); //i file già copiati sono già sul disco esterno
+        }
+        let available_space = available_space(dst_path)?; //verifica se c'è abbastanza spazio nella destinazione
+        if available_space < total_size && options.best_fit {
+            println!("Spazio insufficiente nella destinazione: verranno copiati solo i file più importanti che ci stanno.");
+        } else if available_space < total_size {
+            println!("Spazio insufficiente nella destinazione. Backup annullato.");
+            let s = format!("Il disco esterno '{}' non ha lo spazio sufficiente per il backup, liberare spazio (almeno {}byte) o rimuovere il disco e riprovare con un altro (con l'opzione 'best_fit' verrebbero copiati solo i file più importanti).", dst, total_size.clone());
+            start_notify("Errore backup: spazio insufficiente", &s);
+            return Ok(-1);
+        }
+    } else {
+        println!("Copia avviata mentre viene calcolata la dimensione della sorgente.");
+    }
 
-    let available_space = get_available_space(dst_path)?; //verifica se c'è abbastanza spazio nella destinazione
-    if available_space < total_size {
-        println!("Spazio insufficiente nella destinazione. Backup annullato.");
-        let s = format!("Il disco esterno '{}' non ha lo spazio sufficiente per il backup, liberare spazio (almeno {}byte) o rimuovere il disco e riprovare con un altro.", dst.to_string(), total_size.clone());
+    let (backup_path, work_path, journal) = match resumed {
+        Some((path, journal)) => {
+            let backup_path = match path.to_string_lossy().strip_suffix(INCOMPLETE_SUFFIX) {
+                Some(name) => PathBuf::from(name), //snapshot interrotto: al termine viene rinominato con il nome definitivo
+                None => path.clone(),
+            };
+            (backup_path, path, Some(journal))
+        }
+        None => {
+            let (backup_path, work_path) = prepare_destination(dst_path, options)?;
+            let journal = if journaled { Some(Journal::create(&work_path, src, options)?) } else { None };
+            (backup_path, work_path, journal)
+        }
+    };
+
+    //con l'opzione "best_fit" i file da copiare vengono scelti in modo da stare nello spazio libero della destinazione
+    //(i file già copiati da un backup interrotto sono già sul disco e vengono riutilizzati)
+    let space = match options.best_fit {
+        true => Some(SpaceBudget::new(available_space(dst_path)? + journal.as_ref().map_or(0, |j| j.resumed_size()), allocation_unit(&work_path), options)),
+        false => None,
+    };
+
+    let mut copied_files = 0;  //contatore dei file copiati
+    let mut copied_size = 0;   //somma della dimensione totale dei file copiati
+
+    //la chiave del backup viene generata e salvata (cifrata con la passphrase o con le chiavi pubbliche configurate) prima di scrivere i file
+    let cipher = if options.encryption.is_enabled() {
+        println!("Il backup verrà cifrato.");
+        Some(BackupCipher::create(&work_path, &options.encryption)?)
+    } else {
+        None
+    };
+
+    let format = options.format.name();
+    let archive = match options.format {
+        OutputFormat::Directory => None,
+        OutputFormat::TarZst => {
+            //se il file system della destinazione non ammette file oltre una certa dimensione (FAT32), l'archivio viene diviso in parti
+            let archive_path = work_path.join(TAR_ZST_FILE);
+            let part_size = max_file_size(dst_path);
+            match part_size {
+                Some(size) => println!("Scrittura dell'archivio '{}' (livello di compressione {}, parti da massimo {} byte).", archive_path.to_string_lossy(), options.compression_level, size),
+                None => println!("Scrittura dell'archivio '{}' (livello di compressione {}).", archive_path.to_string_lossy(), options.compression_level),
+            }
+            Some(ArchiveWriter::TarZst(Box::new(TarZstWriter::create(&archive_path, options.compression_level, &options.store_extensions, cipher.as_ref(), part_size)?)))
+        }
+        OutputFormat::Zip => {
+            //se il file system della destinazione non ammette file oltre una certa dimensione (FAT32), l'archivio viene diviso in volumi
+            let volume_size = match (options.volume_size, max_file_size(dst_path)) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            match volume_size {
+                Some(size) => println!("Scrittura dell'archivio zip in '{}' (volumi da massimo {} byte).", work_path.to_string_lossy(), size),
+                None => println!("Scrittura dell'archivio zip in '{}'.", work_path.to_string_lossy()),
+            }
+            Some(ArchiveWriter::Zip(Box::new(ZipVolumes::create(&work_path, volume_size, &options.store_extensions)?)))
+        }
+    };
+
+    //nel formato "directory" i nomi dei file vengono adattati al file system della destinazione (FAT32, exFAT, NTFS),
+    //negli archivi e con i nomi cifrati vengono salvati senza modifiche
+    let names = match (&archive, &cipher) {
+        (None, Some(c)) if c.encrypts_names() => DestinationNames::with_kind(FileSystemKind::Other, 0),
+        (None, _) => DestinationNames::new(&work_path),
+        (Some(_), _) => DestinationNames::with_kind(FileSystemKind::Other, 0),
+    };
+
+    let mut context = CopyContext {
+        src_root: src_path,
+        backup_root,
+        location: relative_key(&backup_path, backup_root),
+        options,
+        previous,
+        last_index,
+        index: BackupIndex::new(src, &Utc::now().format("%Y%m%d%H%M%S%3f").to_string()),
+        unchanged_files: 0,
+        manifest: Manifest::new(src, &backup_path.to_string_lossy(), &options.profile, &format!("{:?}", options.mode).to_lowercase(), format),
+        archive,
+        cipher,
+        names,
+        metadata: if options.preserve_metadata { Some(MetadataSidecar::default()) } else { None },
+        previous_metadata,
+        special: SpecialFiles::default(),
+        hard_links: HardLinks::default(),
+        sparse_files: 0,
+        sparse_holes: 0,
+        failures: CopyFailures::default(),
+        journal,
+        priority_pass: None,
+        pool: None,
+        folders: Vec::new(),
+        strategies: CopyStrategies::default(),
+        estimator,
+        budget,
+        space,
+    };
+    context.index.format = format.to_string();
+
+    //nel formato "directory" senza cifratura i file vengono copiati da più worker, mentre la visita della sorgente prosegue.
+    //Il numero di worker dipende dai dischi: alto per una sorgente SSD/NVMe, basso per un disco rotativo o una destinazione USB
+    let (workers, disks) = match options.workers {
+        Some(n) => (n, "opzione 'workers'".to_string()),
+        None if journaled && !options.salvage => auto_workers(src_path, &work_path),
+        None => (1, String::new()),
+    };
+    if workers > 1 {
+        println!("Copia parallela con {} worker ({}).", workers, disks);
+        context.pool = Some(CopyPool::new(workers));
+    }
+
+    let copy_start = Instant::now();
+    let copy_result = copy_source(src_path, &work_path, &mut copied_files, &mut copied_size, &extensions, &mut context);
+    let copy_time = copy_start.elapsed();
+    let budget_note = context.budget.as_ref().map(|b| b.note());
+    let space_note = context.space.as_ref().map(|s| s.note());
+    let stopped = context.budget.as_ref().is_some_and(|b| b.stopped());
+    if let Err(e) = copy_result {
+        let Some(space) = e.get_ref().and_then(|e| e.downcast_ref::<InsufficientSpace>()) else {
+            return Err(e);
+        };
+        //la destinazione non basta per i file ancora da copiare: un backup che può essere ripreso resta sul disco esterno,
+        //gli archivi e i backup cifrati (che ricominciano da capo) vengono eliminati
+        if let Some(pool) = context.pool.take() {
+            pool.finish();
+        }
+        println!("Spazio insufficiente nella destinazione ({}). Backup interrotto.", space);
+        let mut s = format!("Il disco esterno '{}' non ha lo spazio sufficiente per il backup, liberare spazio (almeno {}byte) o rimuovere il disco e riprovare con un altro.", dst, space.needed);
+        if context.journal.is_some() {
+            s = format!("{} Il backup interrotto verrà ripreso dallo stesso disco.", s);
+        } else {
+            drop(context.archive.take());
+            fs::remove_dir_all(&work_path)?;
+        }
         start_notify("Errore backup: spazio insufficiente", &s);
         return Ok(-1);
     }
+    //le date delle cartelle vengono copiate per ultime (dalla più interna), perché la scrittura dei file al loro interno le modifica
+    for (key, path, dest_path) in std::mem::take(&mut context.folders) {
+        match path.metadata() {
+            Ok(dir_metadata) => {
+                let record = copy_metadata(&path, &dir_metadata, &dest_path);
+                if let Some(metadata) = context.metadata.as_mut().filter(|_| !record.is_empty()) {
+                    metadata.files.insert(key, record);
+                }
+            }
+            Err(e) => context.failures.record(&key, e)?,
+        }
+    }
+
+    //l'indice viene scritto per ultimo: un backup senza indice è incompleto e non viene usato come base per i backup incrementali
+    let mut notes = Vec::new();
+    if let Some(archive) = context.archive.take() {
+        for file in archive.finish()? {
+            let archive_size = fs::metadata(&file)?.len();
+            notes.push(format!("Archivio: {} ({} byte compressi)", file.file_name().unwrap_or_default().to_string_lossy(), archive_size));
+        }
+    }
+    if context.names.renamed_count() > 0 {
+        context.names.save(&work_path)?;
+        notes.push(format!("File salvati con un nome diverso o divisi in parti per il file system della destinazione ({:?}): {} (elenco in {})", context.names.kind(), context.names.renamed_count(), RENAMED_FILE));
+    }
+    if let Some(metadata) = &context.metadata {
+        metadata.save(&work_path)?;
+        if !metadata.files.is_empty() {
+            notes.push(format!("Metadati non rappresentabili sulla destinazione: {} voci, di cui {} collegamenti simbolici (elenco in {})", metadata.files.len(), metadata.symlink_count(), METADATA_FILE));
+        }
+    }
+    if context.sparse_files > 0 {
+        notes.push(format!("File sparsi copiati senza scrivere le zone vuote: {} ({} byte non scritti)", context.sparse_files, context.sparse_holes));
+    }
+    if context.hard_links.linked > 0 {
+        notes.push(format!("File collegati tramite hard link come nella sorgente: {}", context.hard_links.linked));
+    }
+    if !context.special.files.is_empty() {
+        match options.special_files {
+            SpecialFilesPolicy::Record => {
+                context.special.save(&work_path)?;
+                notes.push(format!("File speciali registrati, ricreati dal comando 'restore': {} (elenco in {})", context.special.files.len(), SPECIAL_FILE));
+            }
+            SpecialFilesPolicy::Skip => notes.push(format!("File speciali ignorati (named pipe, socket, dispositivi): {}", context.special.files.len())),
+        }
+        if context.cipher.is_none() { //in un backup cifrato il log non elenca i nomi dei file
+            for (key, (kind, _, _)) in &context.special.files {
+                notes.push(format!("    {}: {}", kind.name(), key));
+            }
+        }
+    }
+    if !removed.is_empty() {
+        notes.push(format!("Backup precedenti eliminati (politica di conservazione): {}", removed.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join(", ")));
+    }
+    if let Some(previous) = &context.previous {
+        match options.mode {
+            BackupMode::Snapshot => notes.push(format!("File invariati rispetto al backup precedente (collegati tramite hard link): {}", context.unchanged_files)),
+            _ => notes.push(format!("File invariati rispetto al backup precedente (non copiati): {}", context.unchanged_files)),
+        }
+        if stopped { //i file non esaminati non sono nell'indice, ma non sono stati eliminati dalla sorgente
+            notes.push("File eliminati dalla sorgente dopo il backup precedente: non calcolati (visita della sorgente interrotta)".to_string());
+        } else {
+            let deleted = save_deleted(&work_path, previous, &context.index, &context.failures.keys())?;
+            notes.push(format!("File eliminati dalla sorgente dopo il backup precedente: {}", deleted));
+        }
+    }
+    if let Some(note) = budget_note {
+        notes.push(note);
+        if stopped {
+            let total = if context.estimator.is_done() { format!(" (file da copiare nella sorgente: {})", context.estimator.files()) } else { String::new() };
+            notes.push(format!("Visita della sorgente interrotta alla scadenza: i file non ancora esaminati non sono stati salvati{}", total));
+        }
+    }
+    if let Some(note) = space_note {
+        notes.push(note);
+    }
+    if streamed {
+        notes.push(format!("Dimensione della sorgente calcolata durante la copia: {} file, {} byte stimati", context.estimator.files(), context.estimator.total_size()));
+    }
+    if workers > 1 {
+        notes.push(format!("Copia parallela: {} worker ({})", workers, disks));
+    }
+    notes.push(bandwidth::report(copied_size, copy_time));
+    if options.idle_io {
+        notes.push(format!("Priorità di I/O del backup: {}", if idle_io.is_some() { "idle" } else { "normale (impossibile impostare \"idle\")" }));
+    }
+    if let Some(note) = context.strategies.note() {
+        println!("{}.", note);
+        notes.push(note);
+    }
+    if let Some(journal) = context.journal.as_ref().filter(|j| j.is_resumed()) {
+        notes.push(format!("Backup ripreso dopo un'interruzione: {} file già copiati riutilizzati (verificati tramite hash)", journal.reused));
+    }
+    notes.extend(context.failures.notes(context.cipher.is_none())); //in un backup cifrato il log non elenca i nomi dei file
+    if stopped || !context.failures.not_saved.is_empty() || !context.failures.excluded.is_empty() {
+        //backup parziale: la politica di conservazione non lo conta come un backup (vedi retention.rs)
+        context.index.partial = true;
+        context.manifest.set_partial(&context.failures.not_saved, &context.failures.excluded);
+    }
+    if let Some(cipher) = &context.cipher {
+        cipher.finish()?;
+        context.index.files.clear(); //l'indice di un backup cifrato contiene solo l'intestazione, per non rivelare i nomi dei file
+        notes.push("Backup cifrato: usare il comando 'decrypt' per recuperare i file.".to_string());
+    }
+    context.index.save(&work_path)?;
+    if let Some(journal) = context.journal.take() {
+        journal.finish()?; //con l'indice scritto il backup è completo, il giornale non serve più
+    }
+
+    let duration = start.elapsed();
+
+    if let Err(e) = save_log(work_path.to_str().unwrap(), duration, copied_size, &notes, &context.manifest, context.cipher.as_ref()) {
+        eprintln!("Errore durante il salvataggio del log: {}", e);
+    }
+
+    //i dati di parità vengono calcolati per ultimi, sui file scritti nella cartella del backup (compresi manifest e indice)
+    let mut parity_size = None;
+    if options.parity > 0 {
+        println!("Calcolo dei dati di parità ({}% di ridondanza).", options.parity);
+        parity_size = Some(create_parity(&work_path, options.parity)?);
+    }
+
+    if options.mode == BackupMode::Snapshot {
+        finish_snapshot(&work_path, &backup_path)?;
+    }
+
+    let mut s = format!("{} byte copiati da '{}' a '{}'.", copied_size, src_path.to_string_lossy(), backup_path.to_string_lossy());
+    if context.previous.is_some() {
+        match options.mode {
+            BackupMode::Snapshot => s = format!("{} {} file invariati collegati allo snapshot precedente.", s, context.unchanged_files),
+            _ => s = format!("{} {} file invariati non copiati.", s, context.unchanged_files),
+        }
+    }
+    if let Some(size) = parity_size {
+        s = format!("{} {} byte di dati di parità per la riparazione (comando 'repair').", s, size);
+    }
+    if stopped || !context.failures.not_saved.is_empty() || !context.failures.excluded.is_empty() {
+        //backup d'emergenza o ridotto allo spazio disponibile: l'utente deve sapere che il backup contiene solo una parte dei file
+        let title = match stopped || !context.failures.not_saved.is_empty() {
+            true => "Backup parziale: tempo a disposizione terminato.",
+            false => "Backup parziale: spazio insufficiente nella destinazione.",
+        };
+        let mut missing = Vec::new();
+        if !context.failures.excluded.is_empty() {
+            let size: u64 = context.failures.excluded.iter().map(|(_, size)| size).sum();
+            let mut excluded = format!("{} file ({} byte) esclusi perché non c'è spazio sufficiente", context.failures.excluded.len(), size);
+            if context.cipher.is_none() { //in un backup cifrato i nomi dei file non vengono mostrati
+                let names: Vec<&str> = context.failures.excluded.iter().take(EXCLUDED_SHOWN).map(|(key, _)| key.as_str()).collect();
+                let more = if context.failures.excluded.len() > EXCLUDED_SHOWN { ", ..." } else { "" };
+                excluded = format!("{}: {}{}", excluded, names.join(", "), more);
+            }
+            missing.push(excluded);
+        }
+        if !context.failures.not_saved.is_empty() {
+            missing.push(format!("{} file non salvati per il limite di tempo", context.failures.not_saved.len()));
+        }
+        if stopped {
+            missing.push("i file della sorgente non ancora esaminati alla scadenza non sono stati salvati".to_string());
+        }
+        if !context.failures.is_empty() {
+            missing.push(format!("{} file non copiati o copiati in parte a causa di un errore", context.failures.len()));
+        }
+        s = format!("{} Il backup non è completo: {} (elenco nel file backup_log.txt del backup).", s, missing.join("; "));
+        println!("\n{}\n{}", title, s);
+        start_notify(title, &s);
+    } else if context.failures.is_empty() {
+        println!("\nBackup terminato con successo.\n{}", s);
+        start_notify("Backup terminato con successo.", &s);
+    } else {
+        let title = format!("Backup terminato con {} errori.", context.failures.len());
+        s = format!("{} {} file non copiati o copiati in parte a causa di un errore (elenco nel file backup_log.txt del backup).", s, context.failures.len());
+        println!("\n{}\n{}", title, s);
+        start_notify(&title, &s);
+    }
+
+    Ok(copied_size as i64)
+}
+
+/***
+prepare_destination: funzione per creare la cartella di un nuovo backup
+    dst_path: Path di destinazione (se la destinazione è una cartella non vuota viene creata una cartella "backup")
+    options: opzioni del backup
+    -> restituisce: il percorso definitivo del backup e il percorso in cui viene scritto (diverso solo in modalità snapshot)
+***/
+fn prepare_destination(dst_path: &Path, options: &BackupOptions) -> io::Result<(PathBuf, PathBuf)> {
+    //in modalità snapshot il backup viene costruito in una cartella temporanea, rinominata solo al termine della copia (vedi finish_snapshot):
+    //uno snapshot incompleto non è mai visibile con il nome definitivo, che quindi non viene creato qui
+    if options.mode == BackupMode::Snapshot {
+        let backup_path = match dst_path.exists() {
+            true if fs::read_dir(dst_path)?.next().is_some() => unique_path(&dst_path.join("backup")),
+            true => {
+                fs::remove_dir(dst_path)?; //cartella vuota: il suo posto viene preso dallo snapshot
+                dst_path.to_path_buf()
+            }
+            false => dst_path.to_path_buf(),
+        };
+        let incomplete = incomplete_path(&backup_path);
+        if incomplete.exists() {
+            fs::remove_dir_all(&incomplete)?;
+        }
+        fs::create_dir_all(&incomplete)?;
+        return Ok((backup_path, incomplete));
+    }
 
     if dst_path.exists() { //verifica se il percorso di destinazione esiste e non è vuoto
         if fs::read_dir(dst_path)?.count() > 0 {
@@ -54,25 +574,31 @@ pub fn copy_dir(src: &str, dst: &str, extensions: Vec<String>) -> io::Result<i64
         dst_path.to_path_buf()
     };
 
-    let mut copied_files = 0;  //contatore dei file copiati
-    let mut copied_size = 0;   //somma della dimensione totale dei file copiati
-
-    copy_dir_recursive(src_path, &backup_path, total_files, &mut copied_files, &mut copied_size, &extensions)?;
-
-    let duration = start.elapsed();
+    Ok((backup_path.clone(), backup_path))
+}
 
-    if let Err(e) = save_log(backup_path.to_str().unwrap(), duration, copied_size) {
-        eprintln!("Errore durante il salvataggio del log: {}", e);
+/***
+finish_snapshot: funzione per rendere visibile uno snapshot completato, con una sola rinomina della cartella temporanea
+    work_path: cartella in cui è stato scritto lo snapshot ("<nome>.incomplete")
+    backup_path: nome definitivo dello snapshot
+***/
+fn finish_snapshot(work_path: &Path, backup_path: &Path) -> io::Result<()> {
+    if backup_path.exists() { //nome occupato durante la copia: la rinomina lo sostituirebbe (o non riuscirebbe)
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("La cartella '{}' esiste già: lo snapshot resta in '{}'", backup_path.to_string_lossy(), work_path.to_string_lossy())));
     }
-
-    let s = format!("{} byte copiati da '{}' a '{}'.", copied_size, src_path.to_string_lossy(), dst_path.to_string_lossy());
-    println!("\nBackup terminato con successo.\n{}", s);
-    start_notify("Backup terminato con successo.", &s);
-
-    Ok(copied_size as i64)
+    fs::rename(work_path, backup_path)
 }
 
-fn save_log(path: &str, duration: Duration, size_in_bytes: u64) -> io::Result<()> {
+/***
+save_log: funzione per scrivere il file di log nella cartella del backup
+    path: stringa del percorso del backup
+    duration: tempo impiegato
+    size_in_bytes: dimensione totale dei file copiati
+    notes: righe aggiuntive da riportare nel log
+    manifest: manifest del backup, scritto in formato JSON accanto al log
+    cipher: chiave del backup, se il backup è cifrato
+***/
+fn save_log(path: &str, duration: Duration, size_in_bytes: u64, notes: &[String], manifest: &Manifest, cipher: Option<&BackupCipher>) -> io::Result<()> {
     let log_path = Path::new(path).join("backup_log.txt"); //crea il percorso per il file log.txt
     let mut file = File::create(log_path)?; //apre (o crea) il file log.txt in modalità scrittura
     let duration_secs = duration.as_secs_f64(); //conversioni
@@ -80,6 +606,41 @@ fn save_log(path: &str, duration: Duration, size_in_bytes: u64) -> io::Result<()
 
     writeln!(file, "Dimensione totale del backup: {:.2} MB ({:.2} Bytes)", size_in_mb, size_in_bytes)?;
     writeln!(file, "Tempo di CPU impiegato: {:.2} secondi", duration_secs)?;
+    for note in notes {
+        writeln!(file, "{}", note)?;
+    }
+
+    manifest.save(Path::new(path), cipher)?;
+    writeln!(file, "Manifest: {} ({} file, {} byte, hash {})", MANIFEST_FILE, manifest.total_files, manifest.total_size, manifest.hash_algorithm)?;
+    Ok(())
+}
+
+/***
+copy_source: funzione per copiare la sorgente nella cartella del backup (un passaggio per classe con l'opzione "priority" o in modalità salvataggio),
+    aspettando la fine delle copie dei worker
+    src_path, dst_path, copied_files, copied_size, extensions, context: come in copy_dir_recursive
+    -> restituisce: Err se il backup non può proseguire (Err(InsufficientSpace) se lo spazio della destinazione non basta)
+***/
+fn copy_source(src_path: &Path, dst_path: &Path, copied_files: &mut usize, copied_size: &mut u64, extensions: &Vec<String>, context: &mut CopyContext) -> io::Result<()> {
+    if context.options.priority.is_enabled() {
+        //la sorgente viene percorsa una volta per classe di file (documenti, percorsi critici, ...), così che un backup interrotto
+        //(computer spento, disco sorgente danneggiato che smette di rispondere) contenga i file più utili. La visita delle cartelle
+        //viene ripetuta, ma la memoria usata non dipende dal numero di file della sorgente
+        for pass in 0..context.options.priority.passes() {
+            if context.budget.as_mut().is_some_and(|b| b.is_expired()) {
+                break;
+            }
+            context.priority_pass = Some(pass);
+            copy_dir_recursive(src_path, dst_path, copied_files, copied_size, extensions, context)?;
+        }
+    } else {
+        copy_dir_recursive(src_path, dst_path, copied_files, copied_size, extensions, context)?;
+    }
+    if let Some(pool) = context.pool.take() {
+        for (copy, result) in pool.finish() {
+            complete_copy(copy, result, copied_files, copied_size, context)?;
+        }
+    }
     Ok(())
 }
 
@@ -87,32 +648,39 @@ fn save_log(path: &str, duration: Duration, size_in_bytes: u64) -> io::Result<()
 copy_dir_recursive: funzione di copia ricorsiva
     src_path: Path della sorgente
     dst_path: Path della destinazione
-    total_files: numero totale dei file da copiare
     copied_files: numero totale dei file copiati finora (utile per la percentuale di avanzamento)
     copied_size: dimensione totale dei file copiati finora
     extensions: vettore di stringhe delle estensioni dei file da copiare, se è vuoto vengono copiati tutti i file
+    context: stato del backup (indice corrente e, in modalità incrementale, indice del backup precedente)
     -> restituisce: Ok(()) se l'operazione è andata a buon fine
 ***/
-fn copy_dir_recursive(src_path: &Path, dst_path: &Path, total_files: usize, copied_files: &mut usize, copied_size: &mut u64, extensions: &Vec<String>) -> io::Result<()> {
-    if !dst_path.exists() { // se il percorso di destinazione non esiste (ma esiste il disco esterno corrispondente alla root del path), crea la cartella
+fn copy_dir_recursive(src_path: &Path, dst_path: &Path, copied_files: &mut usize, copied_size: &mut u64, extensions: &Vec<String>, context: &mut CopyContext) -> io::Result<()> {
+    let flat = context.cipher.as_ref().is_some_and(|c| c.encrypts_names()); //con i nomi cifrati, tutti i file vengono salvati nella radice del backup
+    if context.archive.is_none() && !flat && !dst_path.exists() { // se il percorso di destinazione non esiste (ma esiste il disco esterno corrispondente alla root del path), crea la cartella
         fs::create_dir(dst_path)?;
     }
 
-    for entry in fs::read_dir(src_path)? {
-        let entry = entry?;
-        let path = entry.path();
-        let dest_path = dst_path.join(entry.file_name());
-
-        if path.is_dir() {
-            copy_dir_recursive(&path, &dest_path, total_files, copied_files, copied_size, extensions)?;  //chiamata ricorsiva per le directory
-        } else { //se il vettore di estensioni non è vuoto, copia solo i file che corrispondono alle estensioni
-            if extensions.is_empty() || check_extension(&path, extensions) {
-                let file_size = path.metadata()?.len();
-                fs::copy(&path, &dest_path)?;
-                *copied_files += 1;
-                *copied_size += file_size;
-                //print_progress(*copied_files, total_files); //funzione rimossa per minimizzare il consumo di cpu
+    //un file che non è possibile copiare (permessi, file eliminato durante il backup, errore di I/O) non interrompe il backup:
+    //l'errore viene registrato e la copia prosegue con gli altri file
+    let mut entries: Vec<io::Result<fs::DirEntry>> = fs::read_dir(src_path)?.collect();
+    if context.priority_pass.is_some() {
+        //a parità di classe i file della cartella vengono copiati secondo le regole (più recenti, più piccoli, ...), le sottocartelle per ultime
+        entries.sort_by_cached_key(|entry| priority_key(entry, context));
+    }
+    for entry in entries {
+        throttle(); //pausa se il backup supera il limite di CPU (vedi cpu_governor)
+        if context.budget.as_mut().is_some_and(|b| b.is_expired()) {
+            return Ok(()); //tempo a disposizione terminato: la visita della sorgente si ferma
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                context.failures.record(&relative_key(src_path, context.src_root), e)?;
+                continue;
             }
+        };
+        if let Err(e) = copy_entry(&entry, dst_path, copied_files, copied_size, extensions, context) {
+            context.failures.record(&relative_key(&entry.path(), context.src_root), e)?;
         }
     }
 
@@ -120,62 +688,357 @@ fn copy_dir_recursive(src_path: &Path, dst_path: &Path, total_files: usize, copi
 }
 
 /***
-check_extension: funzione di controllo dell'estensione del file
-    path: Path del file da controllare
-    extensions: vettore di stringhe delle estensioni
-    -> restituisce: true se l'estensione corrisponde a una delle estensioni specificate, altrimenti false
+priority_key: funzione per ordinare gli elementi di una cartella della sorgente (vedi priority::PriorityRules::sort_key)
+    entry: elemento della cartella
+    context: stato del backup
+    -> restituisce: la chiave di ordinamento; le sottocartelle e gli elementi illeggibili hanno la chiave massima
 ***/
-fn check_extension(path: &Path, extensions: &Vec<String>) -> bool {
-    if let Some(ext) = path.extension() {
-        if let Some(ext_str) = ext.to_str() {
-            return extensions.iter().any(|e| e == ext_str);
-        }
+fn priority_key(entry: &io::Result<fs::DirEntry>, context: &CopyContext) -> Vec<u64> {
+    let Ok(entry) = entry else { return vec![u64::MAX] };
+    match entry.metadata() {
+        Ok(metadata) if !metadata.is_dir() => context.options.priority.sort_key(&relative_key(&entry.path(), context.src_root), &metadata),
+        _ => vec![u64::MAX],
     }
-    false
 }
 
 /***
-count_files_and_size: funzione per contare il numero totale di file e la dimensione totale dei file da copiare
-    path: Path della sorgente
-    extensions: vettore di stringhe delle estensioni
-    -> restituisce: un tuple con il numero totale di file e la dimensione totale in byte
+copy_entry: funzione per copiare un elemento di una cartella della sorgente (file, cartella o collegamento simbolico)
+    entry: elemento da copiare
+    dst_path: Path della cartella di destinazione
+    copied_files, copied_size, extensions, context: come in copy_dir_recursive
+    -> restituisce: Err se non è stato possibile copiare l'elemento (la copia parziale viene eliminata)
 ***/
-fn count_files_and_size(path: &Path, extensions: &Vec<String>) -> io::Result<(usize, u64)> {
-    let mut count = 0;
-    let mut size = 0;
+fn copy_entry(entry: &fs::DirEntry, dst_path: &Path, copied_files: &mut usize, copied_size: &mut u64, extensions: &Vec<String>, context: &mut CopyContext) -> io::Result<()> {
+    let path = entry.path();
+    let key = relative_key(&path, context.src_root);
+
+    //con l'opzione "preserve_metadata" i collegamenti simbolici vengono copiati come collegamenti, senza seguirli
+    if context.metadata.is_some() && entry.file_type()?.is_symlink() {
+        if context.priority_pass.is_some_and(|p| p > 0) { //i collegamenti vengono copiati nel primo passaggio
+            return Ok(());
+        }
+        if path.is_dir() || extensions.is_empty() || check_extension(&path, extensions) {
+            let stored = context.names.map_file(&key);
+            let record = copy_symlink(&path, &dst_path.join(stored.rsplit('/').next().unwrap_or_default()))?;
+            if let Some(metadata) = context.metadata.as_mut() {
+                metadata.files.insert(key, record);
+            }
+        }
+        return Ok(());
+    }
+
+    if path.is_dir() {
+        let stored = context.names.map_dir(&key);
+        let dest_path = dst_path.join(stored.rsplit('/').next().unwrap_or_default());
+        copy_dir_recursive(&path, &dest_path, copied_files, copied_size, extensions, context)?;  //chiamata ricorsiva per le directory
+        //i metadati della cartella vengono copiati al termine del backup, dopo la scrittura del contenuto (che ne modifica la data)
+        if context.metadata.is_some() && context.priority_pass.is_none_or(|p| p + 1 == context.options.priority.passes()) {
+            context.folders.push((key, path, dest_path));
+        }
+    } else { //se il vettore di estensioni non è vuoto, copia solo i file che corrispondono alle estensioni
+        if extensions.is_empty() || check_extension(&path, extensions) {
+            let metadata = path.metadata()?;
+            if context.priority_pass.is_some_and(|p| p != context.options.priority.class(&key, &metadata)) {
+                return Ok(()); //il file viene copiato in un altro passaggio (vedi priority::PriorityRules)
+            }
+            //i file speciali non vengono letti (una named pipe bloccherebbe la copia), ma solo elencati nel log o registrati
+            if let Some(kind) = SpecialKind::detect(&metadata.file_type()) {
+                println!("File speciale ({}) non copiato: {}", kind.name(), path.to_string_lossy());
+                context.special.add(&key, kind, &metadata);
+                return Ok(());
+            }
+            let file_size = metadata.len();
+            let mtime = modified_nanos(&metadata);
+            let hash = if context.options.hash { Some(hash_file(&path)?) } else { None };
+            let unchanged = context.previous.as_ref().and_then(|p| p.is_unchanged(&key, file_size, mtime, hash.as_deref(), context.backup_root)).cloned();
+
+            //in modalità incrementale, i file invariati non vengono copiati: l'indice punta alla copia presente nel backup precedente
+            if let (Some(old), BackupMode::Incremental) = (&unchanged, context.options.mode) {
+                //il manifest elenca anche i file invariati, con l'hash e il backup che ne contiene la copia
+                //(gli indici meno recenti non hanno l'hash di tutti i file: viene calcolato sulla copia)
+                let reused_hash = match &old.hash {
+                    Some(h) => h.clone(),
+                    None => hash_file(&context.backup_root.join(&old.location).join(old.stored_path(&key)))?,
+                };
+                context.manifest.add_reused(&key, &metadata, &reused_hash, &old.location);
+                carry_metadata(context, &key);
+                context.index.files.insert(key, IndexEntry { hash: Some(reused_hash), ..old.clone() });
+                context.unchanged_files += 1;
+                return Ok(());
+            }
+
+            let stored = context.names.map_file(&key); //percorso del file nel backup, compatibile con il file system della destinazione
+            let dest_path = dst_path.join(stored.rsplit('/').next().unwrap_or_default());
+            let stored = if stored != key { Some(stored) } else { None };
+
+            //ripresa di un backup interrotto: una copia non registrata nel giornale viene eliminata prima di scriverla di nuovo
+            //(potrebbe essere incompleta, o un hard link alla copia di un altro backup, che verrebbe sovrascritta)
+            if context.journal.as_ref().is_some_and(|j| j.is_resumed() && !j.contains(&key)) {
+                let _ = fs::remove_file(&dest_path);
+            }
 
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let entry_path = entry.path();
+            //in modalità snapshot, i file invariati vengono collegati tramite hard link alla copia del backup precedente.
+            //Se il file system non supporta gli hard link (es. FAT32/exFAT), il file viene copiato
+            if let (Some(old), BackupMode::Snapshot) = (&unchanged, context.options.mode) {
+                let original = context.backup_root.join(&old.location).join(old.stored_path(&key));
+                if fs::hard_link(&original, &dest_path).is_ok() {
+                    let linked_hash = match &old.hash {
+                        Some(h) => h.clone(),
+                        None => hash_file(&dest_path)?,
+                    };
+                    context.manifest.add(&key, &metadata, &linked_hash);
+                    carry_metadata(context, &key); //l'hard link condivide i metadati con la copia del backup precedente
+                    context.index.files.insert(key, IndexEntry { location: context.location.clone(), hash: Some(linked_hash), stored, ..old.clone() });
+                    context.unchanged_files += 1;
+                    return Ok(());
+                }
+            }
+
+            //i file con più hard link nella sorgente vengono collegati alla copia già presente nel backup, invece di essere copiati di nuovo.
+            //Se il file system non supporta gli hard link (es. FAT32/exFAT), il file viene copiato
+            let link = if context.archive.is_none() && context.cipher.is_none() { link_id(&metadata) } else { None };
+            if let Some((first, first_key, linked_hash)) = link.and_then(|id| context.hard_links.copies.get(&id)).cloned() {
+                if fs::hard_link(&first, &dest_path).is_ok() {
+                    context.hard_links.linked += 1;
+                    *copied_files += 1;
+                    context.manifest.add(&key, &metadata, &linked_hash);
+                    if let Some(sidecar) = context.metadata.as_mut() {
+                        if let Some(record) = sidecar.files.get(&first_key).cloned() {
+                            sidecar.files.insert(key.clone(), record);
+                        }
+                    }
+                    context.index.files.insert(key, IndexEntry { size: file_size, mtime, hash: Some(linked_hash), location: context.location.clone(), stored });
+                    return Ok(());
+                }
+            }
 
-        if entry_path.is_dir() {
-            let (sub_count, sub_size) = count_files_and_size(&entry_path, extensions)?;
-            count += sub_count;
-            size += sub_size;
-        } else if extensions.is_empty() || check_extension(&entry_path, extensions) {
-            count += 1;
-            size += entry.metadata()?.len();
+            //con l'opzione "best_fit" un file che non sta nello spazio rimasto viene escluso; con un tempo a disposizione,
+            //un file che non può essere copiato entro la scadenza non viene salvato
+            if context.space.as_ref().is_some_and(|s| !s.fits(file_size)) {
+                context.failures.excluded.push((key, file_size));
+                return Ok(());
+            }
+            if context.budget.as_mut().is_some_and(|b| !b.try_start(file_size)) {
+                context.failures.not_saved.push((key, file_size));
+                return Ok(());
+            }
+            if let Some(space) = context.space.as_mut() {
+                space.take(file_size);
+            }
+
+            let copy = PendingCopy { path, key, dest_path, stored, metadata, link };
+            //i file senza trattamenti particolari vengono copiati dai worker: il risultato viene registrato quando la copia termina
+            if context.pool.is_some() && link.is_none() && parallel_copy(&copy.metadata, context) {
+                let reused = context.journal.as_mut().and_then(|j| j.completed(&copy.key, &copy.metadata, &copy.dest_path));
+                let completed = match reused {
+                    Some(hash) => vec![(copy, Ok((hash, false, None)))],
+                    None => {
+                        let (src, dst, known_hash) = (copy.path.clone(), copy.dest_path.clone(), known_hash(&copy.key, &copy.metadata, context));
+                        context.pool.as_mut().unwrap().submit(copy, move || copy_file_fast(&src, &dst, known_hash.as_deref()).map(|(hash, strategy)| (hash, false, Some(strategy))))
+                    }
+                };
+                for (copy, result) in completed {
+                    complete_copy(copy, result, copied_files, copied_size, context)?;
+                }
+                return Ok(());
+            }
+            let result = copy_file_data(&copy.path, &copy.key, &copy.dest_path, copy.stored.as_deref(), &copy.metadata, context);
+            complete_copy(copy, result, copied_files, copied_size, context)?;
+            //print_progress(*copied_files, context.estimator.files()); //funzione rimossa per minimizzare il consumo di cpu
         }
     }
 
-    Ok((count, size))
+    Ok(())
 }
 
 /***
-get_available_space: funzione per ottenere lo spazio disponibile sul disco di destinazione
-    path: Path della destinazione
-    -> restituisce: la dimensione disponibile in byte
+complete_copy: funzione per registrare nell'indice, nel manifest e nel giornale un file copiato
+    copy: file copiato
+    result: risultato della copia (vedi CopiedData)
+    copied_files, copied_size: come in copy_dir_recursive
+    context: stato del backup
+    -> restituisce: Err solo se dopo l'errore il backup non può proseguire (gli altri errori vengono registrati in context.failures)
 ***/
-fn get_available_space(path: &Path) -> io::Result<u64> {
-    let mut sys = System::new_all();
-    sys.refresh_all();
-    for disk in Disks::new_with_refreshed_list().list() {
-        if path.starts_with(disk.mount_point()) {
-            return Ok(disk.available_space());
+fn complete_copy(copy: PendingCopy, result: io::Result<CopiedData>, copied_files: &mut usize, copied_size: &mut u64, context: &mut CopyContext) -> io::Result<()> {
+    let PendingCopy { path, key, dest_path, stored, metadata, link } = copy;
+    if let Some(budget) = context.budget.as_mut() {
+        budget.finish(metadata.len());
+    }
+    let (copied_hash, split, strategy) = match result {
+        Ok(copied) => copied,
+        Err(e) => {
+            if context.archive.is_none() && context.cipher.is_none() {
+                remove_partial(&dest_path); //nel backup non resta una copia parziale del file (encrypt_file elimina già la propria)
+            }
+            //con l'opzione "best_fit" un file che non sta nella destinazione (stima per difetto) viene escluso invece di annullare il backup
+            if context.space.is_some() && is_space_exhausted(&e) {
+                context.failures.excluded.push((key, metadata.len()));
+                return Ok(());
+            }
+            return context.failures.record(&key, e);
         }
+    };
+    let file_size = metadata.len();
+    if let Some(strategy) = strategy {
+        context.strategies.add(strategy);
     }
+    if let (Some(id), false) = (link, split) {
+        context.hard_links.copies.insert(id, (dest_path.clone(), key.clone(), copied_hash.clone()));
+    }
+    *copied_files += 1;
+    *copied_size += file_size;
+    if context.space.is_none() {
+        context.estimator.check_space(*copied_size)?; //la stima può essere cresciuta dall'ultima verifica
+    }
+    context.manifest.add(&key, &metadata, &copied_hash);
+    if let Some(sidecar) = context.metadata.as_mut() {
+        let record = copy_metadata(&path, &metadata, &dest_path);
+        if !record.is_empty() {
+            sidecar.files.insert(key.clone(), record);
+        }
+    }
+    //un file copiato in parte viene registrato nell'indice senza data di modifica, così che il backup successivo provi a copiarlo di nuovo
+    let mtime = if context.failures.is_salvaged(&key) { 0 } else { modified_nanos(&metadata) };
+    if let (Some(journal), false, true) = (context.journal.as_mut(), split, mtime != 0) {
+        journal.record_done(&key, file_size, mtime, &copied_hash)?; //i file divisi in parti vengono copiati di nuovo se il backup viene ripreso
+    }
+    context.index.files.insert(key, IndexEntry { size: file_size, mtime, hash: Some(copied_hash), location: context.location.clone(), stored });
+    Ok(())
+}
+
+/***
+parallel_copy: funzione per decidere se un file può essere copiato da un worker
+    metadata: metadati del file sorgente
+    context: stato del backup
+    -> restituisce: true se il file viene copiato così com'è; i file scritti in un archivio, cifrati, divisi in parti, sparsi,
+        copiati in modalità salvataggio o con i punti di ripresa nel giornale vengono copiati dal thread del backup (vedi copy_file_data)
+***/
+fn parallel_copy(metadata: &fs::Metadata, context: &CopyContext) -> bool {
+    context.archive.is_none() && context.cipher.is_none() && !context.options.salvage && !is_sparse(metadata)
+        && context.names.kind().max_file_size().is_none_or(|limit| metadata.len() <= limit)
+        && !(metadata.len() >= CHECKPOINT_SIZE && context.journal.is_some())
+}
+
+/***
+copy_file_data: funzione per scrivere il contenuto di un file nel backup (nell'archivio, cifrato, diviso in parti, sparso o copiato)
+    path: Path del file sorgente
+    key: percorso relativo del file
+    dest_path: Path della copia (nel formato "directory")
+    stored: percorso del file nel backup, se diverso da key
+    metadata: metadati del file sorgente
+    context: stato del backup
+    -> restituisce: l'hash del contenuto, true se il file è stato diviso in parti e la strategia di copia (vedi CopiedData)
+***/
+fn copy_file_data(path: &Path, key: &str, dest_path: &Path, stored: Option<&str>, metadata: &fs::Metadata, context: &mut CopyContext) -> io::Result<CopiedData> {
+    //ripresa di un backup interrotto: un file già copiato (e invariato) non viene copiato di nuovo
+    if let Some(hash) = context.journal.as_mut().and_then(|j| j.completed(key, metadata, dest_path)) {
+        return Ok((hash, false, None));
+    }
+    let mut split = false;
+    let mut strategy = Some(CopyStrategy::Buffered);
+    let copied_hash = match (context.archive.as_mut(), context.cipher.as_mut(), context.names.kind().max_file_size()) {
+        (Some(archive), _, _) => {
+            File::open(path)?; //un file non leggibile viene saltato prima di iniziare a scriverlo nell'archivio
+            strategy = None;
+            archive.append_file(path, key, metadata)? //un errore a metà del file lascerebbe l'archivio inutilizzabile ed è bloccante (vedi ArchiveWriter::append_file)
+        }
+        //sulle destinazioni con un limite alla dimensione dei file (FAT32) il file cifrato viene diviso in parti, riunite dal comando "decrypt"
+        (None, Some(cipher), limit) => {
+            strategy = None;
+            let (encrypted_hash, encrypted_split) = cipher.encrypt_file(path, key, dest_path, limit)?;
+            split = encrypted_split;
+            encrypted_hash
+        }
+        //i file più grandi del limite del file system (FAT32) vengono divisi in parti, ricomposte dal comando "restore".
+        //Nell'indice il percorso nel backup resta quello del file intero: al backup successivo il file viene copiato di nuovo
+        (None, None, Some(limit)) if metadata.len() > limit => {
+            let (parts, split_hash) = copy_file_split(path, dest_path, limit)?;
+            let whole = stored.unwrap_or(key).to_string();
+            context.names.record_parts(&whole, key, parts);
+            split = true;
+            split_hash
+        }
+        //modalità salvataggio: le letture non riuscite vengono ripetute e le zone illeggibili riempite di zeri, registrandole nel log
+        (None, None, _) if context.options.salvage => {
+            let (salvaged_hash, lost) = copy_file_salvage(path, dest_path)?;
+            if !lost.is_empty() {
+                context.failures.record_salvaged(key, lost);
+            }
+            salvaged_hash
+        }
+        //nei file sparsi (es. immagini di macchine virtuali) le zone vuote non vengono scritte, così che la copia non occupi la dimensione intera.
+        //Un clone (reflink) condivide i blocchi della sorgente e conserva anche le zone vuote
+        (None, None, _) if is_sparse(metadata) => match reflink_hashed(path, dest_path, known_hash(key, metadata, context).as_deref())? {
+            Some(cloned_hash) => {
+                strategy = Some(CopyStrategy::Reflink);
+                cloned_hash
+            }
+            None => {
+                let (holes, sparse_hash) = copy_file_sparse(path, dest_path)?;
+                context.sparse_files += 1;
+                context.sparse_holes += holes;
+                sparse_hash
+            }
+        },
+        //i file grandi vengono copiati registrando nel giornale il punto raggiunto, per riprendere la copia se il backup viene interrotto
+        //(un clone è immediato e non ha bisogno dei punti di ripresa)
+        (None, None, _) if metadata.len() >= CHECKPOINT_SIZE && context.journal.is_some() => match reflink_hashed(path, dest_path, known_hash(key, metadata, context).as_deref())? {
+            Some(cloned_hash) => {
+                strategy = Some(CopyStrategy::Reflink);
+                cloned_hash
+            }
+            None => copy_file_resumable(path, dest_path, key, context.journal.as_mut().unwrap())?,
+        },
+        //sullo stesso file system btrfs/XFS i file vengono clonati (reflink), altrimenti copiati dal kernel (copy_file_range) o dal tool
+        (None, None, _) => {
+            let (fast_hash, fast_strategy) = copy_file_fast(path, dest_path, known_hash(key, metadata, context).as_deref())?;
+            strategy = Some(fast_strategy);
+            fast_hash
+        }
+    };
+    Ok((copied_hash, split, strategy))
+}
+
+// hash di un file invariato registrato nell'indice dell'ultimo backup (vedi BackupIndex::known_hash)
+fn known_hash(key: &str, metadata: &fs::Metadata, context: &CopyContext) -> Option<String> {
+    context.last_index.as_ref()?.known_hash(key, metadata.len(), modified_nanos(metadata)).map(str::to_string)
+}
+
+/***
+carry_metadata: funzione per riportare nel backup corrente i metadati registrati dal backup precedente per un file invariato
+    context: stato del backup
+    key: percorso relativo del file
+***/
+fn carry_metadata(context: &mut CopyContext, key: &str) {
+    if let (Some(metadata), Some(record)) = (context.metadata.as_mut(), context.previous_metadata.files.get(key)) {
+        metadata.files.insert(key.to_string(), record.clone());
+    }
+}
+
+/***
+incomplete_path: funzione per ottenere il percorso temporaneo in cui viene costruito uno snapshot
+    backup_path: percorso definitivo dello snapshot
+    -> restituisce: il percorso "<backup_path>.incomplete"
+***/
+fn incomplete_path(backup_path: &Path) -> PathBuf {
+    let mut name = backup_path.file_name().unwrap_or_default().to_os_string();
+    name.push(INCOMPLETE_SUFFIX);
+    backup_path.with_file_name(name)
+}
 
-    Err(io::Error::new(io::ErrorKind::NotFound, "Disco non trovato"))
+/***
+check_extension: funzione di controllo dell'estensione del file
+    path: Path del file da controllare
+    extensions: vettore di stringhe delle estensioni
+    -> restituisce: true se l'estensione corrisponde a una delle estensioni specificate, altrimenti false
+***/
+pub fn check_extension(path: &Path, extensions: &[String]) -> bool {
+    if let Some(ext) = path.extension() {
+        if let Some(ext_str) = ext.to_str() {
+            return extensions.iter().any(|e| e == ext_str);
+        }
+    }
+    false
 }
 
 /***
@@ -231,6 +1094,9 @@ pub fn get_extensions(filename: &str) -> io::Result<Vec<String>> {
 
     for line in reader.lines().skip(1) {  // Salta la prima riga
         if let Ok(line) = line {
+            if is_option_line(&line) { // le righe "chiave=valore" contengono le opzioni avanzate (vedi backup_options.rs)
+                continue;
+            }
             // Suddivide la riga in base a spazi o virgole e filtra le stringhe vuote
             let parts: Vec<String> = line
                 .split_whitespace() // o `split(',')` se vogliamo usare la virgola
@@ -300,27 +1166,48 @@ pub fn get_usb_path() -> Option<String> {
 }
 
 /***
-generate_backup_name: funzione per generare un nome per il backup
+generate_backup_name: funzione per generare un nome per il backup a partire dal modello configurato (opzione "name_template")
     src: stringa del percorso sorgente
     dest: stringa del percorso di destinazione
-    -> restituisce: una stringa con il nome del backup generato
+    options: opzioni del backup (modello del nome e profilo)
+        nel modello, le sequenze strftime (es. %Y%m%d) vengono sostituite con la data corrente (ora locale),
+        {source} con il nome della cartella sorgente, {host} con il nome del computer e {profile} con il nome del profilo
+    -> restituisce: una stringa con il percorso del backup generato; se la cartella esiste già (es. modello con la sola data
+        e più backup nello stesso giorno) viene aggiunto il suffisso "-2", "-3", ... (vedi unique_path)
 ***/
-pub fn generate_backup_name(src: &str, dest: &str) -> String {
-    // Split the `dest` string by "\\" and collect the parts into a vector
-    #[cfg(not(target_os = "linux"))] let parts: Vec<&str> = src.split("\\").collect();
-    #[cfg(target_os = "linux")] let parts: Vec<&str> = src.split("/").collect();
-    // Get the last part of the vector
-    if let Some(last_word) = parts.last() {
-        // Get the current timestamp in the desired format
-        let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
-
-        // Create the backup name
-         #[cfg(target_os = "windows")] return format!("{}{}_backup_{}", dest, last_word, timestamp);
-        #[cfg(not(target_os = "windows"))] return format!("{}/{}_backup_{}", dest, last_word, timestamp);
-    }
-
-    // Return an empty string if `dest` does not contain any parts
-    String::new()
+pub fn generate_backup_name(src: &str, dest: &str, options: &BackupOptions) -> String {
+    let source_name = Path::new(src).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let host = System::host_name().unwrap_or_else(|| "host".to_string());
+
+    // Format the timestamp first, so that '%' characters in the source name or host name are not interpreted
+    let mut name = String::new();
+    let now = Local::now();
+    if write!(name, "{}", now.format(&options.name_template)).is_err() {
+        println!("Modello del nome del backup '{}' non valido, verrà usato quello di default.", options.name_template);
+        name = now.format(DEFAULT_NAME_TEMPLATE).to_string();
+    }
+
+    let name = name
+        .replace("{source}", &source_name)
+        .replace("{host}", &host)
+        .replace("{profile}", &options.profile)
+        .replace(['/', '\\'], "_"); // il nome non deve contenere separatori di percorso
+
+    unique_path(&Path::new(dest).join(name)).to_string_lossy().to_string()
+}
+
+/***
+unique_path: funzione per ottenere un percorso non ancora usato per un nuovo backup
+    path: percorso desiderato
+    -> restituisce: path se né la cartella né lo snapshot incompleto con lo stesso nome esistono, altrimenti il primo tra
+        "<path>-2", "<path>-3", ... libero. Un backup non viene mai scritto dentro un altro (dove list_backups non lo troverebbe
+        e la politica di conservazione lo eliminerebbe insieme alla cartella esterna)
+***/
+fn unique_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    (1..).map(|n| if n == 1 { path.to_path_buf() } else { path.with_file_name(format!("{}-{}", name, n)) })
+        .find(|p| !p.exists() && !incomplete_path(p).exists())
+        .unwrap()
 }
 
 pub fn log_cpu_usage() {
@@ -376,6 +1263,9 @@ fn log_cpu() {
                 log_file
                     .write_all(log_entry.as_bytes())
                     .expect("Scrittura log fallita");
+                for line in cpu_governor::report() { //limiti di CPU e consumo medio di ogni fase dall'avvio
+                    writeln!(log_file, "{}", line).expect("Scrittura log fallita");
+                }
 
                 log_file.write_all(b"\n").expect("Scrittura log fallita");
 
@@ -402,4 +1292,119 @@ fn print_progress(copied: usize, total: usize) {
     print!("\rAvanzamento: {:.2}%", percentage);  // Stampa la percentuale sulla stessa riga
     io::stdout().flush().unwrap();  // Forza l'output del buffer
 }
-*/
\ No newline at end of file
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_names_are_unique() {
+        let dest = tempfile::tempdir().unwrap();
+        let dest_str = dest.path().to_string_lossy().to_string();
+        for template in ["{source}-%Y-%m-%d", DEFAULT_NAME_TEMPLATE] {
+            let options = BackupOptions { name_template: template.to_string(), ..BackupOptions::default() };
+            // due backup nello stesso secondo: il primo ha già creato la sua cartella quando viene generato il nome del secondo
+            let first = generate_backup_name("/home/utente/documenti", &dest_str, &options);
+            fs::create_dir(&first).unwrap();
+            fs::write(Path::new(&first).join("file.txt"), "primo").unwrap();
+            let second = generate_backup_name("/home/utente/documenti", &dest_str, &options);
+            assert_ne!(first, second);
+            assert_eq!(second, format!("{}-2", first));
+            assert_eq!(Path::new(&second).parent(), Some(dest.path()));
+
+            // uno snapshot non ancora completato occupa il suo nome definitivo
+            fs::create_dir(incomplete_path(Path::new(&second))).unwrap();
+            assert_eq!(generate_backup_name("/home/utente/documenti", &dest_str, &options), format!("{}-3", first));
+        }
+    }
+    #[test]
+    fn snapshots_appear_only_when_complete() {
+        let root = tempfile::tempdir().unwrap();
+        let options = BackupOptions { mode: BackupMode::Snapshot, ..BackupOptions::default() };
+
+        // durante la copia esiste solo la cartella temporanea, e al termine una sola rinomina la rende visibile
+        let dst = root.path().join("snapshot");
+        let (backup_path, work_path) = prepare_destination(&dst, &options).unwrap();
+        assert_eq!(backup_path, dst);
+        assert_eq!(work_path, incomplete_path(&dst));
+        assert!(work_path.is_dir() && !backup_path.exists());
+        fs::write(work_path.join("file.txt"), "contenuto").unwrap();
+        finish_snapshot(&work_path, &backup_path).unwrap();
+        assert_eq!(fs::read_to_string(dst.join("file.txt")).unwrap(), "contenuto");
+        assert!(!work_path.exists());
+
+        // destinazione non vuota con una cartella "backup" già presente: lo snapshot usa un nome libero e non tocca quella esistente
+        fs::create_dir(dst.join("backup")).unwrap();
+        fs::write(dst.join("backup").join("vecchio.txt"), "vecchio").unwrap();
+        let (backup_path, work_path) = prepare_destination(&dst, &options).unwrap();
+        assert_eq!(backup_path, dst.join("backup-2"));
+        finish_snapshot(&work_path, &backup_path).unwrap();
+        assert!(dst.join("backup-2").is_dir());
+        assert_eq!(fs::read_to_string(dst.join("backup").join("vecchio.txt")).unwrap(), "vecchio");
+
+        // se il nome definitivo viene occupato durante la copia, lo snapshot resta nella cartella temporanea
+        let (backup_path, work_path) = prepare_destination(&root.path().join("altro"), &options).unwrap();
+        fs::create_dir(&backup_path).unwrap();
+        fs::write(backup_path.join("file.txt"), "altro").unwrap();
+        assert_eq!(finish_snapshot(&work_path, &backup_path).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(work_path.is_dir());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_stay_linked_in_the_backup() {
+        use std::os::unix::fs::MetadataExt;
+        let src = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let dst = root.path().join("backup");
+        fs::create_dir(&dst).unwrap();
+        fs::write(src.path().join("a.txt"), "contenuto condiviso").unwrap();
+        fs::hard_link(src.path().join("a.txt"), src.path().join("b.txt")).unwrap();
+
+        let options = BackupOptions::default();
+        let src_str = src.path().to_string_lossy().to_string();
+        let mut context = CopyContext {
+            src_root: src.path(),
+            backup_root: root.path(),
+            location: "backup".to_string(),
+            options: &options,
+            previous: None,
+            last_index: None,
+            index: BackupIndex::new(&src_str, "20260101120000000"),
+            unchanged_files: 0,
+            manifest: Manifest::new(&src_str, &dst.to_string_lossy(), &options.profile, "full", "directory"),
+            archive: None,
+            cipher: None,
+            names: DestinationNames::with_kind(FileSystemKind::Other, 0),
+            metadata: None,
+            previous_metadata: MetadataSidecar::default(),
+            special: SpecialFiles::default(),
+            hard_links: HardLinks::default(),
+            sparse_files: 0,
+            sparse_holes: 0,
+            failures: CopyFailures::default(),
+            journal: None,
+            priority_pass: None,
+            pool: None,
+            folders: Vec::new(),
+            strategies: CopyStrategies::default(),
+            estimator: SizeEstimator::start(src.path(), &[], None, root.path(), &options, &dst),
+            budget: None,
+            space: None,
+        };
+        let mut entries: Vec<fs::DirEntry> = fs::read_dir(src.path()).unwrap().map(|e| e.unwrap()).collect();
+        entries.sort_by_key(|e| e.file_name());
+        let (mut copied_files, mut copied_size) = (0, 0);
+        for entry in &entries {
+            copy_entry(entry, &dst, &mut copied_files, &mut copied_size, &Vec::new(), &mut context).unwrap();
+        }
+
+        // il secondo collegamento punta alla copia del primo: un solo inode anche nel backup
+        let (a, b) = (fs::metadata(dst.join("a.txt")).unwrap(), fs::metadata(dst.join("b.txt")).unwrap());
+        assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+        assert_eq!(a.nlink(), 2);
+        assert_eq!(context.hard_links.linked, 1);
+        assert_eq!(copied_files, 2);
+        assert_eq!(context.index.files["a.txt"].hash, context.index.files["b.txt"].hash);
+    }
+}
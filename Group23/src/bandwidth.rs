@@ -0,0 +1,149 @@
+use std::io;
+use std::sync::{LazyLock, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_PAUSE: Duration = Duration::from_secs(1); // pausa massima imposta in una volta sola
+
+/***
+Limiter: limitatore della banda di I/O del backup (token bucket), condiviso da tutti i thread della copia
+    rate: byte al secondo concessi; 0 = nessun limite
+    burst: byte che possono essere trasferiti di seguito senza pause dopo un periodo di inattività
+    tokens: byte che possono essere trasferiti adesso (negativo se i thread sono in anticipo sul limite)
+    last: ultimo aggiornamento di tokens
+    paused: durata totale delle pause imposte
+***/
+struct Limiter {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last: Instant,
+    paused: Duration,
+}
+
+static TRANSFERRED: AtomicU64 = AtomicU64::new(0); // byte trasferiti da tutti i cicli di copia dall'avvio del tool
+
+static LIMITER: LazyLock<Mutex<Limiter>> = LazyLock::new(|| {
+    Mutex::new(Limiter { rate: 0, burst: 0, tokens: 0.0, last: Instant::now(), paused: Duration::ZERO })
+});
+
+/***
+configure: funzione per impostare il limite di banda del backup (opzioni "bandwidth_limit" e "bandwidth_burst")
+    rate: byte al secondo, None = nessun limite
+    burst: byte trasferiti senza pause dopo un periodo di inattività; None = quelli di un secondo
+***/
+pub fn configure(rate: Option<u64>, burst: Option<u64>) {
+    if let Ok(mut limiter) = LIMITER.lock() {
+        limiter.rate = rate.unwrap_or(0);
+        limiter.burst = burst.unwrap_or(limiter.rate).max(1);
+        limiter.tokens = limiter.burst as f64;
+        limiter.last = Instant::now();
+        limiter.paused = Duration::ZERO;
+    }
+}
+
+/***
+limit: funzione chiamata dai cicli di copia dopo aver letto o scritto un blocco: se i byte trasferiti superano quelli concessi
+    dal limite, il thread viene messo in pausa finché il trasferimento non rientra nel limite
+    bytes: byte appena trasferiti
+***/
+pub fn limit(bytes: u64) {
+    TRANSFERRED.fetch_add(bytes, Ordering::Relaxed);
+    let pause = {
+        let Ok(mut limiter) = LIMITER.lock() else { return };
+        if limiter.rate == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let refill = (now - limiter.last).as_secs_f64() * limiter.rate as f64;
+        limiter.tokens = (limiter.tokens + refill).min(limiter.burst as f64) - bytes as f64;
+        limiter.last = now;
+        if limiter.tokens >= 0.0 {
+            return;
+        }
+        let pause = Duration::from_secs_f64(-limiter.tokens / limiter.rate as f64).min(MAX_PAUSE);
+        limiter.paused += pause;
+        pause
+    };
+    thread::sleep(pause);
+}
+
+// byte trasferiti da tutti i cicli di copia dall'avvio del tool (usati per stimare la velocità della copia, vedi time_budget)
+pub fn transferred() -> u64 {
+    TRANSFERRED.load(Ordering::Relaxed)
+}
+
+/***
+chunk_size: funzione per ridurre la dimensione dei blocchi copiati dal kernel (copy_file_range) quando la banda è limitata,
+    in modo che le pause siano brevi e frequenti invece di lunghe e rare
+    max: dimensione massima del blocco
+    -> restituisce: la dimensione del blocco
+***/
+pub fn chunk_size(max: usize) -> usize {
+    match LIMITER.lock() {
+        Ok(limiter) if limiter.rate > 0 => max.min(limiter.burst as usize).max(64 * 1024),
+        _ => max,
+    }
+}
+
+/***
+report: funzione per descrivere la velocità effettiva della copia, riportata nel log del backup
+    copied: byte copiati
+    elapsed: durata della copia
+    -> restituisce: la riga da scrivere nel log
+***/
+pub fn report(copied: u64, elapsed: Duration) -> String {
+    let mb = 1024.0 * 1024.0;
+    let speed = copied as f64 / mb / elapsed.as_secs_f64().max(0.001);
+    match LIMITER.lock() {
+        Ok(limiter) if limiter.rate > 0 => format!("Velocità media della copia: {:.2} MB/s (limite {:.2} MB/s, burst {:.2} MB, in pausa per {:.1} secondi)",
+            speed, limiter.rate as f64 / mb, limiter.burst as f64 / mb, limiter.paused.as_secs_f64()),
+        _ => format!("Velocità media della copia: {:.2} MB/s (nessun limite)", speed),
+    }
+}
+
+/***
+IdleIo: priorità di I/O "idle" del thread del backup: il disco viene usato dal backup solo quando nessun altro processo lo usa.
+    I thread creati dopo (worker della copia, stima della dimensione) ereditano la priorità; quella precedente viene
+    ripristinata quando IdleIo viene eliminato
+    previous: priorità del thread prima del backup
+***/
+pub struct IdleIo {
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    previous: i32,
+}
+
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1; // con who = 0 indica il thread chiamante
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_IDLE: libc::c_int = 3 << 13; // classe "idle" (IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT)
+
+impl IdleIo {
+    /***
+    enter: porta il thread corrente nella classe di I/O "idle" (solo su Linux)
+        -> restituisce: IdleIo, che ripristina la priorità precedente quando viene eliminato
+    ***/
+    #[cfg(target_os = "linux")]
+    pub fn enter() -> io::Result<IdleIo> {
+        let previous = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+        if previous < 0 || unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_CLASS_IDLE) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(IdleIo { previous: previous as i32 })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn enter() -> io::Result<IdleIo> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "la priorità di I/O \"idle\" è disponibile solo su Linux"))
+    }
+}
+
+impl Drop for IdleIo {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, self.previous);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use cpu_time::ProcessTime;
+
+pub const DEFAULT_LISTENING_LIMIT: u8 = 5; // limite di CPU (in % della capacità totale) mentre il tool aspetta le gesture
+pub const DEFAULT_BACKUP_LIMIT: u8 = 40; // limite di CPU (in % della capacità totale) durante il backup
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(20); // il consumo viene misurato al massimo una volta ogni CHECK_INTERVAL
+const WINDOW: Duration = Duration::from_secs(1); // durata minima della finestra su cui viene calcolato il consumo
+const MAX_PAUSE: Duration = Duration::from_secs(1); // pausa massima imposta in una volta sola
+
+/***
+Phase: fase del tool, con un limite di CPU diverso
+    Listening: il tool aspetta le gesture del mouse
+    Backup: il tool sta copiando i file
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Phase {
+    Listening,
+    Backup,
+}
+
+impl Phase {
+    fn index(self) -> usize {
+        match self {
+            Phase::Listening => 0,
+            Phase::Backup => 1,
+        }
+    }
+}
+
+/***
+Governor: stato del limitatore di CPU, condiviso da tutti i thread del processo
+    limits: limite di ogni fase, in % della capacità totale (tutti i core); 100 = nessun limite
+    phase: fase corrente
+    cpus: numero di core
+    window_start, window_cpu: inizio della finestra corrente e tempo di CPU del processo in quel momento
+    last_check: ultima misura del consumo
+    pause_until: fino a questo istante i thread che chiamano throttle restano in pausa
+    phase_start, phase_cpu: inizio della fase corrente e tempo di CPU del processo in quel momento
+    totals: per ogni fase, tempo trascorso, tempo di CPU consumato e durata delle pause imposte
+***/
+struct Governor {
+    limits: [u8; 2],
+    phase: Phase,
+    cpus: usize,
+    window_start: Instant,
+    window_cpu: Duration,
+    last_check: Instant,
+    pause_until: Instant,
+    phase_start: Instant,
+    phase_cpu: Duration,
+    totals: [(Duration, Duration, Duration); 2],
+}
+
+static GOVERNOR: LazyLock<Mutex<Governor>> = LazyLock::new(|| {
+    let (now, cpu) = (Instant::now(), process_cpu());
+    Mutex::new(Governor {
+        limits: [100, 100], // nessun limite finché non viene chiamata configure: i comandi (verify, restore, ...) non vengono rallentati
+        phase: Phase::Listening,
+        cpus: num_cpus::get().max(1),
+        window_start: now,
+        window_cpu: cpu,
+        last_check: now,
+        pause_until: now,
+        phase_start: now,
+        phase_cpu: cpu,
+        totals: [(Duration::ZERO, Duration::ZERO, Duration::ZERO); 2],
+    })
+});
+
+// tempo di CPU consumato dal processo (tutti i thread, in modalità utente e kernel)
+fn process_cpu() -> Duration {
+    ProcessTime::try_now().map(|t| t.as_duration()).unwrap_or_default()
+}
+
+/***
+configure: funzione per impostare i limiti di CPU (opzioni "cpu_limit_idle" e "cpu_limit_backup")
+    listening: limite mentre il tool aspetta le gesture, in % della capacità totale
+    backup: limite durante il backup, in % della capacità totale
+***/
+pub fn configure(listening: u8, backup: u8) {
+    if let Ok(mut governor) = GOVERNOR.lock() {
+        governor.limits = [listening, backup];
+    }
+}
+
+/***
+set_phase: funzione per passare a un'altra fase (e al suo limite di CPU), registrando il consumo della fase precedente
+    phase: nuova fase
+***/
+pub fn set_phase(phase: Phase) {
+    if let Ok(mut governor) = GOVERNOR.lock() {
+        let (now, cpu) = (Instant::now(), process_cpu());
+        governor.close_phase(now, cpu);
+        governor.phase = phase;
+        governor.window_start = now;
+        governor.window_cpu = cpu;
+        governor.pause_until = now;
+    }
+}
+
+/***
+throttle: funzione chiamata periodicamente dai cicli di attesa delle gesture e dai cicli di copia: se il processo ha consumato più CPU
+    del limite della fase corrente, il thread viene messo in pausa finché il consumo medio della finestra non rientra nel limite.
+    Tutti i thread che chiamano throttle durante la pausa (es. i worker della copia) restano in pausa fino alla stessa scadenza
+***/
+pub fn throttle() {
+    let pause = {
+        let Ok(mut governor) = GOVERNOR.lock() else { return };
+        let now = Instant::now();
+        if governor.pause_until > now {
+            governor.pause_until - now
+        } else if governor.limits[governor.phase.index()] >= 100 || now - governor.last_check < CHECK_INTERVAL {
+            return;
+        } else {
+            governor.last_check = now;
+            let cpu = process_cpu();
+            let elapsed = now - governor.window_start;
+            //tempo che deve trascorrere perché il consumo della finestra sia pari al limite
+            let budget = governor.limits[governor.phase.index()] as f64 / 100.0 * governor.cpus as f64;
+            let needed = Duration::from_secs_f64(cpu.saturating_sub(governor.window_cpu).as_secs_f64() / budget.max(0.01));
+            if needed <= elapsed {
+                if elapsed >= WINDOW { //consumo nel limite: inizia una nuova finestra
+                    governor.window_start = now;
+                    governor.window_cpu = cpu;
+                }
+                return;
+            }
+            let pause = (needed - elapsed).min(MAX_PAUSE);
+            let phase = governor.phase.index();
+            governor.totals[phase].2 += pause;
+            governor.pause_until = now + pause;
+            pause
+        }
+    };
+    thread::sleep(pause);
+}
+
+/***
+report: funzione per descrivere i limiti e il consumo medio di ogni fase, riportati nel log della CPU
+    -> restituisce: le righe da scrivere nel log
+***/
+pub fn report() -> Vec<String> {
+    let Ok(mut governor) = GOVERNOR.lock() else { return Vec::new() };
+    let (now, cpu) = (Instant::now(), process_cpu());
+    governor.close_phase(now, cpu);
+    let mut lines = vec![format!("CPU limits: {}% while listening, {}% during backup (current phase: {:?})", governor.limits[0], governor.limits[1], governor.phase)];
+    for (phase, name) in [(Phase::Listening, "while listening"), (Phase::Backup, "during backup")] {
+        let (wall, used, paused) = governor.totals[phase.index()];
+        if wall.is_zero() {
+            continue;
+        }
+        let average = used.as_secs_f64() / wall.as_secs_f64() / governor.cpus as f64 * 100.0;
+        lines.push(format!("Average CPU usage {}: {:05.2}% over {} s (limit {}%, throttled for {:.1} s)", name, average, wall.as_secs(), governor.limits[phase.index()], paused.as_secs_f64()));
+    }
+    lines
+}
+
+impl Governor {
+    // aggiunge ai totali della fase corrente il tempo e la CPU consumata dall'inizio della fase (che riparte da adesso)
+    fn close_phase(&mut self, now: Instant, cpu: Duration) {
+        let phase = self.phase.index();
+        self.totals[phase].0 += now - self.phase_start;
+        self.totals[phase].1 += cpu.saturating_sub(self.phase_cpu);
+        self.phase_start = now;
+        self.phase_cpu = cpu;
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use age::secrecy::{ExposeSecret, SecretString};
+use age::stream::{StreamReader, StreamWriter};
+use age::{x25519, Decryptor, Encryptor, Identity, IdentityFile, Recipient};
+use crate::archive::{SplitFile, TAR_ZST_FILE, open_split, split_parts};
+use crate::backup_index::{check_relative_key, relative_key};
+use crate::fs_compat::{PART_SUFFIX, original_names, part_name};
+use crate::backup_options::get_options;
+use crate::manifest::MANIFEST_FILE;
+use crate::cpu_governor::throttle;
+use crate::bandwidth::limit;
+
+pub const KEY_FILE: &str = "backup_key.age";        // chiave del backup, cifrata con la passphrase o con le chiavi pubbliche della configurazione
+pub const NAMES_FILE: &str = "backup_names.age";    // corrispondenza tra i nomi cifrati e i percorsi originali dei file (opzione "encrypt_names")
+pub const ENCRYPTED_SUFFIX: &str = ".age";          // estensione dei file cifrati
+
+/***
+EncryptionOptions: opzioni di cifratura del backup (formato age: X25519 o scrypt, ChaCha20-Poly1305)
+    passphrase: passphrase da cui viene derivata la chiave (chiave "passphrase")
+    recipients: chiavi pubbliche age ("age1...") dei destinatari del backup (chiave "recipient", ripetibile); se presenti, la passphrase viene ignorata
+    encrypt_names: se true vengono nascosti anche i nomi dei file (chiave "encrypt_names")
+***/
+#[derive(Clone, Default)]
+pub struct EncryptionOptions {
+    pub passphrase: Option<String>,
+    pub recipients: Vec<String>,
+    pub encrypt_names: bool,
+}
+
+impl EncryptionOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.passphrase.is_some() || !self.recipients.is_empty()
+    }
+}
+
+// la passphrase non deve comparire nell'output del programma
+impl fmt::Debug for EncryptionOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionOptions")
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "***"))
+            .field("recipients", &self.recipients)
+            .field("encrypt_names", &self.encrypt_names)
+            .finish()
+    }
+}
+
+/***
+BackupCipher: cifratura dei file di un backup.
+    Per ogni backup viene generata una chiave X25519 casuale: i file sono cifrati con questa chiave (operazione veloce anche per migliaia di file),
+    mentre la chiave stessa viene salvata nel file KEY_FILE cifrata con la passphrase o con le chiavi pubbliche della configurazione.
+    Tutti i file sono nel formato age, quindi il backup si può decifrare anche con il programma "age".
+    dir: cartella del backup
+    encrypt_names: se true i file vengono salvati come "00000001.age", "00000002.age", ... nella radice del backup
+    names: corrispondenza nome cifrato -> percorso originale, salvata in NAMES_FILE
+***/
+pub struct BackupCipher {
+    identity: x25519::Identity,
+    dir: PathBuf,
+    encrypt_names: bool,
+    names: Vec<(String, String)>,
+}
+
+impl BackupCipher {
+    /***
+    create: genera la chiave di un nuovo backup e la salva, cifrata, nella cartella del backup
+        dir: cartella del backup
+        options: opzioni di cifratura
+    ***/
+    pub fn create(dir: &Path, options: &EncryptionOptions) -> io::Result<Self> {
+        let identity = x25519::Identity::generate();
+
+        let encryptor = if !options.recipients.is_empty() {
+            let mut recipients: Vec<x25519::Recipient> = Vec::new();
+            for r in &options.recipients {
+                recipients.push(r.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Chiave pubblica '{}' non valida: {}", r, e)))?);
+            }
+            Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn Recipient)).map_err(io::Error::other)?
+        } else {
+            let passphrase = options.passphrase.clone().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Nessuna chiave di cifratura configurata"))?;
+            Encryptor::with_user_passphrase(SecretString::from(passphrase))
+        };
+
+        let mut writer = encryptor.wrap_output(BufWriter::new(File::create(dir.join(KEY_FILE))?))?;
+        writer.write_all(identity.to_string().expose_secret().as_bytes())?;
+        writer.finish()?.flush()?;
+
+        Ok(BackupCipher { identity, dir: dir.to_path_buf(), encrypt_names: options.encrypt_names, names: Vec::new() })
+    }
+
+    /***
+    open: legge la chiave di un backup esistente
+        dir: cartella del backup
+        identities: passphrase o chiavi private con cui è stata cifrata la chiave del backup
+    ***/
+    pub fn open(dir: &Path, identities: &[Box<dyn Identity>]) -> io::Result<Self> {
+        let decryptor = Decryptor::new_buffered(io::BufReader::new(File::open(dir.join(KEY_FILE))?)).map_err(io::Error::other)?;
+        let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref())).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, format!("Impossibile decifrare la chiave del backup: {}", e)))?;
+        let mut key = String::new();
+        reader.read_to_string(&mut key)?;
+        let identity = key.trim().parse().map_err(|e: &str| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(BackupCipher { identity, dir: dir.to_path_buf(), encrypt_names: false, names: Vec::new() })
+    }
+
+    /***
+    encrypts_names: -> restituisce: true se i file vengono salvati con nomi cifrati nella radice del backup
+    ***/
+    pub fn encrypts_names(&self) -> bool {
+        self.encrypt_names
+    }
+
+    /***
+    wrap: cifra tutto ciò che viene scritto su un writer (usata per l'archivio tar.zst)
+        output: writer di destinazione
+        -> restituisce: il writer cifrato (deve essere chiuso con finish)
+    ***/
+    pub fn wrap<W: Write>(&self, output: W) -> io::Result<StreamWriter<W>> {
+        let recipient = self.identity.to_public();
+        Encryptor::with_recipients(iter::once(&recipient as &dyn Recipient)).map_err(io::Error::other)?.wrap_output(output)
+    }
+
+    /***
+    decrypt_reader: decifra un file cifrato con la chiave del backup
+        input: contenuto cifrato
+    ***/
+    pub fn decrypt_reader<R: Read>(&self, input: R) -> io::Result<StreamReader<R>> {
+        let decryptor = Decryptor::new(input).map_err(io::Error::other)?;
+        decryptor.decrypt(iter::once(&self.identity as &dyn Identity)).map_err(io::Error::other)
+    }
+
+    /***
+    encrypt_file: cifra un file della sorgente nella cartella del backup
+        src: percorso del file sorgente
+        key: percorso relativo del file nella sorgente
+        dst: percorso di destinazione del file in chiaro (al nome viene aggiunto ENCRYPTED_SUFFIX; se encrypt_names è true viene ignorato)
+        max_part_size: dimensione massima di un file sulla destinazione (FAT32): il file cifrato più grande viene diviso in parti (vedi SplitFile)
+        -> restituisce: l'hash BLAKE3 del contenuto in chiaro e true se il file cifrato è stato diviso in parti
+    ***/
+    pub fn encrypt_file(&mut self, src: &Path, key: &str, dst: &Path, max_part_size: Option<u64>) -> io::Result<(String, bool)> {
+        let mut input = File::open(src)?; // aperto prima di scegliere il nome cifrato: un file illeggibile non deve lasciare un nome senza file
+        let stored = format!("{:08}{}", self.names.len() + 1, ENCRYPTED_SUFFIX);
+        let target = if self.encrypt_names {
+            self.dir.join(&stored)
+        } else {
+            let mut name = dst.as_os_str().to_os_string();
+            name.push(ENCRYPTED_SUFFIX);
+            PathBuf::from(name)
+        };
+
+        match self.encrypt_to(&mut input, &target, max_part_size) {
+            Ok((hash, split)) => {
+                if self.encrypt_names {
+                    self.names.push((stored, key.to_string()));
+                }
+                Ok((hash, split))
+            }
+            Err(e) => {
+                // il file cifrato incompleto (o le sue parti) viene eliminato, così che il backup non contenga file a metà
+                for part in split_parts(&target) {
+                    let _ = fs::remove_file(part);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // cifra il contenuto di input nel file target (diviso in parti oltre max_part_size) e restituisce l'hash BLAKE3 del contenuto in chiaro
+    // e true se il file è stato diviso
+    fn encrypt_to(&self, input: &mut File, target: &Path, max_part_size: Option<u64>) -> io::Result<(String, bool)> {
+        let mut output = self.wrap(SplitFile::create(target, max_part_size)?)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = input.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            throttle();
+            limit(n as u64);
+            output.write_all(&buffer[..n])?;
+        }
+        let parts = output.finish()?.finish()?;
+        Ok((hasher.finalize().to_hex().to_string(), parts.len() > 1))
+    }
+
+    /***
+    write_encrypted: scrive un file di servizio cifrato nella cartella del backup (es. il manifest)
+        name: nome del file (senza ENCRYPTED_SUFFIX)
+        data: contenuto in chiaro
+    ***/
+    pub fn write_encrypted(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let mut output = self.wrap(BufWriter::new(File::create(self.dir.join(format!("{}{}", name, ENCRYPTED_SUFFIX)))?))?;
+        output.write_all(data)?;
+        output.finish()?.flush()
+    }
+
+    /***
+    finish: salva, cifrata, la corrispondenza tra i nomi cifrati e i percorsi originali (solo se encrypt_names è true)
+    ***/
+    pub fn finish(&self) -> io::Result<()> {
+        if self.names.is_empty() {
+            return Ok(());
+        }
+        let content: String = self.names.iter().map(|(stored, original)| format!("{}\t{}\n", stored, original)).collect();
+        let mut output = self.wrap(BufWriter::new(File::create(self.dir.join(NAMES_FILE))?))?;
+        output.write_all(content.as_bytes())?;
+        output.finish()?.flush()
+    }
+}
+
+/***
+is_encrypted: funzione per verificare se un backup è cifrato
+    dir: cartella del backup
+***/
+pub fn is_encrypted(dir: &Path) -> bool {
+    dir.join(KEY_FILE).is_file()
+}
+
+/***
+decrypt_backup: funzione per decifrare un backup cifrato, ricreando i file originali
+    backup: cartella del backup
+    output: cartella in cui vengono scritti i file decifrati
+    identities: passphrase o chiavi private con cui è stata cifrata la chiave del backup
+    -> restituisce: il numero di file decifrati
+***/
+pub fn decrypt_backup(backup: &Path, output: &Path, identities: &[Box<dyn Identity>]) -> io::Result<usize> {
+    let cipher = BackupCipher::open(backup, identities)?;
+    fs::create_dir_all(output)?;
+
+    // backup in formato tar.zst: l'intero archivio è cifrato
+    let archive = backup.join(format!("{}{}", TAR_ZST_FILE, ENCRYPTED_SUFFIX));
+    if !split_parts(&archive).is_empty() {
+        let decoder = zstd::stream::read::Decoder::new(cipher.decrypt_reader(open_split(&archive)?)?)?;
+        let mut archive = tar::Archive::new(decoder);
+        let mut count = 0;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            entry.unpack_in(output)?;
+            count += 1;
+        }
+        return Ok(count);
+    }
+
+    // corrispondenza nome cifrato -> percorso originale (se i nomi sono stati cifrati)
+    let mut names = HashMap::new();
+    if backup.join(NAMES_FILE).is_file() {
+        let reader = io::BufReader::new(cipher.decrypt_reader(File::open(backup.join(NAMES_FILE))?)?);
+        for line in reader.lines() {
+            if let Some((stored, original)) = line?.split_once('\t') {
+                names.insert(stored.to_string(), original.to_string());
+            }
+        }
+    }
+
+    // file salvati con un nome diverso per il file system della destinazione (percorso nel backup -> percorso originale)
+    let renamed = original_names(backup)?;
+
+    let service_files = [KEY_FILE.to_string(), NAMES_FILE.to_string(), format!("{}{}", MANIFEST_FILE, ENCRYPTED_SUFFIX)];
+    let mut count = 0;
+    let mut stack = vec![backup.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                stack.push(path);
+                continue;
+            }
+            let mut key = relative_key(&path, backup);
+            if service_files.contains(&key) {
+                continue;
+            }
+            // un file cifrato diviso in parti viene decifrato una sola volta, a partire dalla prima parte
+            let mut path = path;
+            if let Some((whole, _)) = key.rsplit_once(PART_SUFFIX).filter(|(whole, _)| whole.ends_with(ENCRYPTED_SUFFIX)) {
+                if key != part_name(whole, 1) {
+                    continue;
+                }
+                key = whole.to_string();
+                path = backup.join(&key);
+            }
+            let original = match (names.get(&key), key.strip_suffix(ENCRYPTED_SUFFIX)) {
+                (Some(original), _) => original.clone(),
+                (None, Some(stored)) => renamed.get(stored).cloned().unwrap_or_else(|| stored.to_string()),
+                (None, None) => continue, // file non cifrati (log, indice)
+            };
+            check_relative_key(&original)?; // un backup manomesso non deve poter scrivere fuori dalla cartella di destinazione
+
+            let destination = output.join(&original);
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut reader = cipher.decrypt_reader(io::BufReader::new(open_split(&path)?))?;
+            let mut writer = BufWriter::new(File::create(&destination)?);
+            io::copy(&mut reader, &mut writer)?;
+            writer.flush()?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/***
+decrypt_command: funzione che esegue il comando "decrypt" da riga di comando
+    uso: progetto_rust decrypt <cartella del backup> <cartella di destinazione> [--identity <file delle chiavi private>]
+    senza --identity viene usata la passphrase indicata nel file di configurazione
+    conf_file: percorso del file di configurazione
+    args: argomenti successivi a "decrypt"
+    -> restituisce: il codice di uscita del programma (0 se il backup è stato decifrato, 2 in caso di errore)
+***/
+pub fn decrypt_command(conf_file: &str, args: &[String]) -> i32 {
+    let mut paths = Vec::new();
+    let mut identity_file = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--identity" {
+            identity_file = iter.next().cloned();
+        } else {
+            paths.push(arg);
+        }
+    }
+    if paths.len() != 2 {
+        println!("Uso: progetto_rust decrypt <cartella del backup> <cartella di destinazione> [--identity <file>]");
+        return 2;
+    }
+
+    let identities: Vec<Box<dyn Identity>> = match identity_file {
+        Some(file) => match IdentityFile::from_file(file.clone()).map_err(io::Error::other).and_then(|f| f.into_identities().map_err(io::Error::other)) {
+            Ok(identities) => identities,
+            Err(e) => {
+                println!("Impossibile leggere le chiavi private da '{}': {}", file, e);
+                return 2;
+            }
+        },
+        None => match get_options(conf_file).ok().and_then(|o| o.encryption.passphrase) {
+            Some(passphrase) => vec![Box::new(age::scrypt::Identity::new(SecretString::from(passphrase)))],
+            None => {
+                println!("Nessuna passphrase nel file di configurazione: indicare il file delle chiavi private con --identity.");
+                return 2;
+            }
+        },
+    };
+
+    let (backup, output) = (Path::new(paths[0]), Path::new(paths[1]));
+    if !is_encrypted(backup) {
+        println!("Il backup '{}' non è cifrato.", backup.to_string_lossy());
+        return 2;
+    }
+    match decrypt_backup(backup, output, &identities) {
+        Ok(count) => {
+            println!("{} file decifrati in '{}'.", count, output.to_string_lossy());
+            0
+        }
+        Err(e) => {
+            println!("Errore durante la decifratura del backup: {}", e);
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::content;
+    use crate::fs_compat::{DestinationNames, FileSystemKind};
+
+    // chiave di prova: il backup viene cifrato per la sua chiave pubblica e decifrato con la chiave privata
+    fn test_key() -> (EncryptionOptions, Vec<Box<dyn Identity>>) {
+        let identity = x25519::Identity::generate();
+        let options = EncryptionOptions { recipients: vec![identity.to_public().to_string()], ..EncryptionOptions::default() };
+        (options, vec![Box::new(identity)])
+    }
+
+    // contenuto diverso per ogni file di prova
+    #[test]
+    fn round_trip_with_parts_and_renamed_files() {
+        let (src, backup, output) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (options, identities) = test_key();
+        let mut cipher = BackupCipher::create(backup.path(), &options).unwrap();
+
+        // su FAT32 "dir/A.txt" viene salvato come "dir/A (1).txt" e il file grande viene diviso in parti
+        let mut names = DestinationNames::with_kind(FileSystemKind::Fat32, 3);
+        fs::create_dir(src.path().join("dir")).unwrap();
+        let files = [("dir/a.txt", content(1, 100)), ("dir/A.txt", content(2, 100)), ("dir/grande.bin", content(3, 50_000))];
+        for (key, data) in &files {
+            fs::write(src.path().join(key.replace('/', "-")), data).unwrap();
+            let stored = names.map_file(key);
+            fs::create_dir_all(backup.path().join(&stored).parent().unwrap()).unwrap();
+            let (hash, split) = cipher.encrypt_file(&src.path().join(key.replace('/', "-")), key, &backup.path().join(&stored), Some(20_000)).unwrap();
+            assert_eq!(hash, blake3::hash(data).to_hex().to_string());
+            assert_eq!(split, data.len() > 20_000);
+        }
+        names.save(backup.path()).unwrap();
+        cipher.finish().unwrap();
+        assert!(backup.path().join("dir/A (1).txt.age").is_file());
+        assert_eq!(split_parts(&backup.path().join("dir/grande.bin.age")).len(), 3);
+
+        assert_eq!(decrypt_backup(backup.path(), output.path(), &identities).unwrap(), 3);
+        for (key, data) in &files {
+            assert_eq!(&fs::read(output.path().join(key)).unwrap(), data, "{}", key);
+        }
+    }
+
+    #[test]
+    fn round_trip_with_encrypted_names() {
+        let (src, backup, output) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (mut options, identities) = test_key();
+        options.encrypt_names = true;
+        let mut cipher = BackupCipher::create(backup.path(), &options).unwrap();
+
+        let files = [("documenti/segreto.txt", content(4, 1000)), ("grande.bin", content(5, 50_000))];
+        for (i, (key, data)) in files.iter().enumerate() {
+            let path = src.path().join(format!("{}", i));
+            fs::write(&path, data).unwrap();
+            cipher.encrypt_file(&path, key, &backup.path().join(key), Some(20_000)).unwrap();
+        }
+        cipher.finish().unwrap();
+        // nel backup compaiono solo i nomi cifrati
+        assert!(backup.path().join("00000001.age").is_file() && !backup.path().join("documenti").exists());
+        assert_eq!(split_parts(&backup.path().join("00000002.age")).len(), 3);
+
+        assert_eq!(decrypt_backup(backup.path(), output.path(), &identities).unwrap(), 2);
+        for (key, data) in &files {
+            assert_eq!(&fs::read(output.path().join(key)).unwrap(), data, "{}", key);
+        }
+    }
+
+    #[test]
+    fn tampered_names_are_rejected() {
+        let (src, backup, output) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (options, identities) = test_key();
+        let mut cipher = BackupCipher::create(backup.path(), &options).unwrap();
+        fs::write(src.path().join("file.txt"), "contenuto").unwrap();
+        cipher.encrypt_file(&src.path().join("file.txt"), "file.txt", &backup.path().join("00000001"), None).unwrap();
+        // chi conosce la chiave pubblica può scrivere un elenco dei nomi che punta fuori dalla cartella di destinazione
+        cipher.write_encrypted(NAMES_FILE.strip_suffix(ENCRYPTED_SUFFIX).unwrap(), b"00000001.age\t../fuori.txt\n").unwrap();
+
+        let error = decrypt_backup(backup.path(), &output.path().join("decifrato"), &identities).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!output.path().join("fuori.txt").exists());
+    }
+}
@@ -0,0 +1,266 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use sysinfo::Disks;
+use crate::archive::archive_size_estimate;
+use crate::backup_index::{BackupIndex, relative_key, modified_nanos};
+use crate::backup_options::{BackupOptions, OutputFormat};
+use crate::backup_tool::check_extension;
+use crate::cpu_governor::throttle;
+use crate::fs_compat::disk_for;
+use crate::special_files::SpecialKind;
+
+pub const ESTIMATE_WAIT: Duration = Duration::from_secs(2); // attesa massima della stima prima di iniziare la copia: per le sorgenti piccole lo spazio viene verificato prima di creare il backup
+const SPACE_CHECK_BYTES: u64 = 256 * 1024 * 1024; // lo spazio libero della destinazione viene verificato di nuovo ogni volta che vengono copiati questi byte
+
+/***
+Progress: stato della stima, condiviso tra il thread che visita la sorgente e il thread del backup
+    files: numero di file da copiare trovati finora
+    size: dimensione dei file da copiare trovati finora
+    done: true quando la visita della sorgente è terminata
+    stop: true se il backup è terminato (o interrotto) prima della stima
+***/
+#[derive(Default)]
+struct Progress {
+    files: AtomicUsize,
+    size: AtomicU64,
+    done: AtomicBool,
+    stop: AtomicBool,
+}
+
+/***
+SizeEstimator: stima del numero di file e della dimensione del backup, calcolata da un thread che visita la sorgente
+    mentre il backup copia i file, invece di contare tutti i file prima di iniziare la copia. Durante la visita vengono
+    conservati solo i totali, quindi la memoria usata non dipende dal numero di file della sorgente
+    progress: stato della stima
+    dst: Path della destinazione, di cui viene verificato lo spazio libero
+    format: formato del backup (gli archivi hanno anche i record finali)
+    parity: percentuale di ridondanza dei dati di parità
+    next_check: byte copiati dopo i quali lo spazio libero viene verificato di nuovo
+    final_check: true se lo spazio è già stato verificato con la stima completa
+***/
+pub struct SizeEstimator {
+    progress: Arc<Progress>,
+    dst: PathBuf,
+    format: OutputFormat,
+    parity: u8,
+    next_check: u64,
+    final_check: bool,
+}
+
+impl SizeEstimator {
+    /***
+    start: avvia la stima in un altro thread
+        src: Path della sorgente
+        extensions: estensioni dei file da copiare, se è vuoto vengono copiati tutti i file
+        previous: indice del backup precedente; se presente, i file invariati non vengono conteggiati, con la stessa regola della copia
+            (vedi BackupIndex::is_unchanged: stessa dimensione e data di modifica e copia ancora presente sul disco esterno; l'hash non viene calcolato)
+        backup_root: Path della radice del disco esterno, in cui si trovano le copie dei file del backup precedente
+        options: opzioni del backup: formato (per gli archivi la dimensione di ogni file è quella occupata nell'archivio, intestazioni comprese,
+            senza considerare la compressione), percentuale dei dati di parità e "preserve_metadata" (i collegamenti simbolici non vengono seguiti)
+        dst: Path della destinazione
+    ***/
+    pub fn start(src: &Path, extensions: &[String], previous: Option<Arc<BackupIndex>>, backup_root: &Path, options: &BackupOptions, dst: &Path) -> Self {
+        let progress = Arc::new(Progress::default());
+        let (shared, src, extensions, backup_root) = (Arc::clone(&progress), src.to_path_buf(), extensions.to_vec(), backup_root.to_path_buf());
+        let (format, parity, follow_symlinks) = (options.format, options.parity, !options.preserve_metadata);
+        thread::spawn(move || {
+            count_files_and_size(&src, &src, &extensions, previous.as_deref().map(|p| (p, backup_root.as_path())), format, follow_symlinks, &shared);
+            shared.done.store(true, Ordering::Relaxed);
+        });
+        SizeEstimator { progress, dst: dst.to_path_buf(), format, parity, next_check: 0, final_check: false }
+    }
+
+    /***
+    wait: aspetta la fine della stima
+        timeout: attesa massima
+        -> restituisce: true se la stima è completa
+    ***/
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while !self.is_done() && start.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.is_done()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress.done.load(Ordering::Relaxed)
+    }
+
+    // numero di file da copiare trovati finora
+    pub fn files(&self) -> usize {
+        self.progress.files.load(Ordering::Relaxed)
+    }
+
+    // spazio richiesto dal backup secondo la stima corrente (record finali dell'archivio e dati di parità compresi)
+    pub fn total_size(&self) -> u64 {
+        let mut size = self.progress.size.load(Ordering::Relaxed);
+        if self.format != OutputFormat::Directory {
+            size += 1024; //record finali dell'archivio
+        }
+        size + size * self.parity as u64 / 100 //dati di parità (stima)
+    }
+
+    /***
+    check_space: verifica che lo spazio libero della destinazione basti per i file ancora da copiare. La verifica avviene
+        ogni SPACE_CHECK_BYTES byte copiati e quando la stima diventa completa; finché la stima non è completa lo spazio
+        richiesto è un limite inferiore, quindi il backup viene interrotto solo se lo spazio non basta di sicuro
+        copied_size: byte copiati finora (compresi i file già copiati da un backup interrotto)
+        -> restituisce: Err(InsufficientSpace) se lo spazio non basta
+    ***/
+    pub fn check_space(&mut self, copied_size: u64) -> io::Result<()> {
+        let done = self.is_done();
+        if copied_size < self.next_check && (self.final_check || !done) {
+            return Ok(());
+        }
+        self.next_check = copied_size + SPACE_CHECK_BYTES;
+        self.final_check = done;
+        let needed = self.total_size().saturating_sub(copied_size);
+        let available = available_space(&self.dst)?;
+        if available < needed {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, InsufficientSpace { needed, available }));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SizeEstimator {
+    fn drop(&mut self) {
+        self.progress.stop.store(true, Ordering::Relaxed); // la visita della sorgente non serve più
+    }
+}
+
+/***
+InsufficientSpace: errore che interrompe il backup quando lo spazio libero della destinazione non basta per i file ancora da copiare
+    needed: byte ancora da copiare secondo la stima
+    available: byte liberi sulla destinazione
+***/
+#[derive(Debug)]
+pub struct InsufficientSpace {
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} byte liberi, almeno {} byte richiesti", self.available, self.needed)
+    }
+}
+
+impl Error for InsufficientSpace {}
+
+/***
+count_files_and_size: funzione per contare i file da copiare e la loro dimensione, aggiornando progress durante la visita.
+    Le cartelle e i file non leggibili vengono ignorati (l'errore viene registrato dalla copia)
+    path: Path della cartella da visitare
+    src_root: Path della radice della sorgente (per calcolare i percorsi relativi)
+    extensions, format: come in SizeEstimator::start
+    previous: indice del backup precedente e radice del disco esterno (vedi SizeEstimator::start)
+    follow_symlinks: false se i collegamenti simbolici vengono copiati come collegamenti (opzione "preserve_metadata", vedi backup_tool::copy_entry):
+        in questo caso non vengono seguiti e non occupano spazio nella stima
+    progress: stato della stima
+***/
+fn count_files_and_size(path: &Path, src_root: &Path, extensions: &[String], previous: Option<(&BackupIndex, &Path)>, format: OutputFormat, follow_symlinks: bool, progress: &Progress) {
+    let Ok(entries) = fs::read_dir(path) else { return };
+    for entry in entries.flatten() {
+        if progress.stop.load(Ordering::Relaxed) {
+            return;
+        }
+        throttle();
+        let entry_path = entry.path();
+        let Ok(file_type) = entry.file_type() else { continue };
+        if file_type.is_symlink() && !follow_symlinks {
+            continue;
+        }
+        let is_dir = if file_type.is_symlink() { entry_path.is_dir() } else { file_type.is_dir() };
+
+        if is_dir {
+            count_files_and_size(&entry_path, src_root, extensions, previous, format, follow_symlinks, progress);
+        } else if extensions.is_empty() || check_extension(&entry_path, extensions) {
+            let metadata = if file_type.is_symlink() { fs::metadata(&entry_path) } else { entry.metadata() };
+            let Ok(metadata) = metadata else { continue };
+            if SpecialKind::detect(&metadata.file_type()).is_some() {
+                continue; //i file speciali non vengono copiati
+            }
+            if let Some((previous, backup_root)) = previous {
+                let key = relative_key(&entry_path, src_root);
+                if previous.is_unchanged(&key, metadata.len(), modified_nanos(&metadata), None, backup_root).is_some() {
+                    continue;
+                }
+            }
+            progress.files.fetch_add(1, Ordering::Relaxed);
+            progress.size.fetch_add(archive_size_estimate(format, metadata.len()), Ordering::Relaxed);
+        }
+    }
+}
+
+/***
+available_space: funzione per ottenere lo spazio disponibile sul disco di destinazione
+    path: Path della destinazione
+    -> restituisce: la dimensione disponibile in byte
+***/
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let disks = Disks::new_with_refreshed_list();
+    match disk_for(&disks, path) {
+        Some(disk) => Ok(disk.available_space()),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "Disco non trovato")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_index::IndexEntry;
+
+    #[test]
+    fn unchanged_files_need_their_copy_on_the_disk() {
+        let src = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap(); // radice del disco esterno
+        for (key, content) in [("a.txt", "invariato"), ("b.txt", "copia eliminata"), ("c.txt", "nuovo")] {
+            fs::write(src.path().join(key), content).unwrap();
+        }
+
+        // backup precedente: a.txt e b.txt sono nell'indice, ma la copia di b.txt è stata eliminata dal disco esterno
+        fs::create_dir(root.path().join("precedente")).unwrap();
+        fs::write(root.path().join("precedente/a.txt"), "invariato").unwrap();
+        let mut previous = BackupIndex::new(&src.path().to_string_lossy(), "20240101000000000");
+        for key in ["a.txt", "b.txt"] {
+            let metadata = fs::metadata(src.path().join(key)).unwrap();
+            previous.files.insert(key.to_string(), IndexEntry { size: metadata.len(), mtime: modified_nanos(&metadata), hash: None, location: "precedente".to_string(), stored: None });
+        }
+
+        // come nella copia, b.txt verrà copiato di nuovo e va conteggiato insieme al file nuovo
+        let estimator = SizeEstimator::start(src.path(), &[], Some(Arc::new(previous)), root.path(), &BackupOptions::default(), root.path());
+        assert!(estimator.wait(Duration::from_secs(10)));
+        assert_eq!((estimator.files(), estimator.total_size()), (2, ("copia eliminata".len() + "nuovo".len()) as u64));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_copied_as_links_are_not_followed() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        fs::create_dir(src.path().join("dati")).unwrap();
+        fs::write(src.path().join("dati/file.txt"), "contenuto").unwrap();
+        std::os::unix::fs::symlink(src.path().join("dati"), src.path().join("collegamento")).unwrap();
+        std::os::unix::fs::symlink(".", src.path().join("dati/ciclo")).unwrap(); // "ciclo -> ." verrebbe contato fino a ELOOP
+
+        let options = BackupOptions { preserve_metadata: true, ..BackupOptions::default() };
+        let estimator = SizeEstimator::start(src.path(), &[], None, dst.path(), &options, dst.path());
+        assert!(estimator.wait(Duration::from_secs(10)));
+        assert_eq!((estimator.files(), estimator.total_size()), (1, "contenuto".len() as u64));
+
+        // senza preserve_metadata la copia segue il collegamento alla cartella, e così la stima
+        fs::remove_file(src.path().join("dati/ciclo")).unwrap();
+        let estimator = SizeEstimator::start(src.path(), &[], None, dst.path(), &BackupOptions::default(), dst.path());
+        assert!(estimator.wait(Duration::from_secs(10)));
+        assert_eq!(estimator.files(), 2);
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::fs_compat::part_name;
+use crate::salvage::{format_ranges, lost_bytes};
+
+/***
+FailureKind: tipo di errore incontrato copiando un file
+    PermissionDenied: il tool non ha i permessi per leggere il file
+    Vanished: il file è stato eliminato o spostato durante il backup
+    Io: errore di lettura o scrittura (es. settore danneggiato)
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FailureKind {
+    PermissionDenied,
+    Vanished,
+    Io,
+}
+
+impl FailureKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailureKind::PermissionDenied => "permesso negato",
+            FailureKind::Vanished => "file non più presente",
+            FailureKind::Io => "errore di I/O",
+        }
+    }
+}
+
+/***
+CopyFailure: file (o cartella) che non è stato possibile copiare
+    key: percorso relativo alla sorgente
+    kind: tipo di errore
+    message: messaggio dell'errore
+***/
+#[derive(Debug)]
+pub struct CopyFailure {
+    pub key: String,
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+/***
+CopyFailures: elenco dei file che non è stato possibile copiare (il backup prosegue con gli altri file)
+    list: file non copiati
+    salvaged: file copiati in parte in modalità salvataggio (percorso relativo, zone illeggibili riempite di zeri)
+    not_saved: file non copiati perché il tempo a disposizione (opzione "time_limit") non bastava (percorso relativo, dimensione)
+    excluded: file esclusi perché lo spazio della destinazione non bastava (opzione "best_fit") (percorso relativo, dimensione)
+    failed_keys, salvaged_keys: percorsi di list e salvaged, per controllare in tempo costante se un file è già registrato
+***/
+#[derive(Default, Debug)]
+pub struct CopyFailures {
+    pub list: Vec<CopyFailure>,
+    pub salvaged: Vec<(String, Vec<(u64, u64)>)>,
+    pub not_saved: Vec<(String, u64)>,
+    pub excluded: Vec<(String, u64)>,
+    failed_keys: HashSet<String>,
+    salvaged_keys: HashSet<String>,
+}
+
+impl CopyFailures {
+    /***
+    record: registra l'errore incontrato copiando un file
+        key: percorso relativo del file
+        error: errore incontrato
+        -> restituisce: Err(error) se il backup non può proseguire (destinazione piena o in sola lettura, archivio incompleto), altrimenti Ok(())
+    ***/
+    pub fn record(&mut self, key: &str, error: io::Error) -> io::Result<()> {
+        if is_fatal(&error) {
+            return Err(error);
+        }
+        let kind = match error.kind() {
+            io::ErrorKind::PermissionDenied => FailureKind::PermissionDenied,
+            io::ErrorKind::NotFound => FailureKind::Vanished,
+            _ => FailureKind::Io,
+        };
+        if !self.failed_keys.insert(key.to_string()) { // in modalità salvataggio la stessa cartella illeggibile viene incontrata a ogni passaggio
+            return Ok(());
+        }
+        println!("Impossibile copiare '{}' ({}): {}", key, kind.name(), error);
+        self.list.push(CopyFailure { key: key.to_string(), kind, message: error.to_string() });
+        Ok(())
+    }
+
+    /***
+    record_salvaged: registra un file copiato in parte in modalità salvataggio
+        key: percorso relativo del file
+        lost: zone illeggibili riempite di zeri (inizio, fine)
+    ***/
+    pub fn record_salvaged(&mut self, key: &str, lost: Vec<(u64, u64)>) {
+        if self.salvaged_keys.insert(key.to_string()) {
+            self.salvaged.push((key.to_string(), lost));
+        }
+    }
+
+    // file non copiati o copiati in parte
+    pub fn len(&self) -> usize {
+        self.list.len() + self.salvaged.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty() && self.salvaged.is_empty()
+    }
+
+    // true se il file è stato copiato in parte (zone illeggibili riempite di zeri)
+    pub fn is_salvaged(&self, key: &str) -> bool {
+        self.salvaged_keys.contains(key)
+    }
+
+    // percorsi relativi dei file non copiati (a causa di un errore, del limite di tempo o dello spazio insufficiente)
+    pub fn keys(&self) -> Vec<&str> {
+        self.list.iter().map(|f| f.key.as_str()).chain(self.not_saved.iter().chain(&self.excluded).map(|(k, _)| k.as_str())).collect()
+    }
+
+    /***
+    notes: righe da riportare nel log del backup, una per ogni file non copiato
+        show_names: se false i percorsi non vengono riportati (backup cifrato)
+    ***/
+    pub fn notes(&self, show_names: bool) -> Vec<String> {
+        let mut notes = Vec::new();
+        if !self.salvaged.is_empty() {
+            notes.push(format!("File copiati in parte (zone illeggibili della sorgente riempite di zeri): {}", self.salvaged.len()));
+            for (key, lost) in &self.salvaged {
+                let key = if show_names { key.as_str() } else { "-" };
+                notes.push(format!("    RECUPERATO\t{}\t{} byte persi\t{}", key, lost_bytes(lost), format_ranges(lost)));
+            }
+        }
+        if !self.not_saved.is_empty() {
+            notes.push(format!("File non salvati per il limite di tempo: {} ({} byte)", self.not_saved.len(), self.not_saved.iter().map(|(_, size)| size).sum::<u64>()));
+            for (key, size) in &self.not_saved {
+                let key = if show_names { key.as_str() } else { "-" };
+                notes.push(format!("    NON SALVATO\t{}\t{} byte", key, size));
+            }
+        }
+        if !self.excluded.is_empty() {
+            notes.push(format!("File esclusi per lo spazio insufficiente della destinazione: {} ({} byte)", self.excluded.len(), self.excluded.iter().map(|(_, size)| size).sum::<u64>()));
+            for (key, size) in &self.excluded {
+                let key = if show_names { key.as_str() } else { "-" };
+                notes.push(format!("    ESCLUSO\t{}\t{} byte", key, size));
+            }
+        }
+        if self.list.is_empty() {
+            return notes;
+        }
+        notes.push(format!("File non copiati a causa di un errore: {}", self.list.len()));
+        for failure in &self.list {
+            if show_names {
+                notes.push(format!("    ERRORE\t{}\t{}\t{}", failure.kind.name(), failure.key, failure.message));
+            } else {
+                notes.push(format!("    ERRORE\t{}\t{}", failure.kind.name(), failure.message));
+            }
+        }
+        notes
+    }
+}
+
+/***
+FatalCopyError: errore dopo il quale il backup non può proseguire, anche se riguarda un solo file (es. l'archivio è rimasto a metà di un file)
+***/
+#[derive(Debug)]
+pub struct FatalCopyError(pub io::Error);
+
+impl fmt::Display for FatalCopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for FatalCopyError {}
+
+// segna un errore come bloccante per tutto il backup
+pub fn fatal(error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), FatalCopyError(error))
+}
+
+// restituisce true se l'errore indica che la destinazione è piena e non riguarda un archivio rimasto a metà (segnato con fatal):
+// con l'opzione "best_fit" il file viene escluso e il backup prosegue con i successivi
+pub fn is_space_exhausted(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::StorageFull && !error.get_ref().is_some_and(|e| e.is::<FatalCopyError>())
+}
+
+// restituisce true se dopo l'errore il backup non può proseguire: destinazione piena o in sola lettura, oppure errore segnato con fatal
+pub fn is_fatal(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::StorageFull | io::ErrorKind::ReadOnlyFilesystem)
+        || error.get_ref().is_some_and(|e| e.is::<FatalCopyError>())
+}
+
+/***
+remove_partial: funzione per eliminare la copia parziale di un file non copiato (e le sue parti, se era diviso in parti)
+    dest_path: percorso della copia
+***/
+pub fn remove_partial(dest_path: &Path) {
+    if dest_path.is_file() {
+        let _ = fs::remove_file(dest_path);
+    }
+    let name = dest_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    for n in 1.. {
+        let part = dest_path.with_file_name(part_name(&name, n));
+        if fs::remove_file(&part).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fatal_errors_stop_the_backup() {
+        assert!(is_fatal(&io::Error::from(io::ErrorKind::StorageFull)));
+        assert!(is_fatal(&io::Error::from(io::ErrorKind::ReadOnlyFilesystem)));
+        assert!(is_fatal(&fatal(io::Error::from(io::ErrorKind::UnexpectedEof))));
+        assert!(!is_fatal(&io::Error::from(io::ErrorKind::PermissionDenied)));
+        assert!(!is_fatal(&io::Error::from(io::ErrorKind::NotFound)));
+
+        // la destinazione piena esclude il file solo se non ha lasciato un archivio a metà
+        assert!(is_space_exhausted(&io::Error::from(io::ErrorKind::StorageFull)));
+        assert!(!is_space_exhausted(&fatal(io::Error::from(io::ErrorKind::StorageFull))));
+
+        let mut failures = CopyFailures::default();
+        assert!(failures.record("a.txt", io::Error::from(io::ErrorKind::StorageFull)).is_err());
+        assert!(failures.is_empty());
+    }
+
+    #[test]
+    fn failures_are_recorded_once_across_passes() {
+        let mut failures = CopyFailures::default();
+        for _ in 0..3 { // ogni passaggio della modalità salvataggio incontra di nuovo gli stessi file
+            failures.record("illeggibile", io::Error::from(io::ErrorKind::PermissionDenied)).unwrap();
+            failures.record("sparito.txt", io::Error::from(io::ErrorKind::NotFound)).unwrap();
+            failures.record_salvaged("danneggiato.bin", vec![(0, 512)]);
+        }
+        assert_eq!(failures.len(), 3);
+        assert_eq!(failures.list[0].kind, FailureKind::PermissionDenied);
+        assert_eq!(failures.list[1].kind, FailureKind::Vanished);
+        assert!(failures.is_salvaged("danneggiato.bin"));
+        assert!(!failures.is_salvaged("illeggibile"));
+        assert_eq!(failures.keys(), vec!["illeggibile", "sparito.txt"]);
+    }
+}
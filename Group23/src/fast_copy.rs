@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use crate::backup_index::{copy_file_hashed, hash_file};
+use crate::cpu_governor::throttle;
+use crate::bandwidth::{limit, chunk_size};
+
+/***
+CopyStrategy: modo in cui un file viene copiato nella cartella del backup
+    Reflink: il file system (btrfs, XFS) condivide i blocchi della sorgente con la copia, senza scrivere i dati (ioctl FICLONE)
+    CopyFileRange: i dati vengono copiati dal kernel senza passare dal tool (copy_file_range), anche tra file system diversi
+    Buffered: i dati vengono letti e scritti dal tool, calcolando l'hash durante la copia
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CopyStrategy {
+    Reflink,
+    CopyFileRange,
+    Buffered,
+}
+
+/***
+CopyStrategies: numero di file copiati con ogni strategia durante un backup, riportato nel log
+***/
+#[derive(Default, Debug)]
+pub struct CopyStrategies {
+    pub reflink: usize,
+    pub copy_file_range: usize,
+    pub buffered: usize,
+}
+
+impl CopyStrategies {
+    pub fn add(&mut self, strategy: CopyStrategy) {
+        match strategy {
+            CopyStrategy::Reflink => self.reflink += 1,
+            CopyStrategy::CopyFileRange => self.copy_file_range += 1,
+            CopyStrategy::Buffered => self.buffered += 1,
+        }
+    }
+
+    // riga del log del backup, None se non è stato copiato nessun file
+    pub fn note(&self) -> Option<String> {
+        if self.reflink + self.copy_file_range + self.buffered == 0 {
+            return None;
+        }
+        Some(format!("Strategia di copia: {} file clonati (reflink), {} copiati dal kernel (copy_file_range), {} copiati dal tool", self.reflink, self.copy_file_range, self.buffered))
+    }
+}
+
+/***
+copy_file_fast: funzione per copiare un file con la strategia più veloce disponibile: reflink, poi copy_file_range, poi la copia del tool.
+    Con reflink e copy_file_range la copia viene poi letta per calcolarne l'hash (senza riscrivere i dati), a meno che l'hash non sia già noto
+    src: percorso del file sorgente
+    dst: percorso della copia
+    known_hash: hash del file registrato nell'indice dell'ultimo backup, se il file è invariato (vedi BackupIndex::known_hash)
+    -> restituisce: l'hash BLAKE3 del contenuto e la strategia usata
+***/
+pub fn copy_file_fast(src: &Path, dst: &Path, known_hash: Option<&str>) -> io::Result<(String, CopyStrategy)> {
+    if let Some(hash) = reflink_hashed(src, dst, known_hash)? {
+        return Ok((hash, CopyStrategy::Reflink));
+    }
+    if copy_range(src, dst)? {
+        let hash = match known_hash {
+            Some(h) => h.to_string(),
+            None => hash_file(dst)?,
+        };
+        return Ok((hash, CopyStrategy::CopyFileRange));
+    }
+    Ok((copy_file_hashed(src, dst)?.1, CopyStrategy::Buffered))
+}
+
+/***
+reflink_hashed: funzione per clonare un file se il file system lo permette (vedi reflink)
+    src: percorso del file sorgente
+    dst: percorso della copia
+    known_hash: hash già noto del file (vedi copy_file_fast); se presente la copia non viene riletta, così che il clone resti immediato
+    -> restituisce: l'hash BLAKE3 del contenuto se il file è stato clonato, None se va copiato con un'altra strategia
+***/
+pub fn reflink_hashed(src: &Path, dst: &Path, known_hash: Option<&str>) -> io::Result<Option<String>> {
+    if reflink(src, dst)? {
+        return match known_hash {
+            Some(h) => Ok(Some(h.to_string())),
+            None => Ok(Some(hash_file(dst)?)),
+        };
+    }
+    Ok(None)
+}
+
+/***
+reflink: funzione per clonare un file sullo stesso file system (btrfs, XFS): la copia è immediata e non occupa altro spazio finché uno dei due file non viene modificato
+    src: percorso del file sorgente
+    dst: percorso della copia (viene creata o sovrascritta)
+    -> restituisce: Ok(false) se il file system (o il sistema operativo) non supporta i reflink tra i due percorsi
+***/
+#[allow(unused_variables)]
+fn reflink(src: &Path, dst: &Path) -> io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let input = File::open(src)?;
+        let output = File::create(dst)?;
+        if unsafe { libc::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd()) } == 0 {
+            output.set_permissions(input.metadata()?.permissions())?; // come fs::copy, vengono copiati anche i permessi
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        if !unsupported(&error) {
+            return Err(error);
+        }
+    }
+    Ok(false)
+}
+
+/***
+copy_range: funzione per copiare un file con copy_file_range: i dati non passano dalla memoria del tool e il kernel può
+    usare le ottimizzazioni del file system (es. copia lato server su NFS, reflink su btrfs/XFS con kernel recenti)
+    src: percorso del file sorgente
+    dst: percorso della copia (viene creata o sovrascritta)
+    -> restituisce: Ok(false) se il sistema operativo non supporta copy_file_range tra i due percorsi (la copia va fatta con un'altra strategia)
+***/
+#[allow(unused_variables)]
+fn copy_range(src: &Path, dst: &Path) -> io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let input = File::open(src)?;
+        let metadata = input.metadata()?;
+        let output = File::create(dst)?;
+        let mut remaining = metadata.len();
+        let mut copied = 0;
+        while remaining > 0 {
+            let chunk = remaining.min(chunk_size(64 << 20) as u64) as usize; // blocchi da 64 MB (più piccoli con la banda limitata), per poter rispettare i limiti di CPU e di banda anche sui file grandi
+            let n = unsafe { libc::copy_file_range(input.as_raw_fd(), std::ptr::null_mut(), output.as_raw_fd(), std::ptr::null_mut(), chunk, 0) };
+            if n < 0 {
+                let error = io::Error::last_os_error();
+                match error.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    _ if copied == 0 && unsupported(&error) => return Ok(false), // nessun byte copiato: il file viene copiato da capo con un'altra strategia
+                    _ => return Err(error),
+                }
+            }
+            if n == 0 {
+                // il file si è accorciato durante la copia: la copia incompleta non deve essere registrata come riuscita
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Il file '{}' è stato accorciato durante la copia", src.to_string_lossy())));
+            }
+            remaining -= n as u64;
+            copied += n as u64;
+            throttle();
+            limit(n as u64);
+        }
+        output.set_permissions(metadata.permissions())?;
+        Ok(true)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Ok(false)
+    }
+}
+
+// restituisce true se l'errore indica che la strategia non è disponibile tra i due file (file system diversi o senza supporto)
+#[cfg(target_os = "linux")]
+fn unsupported(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL | libc::ENOSYS | libc::ENOTTY | libc::EBADF | libc::EPERM))
+}
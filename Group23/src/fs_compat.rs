@@ -0,0 +1,533 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use sysinfo::{Disk, Disks};
+use crate::archive::FAT_MAX_FILE_SIZE;
+use crate::cpu_governor::throttle;
+use crate::bandwidth::limit;
+
+pub const RENAMED_FILE: &str = "backup_renamed.txt"; // file salvati con un nome diverso da quello originale (percorso nel backup \t percorso originale)
+pub const PART_SUFFIX: &str = ".part";               // suffisso delle parti di un file diviso (".part001", ".part002", ...)
+
+const DEFAULT_ALLOCATION_UNIT: u64 = 4096; // unità di allocazione usata se non è possibile leggere quella del file system
+const MAX_NAME_LEN: usize = 255; // lunghezza massima di un nome (caratteri UTF-16) su FAT32, exFAT e NTFS
+const MAX_PATH_LEN: usize = 259; // lunghezza massima di un percorso completo su Windows (MAX_PATH, "E:\..." senza terminatore)
+const RESERVED_NAMES: [&str; 22] = ["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+                                    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"]; // nomi riservati di Windows
+
+/***
+FileSystemKind: tipo di file system della destinazione
+    Fat32: nomi con le regole di Windows, senza distinzione tra maiuscole e minuscole, file di al massimo 4 GB
+    ExFat, Ntfs: nomi con le regole di Windows, senza distinzione tra maiuscole e minuscole
+    Other: file system Unix (ext4, APFS, ...), i nomi vengono copiati senza modifiche
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileSystemKind {
+    Fat32,
+    ExFat,
+    Ntfs,
+    Other,
+}
+
+impl FileSystemKind {
+    /***
+    detect: riconosce il file system del disco che contiene un percorso
+        path: percorso sul disco
+    ***/
+    pub fn detect(path: &Path) -> Self {
+        Self::detect_with_mount_point(path).0
+    }
+
+    // file system e punto di montaggio del disco che contiene il percorso
+    fn detect_with_mount_point(path: &Path) -> (Self, PathBuf) {
+        let disks = Disks::new_with_refreshed_list();
+        let disk = match disk_for(&disks, path) {
+            Some(d) => d,
+            None => return (FileSystemKind::Other, PathBuf::new()),
+        };
+        let kind = match disk.file_system().to_string_lossy().to_lowercase().as_str() {
+            "vfat" | "fat" | "fat16" | "fat32" | "msdos" => FileSystemKind::Fat32,
+            "exfat" => FileSystemKind::ExFat,
+            "ntfs" | "ntfs3" | "fuseblk" => FileSystemKind::Ntfs, // fuseblk: ntfs-3g o exfat-fuse, in entrambi i casi valgono le regole di Windows
+            _ => FileSystemKind::Other,
+        };
+        (kind, disk.mount_point().to_path_buf())
+    }
+
+    /***
+    max_file_size: -> restituisce: Some(dimensione massima di un file) se il file system ha un limite (FAT32), altrimenti None
+    ***/
+    pub fn max_file_size(&self) -> Option<u64> {
+        match self {
+            FileSystemKind::Fat32 => Some(FAT_MAX_FILE_SIZE),
+            _ => None,
+        }
+    }
+}
+
+/***
+disk_for: funzione per trovare il disco che contiene un percorso: quello con il punto di montaggio più lungo che lo contiene
+          (es. "/media/usb" invece di "/")
+    disks: elenco dei dischi
+    path: percorso sul disco
+    -> restituisce: il disco, None se nessun punto di montaggio contiene il percorso
+***/
+pub fn disk_for<'a>(disks: &'a Disks, path: &Path) -> Option<&'a Disk> {
+    disks.list().iter().filter(|d| path.starts_with(d.mount_point())).max_by_key(|d| d.mount_point().as_os_str().len())
+}
+
+/***
+allocation_unit: funzione per ottenere l'unità di allocazione (cluster) del file system che contiene un percorso: ogni file occupa
+    un numero intero di unità, da 4 KB su ext4 e NTFS fino a 128 KB sulle chiavette FAT32 ed exFAT
+    path: percorso sul disco (deve esistere)
+    -> restituisce: la dimensione dell'unità in byte (DEFAULT_ALLOCATION_UNIT se non è possibile leggerla)
+***/
+pub fn allocation_unit(path: &Path) -> u64 {
+    cluster_size(path).ok().filter(|&size| size > 0).unwrap_or(DEFAULT_ALLOCATION_UNIT)
+}
+
+#[cfg(unix)]
+fn cluster_size(path: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn cluster_size(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Component;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceW(root: *const u16, sectors_per_cluster: *mut u32, bytes_per_sector: *mut u32, free_clusters: *mut u32, total_clusters: *mut u32) -> i32;
+    }
+    // GetDiskFreeSpaceW accetta solo la radice del disco (es. "E:\")
+    let root: PathBuf = path.components().take_while(|c| matches!(c, Component::Prefix(_) | Component::RootDir)).collect();
+    let wide: Vec<u16> = root.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let (mut sectors, mut bytes, mut free, mut total) = (0u32, 0u32, 0u32, 0u32);
+    if unsafe { GetDiskFreeSpaceW(wide.as_ptr(), &mut sectors, &mut bytes, &mut free, &mut total) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sectors as u64 * bytes as u64)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn cluster_size(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "unità di allocazione non disponibile"))
+}
+
+/***
+DestinationNames: nomi con cui i file vengono salvati nella cartella del backup, compatibili con il file system della destinazione.
+    I caratteri non ammessi vengono sostituiti con '_', i nomi riservati di Windows ricevono il prefisso '_', i nomi e i percorsi
+    troppo lunghi vengono accorciati e, se due nomi differiscono solo per maiuscole/minuscole, al secondo viene aggiunto il suffisso " (n)".
+    Ogni file salvato con un nome diverso viene registrato in RENAMED_FILE.
+    kind: file system della destinazione
+    base_len: lunghezza (su Windows) del percorso della cartella del backup, compresa la lettera di unità
+    dirs: cartelle già incontrate (percorso originale -> percorso nel backup)
+    used: percorsi già assegnati nel backup, in minuscolo
+    renamed: file salvati con un nome diverso (percorso nel backup, percorso originale)
+***/
+pub struct DestinationNames {
+    kind: FileSystemKind,
+    base_len: usize,
+    dirs: HashMap<String, String>,
+    used: HashSet<String>,
+    renamed: Vec<(String, String)>,
+}
+
+impl DestinationNames {
+    /***
+    new: crea i nomi per una cartella di backup, riconoscendo il file system della destinazione
+        backup_dir: cartella del backup
+    ***/
+    pub fn new(backup_dir: &Path) -> Self {
+        let (kind, mount_point) = FileSystemKind::detect_with_mount_point(backup_dir);
+        let base = backup_dir.strip_prefix(&mount_point).unwrap_or(backup_dir);
+        DestinationNames::with_kind(kind, 3 + utf16_len(&base.to_string_lossy()))
+    }
+
+    /***
+    with_kind: crea i nomi per un file system noto (Other per copiare i nomi senza modifiche)
+        kind: file system della destinazione
+        base_len: lunghezza del percorso della cartella del backup
+    ***/
+    pub fn with_kind(kind: FileSystemKind, base_len: usize) -> Self {
+        DestinationNames { kind, base_len, dirs: HashMap::new(), used: HashSet::new(), renamed: Vec::new() }
+    }
+
+    pub fn kind(&self) -> FileSystemKind {
+        self.kind
+    }
+
+    /***
+    map_dir: nome con cui viene salvata una cartella
+        key: percorso relativo della cartella nella sorgente (separatore '/')
+        -> restituisce: il percorso relativo della cartella nel backup
+    ***/
+    pub fn map_dir(&mut self, key: &str) -> String {
+        if let Some(stored) = self.dirs.get(key) {
+            return stored.clone();
+        }
+        let stored = self.map_component(key);
+        self.dirs.insert(key.to_string(), stored.clone());
+        stored
+    }
+
+    /***
+    map_file: nome con cui viene salvato un file; se diverso dall'originale viene registrato in RENAMED_FILE
+        key: percorso relativo del file nella sorgente (separatore '/')
+        -> restituisce: il percorso relativo del file nel backup
+    ***/
+    pub fn map_file(&mut self, key: &str) -> String {
+        let stored = self.map_component(key);
+        if stored != key {
+            self.renamed.push((stored.clone(), key.to_string()));
+        }
+        stored
+    }
+
+    /***
+    record_parts: registra le parti in cui è stato diviso un file troppo grande per il file system della destinazione
+        stored: percorso del file nel backup (senza suffisso)
+        key: percorso originale del file
+        parts: numero di parti
+    ***/
+    pub fn record_parts(&mut self, stored: &str, key: &str, parts: usize) {
+        self.renamed.retain(|(s, _)| s != stored);
+        for n in 1..=parts {
+            self.used.insert(part_name(stored, n).to_lowercase());
+            self.renamed.push((part_name(stored, n), key.to_string()));
+        }
+    }
+
+    /***
+    save: scrive RENAMED_FILE nella cartella del backup (solo se almeno un file è stato rinominato)
+        dir: cartella del backup
+    ***/
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        if self.renamed.is_empty() {
+            return Ok(());
+        }
+        let mut file = BufWriter::new(File::create(dir.join(RENAMED_FILE))?);
+        for (stored, original) in &self.renamed {
+            writeln!(file, "{}\t{}", stored, original)?;
+        }
+        file.flush()
+    }
+
+    pub fn renamed_count(&self) -> usize {
+        self.renamed.len()
+    }
+
+    // calcola il nome dell'ultimo componente di key, all'interno della cartella (già convertita) che lo contiene
+    fn map_component(&mut self, key: &str) -> String {
+        if self.kind == FileSystemKind::Other {
+            return key.to_string();
+        }
+        let (parent, name) = match key.rsplit_once('/') {
+            Some((parent, name)) => (self.map_dir(parent), name),
+            None => (String::new(), key),
+        };
+        let join = |name: &str| if parent.is_empty() { name.to_string() } else { format!("{}/{}", parent, name) };
+
+        let name = sanitize_name(name);
+        let available = MAX_PATH_LEN.saturating_sub(self.base_len + 1 + utf16_len(&join("")));
+        let mut n = 0;
+        loop {
+            // nomi che differiscono solo per maiuscole/minuscole: al secondo viene aggiunto il suffisso " (n)"
+            let (mut candidate, hash_key) = if n == 0 {
+                (name.clone(), key.to_string())
+            } else {
+                let (stem, extension) = split_extension(&name);
+                (format!("{} ({}){}", stem, n, extension), format!("{} ({})", key, n))
+            };
+            // percorso troppo lungo (anche per il suffisso): il nome viene accorciato, mantenendo l'estensione e aggiungendo un hash del percorso originale
+            if utf16_len(&candidate) > available {
+                candidate = shorten_name(&candidate, available, &hash_key);
+            }
+            let candidate = join(&candidate);
+            if self.used.insert(candidate.to_lowercase()) {
+                return candidate;
+            }
+            n += 1;
+        }
+    }
+}
+
+// sostituisce i caratteri non ammessi, aggiunge '_' ai nomi riservati e limita la lunghezza del nome
+fn sanitize_name(name: &str) -> String {
+    let mut name: String = name.chars().map(|c| if (c as u32) < 0x20 || "<>:\"\\|?*".contains(c) { '_' } else { c }).collect();
+    name = name.trim_end_matches(['.', ' ']).to_string(); // Windows non ammette nomi che terminano con punto o spazio
+    if name.is_empty() {
+        name.push('_');
+    }
+    let stem = name.split('.').next().unwrap_or("").trim_end().to_uppercase();
+    if RESERVED_NAMES.contains(&stem.as_str()) {
+        name.insert(0, '_');
+    }
+    if utf16_len(&name) > MAX_NAME_LEN {
+        name = shorten_name(&name, MAX_NAME_LEN, &name.clone());
+    }
+    name
+}
+
+// accorcia un nome a max_len caratteri UTF-16: "<inizio del nome>~<hash><estensione>"
+fn shorten_name(name: &str, max_len: usize, key: &str) -> String {
+    let (stem, extension) = split_extension(name);
+    let extension = if utf16_len(extension) > 16 { "" } else { extension };
+    let suffix = format!("~{}{}", &blake3::hash(key.as_bytes()).to_hex()[..6], extension);
+    let keep = max_len.saturating_sub(utf16_len(&suffix)).max(1);
+    let mut prefix = String::new();
+    for c in stem.chars() {
+        if utf16_len(&prefix) + c.len_utf16() > keep {
+            break;
+        }
+        prefix.push(c);
+    }
+    format!("{}{}", prefix, suffix)
+}
+
+// divide un nome in nome ed estensione (con il punto); i nomi che iniziano con un punto non hanno estensione
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i..]),
+        _ => (name, ""),
+    }
+}
+
+fn utf16_len(s: &str) -> usize {
+    s.encode_utf16().count()
+}
+
+/***
+part_name: funzione per ottenere il nome di una parte di un file diviso
+    stored: percorso del file nel backup
+    n: numero della parte (da 1)
+***/
+pub fn part_name(stored: &str, n: usize) -> String {
+    format!("{}{}{:03}", stored, PART_SUFFIX, n)
+}
+
+/***
+copy_file_split: funzione per copiare un file dividendolo in parti di dimensione massima part_size (<dst>.part001, <dst>.part002, ...),
+    calcolando durante la copia l'hash BLAKE3 del contenuto
+    src: percorso del file sorgente
+    dst: percorso del file di destinazione (senza suffisso)
+    part_size: dimensione massima di una parte
+    -> restituisce: il numero di parti scritte e l'hash in formato esadecimale
+***/
+pub fn copy_file_split(src: &Path, dst: &Path, part_size: u64) -> io::Result<(usize, String)> {
+    let mut input = File::open(src)?;
+    let size = input.metadata()?.len();
+    let permissions = input.metadata()?.permissions();
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let parts = size.div_ceil(part_size).max(1) as usize;
+
+    for n in 1..=parts {
+        let path = PathBuf::from(part_name(&dst.to_string_lossy(), n));
+        let mut output = BufWriter::new(File::create(&path)?);
+        let mut remaining = part_size.min(size - (n as u64 - 1) * part_size);
+        while remaining > 0 {
+            let wanted = buffer.len().min(remaining as usize);
+            let read = input.read(&mut buffer[..wanted])?;
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Il file '{}' è stato accorciato durante la copia", src.to_string_lossy())));
+            }
+            hasher.update(&buffer[..read]);
+            throttle();
+            limit(read as u64);
+            output.write_all(&buffer[..read])?;
+            remaining -= read as u64;
+        }
+        output.flush()?;
+        output.get_ref().set_permissions(permissions.clone())?;
+    }
+    Ok((parts, hasher.finalize().to_hex().to_string()))
+}
+
+/***
+load_renamed: funzione per leggere i file rinominati di un backup, raggruppati per percorso originale
+    dir: cartella del backup
+    -> restituisce: percorso originale -> percorsi nel backup (più di uno se il file è stato diviso in parti, in ordine)
+***/
+fn load_renamed(dir: &Path) -> io::Result<BTreeMap<String, Vec<String>>> {
+    let mut renamed: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let file = match File::open(dir.join(RENAMED_FILE)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(renamed),
+        Err(e) => return Err(e),
+    };
+    for line in io::BufReader::new(file).lines() {
+        if let Some((stored, original)) = line?.split_once('\t') {
+            renamed.entry(original.to_string()).or_default().push(stored.to_string());
+        }
+    }
+    for parts in renamed.values_mut() {
+        parts.sort();
+    }
+    Ok(renamed)
+}
+
+/***
+apply_renamed: funzione per riportare ai percorsi originali i file di un backup salvati con un altro nome o divisi in parti
+    dir: cartella del backup
+    files: mappa percorso nel backup -> percorso del file, aggiornata con i percorsi originali
+    -> restituisce: i file divisi in parti (percorso originale -> percorsi delle parti, in ordine); in files compare solo la prima parte
+***/
+pub fn apply_renamed(dir: &Path, files: &mut BTreeMap<String, PathBuf>) -> io::Result<BTreeMap<String, Vec<PathBuf>>> {
+    let mut split = BTreeMap::new();
+    for (original, stored) in load_renamed(dir)? {
+        let paths: Vec<PathBuf> = stored.iter().filter_map(|s| files.remove(s)).collect();
+        if let Some(first) = paths.first() {
+            files.insert(original.clone(), first.clone());
+        }
+        if stored.len() > 1 {
+            split.insert(original, paths);
+        }
+    }
+    Ok(split)
+}
+
+/***
+original_names: funzione per leggere i percorsi originali dei file di un backup salvati con un altro nome
+    dir: cartella del backup
+    -> restituisce: percorso nel backup -> percorso originale
+***/
+pub fn original_names(dir: &Path) -> io::Result<HashMap<String, String>> {
+    let mut names = HashMap::new();
+    for (original, stored) in load_renamed(dir)? {
+        names.extend(stored.into_iter().map(|s| (s, original.clone())));
+    }
+    Ok(names)
+}
+
+/***
+size_of_parts: funzione per calcolare la dimensione di un file salvato in una o più parti
+    paths: percorsi delle parti
+***/
+pub fn size_of_parts(paths: &[PathBuf]) -> io::Result<u64> {
+    let mut size = 0;
+    for path in paths {
+        size += fs::metadata(path)?.len();
+    }
+    Ok(size)
+}
+
+/***
+hash_parts: funzione per calcolare l'hash BLAKE3 del contenuto di un file salvato in una o più parti
+    paths: percorsi delle parti, in ordine
+    -> restituisce: l'hash in formato esadecimale
+***/
+pub fn hash_parts(paths: &[PathBuf]) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    for path in paths {
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocation_unit_of_an_existing_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = allocation_unit(dir.path());
+        assert!(unit >= 512 && unit.is_power_of_two(), "{}", unit);
+    }
+
+    #[test]
+    fn sanitize_reserved_and_invalid_names() {
+        assert_eq!(sanitize_name("CON.txt"), "_CON.txt");
+        assert_eq!(sanitize_name("lpt1"), "_lpt1");
+        assert_eq!(sanitize_name("CONSOLE.txt"), "CONSOLE.txt");
+        assert_eq!(sanitize_name("nome. "), "nome");
+        assert_eq!(sanitize_name("..."), "_");
+        assert_eq!(sanitize_name("a<b>:c?.txt"), "a_b__c_.txt");
+        assert_eq!(utf16_len(&sanitize_name(&"x".repeat(300))), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn shorten_keeps_extension() {
+        let short = shorten_name(&format!("{}.txt", "a".repeat(100)), 40, "chiave");
+        assert_eq!(utf16_len(&short), 40);
+        assert!(short.starts_with("aaaa") && short.ends_with(".txt") && short.contains('~'));
+        assert_ne!(short, shorten_name(&format!("{}.txt", "a".repeat(100)), 40, "altra chiave"));
+    }
+
+    #[test]
+    fn case_only_collisions() {
+        let mut names = DestinationNames::with_kind(FileSystemKind::Fat32, 3);
+        assert_eq!(names.map_file("dir/a.txt"), "dir/a.txt");
+        assert_eq!(names.map_file("dir/A.txt"), "dir/A (1).txt");
+        assert_eq!(names.map_file("DIR/a.TXT"), "DIR (1)/a.TXT");
+        assert_eq!(names.map_file("dir/b.txt"), "dir/b.txt");
+        assert_eq!(names.renamed_count(), 2);
+
+        let mut names = DestinationNames::with_kind(FileSystemKind::Other, 3);
+        assert_eq!(names.map_file("dir/A.txt"), "dir/A.txt");
+        assert_eq!(names.renamed_count(), 0);
+    }
+
+    #[test]
+    fn path_length_includes_collision_suffix() {
+        let base_len = 100;
+        let mut names = DestinationNames::with_kind(FileSystemKind::Ntfs, base_len);
+        let available = MAX_PATH_LEN - base_len - 1;
+
+        // un nome lungo esattamente quanto lo spazio disponibile non viene accorciato, ma il suffisso " (1)" del secondo lo renderebbe troppo lungo
+        let name = format!("{}.txt", "x".repeat(available - 4));
+        assert_eq!(names.map_file(&name), name);
+        let second = names.map_file(&name.to_uppercase());
+        assert!(utf16_len(&second) <= available, "{}", second);
+        assert!(second.ends_with(".TXT") && second.to_lowercase() != name);
+
+        // nelle sottocartelle lo spazio per il nome è quello rimasto dopo il percorso della cartella
+        let long = format!("{}/{}.pdf", "d".repeat(100), "n".repeat(200));
+        let stored = names.map_file(&long);
+        assert_eq!(base_len + 1 + utf16_len(&stored), MAX_PATH_LEN);
+        assert!(stored.starts_with(&"d".repeat(100)) && stored.ends_with(".pdf"));
+    }
+
+    #[test]
+    fn split_parts_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("sorgente.iso");
+        let content: Vec<u8> = (0..10_000u32).map(|b| (b * 13) as u8).collect();
+        fs::write(&src, &content).unwrap();
+
+        let backup = dir.path().join("backup");
+        fs::create_dir(&backup).unwrap();
+        let mut names = DestinationNames::with_kind(FileSystemKind::Fat32, 3);
+        let stored = names.map_file("dati/grande.iso");
+        fs::create_dir(backup.join("dati")).unwrap();
+        let (parts, hash) = copy_file_split(&src, &backup.join(&stored), 4096).unwrap();
+        assert_eq!(parts, 3);
+        names.record_parts(&stored, "dati/grande.iso", parts);
+        let renamed = names.map_file("CON.txt");
+        fs::write(backup.join(&renamed), "riservato").unwrap();
+        names.save(&backup).unwrap();
+
+        // file del backup come li elenca il controllo del backup: percorso nel backup -> percorso del file
+        let mut files: BTreeMap<String, PathBuf> = ["dati/grande.iso.part001", "dati/grande.iso.part002", "dati/grande.iso.part003", "_CON.txt"]
+            .iter()
+            .map(|key| (key.to_string(), backup.join(key)))
+            .collect();
+        let split = apply_renamed(&backup, &mut files).unwrap();
+
+        assert_eq!(files.keys().collect::<Vec<_>>(), vec!["CON.txt", "dati/grande.iso"]);
+        assert_eq!(files["CON.txt"], backup.join("_CON.txt"));
+        let paths = &split["dati/grande.iso"];
+        assert_eq!(paths, &(1..=3).map(|n| backup.join(part_name("dati/grande.iso", n))).collect::<Vec<_>>());
+        assert_eq!(size_of_parts(paths).unwrap(), content.len() as u64);
+        assert_eq!(hash_parts(paths).unwrap(), hash);
+        assert_eq!(hash, blake3::hash(&content).to_hex().to_string());
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::backup_index::{INDEX_FILE, hash_file, modified_nanos};
+use crate::backup_options::BackupOptions;
+use crate::cpu_governor::throttle;
+use crate::bandwidth::limit;
+
+pub const JOURNAL_FILE: &str = "backup_journal.txt"; // file copiati da un backup in corso, eliminato al termine del backup (se presente, il backup è stato interrotto)
+
+pub const CHECKPOINT_SIZE: u64 = 64 * 1024 * 1024; // i file più grandi vengono copiati registrando nel giornale il punto raggiunto ogni CHECKPOINT_SIZE byte
+
+/***
+Journal: giornale di un backup in corso, salvato nel file JOURNAL_FILE nella cartella del backup.
+    Ogni file copiato viene aggiunto al giornale (riga "DONE"); dei file grandi viene registrato periodicamente anche il punto
+    raggiunto dalla copia (riga "PART"). Se il backup viene interrotto, il backup successivo della stessa sorgente riprende dal giornale.
+    path: percorso del giornale
+    file: giornale aperto in scrittura (le righe vengono aggiunte in fondo)
+    done: file copiati dal backup interrotto: percorso relativo -> (dimensione, data di modifica, hash BLAKE3 della copia)
+    partial: file copiati solo in parte: percorso relativo -> (dimensione, data di modifica, byte copiati, hash BLAKE3 dei byte copiati)
+    resumed: true se il giornale è quello di un backup interrotto
+    reused: numero di file del backup interrotto riutilizzati senza copiarli di nuovo
+***/
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    done: HashMap<String, (u64, u128, String)>,
+    partial: HashMap<String, (u64, u128, u64, String)>,
+    resumed: bool,
+    pub reused: usize,
+}
+
+impl Journal {
+    /***
+    create: crea il giornale di un nuovo backup
+        dir: cartella del backup (viene creata se non esiste)
+        source: percorso sorgente del backup
+        options: opzioni del backup (un backup interrotto viene ripreso solo con lo stesso formato e la stessa modalità)
+    ***/
+    pub fn create(dir: &Path, source: &str, options: &BackupOptions) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(JOURNAL_FILE);
+        let mut file = File::create(&path)?;
+        file.write_all(format!("# source={}\n# format={}\n# mode={}\n", source, options.format.name(), mode_name(options)).as_bytes())?;
+        file.sync_all()?;
+        Ok(Journal { path, file, done: HashMap::new(), partial: HashMap::new(), resumed: false, reused: 0 })
+    }
+
+    /***
+    resume: legge il giornale di un backup interrotto, per riprenderlo
+        dir: cartella del backup interrotto
+    ***/
+    pub fn resume(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(JOURNAL_FILE);
+        let (mut done, mut partial) = (HashMap::new(), HashMap::new());
+        for line in io::BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            // l'ultima riga può essere incompleta (backup interrotto durante la scrittura): le righe non valide vengono ignorate
+            match fields[..] {
+                ["DONE", size, mtime, hash, _, ..] => {
+                    if let (Ok(size), Ok(mtime)) = (size.parse(), mtime.parse()) {
+                        done.insert(fields[4..].join("\t"), (size, mtime, hash.to_string()));
+                    }
+                }
+                ["PART", size, mtime, offset, hash, _, ..] => {
+                    if let (Ok(size), Ok(mtime), Ok(offset)) = (size.parse(), mtime.parse(), offset.parse()) {
+                        partial.insert(fields[5..].join("\t"), (size, mtime, offset, hash.to_string()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Journal { path, file, done, partial, resumed: true, reused: 0 })
+    }
+
+    // true se il backup riprende un backup interrotto
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    // true se il file è stato copiato (anche solo in parte) dal backup interrotto
+    pub fn contains(&self, key: &str) -> bool {
+        self.done.contains_key(key) || self.partial.contains_key(key)
+    }
+
+    // byte già copiati dal backup interrotto
+    pub fn resumed_size(&self) -> u64 {
+        self.done.values().map(|(size, _, _)| size).sum::<u64>() + self.partial.values().map(|(_, _, offset, _)| offset).sum::<u64>()
+    }
+
+    /***
+    completed: verifica se un file è già stato copiato dal backup interrotto
+        key: percorso relativo del file
+        metadata: metadati attuali del file sorgente
+        dest_path: percorso della copia
+        -> restituisce: l'hash della copia se il file sorgente è invariato e la copia è integra (l'hash della copia viene ricalcolato), altrimenti None
+    ***/
+    pub fn completed(&mut self, key: &str, metadata: &fs::Metadata, dest_path: &Path) -> Option<String> {
+        let (size, mtime, hash) = self.done.remove(key)?;
+        if size != metadata.len() || mtime != modified_nanos(metadata) || hash_file(dest_path).ok()? != hash {
+            return None;
+        }
+        self.reused += 1;
+        Some(hash)
+    }
+
+    /***
+    record_done: aggiunge al giornale un file copiato.
+        A differenza delle righe PART, la riga non viene forzata sul disco (una sync per ogni file rallenterebbe molto la copia di tanti file piccoli):
+        dopo un'interruzione di corrente una riga DONE persa fa solo copiare di nuovo il file, e una riga DONE salvata senza i dati della copia
+        non viene creduta, perché completed riutilizza la copia solo se il suo hash corrisponde a quello registrato
+        key: percorso relativo del file
+        size, mtime: dimensione e data di modifica del file sorgente
+        hash: hash BLAKE3 della copia
+    ***/
+    pub fn record_done(&mut self, key: &str, size: u64, mtime: u128, hash: &str) -> io::Result<()> {
+        self.file.write_all(format!("DONE\t{}\t{}\t{}\t{}\n", size, mtime, hash, key).as_bytes()) // una sola scrittura per riga
+    }
+
+    fn record_partial(&mut self, key: &str, size: u64, mtime: u128, offset: u64, hash: &str) -> io::Result<()> {
+        self.file.write_all(format!("PART\t{}\t{}\t{}\t{}\t{}\n", size, mtime, offset, hash, key).as_bytes())?;
+        self.file.sync_data()
+    }
+
+    /***
+    finish: elimina il giornale al termine del backup (dopo aver scritto l'indice)
+    ***/
+    pub fn finish(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+fn mode_name(options: &BackupOptions) -> String {
+    format!("{:?}", options.mode).to_lowercase()
+}
+
+/***
+find_interrupted: funzione per cercare sul disco esterno un backup interrotto della stessa sorgente
+    backup_root: radice del disco esterno (cartella che contiene i backup)
+    source: percorso sorgente del backup
+    options: opzioni del backup
+    -> restituisce: la cartella del backup interrotto più recente con lo stesso formato e la stessa modalità, None se non ne esistono
+***/
+pub fn find_interrupted(backup_root: &Path, source: &str, options: &BackupOptions) -> Option<PathBuf> {
+    let expected = [format!("source={}", source), format!("format={}", options.format.name()), format!("mode={}", mode_name(options))];
+    let mut found: Option<(PathBuf, std::time::SystemTime)> = None;
+    for entry in fs::read_dir(backup_root).ok()?.flatten() {
+        let path = entry.path();
+        let journal = path.join(JOURNAL_FILE);
+        if !journal.is_file() || path.join(INDEX_FILE).is_file() { // un backup con l'indice è completo
+            continue;
+        }
+        let file = match File::open(&journal) {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+        let header: Vec<String> = io::BufReader::new(file).lines().take(3).map_while(Result::ok).map(|l| l.trim_start_matches("# ").to_string()).collect();
+        if header[..] != expected[..] {
+            continue;
+        }
+        let modified = journal.metadata().and_then(|m| m.modified()).unwrap_or(std::time::UNIX_EPOCH);
+        if found.as_ref().is_none_or(|(_, m)| modified > *m) {
+            found = Some((path, modified));
+        }
+    }
+    found.map(|(path, _)| path)
+}
+
+/***
+copy_file_resumable: funzione per copiare un file grande registrando nel giornale il punto raggiunto dalla copia.
+    Se il giornale indica che il file era stato copiato in parte da un backup interrotto, i byte già copiati vengono verificati
+    (tramite l'hash registrato) e la copia riprende da quel punto; altrimenti il file viene copiato da capo
+    src: percorso del file sorgente
+    dst: percorso della copia
+    key: percorso relativo del file
+    journal: giornale del backup
+    -> restituisce: l'hash BLAKE3 del contenuto
+***/
+pub fn copy_file_resumable(src: &Path, dst: &Path, key: &str, journal: &mut Journal) -> io::Result<String> {
+    let mut input = File::open(src)?;
+    let metadata = input.metadata()?;
+    let (size, mtime) = (metadata.len(), modified_nanos(&metadata));
+    let mut hasher = blake3::Hasher::new();
+    let mut copied = 0;
+
+    let mut output = None;
+    if let Some((_, _, offset, hash)) = journal.partial.remove(key).filter(|(s, m, _, _)| *s == size && *m == mtime) {
+        if let Ok(mut file) = OpenOptions::new().read(true).write(true).open(dst) {
+            if file.metadata()?.len() >= offset && io::copy(&mut (&mut file).take(offset), &mut hasher)? == offset && hasher.finalize().to_hex().as_str() == hash {
+                file.set_len(offset)?;
+                file.seek(SeekFrom::Start(offset))?;
+                input.seek(SeekFrom::Start(offset))?;
+                copied = offset;
+                println!("Ripresa della copia di '{}' da {} byte.", key, offset);
+                output = Some(file);
+            } else {
+                hasher.reset(); // la copia parziale non corrisponde al giornale: il file viene copiato da capo
+            }
+        }
+    }
+    let mut output = BufWriter::new(match output {
+        Some(file) => file,
+        None => File::create(dst)?,
+    });
+
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut checkpoint = copied;
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        throttle();
+        limit(n as u64);
+        output.write_all(&buffer[..n])?;
+        copied += n as u64;
+        if copied - checkpoint >= CHECKPOINT_SIZE {
+            output.flush()?;
+            output.get_ref().sync_data()?; // i byte registrati nel giornale devono essere già sul disco
+            journal.record_partial(key, size, mtime, copied, hasher.finalize().to_hex().as_str())?;
+            checkpoint = copied;
+        }
+    }
+    output.flush()?;
+    output.get_ref().set_permissions(metadata.permissions())?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::content;
+
+    // contenuto diverso per ogni file di prova
+    #[test]
+    fn resumes_from_done_and_part_lines() {
+        let src = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap(); // radice del disco esterno, con il solo backup interrotto
+        let dst = root.path().join("backup");
+        let options = BackupOptions::default();
+        let files = [("a.txt", content(1, 5000)), ("b.txt", content(2, 3000)), ("c.txt", content(3, 4000)), ("grande.bin", content(4, 200_000))];
+        for (key, data) in &files {
+            fs::write(src.path().join(key), data).unwrap();
+        }
+        let metadata = |key: &str| fs::metadata(src.path().join(key)).unwrap();
+
+        // backup interrotto: a.txt e b.txt copiati (ma la copia di b.txt è stata danneggiata), c.txt non registrato,
+        // grande.bin copiato fino a 100000 byte
+        let mut journal = Journal::create(&dst, "sorgente", &options).unwrap();
+        for (key, data) in &files[..2] {
+            let path = dst.join(key);
+            fs::write(&path, data).unwrap();
+            let hash = hash_file(&path).unwrap();
+            journal.record_done(key, metadata(key).len(), modified_nanos(&metadata(key)), &hash).unwrap();
+        }
+        fs::write(dst.join("b.txt"), content(9, 3000)).unwrap();
+        // la parte già copiata di grande.bin è diversa dalla sorgente: se la copia riprende davvero, non viene riscritta
+        let prefix = content(8, 100_000);
+        fs::write(dst.join("grande.bin"), &prefix).unwrap();
+        let big = metadata("grande.bin");
+        journal.record_partial("grande.bin", big.len(), modified_nanos(&big), 100_000, blake3::hash(&prefix).to_hex().as_str()).unwrap();
+        drop(journal);
+        // riga incompleta, scritta mentre il backup veniva interrotto
+        OpenOptions::new().append(true).open(dst.join(JOURNAL_FILE)).unwrap().write_all(b"DONE\t4000\t12").unwrap();
+
+        assert_eq!(find_interrupted(root.path(), "sorgente", &options), Some(dst.clone()));
+        let mut journal = Journal::resume(&dst).unwrap();
+        assert!(journal.is_resumed());
+        assert!(journal.contains("a.txt") && journal.contains("b.txt") && journal.contains("grande.bin"));
+        assert!(!journal.contains("c.txt"));
+        assert_eq!(journal.resumed_size(), 5000 + 3000 + 100_000);
+
+        assert_eq!(journal.completed("a.txt", &metadata("a.txt"), &dst.join("a.txt")), Some(hash_file(&src.path().join("a.txt")).unwrap()));
+        assert_eq!(journal.completed("b.txt", &metadata("b.txt"), &dst.join("b.txt")), None);
+        assert_eq!(journal.reused, 1);
+
+        let hash = copy_file_resumable(&src.path().join("grande.bin"), &dst.join("grande.bin"), "grande.bin", &mut journal).unwrap();
+        let mut expected = prefix.clone();
+        expected.extend_from_slice(&files[3].1[100_000..]);
+        assert_eq!(fs::read(dst.join("grande.bin")).unwrap(), expected);
+        assert_eq!(hash, blake3::hash(&expected).to_hex().to_string());
+
+        journal.finish().unwrap();
+        assert!(!dst.join(JOURNAL_FILE).exists());
+    }
+
+    #[test]
+    fn changed_source_is_copied_again() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let data = content(5, 150_000);
+        fs::write(src.path().join("grande.bin"), &data).unwrap();
+        let metadata = fs::metadata(src.path().join("grande.bin")).unwrap();
+
+        // la sorgente è cambiata dopo l'interruzione (dimensione diversa da quella registrata): la copia riparte da capo
+        let mut journal = Journal::create(dst.path(), "sorgente", &BackupOptions::default()).unwrap();
+        fs::write(dst.path().join("grande.bin"), &data[..50_000]).unwrap();
+        journal.record_partial("grande.bin", metadata.len() - 1, modified_nanos(&metadata), 50_000, blake3::hash(&data[..50_000]).to_hex().as_str()).unwrap();
+        journal.record_done("piccolo.txt", 10, modified_nanos(&metadata), "hash").unwrap();
+        drop(journal);
+
+        let mut journal = Journal::resume(dst.path()).unwrap();
+        fs::write(src.path().join("piccolo.txt"), "0123456789").unwrap();
+        let small = fs::metadata(src.path().join("piccolo.txt")).unwrap();
+        assert_eq!(journal.completed("piccolo.txt", &small, &dst.path().join("piccolo.txt")), None);
+        let hash = copy_file_resumable(&src.path().join("grande.bin"), &dst.path().join("grande.bin"), "grande.bin", &mut journal).unwrap();
+        assert_eq!(fs::read(dst.path().join("grande.bin")).unwrap(), data);
+        assert_eq!(hash, blake3::hash(&data).to_hex().to_string());
+    }
+}
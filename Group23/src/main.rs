@@ -8,15 +8,68 @@ use std::time::Duration;
 mod backup_tool;
 use backup_tool::{generate_backup_name, get_extensions, get_usb_path, copy_dir, get_src_path, log_cpu_usage};
 
+mod backup_options;
+use backup_options::{get_options};
+
+mod backup_index;
+
+mod retention;
+
+mod manifest;
+
+mod archive;
+
+mod encryption;
+use encryption::{decrypt_command};
+
+mod parity;
+use parity::{repair_command};
+
+mod verify;
+use verify::{verify_command};
+
+mod fs_compat;
+
+mod metadata;
+
+mod special_files;
+
+mod failures;
+
+mod journal;
+
+mod salvage;
+
+mod priority;
+
+mod time_budget;
+
+mod space_budget;
+
+mod copy_pool;
+
+mod fast_copy;
+
+mod estimator;
+
+mod cpu_governor;
+use cpu_governor::{Phase, set_phase};
+
+mod bandwidth;
+
+mod restore;
+use restore::{restore_command};
+
 mod backup_command;
 use backup_command::{first_command, second_command};
 
 mod notification_window;
 use notification_window::{start_notify};
 
-fn main(){
-    log_cpu_usage();
+#[cfg(test)]
+mod test_data;
 
+fn main(){
     let exe = env::current_exe().unwrap();
     let exe_path = exe.to_string_lossy().to_string();
     let project_root = exe.parent().unwrap().parent().unwrap().parent();
@@ -24,6 +77,25 @@ fn main(){
     let conf_name = "conf.txt";
     let file_di_configurazione = project_root.unwrap().join(conf_name).to_string_lossy().to_string();
 
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "verify" { //confronto tra un backup e la sorgente, senza avviare il tool
+        std::process::exit(verify_command(&file_di_configurazione, &args[2..]));
+    }
+    if args.len() > 1 && args[1] == "decrypt" { //ripristino di un backup cifrato, senza avviare il tool
+        std::process::exit(decrypt_command(&file_di_configurazione, &args[2..]));
+    }
+    if args.len() > 1 && args[1] == "restore" { //ripristino di un backup non cifrato (nomi originali e file divisi in parti), senza avviare il tool
+        std::process::exit(restore_command(&args[2..]));
+    }
+    if args.len() > 1 && args[1] == "repair" { //riparazione di un backup tramite i dati di parità, senza avviare il tool
+        std::process::exit(repair_command(&file_di_configurazione, &args[2..]));
+    }
+
+    //i limiti di CPU valgono già mentre il tool aspetta le gesture, quindi vengono letti all'avvio (e di nuovo a ogni backup)
+    let opzioni_avvio = get_options(&file_di_configurazione).unwrap_or_default();
+    cpu_governor::configure(opzioni_avvio.cpu_limit_idle, opzioni_avvio.cpu_limit_backup);
+    log_cpu_usage();
+
     #[cfg(not(target_os = "macos"))] 
     {
         let auto = AutoLaunchBuilder::new()
@@ -56,6 +128,7 @@ fn main(){
     let mut usb_path;
     let mut estensioni;
     let mut destinazione;
+    let mut opzioni;
 
     loop {
         println!("Traccia un rettangolo con il mouse per iniziare il backup...");
@@ -70,7 +143,9 @@ fn main(){
 
                     if origine.is_some() && usb_path.is_some() {
                         estensioni = get_extensions(&file_di_configurazione).unwrap();
-                        destinazione = generate_backup_name(&origine.clone().unwrap().to_string(), &usb_path.clone().unwrap().to_string());
+                        opzioni = get_options(&file_di_configurazione).unwrap();
+                        cpu_governor::configure(opzioni.cpu_limit_idle, opzioni.cpu_limit_backup);
+                        destinazione = generate_backup_name(&origine.clone().unwrap().to_string(), &usb_path.clone().unwrap().to_string(), &opzioni);
                     }
                     else {
                         println!("Il backup non è andato a buon fine, riavvio della procedura.");
@@ -86,9 +161,17 @@ fn main(){
                             if success {
                                 start_notify("Gesture di conferma riconosciuta", "Backup in corso...");
                                 println!("Rettangolo rilevato! Inizio backup...");
-                                let result = copy_dir(&origine.unwrap().to_string(), &destinazione, estensioni);
-                                if result.unwrap()==-1 {
-                                    continue; //valore speciale "-1" per riavviare la procedura (se avviene un errore tra una gesture e l'altra)
+                                set_phase(Phase::Backup);
+                                let result = copy_dir(&origine.unwrap().to_string(), &destinazione, estensioni, &opzioni);
+                                set_phase(Phase::Listening);
+                                match result {
+                                    Ok(-1) => continue, //valore speciale "-1" per riavviare la procedura (se avviene un errore tra una gesture e l'altra)
+                                    Ok(_) => {}
+                                    Err(e) => { //errore che impedisce di proseguire il backup (es. disco esterno pieno o rimosso)
+                                        println!("Backup interrotto: {}", e);
+                                        start_notify("Errore backup", &format!("Backup interrotto: {}. Riavvio della procedura.", e));
+                                        continue;
+                                    }
                                 }
                                 break; //esce dal loop dopo aver terminato il backup, il programma termina.
                             }
@@ -0,0 +1,229 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::encryption::BackupCipher;
+
+pub const MANIFEST_FILE: &str = "backup_manifest.json"; // manifest del backup, scritto al termine della copia insieme al log
+
+/***
+ManifestEntry: descrizione di un file presente nella cartella del backup (o nell'archivio)
+    path: percorso relativo alla sorgente (separatore '/')
+    size: dimensione in byte
+    mtime: data di ultima modifica del file sorgente (RFC 3339, UTC)
+    permissions: permessi del file sorgente (modo ottale su Unix, "readonly"/"readwrite" sugli altri sistemi)
+    hash: hash BLAKE3 del contenuto copiato
+    location: per i file invariati di un backup incrementale, percorso (relativo alla radice del disco esterno) del backup precedente
+        che contiene la copia del file; assente per i file copiati in questo backup
+***/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub mtime: String,
+    pub permissions: String,
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+/***
+MissingFile: file della sorgente che non è stato salvato nel backup
+    path: percorso relativo alla sorgente (separatore '/')
+    size: dimensione in byte
+***/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MissingFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/***
+Manifest: elenco leggibile da programma dei file contenuti in un backup, con i totali e la configurazione usata.
+    Permette di verificare che il backup sia completo e integro.
+    format: formato del backup ("directory", "tar.zst" o "zip"); per un archivio, path indica il percorso del file all'interno dell'archivio
+    total_files, total_size: numero e dimensione dei file copiati in questo backup
+    reused_files: file invariati non copiati in questo backup (modalità incrementale), elencati in files con il backup che ne contiene la copia
+    complete: false se il backup contiene solo una parte dei file della sorgente (backup parziale)
+    not_saved: file non salvati perché il tempo a disposizione (opzione "time_limit") non bastava
+    excluded: file esclusi perché lo spazio della destinazione non bastava (opzione "best_fit")
+***/
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub source: String,
+    pub destination: String,
+    pub profile: String,
+    pub mode: String,
+    pub format: String,
+    pub created: String,
+    pub hash_algorithm: String,
+    pub total_files: usize,
+    pub total_size: u64,
+    pub reused_files: usize,
+    #[serde(default = "default_complete")]
+    pub complete: bool,
+    #[serde(default)]
+    pub not_saved: Vec<MissingFile>,
+    #[serde(default)]
+    pub excluded: Vec<MissingFile>,
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new(source: &str, destination: &str, profile: &str, mode: &str, format: &str) -> Self {
+        Manifest {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            profile: profile.to_string(),
+            mode: mode.to_string(),
+            format: format.to_string(),
+            created: Utc::now().to_rfc3339(),
+            hash_algorithm: "blake3".to_string(),
+            total_files: 0,
+            total_size: 0,
+            reused_files: 0,
+            complete: true,
+            not_saved: Vec::new(),
+            excluded: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /***
+    add: aggiunge un file al manifest e aggiorna i totali
+        path: percorso relativo del file
+        metadata: metadati del file sorgente
+        hash: hash BLAKE3 del contenuto copiato
+    ***/
+    pub fn add(&mut self, path: &str, metadata: &fs::Metadata, hash: &str) {
+        self.total_files += 1;
+        self.total_size += metadata.len();
+        self.files.push(entry(path, metadata, hash, None));
+    }
+
+    /***
+    add_reused: aggiunge al manifest un file invariato, non copiato in questo backup (modalità incrementale)
+        path: percorso relativo del file
+        metadata: metadati del file sorgente
+        hash: hash BLAKE3 della copia presente nel backup precedente (registrato nel suo indice)
+        location: percorso del backup precedente che contiene la copia, relativo alla radice del disco esterno
+    ***/
+    pub fn add_reused(&mut self, path: &str, metadata: &fs::Metadata, hash: &str, location: &str) {
+        self.reused_files += 1;
+        self.files.push(entry(path, metadata, hash, Some(location.to_string())));
+    }
+
+    /***
+    set_partial: segna il backup come parziale
+        not_saved: file non salvati per il limite di tempo (percorso relativo, dimensione)
+        excluded: file esclusi per lo spazio insufficiente della destinazione (percorso relativo, dimensione)
+    ***/
+    pub fn set_partial(&mut self, not_saved: &[(String, u64)], excluded: &[(String, u64)]) {
+        self.complete = false;
+        self.not_saved = missing_files(not_saved);
+        self.excluded = missing_files(excluded);
+    }
+
+    /***
+    save: scrive il manifest in formato JSON nella cartella del backup
+        dir: cartella del backup
+        cipher: chiave del backup, se il backup è cifrato (il manifest viene salvato cifrato, perché contiene i nomi dei file)
+    ***/
+    pub fn save(&self, dir: &Path, cipher: Option<&BackupCipher>) -> io::Result<()> {
+        if let Some(cipher) = cipher {
+            return cipher.write_encrypted(MANIFEST_FILE, &serde_json::to_vec_pretty(self)?);
+        }
+        let mut writer = BufWriter::new(File::create(dir.join(MANIFEST_FILE))?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+}
+
+// i manifest scritti prima dell'introduzione di complete descrivono backup completi
+fn default_complete() -> bool {
+    true
+}
+
+// voci del manifest dei file non salvati
+fn missing_files(files: &[(String, u64)]) -> Vec<MissingFile> {
+    files.iter().map(|(path, size)| MissingFile { path: path.clone(), size: *size }).collect()
+}
+
+// voce del manifest di un file sorgente
+fn entry(path: &str, metadata: &fs::Metadata, hash: &str, location: Option<String>) -> ManifestEntry {
+    ManifestEntry {
+        path: path.to_string(),
+        size: metadata.len(),
+        mtime: metadata.modified().map(|t| DateTime::<Utc>::from(t).to_rfc3339()).unwrap_or_default(),
+        permissions: permissions_string(metadata),
+        hash: hash.to_string(),
+        location,
+    }
+}
+
+/***
+permissions_string: funzione per rappresentare i permessi di un file come stringa
+    metadata: metadati del file
+***/
+fn permissions_string(metadata: &fs::Metadata) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        format!("{:o}", metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.permissions().readonly() { "readonly".to_string() } else { "readwrite".to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_files_point_to_the_previous_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("nuovo.txt"), "copiato").unwrap();
+        fs::write(dir.path().join("vecchio.txt"), "invariato").unwrap();
+        let metadata = |name: &str| fs::metadata(dir.path().join(name)).unwrap();
+
+        let mut manifest = Manifest::new("/sorgente", "/media/usb/backup_2", "", "incremental", "directory");
+        manifest.add("nuovo.txt", &metadata("nuovo.txt"), "hash-nuovo");
+        manifest.add_reused("vecchio.txt", &metadata("vecchio.txt"), "hash-vecchio", "backup_1");
+        manifest.save(dir.path(), None).unwrap();
+
+        // i totali riguardano i file copiati, mentre files elenca anche quelli invariati
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(dir.path().join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!((saved["total_files"].as_u64(), saved["total_size"].as_u64(), saved["reused_files"].as_u64()), (Some(1), Some(7), Some(1)));
+        assert!(saved["files"][0].get("location").is_none());
+        assert_eq!(saved["files"][1]["location"], "backup_1");
+        assert_eq!((saved["files"][1]["hash"].as_str(), saved["files"][1]["size"].as_u64()), (Some("hash-vecchio"), Some(9)));
+
+        // i manifest scritti prima dell'introduzione di location restano leggibili
+        let old: ManifestEntry = serde_json::from_str(r#"{"path": "a", "size": 1, "mtime": "", "permissions": "644", "hash": "h"}"#).unwrap();
+        assert_eq!(old.location, None);
+    }
+
+    #[test]
+    fn partial_backups_list_the_files_not_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::new("/sorgente", "/media/usb/backup", "", "full", "directory");
+        manifest.save(dir.path(), None).unwrap();
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(dir.path().join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!((saved["complete"].as_bool(), saved["not_saved"].as_array().map(Vec::len)), (Some(true), Some(0)));
+
+        manifest.set_partial(&[("video/grande.mkv".to_string(), 5_000_000)], &[("iso/disco.iso".to_string(), 700_000_000)]);
+        manifest.save(dir.path(), None).unwrap();
+        let saved: Manifest = serde_json::from_slice(&fs::read(dir.path().join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert!(!saved.complete);
+        assert_eq!(saved.not_saved, vec![MissingFile { path: "video/grande.mkv".to_string(), size: 5_000_000 }]);
+        assert_eq!(saved.excluded, vec![MissingFile { path: "iso/disco.iso".to_string(), size: 700_000_000 }]);
+
+        // un manifest senza i nuovi campi descrive un backup completo
+        let old: Manifest = serde_json::from_str(r#"{"source": "/s", "destination": "/d", "profile": "", "mode": "full", "format": "directory",
+            "created": "", "hash_algorithm": "blake3", "total_files": 0, "total_size": 0, "reused_files": 0, "files": []}"#).unwrap();
+        assert!(old.complete && old.not_saved.is_empty() && old.excluded.is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+pub const METADATA_FILE: &str = "backup_metadata.json"; // metadati che la destinazione non può rappresentare e collegamenti simbolici
+
+/***
+MetadataRecord: metadati di un file o di una cartella che non sono stati conservati dalla destinazione (solo i campi presenti)
+    symlink: destinazione del collegamento simbolico (per i collegamenti, registrati sempre)
+    mode: permessi Unix
+    uid, gid: proprietario e gruppo
+    mtime, atime: data di ultima modifica e di ultimo accesso (secondi e nanosecondi dal 1/1/1970)
+    xattrs: attributi estesi (comprese le ACL POSIX), con il valore in esadecimale
+***/
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct MetadataRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<(i64, u32)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<(i64, u32)>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl MetadataRecord {
+    pub fn is_empty(&self) -> bool {
+        *self == MetadataRecord::default()
+    }
+}
+
+/***
+MetadataSidecar: file METADATA_FILE di un backup, con i metadati non rappresentabili sulla destinazione
+    files: percorso relativo (originale) -> metadati
+***/
+#[derive(Serialize, Deserialize, Default)]
+pub struct MetadataSidecar {
+    pub files: BTreeMap<String, MetadataRecord>,
+}
+
+impl MetadataSidecar {
+    /***
+    load: legge i metadati di un backup
+        dir: cartella del backup
+        -> restituisce: i metadati letti (vuoti se il backup non contiene METADATA_FILE)
+    ***/
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        match File::open(dir.join(METADATA_FILE)) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file)).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(MetadataSidecar::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /***
+    save: scrive i metadati nella cartella del backup (solo se ce ne sono)
+        dir: cartella del backup
+    ***/
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+        let mut writer = BufWriter::new(File::create(dir.join(METADATA_FILE))?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+
+    pub fn symlink_count(&self) -> usize {
+        self.files.values().filter(|r| r.symlink.is_some()).count()
+    }
+
+    /***
+    symlinks: -> restituisce: i percorsi dei collegamenti simbolici registrati, da creare una volta sola prima di verificare molti percorsi
+    ***/
+    pub fn symlinks(&self) -> Symlinks {
+        Symlinks(self.files.iter().filter(|(_, r)| r.symlink.is_some()).map(|(k, _)| k.clone()).collect())
+    }
+}
+
+/***
+Symlinks: percorsi relativi dei collegamenti simbolici registrati in un MetadataSidecar
+***/
+pub struct Symlinks(HashSet<String>);
+
+impl Symlinks {
+    /***
+    contains: verifica se un percorso è un collegamento simbolico registrato, o si trova all'interno di una cartella collegata
+        key: percorso relativo (separatore '/')
+    ***/
+    pub fn contains(&self, key: &str) -> bool {
+        !self.0.is_empty() && (self.0.contains(key) || key.match_indices('/').any(|(i, _)| self.0.contains(&key[..i])))
+    }
+}
+
+/***
+copy_metadata: funzione per copiare i metadati di un file o di una cartella (date, permessi, proprietario se il tool ha i privilegi necessari, attributi estesi)
+    src: percorso del file sorgente
+    src_metadata: metadati del file sorgente, letti prima della copia
+    dst: percorso della copia
+    -> restituisce: i metadati che la destinazione non ha conservato (vuoto se sono stati conservati tutti)
+***/
+#[allow(unused_variables)]
+pub fn copy_metadata(src: &Path, src_metadata: &fs::Metadata, dst: &Path) -> MetadataRecord {
+    let mut record = MetadataRecord::default();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        //attributi estesi (su Linux anche le ACL POSIX, salvate come "system.posix_acl_access" e "system.posix_acl_default")
+        if let Ok(names) = xattr::list(src) {
+            for name in names {
+                if let Ok(Some(value)) = xattr::get(src, &name) {
+                    if xattr::set(dst, &name, &value).is_err() {
+                        record.xattrs.insert(name.to_string_lossy().to_string(), to_hex(&value));
+                    }
+                }
+            }
+        }
+        //il proprietario viene cambiato prima dei permessi, perché chown azzera i bit setuid/setgid
+        let _ = std::os::unix::fs::chown(dst, Some(src_metadata.uid()), Some(src_metadata.gid()));
+        let _ = fs::set_permissions(dst, fs::Permissions::from_mode(src_metadata.mode() & 0o7777));
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = fs::set_permissions(dst, src_metadata.permissions());
+    }
+    let atime = FileTime::from_last_access_time(src_metadata);
+    let mtime = FileTime::from_last_modification_time(src_metadata);
+    let _ = filetime::set_file_times(dst, atime, mtime);
+
+    //i metadati vengono riletti dalla copia: ciò che la destinazione non ha conservato (es. permessi e date precise su FAT32) viene registrato
+    let written = fs::metadata(dst).ok();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if written.as_ref().is_none_or(|w| w.uid() != src_metadata.uid() || w.gid() != src_metadata.gid()) {
+            record.uid = Some(src_metadata.uid());
+            record.gid = Some(src_metadata.gid());
+        }
+        if written.as_ref().is_none_or(|w| w.mode() & 0o7777 != src_metadata.mode() & 0o7777) {
+            record.mode = Some(src_metadata.mode() & 0o7777);
+        }
+    }
+    if written.as_ref().is_none_or(|w| FileTime::from_last_modification_time(w) != mtime) {
+        record.mtime = Some((mtime.unix_seconds(), mtime.nanoseconds()));
+    }
+    if written.as_ref().is_none_or(|w| FileTime::from_last_access_time(w) != atime) {
+        record.atime = Some((atime.unix_seconds(), atime.nanoseconds()));
+    }
+    record
+}
+
+/***
+copy_symlink: funzione per copiare un collegamento simbolico come collegamento (senza seguirlo)
+    src: percorso del collegamento
+    dst: percorso della copia
+    -> restituisce: i metadati del collegamento, da registrare in METADATA_FILE (anche se la destinazione non supporta i collegamenti)
+***/
+pub fn copy_symlink(src: &Path, dst: &Path) -> io::Result<MetadataRecord> {
+    let target = fs::read_link(src)?;
+    if create_symlink(&target, dst, src.is_dir()).is_ok() {
+        let metadata = fs::symlink_metadata(src)?;
+        let _ = filetime::set_symlink_file_times(dst, FileTime::from_last_access_time(&metadata), FileTime::from_last_modification_time(&metadata));
+    }
+    Ok(MetadataRecord { symlink: Some(target.to_string_lossy().to_string()), ..Default::default() })
+}
+
+/***
+restore_metadata: funzione per applicare a un file ripristinato i metadati registrati in METADATA_FILE
+    path: percorso del file ripristinato
+    record: metadati registrati
+***/
+pub fn restore_metadata(path: &Path, record: &MetadataRecord) -> io::Result<()> {
+    if let Some(target) = &record.symlink {
+        if fs::symlink_metadata(path).is_ok() {
+            if path.is_dir() && !path.is_symlink() { fs::remove_dir_all(path)? } else { fs::remove_file(path)? }
+        }
+        return create_symlink(Path::new(target), path, false);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        for (name, value) in &record.xattrs {
+            if let Some(value) = from_hex(value) {
+                xattr::set(path, name, &value)?;
+            }
+        }
+        if record.uid.is_some() || record.gid.is_some() {
+            let _ = std::os::unix::fs::chown(path, record.uid, record.gid); //riesce solo se il tool ha i privilegi necessari
+        }
+        if let Some(mode) = record.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    if record.mtime.is_some() || record.atime.is_some() {
+        let metadata = fs::metadata(path)?;
+        let mtime = record.mtime.map_or(FileTime::from_last_modification_time(&metadata), |(s, n)| FileTime::from_unix_time(s, n));
+        let atime = record.atime.map_or(FileTime::from_last_access_time(&metadata), |(s, n)| FileTime::from_unix_time(s, n));
+        filetime::set_file_times(path, atime, mtime)?;
+    }
+    Ok(())
+}
+
+// crea un collegamento simbolico (su Windows serve sapere se punta a una cartella)
+#[allow(unused_variables)]
+fn create_symlink(target: &Path, link: &Path, is_dir: bool) -> io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link);
+    #[cfg(windows)]
+    return if is_dir { std::os::windows::fs::symlink_dir(target, link) } else { std::os::windows::fs::symlink_file(target, link) };
+}
+
+#[cfg(unix)]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(unix)]
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len()).step_by(2).map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlinks_contain_links_and_their_content() {
+        let mut sidecar = MetadataSidecar::default();
+        sidecar.files.insert("dati/collegamento".to_string(), MetadataRecord { symlink: Some("../altro".to_string()), ..MetadataRecord::default() });
+        sidecar.files.insert("dati/file.txt".to_string(), MetadataRecord { mode: Some(0o644), ..MetadataRecord::default() });
+        let links = sidecar.symlinks();
+        assert!(links.contains("dati/collegamento"));
+        assert!(links.contains("dati/collegamento/interno/file.txt"));
+        assert!(!links.contains("dati/collegamento2"));
+        assert!(!links.contains("dati/file.txt"));
+        assert!(!links.contains("dati"));
+    }
+}
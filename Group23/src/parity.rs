@@ -0,0 +1,566 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use crate::backup_index::{find_previous_backup, relative_key};
+use crate::backup_tool::{get_src_path, get_usb_path};
+use crate::cpu_governor::throttle;
+
+pub const PARITY_FILE: &str = "backup_parity.bin";        // blocchi di parità Reed-Solomon del backup
+pub const PARITY_INDEX_FILE: &str = "backup_parity.json"; // descrizione dei blocchi protetti dai dati di parità (file, dimensioni, hash dei blocchi)
+
+const MIN_BLOCK_SIZE: u64 = 4096;  // dimensione minima di un blocco (raddoppiata finché il backup non è diviso in al massimo MAX_BLOCKS blocchi)
+const MAX_BLOCKS: usize = 1 << 16;
+const MAX_SHARDS: usize = 256;     // limite di Reed-Solomon su GF(2^8): blocchi di dati + blocchi di parità di una striscia
+const CHUNK_SIZE: u64 = 64 * 1024; // i blocchi di una striscia vengono elaborati a pezzi, per limitare la memoria usata
+
+/***
+ParityEntry: file protetto dai dati di parità
+    path: percorso relativo alla cartella del backup (separatore '/')
+    size: dimensione del file in byte
+    first_block: indice del primo blocco del file (ogni file occupa blocchi interi, l'ultimo completato con zeri)
+    hashes: hash BLAKE3 (troncato a 128 bit) di ogni blocco del file, per riconoscere i blocchi danneggiati
+***/
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ParityEntry {
+    pub path: String,
+    pub size: u64,
+    pub first_block: usize,
+    pub hashes: Vec<String>,
+}
+
+/***
+ParityIndex: descrizione dei dati di parità di un backup, salvata in PARITY_INDEX_FILE.
+    I blocchi dei file sono distribuiti a pettine su "stripes" strisce: il blocco b appartiene alla striscia b % stripes,
+    così che un'area danneggiata contigua del disco tocchi pochi blocchi di ogni striscia.
+    Ogni striscia ha data_shards blocchi di dati (i mancanti valgono zero) e parity_shards blocchi di parità in PARITY_FILE:
+    si possono ricostruire fino a parity_shards blocchi danneggiati per striscia.
+    redundancy: percentuale di ridondanza richiesta (opzione "parity")
+    block_size: dimensione di un blocco in byte
+    parity_hashes: hash dei blocchi di parità (striscia * parity_shards + indice)
+***/
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ParityIndex {
+    pub redundancy: u8,
+    pub block_size: u64,
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub stripes: usize,
+    pub files: Vec<ParityEntry>,
+    pub parity_hashes: Vec<String>,
+}
+
+impl ParityIndex {
+    // numero di blocchi di dati del backup
+    fn total_blocks(&self) -> usize {
+        self.files.last().map_or(0, |f| f.first_block + f.hashes.len())
+    }
+
+    // posizione del blocco j della striscia s nella numerazione globale dei blocchi di dati
+    fn block_index(&self, stripe: usize, shard: usize) -> usize {
+        shard * self.stripes + stripe
+    }
+
+    // posizione del blocco di parità j della striscia s in PARITY_FILE
+    fn parity_offset(&self, stripe: usize, shard: usize) -> u64 {
+        (stripe * self.parity_shards + shard) as u64 * self.block_size
+    }
+}
+
+/***
+RepairReport: risultato del controllo (ed eventuale riparazione) di un backup tramite i dati di parità
+    checked_files: file controllati
+    damaged_blocks: blocchi di dati danneggiati o mancanti
+    damaged_parity: blocchi di parità danneggiati o mancanti
+    repaired: file ricostruiti (in tutto o in parte)
+    unrecoverable: file con blocchi non ricostruibili (troppi blocchi danneggiati nella stessa striscia)
+***/
+#[derive(Default, Debug)]
+pub struct RepairReport {
+    pub checked_files: usize,
+    pub damaged_blocks: usize,
+    pub damaged_parity: usize,
+    pub repaired: Vec<String>,
+    pub unrecoverable: Vec<String>,
+}
+
+impl RepairReport {
+    pub fn print(&self) {
+        println!("File controllati: {}", self.checked_files);
+        println!("Blocchi di dati danneggiati: {}, blocchi di parità danneggiati: {}", self.damaged_blocks, self.damaged_parity);
+        for path in &self.repaired {
+            println!("  riparato: {}", path);
+        }
+        for path in &self.unrecoverable {
+            println!("  NON recuperabile: {}", path);
+        }
+    }
+}
+
+/***
+create_parity: funzione per generare i dati di parità di un backup (tutti i file della cartella del backup, esclusi quelli di parità)
+    dir: cartella del backup
+    redundancy: percentuale di ridondanza (da 1 a 100): circa la percentuale dei blocchi del backup che può essere ricostruita
+    -> restituisce: la dimensione in byte dei dati di parità scritti
+***/
+pub fn create_parity(dir: &Path, redundancy: u8) -> io::Result<u64> {
+    let mut paths = Vec::new();
+    list_files(dir, dir, &mut paths)?;
+    paths.sort();
+
+    // la dimensione dei blocchi viene raddoppiata finché il numero di blocchi non è ragionevole (o finché ogni file sta in un blocco)
+    let sizes: Vec<u64> = paths.iter().map(|p| dir.join(p).metadata().map(|m| m.len())).collect::<io::Result<_>>()?;
+    let count_blocks = |block_size: u64| sizes.iter().map(|s| s.div_ceil(block_size) as usize).sum::<usize>();
+    let mut block_size = MIN_BLOCK_SIZE;
+    while count_blocks(block_size) > MAX_BLOCKS && count_blocks(block_size * 2) < count_blocks(block_size) {
+        block_size *= 2;
+    }
+    let total_blocks = count_blocks(block_size);
+    if total_blocks == 0 {
+        return Ok(0);
+    }
+
+    let data_shards = (MAX_SHARDS * 100 / (100 + redundancy as usize)).min(total_blocks).max(1);
+    let parity_shards = (data_shards * redundancy as usize).div_ceil(100).max(1);
+    let mut index = ParityIndex {
+        redundancy,
+        block_size,
+        data_shards,
+        parity_shards,
+        stripes: total_blocks.div_ceil(data_shards),
+        files: Vec::new(),
+        parity_hashes: Vec::new(),
+    };
+    let mut first_block = 0;
+    for (path, size) in paths.iter().zip(&sizes) {
+        let blocks = size.div_ceil(block_size) as usize;
+        index.files.push(ParityEntry { path: path.clone(), size: *size, first_block, hashes: vec![String::new(); blocks] });
+        first_block += blocks;
+    }
+
+    let codec = ReedSolomon::new(data_shards, parity_shards).map_err(|e| io::Error::other(format!("{:?}", e)))?;
+    let mut blocks = BlockReader::new(dir, &index);
+    let mut parity = File::create(dir.join(PARITY_FILE))?;
+    parity.set_len((index.stripes * parity_shards) as u64 * block_size)?;
+    let mut block_hashes = vec![String::new(); total_blocks];
+    let mut parity_hashes = vec![String::new(); index.stripes * parity_shards];
+
+    for stripe in 0..index.stripes {
+        let mut data_hashers = vec![blake3::Hasher::new(); data_shards];
+        let mut parity_hashers = vec![blake3::Hasher::new(); parity_shards];
+        let mut offset = 0;
+        while offset < block_size {
+            let len = CHUNK_SIZE.min(block_size - offset) as usize;
+            let mut data = Vec::with_capacity(data_shards);
+            for (shard, hasher) in data_hashers.iter_mut().enumerate() {
+                let chunk = blocks.read(index.block_index(stripe, shard), offset, len)?;
+                hasher.update(&chunk);
+                data.push(chunk);
+            }
+            let mut chunks = vec![vec![0u8; len]; parity_shards];
+            codec.encode_sep(&data, &mut chunks).map_err(|e| io::Error::other(format!("{:?}", e)))?;
+            throttle();
+            for (shard, chunk) in chunks.iter().enumerate() {
+                parity.seek(SeekFrom::Start(index.parity_offset(stripe, shard) + offset))?;
+                parity.write_all(chunk)?;
+                parity_hashers[shard].update(chunk);
+            }
+            offset += len as u64;
+        }
+
+        for (shard, hasher) in data_hashers.into_iter().enumerate() {
+            let block = index.block_index(stripe, shard);
+            if block < total_blocks {
+                block_hashes[block] = short_hash(hasher);
+            }
+        }
+        for (shard, hasher) in parity_hashers.into_iter().enumerate() {
+            parity_hashes[stripe * parity_shards + shard] = short_hash(hasher);
+        }
+    }
+    parity.sync_all()?;
+    drop(blocks);
+
+    for entry in index.files.iter_mut() {
+        entry.hashes = block_hashes[entry.first_block..entry.first_block + entry.hashes.len()].to_vec();
+    }
+    index.parity_hashes = parity_hashes;
+
+    let mut writer = BufWriter::new(File::create(dir.join(PARITY_INDEX_FILE))?);
+    serde_json::to_writer(&mut writer, &index)?;
+    writer.flush()?;
+
+    Ok(fs::metadata(dir.join(PARITY_FILE))?.len())
+}
+
+/***
+repair_backup: funzione per controllare i file di un backup tramite gli hash dei blocchi e ricostruire, con i dati di parità, quelli danneggiati o mancanti
+    dir: cartella del backup
+    -> restituisce: il risultato del controllo e della riparazione
+***/
+pub fn repair_backup(dir: &Path) -> io::Result<RepairReport> {
+    let file = File::open(dir.join(PARITY_INDEX_FILE)).map_err(|e| io::Error::new(e.kind(), format!("Dati di parità non trovati ({}): {}", PARITY_INDEX_FILE, e)))?;
+    let index: ParityIndex = serde_json::from_reader(io::BufReader::new(file))?;
+    let mut report = RepairReport { checked_files: index.files.len(), ..Default::default() };
+
+    // 1) ricerca dei blocchi danneggiati: file mancanti, blocchi con hash diverso, file di dimensione diversa
+    let mut damaged: HashSet<usize> = HashSet::new();
+    let mut resize: HashSet<usize> = HashSet::new();
+    let mut blocks = BlockReader::new(dir, &index);
+    for (i, entry) in index.files.iter().enumerate() {
+        if fs::metadata(dir.join(&entry.path)).map_or(true, |m| m.len() != entry.size) {
+            resize.insert(i);
+        }
+        for (block, expected) in entry.hashes.iter().enumerate() {
+            if &blocks.block_hash(entry.first_block + block)? != expected {
+                damaged.insert(entry.first_block + block);
+            }
+        }
+    }
+    let mut damaged_parity: HashSet<usize> = HashSet::new();
+    let mut parity_file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join(PARITY_FILE))?;
+    for (i, expected) in index.parity_hashes.iter().enumerate() {
+        let block = read_padded(&mut parity_file, i as u64 * index.block_size, index.block_size as usize, u64::MAX)?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&block);
+        if &short_hash(hasher) != expected {
+            damaged_parity.insert(i);
+        }
+    }
+    report.damaged_blocks = damaged.len();
+    report.damaged_parity = damaged_parity.len();
+
+    // 2) per ogni striscia si possono ricostruire al massimo parity_shards blocchi
+    let mut repaired_files: BTreeMap<usize, bool> = BTreeMap::new(); // file -> true se completamente ricostruito
+    for &i in &resize {
+        repaired_files.insert(i, true);
+    }
+    let mut stripes: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for &block in &damaged {
+        stripes.entry(block % index.stripes).or_default().push(block / index.stripes);
+    }
+    for &i in &damaged_parity {
+        stripes.entry(i / index.parity_shards).or_default();
+    }
+    let total_blocks = index.total_blocks();
+    let mut recoverable = Vec::new();
+    for (&stripe, shards) in &stripes {
+        let parity_lost = (0..index.parity_shards).filter(|j| damaged_parity.contains(&(stripe * index.parity_shards + j))).count();
+        let ok = shards.len() + parity_lost <= index.parity_shards;
+        for &shard in shards {
+            if let Some((file, _)) = blocks.locate(index.block_index(stripe, shard)) {
+                let complete = repaired_files.entry(file).or_insert(true);
+                *complete &= ok;
+            }
+        }
+        if ok {
+            recoverable.push(stripe);
+        }
+    }
+
+    // 3) i file da riparare vengono ricreati (se mancanti) e riportati alla dimensione originale
+    drop(blocks);
+    for &i in repaired_files.keys() {
+        let path = dir.join(&index.files[i].path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new().write(true).create(true).truncate(false).open(&path)?.set_len(index.files[i].size)?;
+    }
+    parity_file.set_len(index.parity_hashes.len() as u64 * index.block_size)?;
+
+    // 4) ricostruzione delle strisce recuperabili, a pezzi di CHUNK_SIZE byte
+    let codec = ReedSolomon::new(index.data_shards, index.parity_shards).map_err(|e| io::Error::other(format!("{:?}", e)))?;
+    let mut blocks = BlockReader::new(dir, &index);
+    for stripe in recoverable {
+        let lost: HashSet<usize> = stripes[&stripe].iter().copied().collect();
+        let mut offset = 0;
+        while offset < index.block_size {
+            let len = CHUNK_SIZE.min(index.block_size - offset) as usize;
+            let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(index.data_shards + index.parity_shards);
+            for shard in 0..index.data_shards {
+                shards.push(if lost.contains(&shard) { None } else { Some(blocks.read(index.block_index(stripe, shard), offset, len)?) });
+            }
+            for shard in 0..index.parity_shards {
+                if damaged_parity.contains(&(stripe * index.parity_shards + shard)) {
+                    shards.push(None);
+                } else {
+                    shards.push(Some(read_padded(&mut parity_file, index.parity_offset(stripe, shard) + offset, len, u64::MAX)?));
+                }
+            }
+            codec.reconstruct(&mut shards).map_err(|e| io::Error::other(format!("{:?}", e)))?;
+
+            for &shard in &lost {
+                let block = index.block_index(stripe, shard);
+                if block < total_blocks {
+                    blocks.write(block, offset, shards[shard].as_ref().unwrap())?;
+                }
+            }
+            for shard in 0..index.parity_shards {
+                if damaged_parity.contains(&(stripe * index.parity_shards + shard)) {
+                    parity_file.seek(SeekFrom::Start(index.parity_offset(stripe, shard) + offset))?;
+                    parity_file.write_all(shards[index.data_shards + shard].as_ref().unwrap())?;
+                }
+            }
+            offset += len as u64;
+        }
+    }
+    blocks.sync()?;
+    parity_file.sync_all()?;
+
+    for (i, complete) in repaired_files {
+        let path = index.files[i].path.clone();
+        if complete { report.repaired.push(path) } else { report.unrecoverable.push(path) }
+    }
+    Ok(report)
+}
+
+/***
+repair_command: funzione che esegue il comando "repair" da riga di comando
+    uso: progetto_rust repair [<cartella del backup>]
+    se la cartella non è indicata viene riparato il backup più recente della sorgente presente sul disco esterno
+    conf_file: percorso del file di configurazione
+    args: argomenti successivi a "repair"
+    -> restituisce: il codice di uscita del programma (0 se il backup è integro o è stato riparato, 1 se alcuni file non sono recuperabili, 2 in caso di errore)
+***/
+pub fn repair_command(conf_file: &str, args: &[String]) -> i32 {
+    let backup = match args.first() {
+        Some(b) => PathBuf::from(b),
+        None => match get_src_path(conf_file).and_then(|src| get_usb_path().and_then(|usb| find_previous_backup(Path::new(&usb), &src))) {
+            Some((path, _)) => path,
+            None => {
+                println!("Nessun backup da riparare trovato.");
+                return 2;
+            }
+        },
+    };
+
+    println!("Controllo del backup '{}' tramite i dati di parità.", backup.to_string_lossy());
+    match repair_backup(&backup) {
+        Ok(report) => {
+            report.print();
+            if !report.unrecoverable.is_empty() {
+                1
+            } else {
+                if report.repaired.is_empty() && report.damaged_parity == 0 {
+                    println!("Il backup è integro.");
+                } else {
+                    println!("Il backup è stato riparato.");
+                }
+                0
+            }
+        }
+        Err(e) => {
+            println!("Errore durante la riparazione: {}", e);
+            2
+        }
+    }
+}
+
+/***
+BlockReader: accesso ai blocchi di dati dei file protetti, numerati come in ParityIndex (i file aperti vengono riutilizzati)
+***/
+struct BlockReader<'a> {
+    dir: &'a Path,
+    index: &'a ParityIndex,
+    owners: Vec<(usize, usize)>, // blocco -> (file, blocco all'interno del file)
+    open: HashMap<usize, File>,
+}
+
+impl<'a> BlockReader<'a> {
+    fn new(dir: &'a Path, index: &'a ParityIndex) -> Self {
+        let owners = index.files.iter().enumerate().flat_map(|(i, f)| (0..f.hashes.len()).map(move |b| (i, b))).collect();
+        BlockReader { dir, index, owners, open: HashMap::new() }
+    }
+
+    fn locate(&self, block: usize) -> Option<(usize, usize)> {
+        self.owners.get(block).copied()
+    }
+
+    fn file(&mut self, i: usize) -> io::Result<&mut File> {
+        if !self.open.contains_key(&i) {
+            if self.open.len() >= 64 {
+                self.open.clear(); // limite ai file aperti contemporaneamente
+            }
+            let path = self.dir.join(&self.index.files[i].path);
+            let file = OpenOptions::new().read(true).write(true).open(&path).or_else(|_| File::open(&path))?;
+            self.open.insert(i, file);
+        }
+        Ok(self.open.get_mut(&i).unwrap())
+    }
+
+    // legge len byte del blocco a partire da offset; i byte oltre la fine del file (o dei blocchi) valgono zero, così come quelli di un file illeggibile
+    fn read(&mut self, block: usize, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let (file, block_in_file) = match self.locate(block) {
+            Some(b) => b,
+            None => return Ok(vec![0u8; len]),
+        };
+        let size = self.index.files[file].size;
+        let position = block_in_file as u64 * self.index.block_size + offset;
+        match self.file(file) {
+            Ok(f) => read_padded(f, position, len, size),
+            Err(_) => Ok(vec![0u8; len]),
+        }
+    }
+
+    fn block_hash(&mut self, block: usize) -> io::Result<String> {
+        let mut hasher = blake3::Hasher::new();
+        let mut offset = 0;
+        while offset < self.index.block_size {
+            let len = CHUNK_SIZE.min(self.index.block_size - offset) as usize;
+            hasher.update(&self.read(block, offset, len)?);
+            offset += len as u64;
+        }
+        Ok(short_hash(hasher))
+    }
+
+    // scrive i dati ricostruiti di un blocco, senza superare la dimensione originale del file
+    fn write(&mut self, block: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        let (file, block_in_file) = self.locate(block).unwrap();
+        let size = self.index.files[file].size;
+        let position = block_in_file as u64 * self.index.block_size + offset;
+        if position >= size {
+            return Ok(());
+        }
+        let len = data.len().min((size - position) as usize);
+        let f = self.file(file)?;
+        f.seek(SeekFrom::Start(position))?;
+        f.write_all(&data[..len])
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        for file in self.open.values() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+// legge len byte a partire da position, senza superare limit; la parte mancante viene completata con zeri
+fn read_padded(file: &mut File, position: u64, len: usize, limit: u64) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    if position >= limit {
+        return Ok(buffer);
+    }
+    let wanted = len.min((limit - position).min(usize::MAX as u64) as usize);
+    file.seek(SeekFrom::Start(position))?;
+    let mut filled = 0;
+    while filled < wanted {
+        match file.read(&mut buffer[filled..wanted]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => { // settore illeggibile: il resto del blocco viene considerato danneggiato
+                buffer[filled..].fill(0xff);
+                break;
+            }
+        }
+    }
+    Ok(buffer)
+}
+
+fn short_hash(hasher: blake3::Hasher) -> String {
+    hasher.finalize().to_hex()[..32].to_string()
+}
+
+// elenca ricorsivamente i file della cartella del backup, esclusi i dati di parità
+fn list_files(dir: &Path, root: &Path, files: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_files(&path, root, files)?;
+        } else if file_type.is_file() { // i collegamenti simbolici non vengono seguiti
+            let key = relative_key(&path, root);
+            if key != PARITY_FILE && key != PARITY_INDEX_FILE {
+                files.push(key);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // backup con 6 blocchi di dati (3 + 2 + 1): con il 50% di ridondanza c'è una sola striscia, con 3 blocchi di parità
+    fn create_backup(dir: &Path) -> Vec<(&'static str, Vec<u8>)> {
+        let files = vec![
+            ("foto.jpg", (0..10_000u32).map(|b| (b % 251) as u8).collect()),
+            ("documenti/lettera.txt", b"Gentile cliente, ".repeat(300)),
+            ("documenti/note.txt", b"da ricordare".to_vec()),
+        ];
+        fs::create_dir_all(dir.join("documenti")).unwrap();
+        for (key, content) in &files {
+            fs::write(dir.join(key), content).unwrap();
+        }
+        create_parity(dir, 50).unwrap();
+        let index: ParityIndex = serde_json::from_reader(File::open(dir.join(PARITY_INDEX_FILE)).unwrap()).unwrap();
+        assert_eq!((index.stripes, index.data_shards, index.parity_shards), (1, 6, 3));
+        files
+    }
+
+    // inverte il primo byte del blocco indicato
+    fn flip_block(path: &Path, block: u64) {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let mut byte = [0u8];
+        file.seek(SeekFrom::Start(block * MIN_BLOCK_SIZE)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(block * MIN_BLOCK_SIZE)).unwrap();
+        file.write_all(&[!byte[0]]).unwrap();
+    }
+
+    // "progetto_rust repair <cartella>": con la cartella indicata il file di configurazione non viene letto
+    fn repair(dir: &Path) -> i32 {
+        repair_command("configurazione_inesistente.txt", &[dir.to_string_lossy().to_string()])
+    }
+
+    #[test]
+    fn repair_command_exit_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = create_backup(dir.path());
+        assert_eq!(repair(dir.path()), 0);
+
+        // 2 blocchi danneggiati su 3 recuperabili: file eliminato e blocco modificato
+        fs::remove_file(dir.path().join("documenti/note.txt")).unwrap();
+        flip_block(&dir.path().join("foto.jpg"), 2);
+        assert_eq!(repair(dir.path()), 0);
+        for (key, content) in &files {
+            assert_eq!(&fs::read(dir.path().join(key)).unwrap(), content, "{}", key);
+        }
+
+        // 4 blocchi danneggiati, uno in più dei blocchi di parità
+        for block in 0..3 {
+            flip_block(&dir.path().join("foto.jpg"), block);
+        }
+        flip_block(&dir.path().join("documenti/lettera.txt"), 1);
+        assert_eq!(repair(dir.path()), 1);
+
+        // backup senza dati di parità
+        let empty = tempfile::tempdir().unwrap();
+        assert_eq!(repair(empty.path()), 2);
+    }
+
+    #[test]
+    fn damaged_parity_blocks_are_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        create_backup(dir.path());
+        let parity = fs::read(dir.path().join(PARITY_FILE)).unwrap();
+
+        // gli ultimi 2 blocchi di parità sono andati persi e un blocco di dati è danneggiato: 3 blocchi in tutto, ancora recuperabili
+        OpenOptions::new().write(true).open(dir.path().join(PARITY_FILE)).unwrap().set_len(MIN_BLOCK_SIZE).unwrap();
+        flip_block(&dir.path().join("documenti/lettera.txt"), 0);
+
+        let report = repair_backup(dir.path()).unwrap();
+        assert_eq!((report.damaged_blocks, report.damaged_parity), (1, 2));
+        assert_eq!(report.repaired, vec!["documenti/lettera.txt"]);
+        assert_eq!(fs::read(dir.path().join(PARITY_FILE)).unwrap(), parity);
+
+        let report = repair_backup(dir.path()).unwrap();
+        assert_eq!((report.damaged_blocks, report.damaged_parity), (0, 0));
+    }
+}
@@ -0,0 +1,210 @@
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SMALL_FILE_SIZE: u64 = 1024 * 1024; // dimensione massima dei file della regola "small"
+pub const RECENT_DAYS: u64 = 7; // giorni dall'ultima modifica dei file della regola "recent"
+
+// estensioni dei file (documenti, fogli di calcolo, archivi di password, ...) della regola "documents"
+pub const DOCUMENT_EXTENSIONS: [&str; 20] = ["txt", "md", "rtf", "pdf", "doc", "docx", "odt", "xls", "xlsx", "ods", "csv", "ppt", "pptx", "odp", "tex", "kdbx", "pem", "key", "vcf", "ics"];
+
+/***
+PriorityRule: regola con cui vengono scelti i file copiati per primi
+    Critical: file nei percorsi indicati dall'opzione "priority_paths" (nell'ordine in cui sono elencati)
+    Documents: documenti (estensioni di DOCUMENT_EXTENSIONS)
+    Recent: file modificati negli ultimi "priority_recent_days" giorni (dal più recente)
+    Small: file fino a "priority_small_size" byte (dal più piccolo)
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PriorityRule {
+    Critical,
+    Documents,
+    Recent,
+    Small,
+}
+
+impl PriorityRule {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "critical" => Some(PriorityRule::Critical),
+            "documents" => Some(PriorityRule::Documents),
+            "recent" => Some(PriorityRule::Recent),
+            "small" => Some(PriorityRule::Small),
+            _ => None,
+        }
+    }
+}
+
+/***
+PriorityRules: ordine di copia dei file, scelto in modo che un backup interrotto in qualsiasi momento contenga i dati più utili.
+    Ogni file appartiene alla classe della prima regola che soddisfa (i file che non ne soddisfano nessuna vengono copiati per ultimi);
+    a parità di classe i file vengono ordinati secondo le regole stesse (percorsi critici nell'ordine indicato, più recenti, più piccoli)
+    rules: regole nell'ordine di importanza (opzione "priority"); se è vuoto i file vengono copiati nell'ordine delle cartelle
+    critical: percorsi critici relativi alla sorgente, con "/" come separatore (opzione "priority_paths")
+    recent_days: giorni dall'ultima modifica dei file della regola "recent" (opzione "priority_recent_days")
+    small_size: dimensione massima dei file della regola "small" (opzione "priority_small_size")
+***/
+#[derive(Clone, Debug)]
+pub struct PriorityRules {
+    pub rules: Vec<PriorityRule>,
+    pub critical: Vec<String>,
+    pub recent_days: u64,
+    pub small_size: u64,
+}
+
+impl Default for PriorityRules {
+    fn default() -> Self {
+        PriorityRules { rules: Vec::new(), critical: Vec::new(), recent_days: RECENT_DAYS, small_size: SMALL_FILE_SIZE }
+    }
+}
+
+impl PriorityRules {
+    // regole della modalità salvataggio senza l'opzione "priority": prima i file piccoli (letti in fretta anche da un disco danneggiato), poi i documenti
+    pub fn salvage(&self) -> Self {
+        PriorityRules { rules: vec![PriorityRule::Small, PriorityRule::Documents], ..self.clone() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    // numero di classi, cioè di passaggi sulla sorgente: una per regola più quella dei file che non ne soddisfano nessuna
+    pub fn passes(&self) -> u8 {
+        self.rules.len() as u8 + 1
+    }
+
+    /***
+    class: funzione per scegliere la classe (il passaggio sulla sorgente) in cui viene copiato un file
+        key: percorso del file relativo alla sorgente (vedi backup_index::relative_key)
+        metadata: metadati del file
+        -> restituisce: l'indice della prima regola soddisfatta dal file, il numero di regole se non ne soddisfa nessuna
+    ***/
+    pub fn class(&self, key: &str, metadata: &Metadata) -> u8 {
+        self.rules.iter().position(|rule| self.matches(*rule, key, metadata)).unwrap_or(self.rules.len()) as u8
+    }
+
+    /***
+    sort_key: funzione per ordinare i file di una cartella
+        key, metadata: come in class
+        -> restituisce: la chiave di ordinamento (classe, poi il valore di ogni regola): i file da copiare prima hanno la chiave minore
+    ***/
+    pub fn sort_key(&self, key: &str, metadata: &Metadata) -> Vec<u64> {
+        let mut sort_key = vec![self.class(key, metadata) as u64];
+        for rule in &self.rules {
+            sort_key.push(match rule {
+                PriorityRule::Critical => self.critical.iter().position(|p| under(key, p)).unwrap_or(self.critical.len()) as u64,
+                PriorityRule::Documents => !is_document(key) as u64,
+                PriorityRule::Recent => u64::MAX - modified_secs(metadata),
+                PriorityRule::Small => metadata.len(),
+            });
+        }
+        sort_key
+    }
+
+    fn matches(&self, rule: PriorityRule, key: &str, metadata: &Metadata) -> bool {
+        match rule {
+            PriorityRule::Critical => self.critical.iter().any(|p| under(key, p)),
+            PriorityRule::Documents => is_document(key),
+            PriorityRule::Recent => metadata.modified().ok().and_then(|m| m.elapsed().ok()).is_some_and(|age| age <= Duration::from_secs(self.recent_days * 24 * 3600)),
+            PriorityRule::Small => metadata.len() <= self.small_size,
+        }
+    }
+}
+
+// true se il percorso key coincide con path o si trova al suo interno
+fn under(key: &str, path: &str) -> bool {
+    key.strip_prefix(path).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn is_document(key: &str) -> bool {
+    key.rsplit('/').next().and_then(|name| name.rsplit_once('.')).is_some_and(|(_, e)| DOCUMENT_EXTENSIONS.iter().any(|d| e.eq_ignore_ascii_case(d)))
+}
+
+fn modified_secs(metadata: &Metadata) -> u64 {
+    metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH).duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use filetime::{set_file_mtime, FileTime};
+
+    // crea il file key nella cartella, con la dimensione e i giorni dall'ultima modifica indicati, e ne restituisce i metadati
+    fn create_file(dir: &Path, key: &str, size: usize, days: u64) -> Metadata {
+        let path = dir.join(key);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, vec![1u8; size]).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        set_file_mtime(&path, FileTime::from_unix_time((now - days * 24 * 3600) as i64, 0)).unwrap();
+        fs::metadata(&path).unwrap()
+    }
+
+    #[test]
+    fn class_is_the_first_matching_rule() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = PriorityRules {
+            rules: vec![PriorityRule::Critical, PriorityRule::Documents, PriorityRule::Small],
+            critical: vec!["Documenti/Tasse".to_string()],
+            small_size: 100,
+            ..Default::default()
+        };
+        assert_eq!(rules.passes(), 4);
+
+        let cases = [
+            ("Documenti/Tasse/730.pdf", 5000, 0),
+            ("Documenti/Tasse", 5000, 0),            // il percorso critico può essere anche un file
+            ("Documenti/Tasse 2023/730.pdf", 5000, 1), // ma non il prefisso di un altro nome
+            ("Documenti/NOTE.TXT", 5000, 1),         // estensione senza distinzione tra maiuscole e minuscole
+            ("Musica/brano.mp3", 50, 2),
+            ("Musica/album.flac", 5000, 3),
+            ("Musica/senza_estensione", 5000, 3),
+        ];
+        for (i, (key, size, class)) in cases.iter().enumerate() {
+            // ogni file in una cartella diversa, così che "Documenti/Tasse" possa essere sia file che cartella
+            let metadata = create_file(&dir.path().join(i.to_string()), key, *size, 30);
+            assert_eq!(rules.class(key, &metadata), *class, "{}", key);
+        }
+
+        // senza l'opzione "priority" c'è un solo passaggio, mentre la modalità salvataggio copia prima i file piccoli e poi i documenti
+        let metadata = create_file(dir.path(), "Musica/brano.mp3", 50, 30);
+        assert_eq!((PriorityRules::default().passes(), PriorityRules::default().class("Musica/brano.mp3", &metadata)), (1, 0));
+        let salvage = rules.salvage();
+        assert_eq!((salvage.rules, salvage.critical, salvage.small_size), (vec![PriorityRule::Small, PriorityRule::Documents], rules.critical.clone(), 100));
+    }
+
+    #[test]
+    fn sort_key_orders_files_of_the_same_class() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = PriorityRules {
+            rules: vec![PriorityRule::Critical, PriorityRule::Recent, PriorityRule::Small],
+            critical: vec!["lavoro".to_string(), "casa".to_string()],
+            recent_days: 10,
+            ..Default::default()
+        };
+        let files = [
+            ("casa/mutuo.pdf", 10, 400),
+            ("lavoro/contratto.pdf", 5_000_000, 400),
+            ("foto/ieri.jpg", 2_000_000, 1),
+            ("foto/settimana.jpg", 2_000_000, 7),
+            ("vecchi/grande.iso", 3_000_000, 400),
+            ("vecchi/piccolo.bin", 10, 400),
+            ("vecchi/medio.bin", 200_000, 400),
+        ];
+        let mut keys: Vec<(Vec<u64>, &str)> = files.iter().map(|(key, size, days)| (rules.sort_key(key, &create_file(dir.path(), key, *size, *days)), *key)).collect();
+        keys.sort();
+
+        assert_eq!(keys.iter().map(|(_, key)| *key).collect::<Vec<_>>(), vec![
+            // percorsi critici nell'ordine di "priority_paths", indipendentemente da dimensione e data
+            "lavoro/contratto.pdf", "casa/mutuo.pdf",
+            // file recenti, dal più recente
+            "foto/ieri.jpg", "foto/settimana.jpg",
+            // file piccoli (fino a 1 MB), dal più piccolo
+            "vecchi/piccolo.bin", "vecchi/medio.bin",
+            // nessuna regola
+            "vecchi/grande.iso",
+        ]);
+        assert_eq!(keys[0].0[..2], [0, 0]);
+        assert_eq!(keys[1].0[..2], [0, 1]);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use crate::backup_index::{BackupIndex, INDEX_FILE, check_relative_key};
+use crate::encryption::is_encrypted;
+use crate::fs_compat::apply_renamed;
+use crate::metadata::{MetadataSidecar, copy_metadata, restore_metadata};
+use crate::special_files::{SpecialFiles, link_id, is_sparse, copy_file_sparse};
+use crate::verify::{backup_root, is_tool_file, walk_files};
+
+/***
+restore_backup: funzione per ripristinare un backup in formato "directory", ricreando i percorsi originali dei file
+    (anche quelli salvati con un altro nome per il file system della destinazione) e ricomponendo i file divisi in parti.
+    Date, permessi e attributi estesi vengono copiati dal backup e, se il backup li ha registrati in METADATA_FILE, ripristinati dai valori originali.
+    I file che nel backup sono hard link dello stesso file vengono ripristinati come hard link, e i file speciali registrati in SPECIAL_FILE vengono ricreati
+    backup: cartella del backup
+    output: cartella in cui vengono ripristinati i file
+    -> restituisce: il numero di file ripristinati
+***/
+pub fn restore_backup(backup: &Path, output: &Path) -> io::Result<usize> {
+    let index = if backup.join(INDEX_FILE).is_file() { Some(BackupIndex::load(backup)?) } else { None };
+    if let Some(format) = index.as_ref().map(|i| i.format.as_str()).filter(|f| *f != "directory") {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("il backup è in formato {}: estrarre l'archivio con un programma compatibile", format)));
+    }
+
+    let mut files = BTreeMap::new();
+    walk_files(backup, backup, &mut files, &|p| !is_tool_file(p, backup))?;
+    let parts = apply_renamed(backup, &mut files)?;
+
+    if let Some(index) = &index {
+        // nei backup incrementali, i file invariati vengono presi dai backup precedenti indicati dall'indice
+        // (i percorsi letti da backup_index.txt vengono verificati prima di unirli alla radice del disco esterno)
+        let root = backup_root(backup, index);
+        for (key, entry) in &index.files {
+            check_relative_key(key)?;
+            check_relative_key(&entry.location)?;
+            check_relative_key(entry.stored_path(key))?;
+            files.entry(key.clone()).or_insert_with(|| root.join(&entry.location).join(entry.stored_path(key)));
+        }
+    }
+
+    // i collegamenti simbolici vengono ricreati dai metadati registrati, senza copiare il contenuto a cui puntano
+    let metadata = MetadataSidecar::load(backup)?;
+    let links = metadata.symlinks();
+    files.retain(|k, _| !links.contains(k));
+
+    fs::create_dir_all(output)?;
+    let mut count = 0;
+    let mut restored: HashMap<(u64, u64), PathBuf> = HashMap::new(); // (dispositivo, inode) nel backup -> primo file ripristinato
+    for (key, path) in &files {
+        check_relative_key(key)?;
+        let destination = output.join(key);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match parts.get(key) {
+            Some(paths) => join_parts(paths, &destination)?,
+            None => {
+                let metadata = fs::metadata(path)?;
+                if let Some(id) = link_id(&metadata) {
+                    if restored.get(&id).is_some_and(|first| fs::hard_link(first, &destination).is_ok()) {
+                        count += 1;
+                        continue;
+                    }
+                    restored.insert(id, destination.clone());
+                }
+                if is_sparse(&metadata) {
+                    copy_file_sparse(path, &destination)?;
+                } else {
+                    fs::copy(path, &destination)?;
+                }
+                copy_metadata(path, &metadata, &destination);
+            }
+        }
+        count += 1;
+    }
+
+    count += SpecialFiles::load(backup)?.recreate(output)?;
+
+    // in ordine inverso, per applicare le date delle cartelle dopo quelle dei file che contengono
+    for (key, record) in metadata.files.iter().rev() {
+        check_relative_key(key)?;
+        let destination = output.join(key);
+        if record.symlink.is_some() {
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        } else if !destination.exists() {
+            continue;
+        }
+        restore_metadata(&destination, record)?;
+    }
+
+    Ok(count)
+}
+
+// ricompone un file diviso in parti, copiando i permessi della prima parte
+fn join_parts(paths: &[PathBuf], destination: &Path) -> io::Result<()> {
+    let mut output = BufWriter::new(File::create(destination)?);
+    for path in paths {
+        io::copy(&mut File::open(path)?, &mut output)?;
+    }
+    output.flush()?;
+    if let Some(first) = paths.first() {
+        output.get_ref().set_permissions(fs::metadata(first)?.permissions())?;
+    }
+    Ok(())
+}
+
+/***
+restore_command: funzione che esegue il comando "restore" da riga di comando
+    uso: progetto_rust restore <cartella del backup> <cartella di destinazione>
+    args: argomenti successivi a "restore"
+    -> restituisce: il codice di uscita del programma (0 se il backup è stato ripristinato, 2 in caso di errore)
+***/
+pub fn restore_command(args: &[String]) -> i32 {
+    if args.len() != 2 {
+        println!("Uso: progetto_rust restore <cartella del backup> <cartella di destinazione>");
+        return 2;
+    }
+
+    let (backup, output) = (Path::new(&args[0]), Path::new(&args[1]));
+    if is_encrypted(backup) {
+        println!("Il backup '{}' è cifrato: usare il comando 'decrypt'.", backup.to_string_lossy());
+        return 2;
+    }
+    match restore_backup(backup, output) {
+        Ok(count) => {
+            println!("{} file ripristinati in '{}'.", count, output.to_string_lossy());
+            0
+        }
+        Err(e) => {
+            println!("Errore durante il ripristino del backup: {}", e);
+            2
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use chrono::{Datelike, NaiveDateTime};
+use crate::backup_index::{BackupIndex, list_backups};
+use crate::backup_options::BackupOptions;
+
+/***
+RetentionPolicy: regole di conservazione dei backup precedenti (lette dal file di configurazione)
+    keep_last: numero di backup più recenti da conservare, oltre a quello che sta per essere eseguito (le regole sono applicate prima del nuovo backup)
+    keep_daily: numero di giorni per cui conservare il backup più recente del giorno
+    keep_weekly: numero di settimane per cui conservare il backup più recente della settimana
+    keep_monthly: numero di mesi per cui conservare il backup più recente del mese
+    max_size: dimensione massima in byte occupata dai backup precedenti; se superata vengono eliminati i backup meno recenti
+***/
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    pub keep_last: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+    pub max_size: Option<u64>,
+}
+
+impl RetentionPolicy {
+    fn has_count_rules(&self) -> bool {
+        self.keep_last.is_some() || self.keep_daily.is_some() || self.keep_weekly.is_some() || self.keep_monthly.is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.has_count_rules() && self.max_size.is_none()
+    }
+}
+
+/***
+apply_retention: funzione per eliminare i backup precedenti della stessa sorgente che non rispettano la politica di conservazione.
+    Vengono considerati solo i backup gestiti dal tool (cartelle che contengono l'indice del backup).
+    Un backup a cui fa riferimento l'indice di un backup conservato (backup incrementali) non viene mai eliminato, nemmeno per rispettare max_size:
+    in quel caso si eliminano altri backup.
+    I backup parziali (vedi BackupIndex::partial) non vengono contati dalle regole sul numero: sono conservati solo se più recenti
+    dell'ultimo backup completo, che non viene mai eliminato.
+    backup_root: radice del disco esterno (cartella che contiene i backup)
+    source: percorso sorgente del backup
+    options: opzioni del backup (contengono la politica di conservazione)
+    -> restituisce: l'elenco dei backup eliminati
+***/
+pub fn apply_retention(backup_root: &Path, source: &str, options: &BackupOptions) -> io::Result<Vec<PathBuf>> {
+    let policy = &options.retention;
+    if policy.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let backups = list_backups(backup_root, source); // dal più recente al meno recente
+    let mut keep = vec![!policy.has_count_rules(); backups.len()]; // senza regole sul numero, viene applicato solo il limite di dimensione
+
+    // le regole sul numero contano solo i backup completi
+    let complete: Vec<usize> = (0..backups.len()).filter(|i| !backups[*i].1.partial).collect();
+    if let Some(n) = policy.keep_last {
+        for i in complete.iter().take(n) {
+            keep[*i] = true;
+        }
+    }
+    keep_buckets(&backups, &mut keep, policy.keep_daily, |d| (d.year(), d.ordinal()));
+    keep_buckets(&backups, &mut keep, policy.keep_weekly, |d| (d.iso_week().year(), d.iso_week().week()));
+    keep_buckets(&backups, &mut keep, policy.keep_monthly, |d| (d.year(), d.month()));
+
+    // l'ultimo backup completo non viene mai eliminato; i backup parziali più recenti contengono le copie più aggiornate dei file salvati
+    let newest_complete = complete.first().copied().unwrap_or(backups.len());
+    for k in keep.iter_mut().take(newest_complete) {
+        *k = true;
+    }
+    if let Some(k) = keep.get_mut(newest_complete) {
+        *k = true;
+    }
+
+    // i backup referenziati dall'indice di un backup conservato (e quelli a cui fanno riferimento a loro volta) non possono essere eliminati
+    let references: Vec<HashSet<PathBuf>> = backups.iter().map(|(_, index)| referenced_backups(backup_root, index)).collect();
+    let is_referenced = |keep: &[bool], i: usize| (0..backups.len()).any(|j| j != i && keep[j] && references[j].contains(&backups[i].0));
+    let mut needed = vec![false; backups.len()]; // backup conservati solo perché referenziati
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..backups.len() {
+            if !keep[i] && is_referenced(&keep, i) {
+                keep[i] = true;
+                needed[i] = true;
+                changed = true;
+            }
+        }
+    }
+
+    // limite di dimensione: si scartano i backup meno recenti finché quelli conservati rientrano nel limite. Un backup viene scartato
+    // (e il suo spazio conteggiato come liberato) solo se nessun backup conservato vi fa riferimento; scartando un backup incrementale
+    // possono diventare eliminabili quelli a cui faceva riferimento, quindi si ripete finché qualcosa cambia
+    if let Some(max_size) = policy.max_size {
+        let sizes: Vec<u64> = backups.iter().map(|(path, _)| dir_size(path).unwrap_or(0)).collect();
+        let mut total: u64 = sizes.iter().zip(&keep).filter(|(_, k)| **k).map(|(s, _)| *s).sum();
+        let mut changed = true;
+        while total > max_size && changed {
+            changed = false;
+            for i in (0..backups.len()).rev() {
+                if total <= max_size {
+                    break;
+                }
+                if keep[i] && i != newest_complete && !is_referenced(&keep, i) {
+                    keep[i] = false;
+                    total -= sizes[i];
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut removed = Vec::new();
+    for (((path, _), k), n) in backups.iter().zip(&keep).zip(&needed) {
+        if *k {
+            if *n {
+                println!("Il backup '{}' è necessario a un backup incrementale più recente e non verrà eliminato.", path.to_string_lossy());
+            }
+            continue;
+        }
+        println!("Eliminazione del backup '{}' (politica di conservazione).", path.to_string_lossy());
+        fs::remove_dir_all(path)?;
+        removed.push(path.clone());
+    }
+
+    Ok(removed)
+}
+
+/***
+keep_buckets: funzione per conservare il backup completo più recente di ognuno degli ultimi 'count' periodi (giorni, settimane o mesi)
+    backups: backup ordinati dal più recente al meno recente
+    keep: vettore dei backup da conservare, aggiornato dalla funzione
+    count: numero di periodi (None se la regola non è configurata)
+    bucket: funzione che associa la data di un backup al suo periodo
+***/
+fn keep_buckets<K: PartialEq>(backups: &[(PathBuf, BackupIndex)], keep: &mut [bool], count: Option<usize>, bucket: impl Fn(NaiveDateTime) -> K) {
+    let count = match count {
+        Some(c) => c,
+        None => return,
+    };
+
+    let mut last_bucket: Option<K> = None;
+    let mut buckets = 0;
+    for (i, (_, index)) in backups.iter().enumerate() {
+        if buckets >= count {
+            break;
+        }
+        if index.partial {
+            continue;
+        }
+        let created = match NaiveDateTime::parse_from_str(&index.created, "%Y%m%d%H%M%S%3f") {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        let b = bucket(created);
+        if last_bucket.as_ref() != Some(&b) {
+            keep[i] = true;
+            buckets += 1;
+            last_bucket = Some(b);
+        }
+    }
+}
+
+/***
+referenced_backups: funzione per ottenere i backup che contengono la copia dei file elencati in un indice
+    backup_root: radice del disco esterno
+    index: indice del backup
+***/
+fn referenced_backups(backup_root: &Path, index: &BackupIndex) -> HashSet<PathBuf> {
+    index.files.values().map(|e| backup_root.join(&e.location)).collect()
+}
+
+/***
+dir_size: funzione per calcolare lo spazio occupato da una cartella (su Unix lo spazio dei file con più hard link viene ripartito tra i collegamenti)
+    path: percorso della cartella
+    -> restituisce: la dimensione in byte
+***/
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                if metadata.nlink() > 1 {
+                    size += metadata.len() / metadata.nlink(); // lo spazio di un file condiviso tra più snapshot viene ripartito tra di essi
+                    continue;
+                }
+            }
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_index::IndexEntry;
+
+    #[test]
+    fn partial_backups_are_not_counted() {
+        let root = tempfile::tempdir().unwrap();
+        // dal meno recente al più recente: completo, parziale, completo, parziale
+        for (name, partial) in [("b1", false), ("b2", true), ("b3", false), ("b4", true)] {
+            let dir = root.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            let mut index = BackupIndex::new("/sorgente", &format!("2026010{}120000000", &name[1..]));
+            index.partial = partial;
+            index.save(&dir).unwrap();
+        }
+        let retention = |policy: RetentionPolicy| {
+            let options = BackupOptions { retention: policy, ..BackupOptions::default() };
+            let mut removed: Vec<String> = apply_retention(root.path(), "/sorgente", &options).unwrap()
+                .iter().map(|p| p.file_name().unwrap().to_string_lossy().to_string()).collect();
+            removed.sort();
+            removed
+        };
+
+        // keep_last conta solo i backup completi; il parziale più recente dell'ultimo completo viene conservato
+        assert_eq!(retention(RetentionPolicy { keep_last: Some(2), ..Default::default() }), vec!["b2"]);
+        assert!(BackupIndex::load(&root.path().join("b4")).unwrap().partial);
+
+        // il limite di dimensione non elimina mai l'ultimo backup completo
+        assert_eq!(retention(RetentionPolicy { max_size: Some(0), ..Default::default() }), vec!["b1", "b4"]);
+        assert!(root.path().join("b3").is_dir());
+    }
+
+    #[test]
+    fn size_limit_counts_only_deleted_backups() {
+        let root = tempfile::tempdir().unwrap();
+        // dal meno recente al più recente: completo, incrementale che usa la copia di b1, completo
+        for name in ["b1", "b2", "b3"] {
+            let dir = root.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            let mut index = BackupIndex::new("/sorgente", &format!("2026010{}120000000", &name[1..]));
+            if name != "b2" {
+                fs::write(dir.join("dati.bin"), vec![0u8; 10_000]).unwrap();
+            }
+            let location = if name == "b2" { "b1" } else { name };
+            index.files.insert("dati.bin".to_string(), IndexEntry { size: 10_000, mtime: 0, hash: None, location: location.to_string(), stored: None });
+            index.save(&dir).unwrap();
+        }
+
+        // b1 non può essere eliminato finché b2 vi fa riferimento: il suo spazio non deve essere contato come liberato
+        let options = BackupOptions { retention: RetentionPolicy { max_size: Some(15_000), ..Default::default() }, ..BackupOptions::default() };
+        let mut removed: Vec<String> = apply_retention(root.path(), "/sorgente", &options).unwrap()
+            .iter().map(|p| p.file_name().unwrap().to_string_lossy().to_string()).collect();
+        removed.sort();
+        assert_eq!(removed, vec!["b1", "b2"]);
+        assert!(dir_size(&root.path().join("b3")).unwrap() <= 15_000);
+    }
+}
@@ -0,0 +1,64 @@
+use crate::archive::archive_size_estimate;
+use crate::backup_options::{BackupOptions, OutputFormat};
+
+const RESERVE: u64 = 16 * 1024 * 1024; // spazio lasciato libero per indice, manifest, giornale, log e metadati del backup
+const ENTRY_SIZE: u64 = 1024; // spazio stimato per le righe di un file nell'indice, nel manifest e nel giornale
+
+/***
+SpaceBudget: spazio della destinazione a disposizione del backup (opzione "best_fit"). Se lo spazio non basta per tutti i file,
+    invece di annullare il backup vengono copiati i file più importanti che ci stanno: i file vengono esaminati nell'ordine
+    di priority::PriorityRules e un file che non ci sta viene escluso, proseguendo con i successivi (più piccoli o meno importanti)
+    available: byte a disposizione dei file (spazio libero della destinazione meno RESERVE)
+    used: byte stimati dei file scelti finora
+    block_size: unità di allocazione della destinazione: nel formato "directory" ogni file occupa blocchi interi (vedi fs_compat::allocation_unit)
+    format: formato del backup
+    parity: percentuale di ridondanza dei dati di parità, che occupano spazio in proporzione ai file
+    encrypted: true se i file vengono cifrati (intestazione e tag di autenticazione di ogni blocco)
+***/
+pub struct SpaceBudget {
+    available: u64,
+    used: u64,
+    block_size: u64,
+    format: OutputFormat,
+    parity: u8,
+    encrypted: bool,
+}
+
+impl SpaceBudget {
+    /***
+    new: funzione per creare lo SpaceBudget di un backup
+        available: byte liberi sulla destinazione (compresi quelli dei file di un backup interrotto, che vengono riutilizzati)
+        block_size: unità di allocazione del file system della destinazione
+        options: opzioni del backup
+    ***/
+    pub fn new(available: u64, block_size: u64, options: &BackupOptions) -> Self {
+        SpaceBudget { available: available.saturating_sub(RESERVE), used: 0, block_size, format: options.format, parity: options.parity, encrypted: options.encryption.is_enabled() }
+    }
+
+    // true se il file (di dimensione size) sta nello spazio rimasto
+    pub fn fits(&self, size: u64) -> bool {
+        self.used + self.needed(size) <= self.available
+    }
+
+    // funzione chiamata quando viene avviata la copia di un file, per riservargli lo spazio
+    pub fn take(&mut self, size: u64) {
+        self.used += self.needed(size);
+    }
+
+    // spazio occupato nel backup da un file di dimensione size, stimato per eccesso
+    fn needed(&self, size: u64) -> u64 {
+        let mut needed = match self.format {
+            OutputFormat::Directory => size.div_ceil(self.block_size) * self.block_size,
+            format => archive_size_estimate(format, size),
+        };
+        if self.encrypted {
+            needed += needed / 4096 + 256; //16 byte ogni 64 KB e l'intestazione del file cifrato
+        }
+        needed + needed * self.parity as u64 / 100 + ENTRY_SIZE
+    }
+
+    // riga del log del backup con lo spazio a disposizione e quello usato
+    pub fn note(&self) -> String {
+        format!("Spazio a disposizione del backup: {} byte, usati dai file copiati (stima): {} byte", self.available, self.used)
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::backup_index::check_relative_key;
+use crate::cpu_governor::throttle;
+use crate::bandwidth::limit;
+
+pub const SPECIAL_FILE: &str = "backup_special.txt"; // file speciali registrati con l'opzione "special_files=record" (tipo \t permessi \t dispositivo \t percorso)
+
+const HOLE_BLOCK: usize = 4096; // granularità con cui vengono cercate le zone vuote di un file sparso
+
+/***
+SpecialFilesPolicy: cosa fare dei file speciali (named pipe, socket, dispositivi) presenti nella sorgente
+    Skip: vengono ignorati, ed elencati nel log del backup
+    Record: vengono registrati in SPECIAL_FILE, così che il comando "restore" possa ricrearli
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SpecialFilesPolicy {
+    Skip,
+    Record,
+}
+
+/***
+SpecialKind: tipo di un file speciale
+    Fifo: named pipe
+    Socket: socket Unix (non può essere ricreato, viene solo registrato)
+    BlockDevice, CharDevice: dispositivi a blocchi e a caratteri
+***/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl SpecialKind {
+    /***
+    detect: funzione per riconoscere un file speciale
+        file_type: tipo del file (i file speciali non possono essere letti come file normali: una named pipe bloccherebbe la copia)
+        -> restituisce: il tipo del file speciale, None per file, cartelle e collegamenti
+    ***/
+    #[allow(unused_variables)]
+    pub fn detect(file_type: &fs::FileType) -> Option<SpecialKind> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if file_type.is_fifo() {
+                return Some(SpecialKind::Fifo);
+            } else if file_type.is_socket() {
+                return Some(SpecialKind::Socket);
+            } else if file_type.is_block_device() {
+                return Some(SpecialKind::BlockDevice);
+            } else if file_type.is_char_device() {
+                return Some(SpecialKind::CharDevice);
+            }
+        }
+        None
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpecialKind::Fifo => "fifo",
+            SpecialKind::Socket => "socket",
+            SpecialKind::BlockDevice => "block",
+            SpecialKind::CharDevice => "char",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<SpecialKind> {
+        [SpecialKind::Fifo, SpecialKind::Socket, SpecialKind::BlockDevice, SpecialKind::CharDevice].into_iter().find(|k| k.name() == name)
+    }
+}
+
+/***
+SpecialFiles: file speciali trovati nella sorgente durante il backup
+    files: percorso relativo -> (tipo, permessi, numero del dispositivo)
+***/
+#[derive(Default)]
+pub struct SpecialFiles {
+    pub files: BTreeMap<String, (SpecialKind, u32, u64)>,
+}
+
+impl SpecialFiles {
+    #[allow(unused_variables)]
+    pub fn add(&mut self, key: &str, kind: SpecialKind, metadata: &fs::Metadata) {
+        #[cfg(unix)]
+        let (mode, rdev) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.mode(), metadata.rdev())
+        };
+        #[cfg(not(unix))]
+        let (mode, rdev) = (0, 0);
+        self.files.insert(key.to_string(), (kind, mode, rdev));
+    }
+
+    /***
+    save: scrive l'elenco dei file speciali nella cartella del backup (solo se ce ne sono)
+        dir: cartella del backup
+    ***/
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+        let mut file = BufWriter::new(File::create(dir.join(SPECIAL_FILE))?);
+        for (key, (kind, mode, rdev)) in &self.files {
+            writeln!(file, "{}\t{:o}\t{}\t{}", kind.name(), mode, rdev, key)?;
+        }
+        file.flush()
+    }
+
+    /***
+    load: legge i file speciali registrati in un backup
+        dir: cartella del backup
+        -> restituisce: l'elenco letto (vuoto se il backup non contiene SPECIAL_FILE)
+    ***/
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let mut special = SpecialFiles::default();
+        let file = match File::open(dir.join(SPECIAL_FILE)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(special),
+            Err(e) => return Err(e),
+        };
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.splitn(4, '\t').collect();
+            if let [kind, mode, rdev, key] = fields[..] {
+                if let (Some(kind), Ok(mode), Ok(rdev)) = (SpecialKind::from_name(kind), u32::from_str_radix(mode, 8), rdev.parse::<u64>()) {
+                    special.files.insert(key.to_string(), (kind, mode, rdev));
+                }
+            }
+        }
+        Ok(special)
+    }
+
+    /***
+    recreate: funzione per ricreare i file speciali registrati in una cartella ripristinata
+        output: cartella in cui è stato ripristinato il backup
+        -> restituisce: il numero di file ricreati (i socket non possono essere ricreati; i dispositivi solo se il tool ha i privilegi necessari)
+    ***/
+    #[allow(unused_variables)]
+    pub fn recreate(&self, output: &Path) -> io::Result<usize> {
+        let mut count = 0;
+        for (key, (kind, mode, rdev)) in &self.files {
+            check_relative_key(key)?; // un backup manomesso non deve poter creare file fuori dalla cartella ripristinata
+            let path = output.join(key);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::ffi::OsStrExt;
+                let c_path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+                    Ok(p) => p,
+                    Err(_) => continue,
+                };
+                let result = match kind {
+                    SpecialKind::Fifo => unsafe { libc::mkfifo(c_path.as_ptr(), (*mode & 0o7777) as libc::mode_t) },
+                    SpecialKind::BlockDevice | SpecialKind::CharDevice => unsafe { libc::mknod(c_path.as_ptr(), *mode as libc::mode_t, *rdev as libc::dev_t) },
+                    SpecialKind::Socket => continue,
+                };
+                if result == 0 {
+                    count += 1;
+                } else {
+                    println!("Impossibile ricreare il file speciale '{}' ({}): {}", key, kind.name(), io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
+/***
+link_id: funzione per ottenere l'identificativo (dispositivo, inode) di un file con più hard link
+    metadata: metadati del file
+    -> restituisce: None se il file ha un solo collegamento (o sul sistema operativo non è possibile saperlo)
+***/
+#[allow(unused_variables)]
+pub fn link_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if metadata.nlink() > 1 {
+            return Some((metadata.dev(), metadata.ino()));
+        }
+    }
+    None
+}
+
+/***
+HardLinks: file della sorgente con più hard link già copiati nel backup
+    copies: (dispositivo, inode) -> (percorso della copia, percorso relativo, hash del contenuto)
+    linked: numero di file collegati tramite hard link alla copia di un altro file, invece di essere copiati
+***/
+#[derive(Default)]
+pub struct HardLinks {
+    pub copies: HashMap<(u64, u64), (PathBuf, String, String)>,
+    pub linked: usize,
+}
+
+/***
+is_sparse: funzione per riconoscere un file sparso (con zone vuote che non occupano spazio sul disco, es. immagini di macchine virtuali)
+    metadata: metadati del file
+***/
+#[allow(unused_variables)]
+pub fn is_sparse(metadata: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    return {
+        use std::os::unix::fs::MetadataExt;
+        metadata.blocks() * 512 < metadata.len()
+    };
+    #[cfg(windows)]
+    return {
+        use std::os::windows::fs::MetadataExt;
+        metadata.file_attributes() & 0x200 != 0 // FILE_ATTRIBUTE_SPARSE_FILE
+    };
+    #[cfg(not(any(unix, windows)))]
+    return false;
+}
+
+/***
+copy_file_sparse: funzione per copiare un file sparso mantenendo le zone vuote: i blocchi di soli zeri non vengono scritti,
+    ma saltati, così che la copia occupi sulla destinazione lo stesso spazio dell'originale (se il file system supporta i file sparsi)
+    src: percorso del file sorgente
+    dst: percorso della copia
+    -> restituisce: il numero di byte non scritti (zone vuote) e l'hash BLAKE3 del contenuto
+***/
+pub fn copy_file_sparse(src: &Path, dst: &Path) -> io::Result<(u64, String)> {
+    let mut input = File::open(src)?;
+    let mut output = BufWriter::new(File::create(dst)?);
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let (mut copied, mut holes) = (0u64, 0u64);
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        throttle();
+        limit(n as u64);
+        for block in buffer[..n].chunks(HOLE_BLOCK) {
+            if block.iter().all(|b| *b == 0) {
+                output.seek(SeekFrom::Current(block.len() as i64))?;
+                holes += block.len() as u64;
+            } else {
+                output.write_all(block)?;
+            }
+        }
+        copied += n as u64;
+    }
+    output.flush()?;
+    output.get_ref().set_len(copied)?; // una zona vuota alla fine del file non viene scritta: la dimensione va impostata
+    output.get_ref().set_permissions(input.metadata()?.permissions())?;
+    Ok((holes, hasher.finalize().to_hex().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_index::hash_file;
+
+    #[test]
+    fn sparse_copy_keeps_holes_size_and_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("disco.img"), dir.path().join("copia.img"));
+        // dati, zona vuota in mezzo, dati, zona vuota alla fine
+        let mut content = vec![7u8; HOLE_BLOCK];
+        content.extend(vec![0u8; 3 * HOLE_BLOCK]);
+        content.extend(vec![9u8; HOLE_BLOCK]);
+        content.extend(vec![0u8; 2 * HOLE_BLOCK]);
+        fs::write(&src, &content).unwrap();
+
+        let (holes, hash) = copy_file_sparse(&src, &dst).unwrap();
+        assert_eq!(holes, 5 * HOLE_BLOCK as u64);
+        assert_eq!(fs::metadata(&dst).unwrap().len(), content.len() as u64); // la zona vuota finale non è scritta, ma la dimensione resta
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        assert_eq!(hash, hash_file(&src).unwrap());
+        assert_eq!(hash, hash_file(&dst).unwrap());
+    }
+
+    #[test]
+    fn special_files_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut special = SpecialFiles::default();
+        special.files.insert("dev/sda".to_string(), (SpecialKind::BlockDevice, 0o60660, 2048));
+        special.files.insert("tmp/pipe\tcon tab".to_string(), (SpecialKind::Fifo, 0o10644, 0));
+        special.files.insert("run/app.sock".to_string(), (SpecialKind::Socket, 0o140755, 0));
+        special.save(dir.path()).unwrap();
+
+        let loaded = SpecialFiles::load(dir.path()).unwrap();
+        assert_eq!(loaded.files, special.files);
+        assert!(loaded.files.contains_key("tmp/pipe\tcon tab"));
+
+        // un backup senza file speciali non contiene SPECIAL_FILE
+        let empty = tempfile::tempdir().unwrap();
+        SpecialFiles::default().save(empty.path()).unwrap();
+        assert!(!empty.path().join(SPECIAL_FILE).exists());
+        assert!(SpecialFiles::load(empty.path()).unwrap().files.is_empty());
+    }
+}
@@ -0,0 +1,9 @@
+/***
+content: funzione per generare il contenuto dei file usati nei test
+    seed: distingue il contenuto di file diversi
+    len: lunghezza in byte
+    -> restituisce: byte tutti diversi da zero, così che le zone riempite di zeri (file sparsi, modalità salvataggio) siano riconoscibili
+***/
+pub fn content(seed: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 31 + seed as usize) % 251 + 1) as u8).collect()
+}
@@ -0,0 +1,150 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::bandwidth::transferred;
+
+const SAFETY: f64 = 0.9; // un file viene copiato solo se la copia stimata termina entro questa frazione del tempo rimasto
+const WARMUP: Duration = Duration::from_secs(1); // durata della copia dopo la quale la velocità viene stimata
+
+/***
+TimeBudget: tempo a disposizione del backup (opzione "time_limit"), per un backup d'emergenza (batteria quasi scarica, computer
+    che si surriscalda). Durante la copia viene stimata la velocità e un file viene copiato solo se la sua copia, dopo quella dei
+    file già avviati, può terminare entro la scadenza; i file copiati per primi sono i più utili (vedi priority::PriorityRules)
+    start: inizio del backup (il tempo a disposizione comprende anche la preparazione)
+    limit: tempo a disposizione
+    copy_start: inizio della copia del primo file e byte trasferiti fino ad allora dai cicli di copia (vedi bandwidth::transferred)
+    started: byte dei file di cui è stata avviata la copia
+    completed: byte dei file di cui è terminata la copia
+    expired: true dopo la scadenza (la copia si ferma)
+***/
+pub struct TimeBudget {
+    start: Instant,
+    limit: Duration,
+    copy_start: Option<(Instant, u64)>,
+    started: u64,
+    completed: u64,
+    expired: bool,
+}
+
+impl TimeBudget {
+    pub fn new(limit: Duration) -> Self {
+        TimeBudget { start: Instant::now(), limit, copy_start: None, started: 0, completed: 0, expired: false }
+    }
+
+    /***
+    try_start: funzione chiamata prima di copiare un file
+        size: dimensione del file
+        -> restituisce: true se il file va copiato (la copia stimata termina prima della scadenza), false se non viene salvato
+    ***/
+    pub fn try_start(&mut self, size: u64) -> bool {
+        if self.is_expired() {
+            return false;
+        }
+        let (copy_start, _) = *self.copy_start.get_or_insert_with(|| (Instant::now(), transferred()));
+        if self.started > self.completed && copy_start.elapsed() < WARMUP {
+            //i worker stanno ancora copiando i primi file: prima di avviarne altri aspetto di poter stimare la velocità
+            thread::sleep(WARMUP - copy_start.elapsed());
+        }
+        if let Some(speed) = self.throughput() {
+            //byte ancora da copiare: quelli dei file avviati (es. in coda ai worker) e quelli del file
+            let pending = self.started.saturating_sub(self.completed) + size;
+            let remaining = self.limit.saturating_sub(self.start.elapsed()).as_secs_f64() * SAFETY;
+            if pending as f64 / speed > remaining {
+                return false; //un file più piccolo, trovato dopo, può ancora essere copiato
+            }
+        }
+        self.started += size;
+        true
+    }
+
+    // funzione chiamata quando termina la copia (anche non riuscita) di un file avviato con try_start
+    pub fn finish(&mut self, size: u64) {
+        self.completed += size;
+    }
+
+    // true se il tempo a disposizione è terminato
+    pub fn is_expired(&mut self) -> bool {
+        self.expired = self.expired || self.start.elapsed() >= self.limit;
+        self.expired
+    }
+
+    // true se la copia è stata fermata dalla scadenza (i file non ancora esaminati non sono stati salvati)
+    pub fn stopped(&self) -> bool {
+        self.expired
+    }
+
+    /***
+    throughput: funzione per stimare la velocità della copia
+        -> restituisce: i byte dei file copiati al secondo, None finché la copia non dura da almeno WARMUP
+    ***/
+    fn throughput(&self) -> Option<f64> {
+        let (copy_start, transferred_start) = self.copy_start?;
+        let elapsed = copy_start.elapsed();
+        if elapsed < WARMUP {
+            return None;
+        }
+        Some(self.speed(transferred().saturating_sub(transferred_start), elapsed))
+    }
+
+    /***
+    speed: funzione per calcolare la velocità della copia dai byte dei file copiati
+        transferred: byte letti e scritti dai cicli di copia dall'inizio della copia
+        elapsed: durata della copia
+        -> restituisce: i byte copiati al secondo (almeno 1)
+    ***/
+    fn speed(&self, transferred: u64, elapsed: Duration) -> f64 {
+        //ai file già copiati si aggiungono i byte trasferiti per quelli in corso, al massimo la loro dimensione: un file può essere letto
+        //più volte (es. copiato dal kernel e poi letto per calcolarne l'hash) e un clone (reflink) non passa dai cicli di copia
+        let in_flight = self.started.saturating_sub(self.completed);
+        let copied = self.completed + transferred.saturating_sub(self.completed).min(in_flight);
+        (copied as f64 / elapsed.as_secs_f64()).max(1.0)
+    }
+
+    // riga del log del backup con il tempo a disposizione e la velocità stimata
+    pub fn note(&self) -> String {
+        let speed = self.throughput().map(|s| format!("{:.2} MB/s", s / (1024.0 * 1024.0))).unwrap_or_else(|| "non stimata".to_string());
+        format!("Tempo a disposizione: {} secondi, copia terminata dopo {} secondi (velocità stimata {}){}", self.limit.as_secs(), self.start.elapsed().as_secs(), speed,
+            if self.expired { ", tempo scaduto" } else { "" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_counts_copied_files_and_bounds_the_files_in_flight() {
+        let mut budget = TimeBudget::new(Duration::from_secs(60));
+        budget.started = 20_000_000;
+        budget.completed = 10_000_000;
+        // file clonati: nessun byte passa dai cicli di copia
+        assert_eq!(budget.speed(0, Duration::from_secs(2)), 5_000_000.0);
+        // file letti due volte (copia e hash): i byte dei file in corso contano al massimo la loro dimensione
+        assert_eq!(budget.speed(50_000_000, Duration::from_secs(2)), 10_000_000.0);
+        assert_eq!(budget.speed(14_000_000, Duration::from_secs(2)), 7_000_000.0);
+        // nessun progresso: velocità minima
+        assert_eq!(TimeBudget::new(Duration::from_secs(60)).speed(0, Duration::from_secs(2)), 1.0);
+    }
+
+    #[test]
+    fn cloned_files_do_not_stop_the_copy() {
+        let mut budget = TimeBudget::new(Duration::from_secs(60));
+        assert!(budget.try_start(100_000_000));
+        budget.finish(100_000_000); // copiato con un reflink, senza passare da limit()
+        thread::sleep(WARMUP);
+        assert!(budget.try_start(100_000_000));
+        budget.finish(100_000_000);
+        assert!(!budget.is_expired() && !budget.stopped());
+    }
+
+    #[test]
+    fn files_are_refused_after_the_limit() {
+        let mut budget = TimeBudget::new(Duration::from_millis(50));
+        assert!(budget.try_start(10));
+        budget.finish(10);
+        assert!(!budget.stopped());
+        thread::sleep(Duration::from_millis(60));
+        assert!(budget.is_expired());
+        assert!(!budget.try_start(1));
+        assert!(budget.stopped());
+    }
+}
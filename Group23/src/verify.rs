@@ -0,0 +1,258 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::backup_index::{BackupIndex, INDEX_FILE, DELETED_FILE, find_previous_backup, relative_key, modified_nanos, hash_file};
+use crate::backup_tool::{check_extension, get_extensions, get_src_path, get_usb_path};
+use crate::manifest::MANIFEST_FILE;
+use crate::archive::{TAR_ZST_FILE, list_tar_zst, list_zip_volumes};
+use crate::encryption::is_encrypted;
+use crate::parity::{PARITY_FILE, PARITY_INDEX_FILE};
+use crate::fs_compat::{RENAMED_FILE, apply_renamed, size_of_parts, hash_parts};
+use crate::metadata::{MetadataSidecar, METADATA_FILE};
+use crate::special_files::{SpecialKind, SPECIAL_FILE};
+
+const TOOL_FILES: [&str; 9] = ["backup_log.txt", INDEX_FILE, DELETED_FILE, MANIFEST_FILE, PARITY_FILE, PARITY_INDEX_FILE, RENAMED_FILE, METADATA_FILE, SPECIAL_FILE]; // file scritti dal tool nella cartella del backup, esclusi dal confronto
+
+/***
+VerifyReport: risultato del confronto tra sorgente e backup
+    checked: numero di file della sorgente confrontati
+    missing: file presenti nella sorgente ma non nel backup
+    extra: file presenti nel backup ma non nella sorgente
+    size_mismatch: file con dimensione diversa
+    content_mismatch: file con contenuto diverso (hash diverso, oppure data di modifica diversa da quella registrata nell'indice in modalità veloce)
+***/
+#[derive(Default, Debug)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    pub size_mismatch: Vec<String>,
+    pub content_mismatch: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.size_mismatch.is_empty() && self.content_mismatch.is_empty()
+    }
+
+    pub fn print(&self) {
+        for (title, list) in [("Mancante nel backup", &self.missing), ("Non presente nella sorgente", &self.extra), ("Dimensione diversa", &self.size_mismatch), ("Contenuto diverso", &self.content_mismatch)] {
+            for path in list {
+                println!("{}: {}", title, path);
+            }
+        }
+        println!("File controllati: {}, mancanti: {}, in più: {}, dimensione diversa: {}, contenuto diverso: {}",
+                 self.checked, self.missing.len(), self.extra.len(), self.size_mismatch.len(), self.content_mismatch.len());
+    }
+}
+
+/***
+verify_backup: funzione per confrontare un backup con la sorgente
+    src: Path della sorgente
+    backup: Path della cartella del backup
+    extensions: vettore di stringhe delle estensioni considerate (gli stessi filtri usati da copy_dir_recursive), se è vuoto vengono considerati tutti i file
+    full: se true confronta anche il contenuto dei file tramite hash BLAKE3, altrimenti solo i metadati (veloce)
+    -> restituisce: il resoconto delle differenze trovate
+***/
+pub fn verify_backup(src: &Path, backup: &Path, extensions: &[String], full: bool) -> io::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+
+    let mut source_files = BTreeMap::new();
+    walk_files(src, src, &mut source_files, &|p| extensions.is_empty() || check_extension(p, extensions))?;
+
+    let index = if backup.join(INDEX_FILE).is_file() { Some(BackupIndex::load(backup)?) } else { None };
+    match index.as_ref().map(|i| i.format.as_str()) {
+        Some("tar.zst") => return verify_archive(&source_files, list_tar_zst(&backup.join(TAR_ZST_FILE), full)?, index.as_ref(), full),
+        Some("zip") => return verify_archive(&source_files, list_zip_volumes(backup, full)?, index.as_ref(), full),
+        _ => {}
+    }
+
+    // file del backup: percorso relativo -> percorso effettivo del file (e voce dell'indice, se presente)
+    let mut backup_files = BTreeMap::new();
+    walk_files(backup, backup, &mut backup_files, &|p| !is_tool_file(p, backup))?;
+    let parts = apply_renamed(backup, &mut backup_files)?; // file salvati con un altro nome o divisi in parti (FAT32)
+
+    // i collegamenti simbolici copiati come collegamenti (opzione "preserve_metadata") non vengono confrontati
+    let metadata = MetadataSidecar::load(backup)?;
+    let links = metadata.symlinks();
+    source_files.retain(|k, _| !links.contains(k));
+    backup_files.retain(|k, _| !links.contains(k));
+
+    if let Some(index) = &index {
+        // nei backup incrementali, i file invariati si trovano nei backup precedenti indicati dall'indice
+        let root = backup_root(backup, index);
+        for (key, entry) in &index.files {
+            backup_files.entry(key.clone()).or_insert_with(|| root.join(&entry.location).join(entry.stored_path(key)));
+        }
+    }
+
+    for (key, src_file) in &source_files {
+        report.checked += 1;
+        let backup_file = match backup_files.get(key) {
+            Some(p) if p.is_file() => p,
+            _ => {
+                report.missing.push(key.clone());
+                continue;
+            }
+        };
+
+        let backup_paths = parts.get(key).cloned().unwrap_or_else(|| vec![backup_file.clone()]);
+        let src_metadata = src_file.metadata()?;
+        if src_metadata.len() != size_of_parts(&backup_paths)? {
+            report.size_mismatch.push(key.clone());
+            continue;
+        }
+
+        if full {
+            if hash_file(src_file)? != hash_parts(&backup_paths)? {
+                report.content_mismatch.push(key.clone());
+            }
+        } else if let Some(entry) = index.as_ref().and_then(|i| i.files.get(key)) {
+            if entry.mtime != modified_nanos(&src_metadata) { // il file sorgente è stato modificato dopo il backup
+                report.content_mismatch.push(key.clone());
+            }
+        }
+    }
+
+    report.extra = backup_files.keys().filter(|k| !source_files.contains_key(*k)).cloned().collect();
+
+    Ok(report)
+}
+
+/***
+verify_archive: funzione per confrontare un backup in formato "tar.zst" o "zip" con la sorgente
+    source_files: file della sorgente (percorso relativo -> percorso del file)
+    archived: file contenuti nell'archivio (percorso -> dimensione e, se full, hash BLAKE3)
+    index: indice del backup, se presente
+    full: se true confronta anche il contenuto dei file tramite hash BLAKE3
+    -> restituisce: il resoconto delle differenze trovate
+***/
+fn verify_archive(source_files: &BTreeMap<String, PathBuf>, archived: BTreeMap<String, (u64, Option<String>)>, index: Option<&BackupIndex>, full: bool) -> io::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+
+    for (key, src_file) in source_files {
+        report.checked += 1;
+        let (size, hash) = match archived.get(key) {
+            Some(entry) => entry,
+            None => {
+                report.missing.push(key.clone());
+                continue;
+            }
+        };
+
+        let src_metadata = src_file.metadata()?;
+        if src_metadata.len() != *size {
+            report.size_mismatch.push(key.clone());
+            continue;
+        }
+
+        if full {
+            if hash.as_deref() != Some(hash_file(src_file)?.as_str()) {
+                report.content_mismatch.push(key.clone());
+            }
+        } else if let Some(entry) = index.and_then(|i| i.files.get(key)) {
+            if entry.mtime != modified_nanos(&src_metadata) {
+                report.content_mismatch.push(key.clone());
+            }
+        }
+    }
+
+    report.extra = archived.keys().filter(|k| !source_files.contains_key(*k)).cloned().collect();
+
+    Ok(report)
+}
+
+/***
+backup_root: funzione per ricavare la radice del disco esterno a cui sono relativi i percorsi "location" dell'indice
+    backup: Path della cartella del backup
+    index: indice del backup
+***/
+pub fn backup_root(backup: &Path, index: &BackupIndex) -> PathBuf {
+    let locations: HashSet<&String> = index.files.values().map(|e| &e.location).collect();
+    for ancestor in backup.ancestors().skip(1) {
+        if locations.contains(&relative_key(backup, ancestor)) {
+            return ancestor.to_path_buf();
+        }
+    }
+    backup.parent().unwrap_or(backup).to_path_buf()
+}
+
+pub fn is_tool_file(path: &Path, backup: &Path) -> bool {
+    path.parent() == Some(backup) && path.file_name().is_some_and(|n| TOOL_FILES.iter().any(|t| n == *t))
+}
+
+/***
+walk_files: funzione per elencare ricorsivamente i file di una cartella (i file speciali, come le named pipe, non vengono elencati)
+    path: cartella corrente
+    root: cartella radice (per calcolare i percorsi relativi)
+    files: mappa percorso relativo -> percorso del file, aggiornata dalla funzione
+    filter: i file per cui restituisce false vengono ignorati
+***/
+pub fn walk_files(path: &Path, root: &Path, files: &mut BTreeMap<String, PathBuf>, filter: &dyn Fn(&Path) -> bool) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        if entry_path.is_dir() {
+            walk_files(&entry_path, root, files, filter)?;
+        } else if fs::metadata(&entry_path).is_ok_and(|m| SpecialKind::detect(&m.file_type()).is_some()) {
+            continue;
+        } else if filter(&entry_path) {
+            files.insert(relative_key(&entry_path, root), entry_path);
+        }
+    }
+    Ok(())
+}
+
+/***
+verify_command: funzione che esegue il comando "verify" da riga di comando
+    uso: progetto_rust verify [<cartella del backup>] [--full]
+    se la cartella del backup non è indicata, viene verificato il backup più recente della sorgente presente sul disco esterno
+    conf_file: percorso del file di configurazione (sorgente ed estensioni)
+    args: argomenti successivi a "verify"
+    -> restituisce: il codice di uscita del programma (0 se il backup corrisponde alla sorgente, 1 se ci sono differenze, 2 in caso di errore)
+***/
+pub fn verify_command(conf_file: &str, args: &[String]) -> i32 {
+    let full = args.iter().any(|a| a == "--full");
+    let backup_arg = args.iter().find(|a| !a.starts_with("--"));
+
+    let src = match get_src_path(conf_file) {
+        Some(s) => s,
+        None => return 2,
+    };
+    let extensions = match get_extensions(conf_file) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Impossibile leggere le estensioni dal file di configurazione: {}", e);
+            return 2;
+        }
+    };
+
+    let backup = match backup_arg {
+        Some(b) => PathBuf::from(b),
+        None => match get_usb_path().and_then(|usb| find_previous_backup(Path::new(&usb), &src)) {
+            Some((path, _)) => path,
+            None => {
+                println!("Nessun backup da verificare trovato.");
+                return 2;
+            }
+        },
+    };
+
+    if is_encrypted(&backup) {
+        println!("Il backup '{}' è cifrato: per controllarlo, ripristinarlo con il comando 'decrypt' e confrontare la cartella ottenuta con la sorgente.", backup.to_string_lossy());
+        return 2;
+    }
+
+    println!("Verifica ({}) del backup '{}' rispetto a '{}'.", if full { "completa" } else { "veloce" }, backup.to_string_lossy(), src);
+    match verify_backup(Path::new(&src), &backup, &extensions, full) {
+        Ok(report) => {
+            report.print();
+            if report.is_ok() { 0 } else { 1 }
+        }
+        Err(e) => {
+            println!("Errore durante la verifica: {}", e);
+            2
+        }
+    }
+}
@@ -1,10 +1,10 @@
+use std::collections::{BTreeSet, HashMap, btree_map::Entry};
 use std::fs::{File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
 use std::{fs, io, thread};
 use std::io::Write;
-use std::time::Duration;
-use copy_dir::copy_dir;
+use std::time::{Duration, Instant};
 use cpu_time::ProcessTime;
 use device_query::{DeviceQuery, DeviceState, MouseState};
 use fs_extra::dir::get_size;
@@ -14,11 +14,28 @@ use rodio::{OutputStream, Sink, Source};
 use rodio::source::SineWave;
 use sysinfo::System;
 use crate::MainThreadMessage;
+use crate::archive::{max_file_size, ArchiveWriter, TarZstWriter, ZipVolumes, ARCHIVE_FILE};
+use crate::bandwidth::{self, IdleIo};
+use crate::copy_pool::{auto_workers, CopyPool};
+use crate::cpu_governor::{self, throttle, Phase};
+use crate::destination;
+use crate::encryption::BackupCipher;
+use crate::failures::CopyFailures;
+use crate::fast_copy::{self, CopyStrategies, CopyStrategy};
+use crate::manifest::{self, Manifest};
+use crate::metadata::{copy_metadata, copy_symlink, MetadataSidecar, METADATA_FILE};
+use crate::parity;
+use crate::priority::PriorityRules;
+use crate::salvage;
+use crate::settings::{BackupSettings, CollisionPolicy, OutputFormat};
+use crate::space_budget::{check_space, fit_to_space};
+use crate::time_budget::TimeBudget;
 
 /**
  * Funzione che rileva se viene disegnato il comando di backup e il relativo comando di conferma
  * Se questi comandi sono disegnati, effettua il backup
- * Inoltre, ogni 2 minuti stampa un file di log contenente i consumi di CPU
+ * Inoltre, ogni 2 minuti stampa un file di log contenente i consumi di CPU. Il consumo dell'applicazione resta entro i limiti
+ * cpu_limit_idle (mentre aspetta il comando) e cpu_limit_backup (durante il backup) delle impostazioni (vedi cpu_governor)
  *
  * @param tx:       invia un messaggio al thread principale chiedendo di aprire una determinata finestra della GUI
  * @param tx_close: invia un messaggio al thread principale chiedendo di chiudere una finestra della GUI
@@ -26,9 +43,11 @@ use crate::MainThreadMessage;
  *                  options[0]: contiene il tipo di backup (tutta la cartella o solo singoli file)
  *                  options[1]: contiene il percorso della cartella sorgente del backup
  *                  options[2]: contiene il percorso della cartella destinazione del backup
+ * @param settings: impostazioni avanzate del backup lette dal file di configurazione
  */
-pub fn start_backup(tx: Sender<MainThreadMessage>, tx_close: Sender<()>, options: Vec<String>) {
+pub fn start_backup(tx: Sender<MainThreadMessage>, tx_close: Sender<()>, options: Vec<String>, settings: BackupSettings) {
     let device_state = DeviceState::new();
+    cpu_governor::configure(settings.cpu_limit_idle, settings.cpu_limit_backup);
 
     //Vettore di 4 elementi che rappresentano i lati di un rettangolo. Se il primo elemento è V (lato verticale), il secondo deve essere H (lato orizzontale), poi V e infine H. Altrimenti, si potrebbe avere H, V, H, V
     let mut sides: Vec<char> = Vec::with_capacity(4);
@@ -50,6 +69,10 @@ pub fn start_backup(tx: Sender<MainThreadMessage>, tx_close: Sender<()>, options
             for i in 0..sys.cpus().len() {
                 log_file.write(("CPU ".to_owned() + &*i.to_string() + ": " + &*sys.cpus()[i].cpu_usage().to_string() + "%\n").as_bytes()).expect("Scrittura log fallita");
             }
+            //Limiti di CPU dell'applicazione e consumo medio ottenuto in ogni fase
+            for line in cpu_governor::report() {
+                log_file.write_all((line + "\n").as_bytes()).expect("Scrittura log fallita");
+            }
             log_file.write("\n".as_bytes()).expect("Scrittura log fallita");
             thread::sleep(Duration::from_secs(120));
         }
@@ -132,35 +155,39 @@ pub fn start_backup(tx: Sender<MainThreadMessage>, tx_close: Sender<()>, options
                                         sound_played = false;
 
                                         if Path::new(&options[1]).exists() {
-                                            //Effettuo il backup. Per prima cosa, elimino la cartella di destinazione (la funzione copy_dir ritorna errore se la cartella di destinazione esiste)
-                                            if Path::new(&options[2]).exists() {
-                                                fs::remove_dir_all(&options[2]).expect("Non sono riuscito a rimuovere la cartella");
-
-                                                println!("Cartella rimossa");
-                                            }
-                                            let start_backup = ProcessTime::try_now().expect("Non sono riuscito ad ottenere il tempo del backup");
-
-                                            if options[0] == "F" {
-                                                //Effettuo il backup di un'intera cartella
-                                                copy_dir(&options[1], &options[2]).expect("Backup fallito");
-                                            } else {
-                                                //In options[0] ho un elenco di tipi di file separati da virgola (,). Li estraggo e li inserisco in un vettore. Poi, richiamo la funzione copy_files che effettua il backup di tali file
-                                                let ext: Vec<&str> = options[0].split(',').collect();
-                                                if let Err(e) = copy_files(&options[1], &options[2], &ext) {
-                                                    eprintln!("Error copying files: {}", e);
+                                            cpu_governor::set_phase(Phase::Backup);
+                                            let result = run_backup(&options, &settings);
+                                            cpu_governor::set_phase(Phase::Listening);
+                                            match result {
+                                                Ok((failures, saved)) if !failures.not_saved.is_empty() || !failures.excluded.is_empty() => {
+                                                    //Il tempo a disposizione è terminato prima della copia di tutti i file (o alcuni file sono stati esclusi per lo spazio insufficiente): ai tre beep aggiungo due beep lunghi
+                                                    play_sound(200);
+                                                    play_sound(200);
+                                                    play_sound(200);
+                                                    play_sound(1000);
+                                                    play_sound(1000);
+                                                    let kept = saved != Path::new(&options[2]);  //Backup parziale salvato accanto al backup completo precedente
+                                                    tx.send(MainThreadMessage::ShowBackupPartialMessage(failures.not_saved.len(), failures.excluded.iter().map(|(path, _)| path.clone()).collect(), saved, kept)).unwrap();
+                                                }
+                                                Ok((failures, _)) if failures.is_empty() => {
+                                                    play_sound(200);
+                                                    play_sound(200);
+                                                    play_sound(200);
+                                                    tx.send(MainThreadMessage::ShowBackupCompleteMessage).unwrap();
+                                                }
+                                                Ok((failures, _)) => {
+                                                    //Il backup è stato salvato, ma alcuni file non sono stati copiati: ai tre beep aggiungo un beep lungo
+                                                    play_sound(200);
+                                                    play_sound(200);
+                                                    play_sound(200);
+                                                    play_sound(1000);
+                                                    tx.send(MainThreadMessage::ShowBackupCompleteWithErrorsMessage(failures.len())).unwrap();
+                                                }
+                                                Err(e) => {
+                                                    eprintln!("Backup fallito: {}", e);
+                                                    tx.send(MainThreadMessage::ShowBackupErrorMessage).unwrap();
                                                 }
                                             }
-
-                                            let cpu_time: Duration = start_backup.try_elapsed().expect("Non sono riuscito ad ottenere il tempo del backup");
-
-                                            play_sound(200);
-                                            play_sound(200);
-                                            play_sound(200);
-                                            tx.send(MainThreadMessage::ShowBackupCompleteMessage).unwrap();
-
-                                            let mut backup_log = File::create(options[2].to_owned() + "/backup_log.txt").unwrap();
-                                            backup_log.write((get_size(&options[2]).unwrap().to_string() + " bytes\n").as_bytes()).expect("Scrittura file fallita");
-                                            backup_log.write((cpu_time.as_millis().to_string() + " millis\n").as_bytes()).expect("Scrittura file fallita");
                                         } else {
                                             //Il percorso di sorgente non esiste, ritorno un errore
                                             tx.send(MainThreadMessage::ShowBackupErrorMessage).unwrap();
@@ -181,13 +208,370 @@ pub fn start_backup(tx: Sender<MainThreadMessage>, tx_close: Sender<()>, options
                 }
             }
 
-            //Per ridurre il consumo di CPU, faccio una sleep di 50ms durante il loop
+            //Per ridurre il consumo di CPU, faccio una sleep di 50ms durante il loop (più lunga se l'applicazione supera il limite di CPU)
             thread::sleep(Duration::from_millis(50));
+            throttle();
         }
     });
 }
 
 
+/**
+ * Esegue il backup. Il nuovo backup viene costruito in una cartella di staging accanto alla destinazione e,
+ * solo se la copia va a buon fine, prende il posto del backup precedente. In caso di errore il backup precedente resta intatto.
+ * Una destinazione esistente viene sostituita solo se è stata creata da questo programma (vedi destination.rs).
+ * I file che non è possibile leggere (permessi, errori di I/O, file eliminati durante il backup) non interrompono il backup:
+ * vengono saltati ed elencati nel log del backup. Un backup parziale (tempo a disposizione terminato o file esclusi con best_fit)
+ * non prende il posto di un backup completo, ma viene salvato accanto alla destinazione (vedi destination::commit_partial)
+ *
+ * @param options:  opzioni del backup (vedi start_backup)
+ * @param settings: impostazioni avanzate del backup
+ * @return          i file che non è stato possibile copiare e la cartella in cui è stato salvato il backup
+ */
+fn run_backup(options: &[String], settings: &BackupSettings) -> io::Result<(CopyFailures, PathBuf)> {
+    let dest = Path::new(&options[2]);
+    let staging = destination::prepare_staging(dest)?;
+
+    let failures = match fill_staging(options, settings, &staging) {
+        Ok(failures) => failures,
+        Err(e) => {
+            if let Err(e) = destination::discard_staging(&staging) {
+                eprintln!("Non sono riuscito a rimuovere la cartella di staging: {}", e);
+            }
+            return Err(e);
+        }
+    };
+
+    let saved = if !failures.not_saved.is_empty() || !failures.excluded.is_empty() {
+        destination::commit_partial(&staging, dest)?
+    } else {
+        destination::commit_staging(&staging, dest)?;
+        dest.to_path_buf()
+    };
+    if !failures.not_saved.is_empty() {
+        println!("Backup parziale salvato in {:?} allo scadere del tempo a disposizione: {} file non salvati (elenco in backup_log.txt)", saved, failures.not_saved.len());
+    } else if !failures.excluded.is_empty() {
+        println!("Backup parziale salvato in {:?} senza {} file che non ci stavano nella destinazione (elenco in backup_log.txt)", saved, failures.excluded.len());
+    } else if failures.is_empty() {
+        println!("Backup salvato in {:?}", dest);
+    } else {
+        println!("Backup salvato in {:?} con {} errori (elenco in backup_log.txt)", dest, failures.len());
+    }
+    if saved != dest {
+        println!("Il backup completo precedente in {:?} è stato mantenuto", dest);
+    }
+    Ok((failures, saved))
+}
+
+//Copia i file nella cartella di staging (o li scrive nell'archivio, se format=tar.zst o zip, e li cifra, se è configurata una chiave) e scrive il file di log del backup.
+//Ritorna i file che non è stato possibile copiare
+fn fill_staging(options: &[String], settings: &BackupSettings, staging: &Path) -> io::Result<CopyFailures> {
+    let start_backup = ProcessTime::try_now().expect("Non sono riuscito ad ottenere il tempo del backup");
+    let start_copy = Instant::now();
+
+    //Limito la banda del backup e, su Linux, lo faccio accedere al disco solo quando nessun altro processo lo usa:
+    //i worker della copia, creati dopo, ereditano la priorità di I/O di questo thread
+    bandwidth::configure(settings.bandwidth_limit, settings.bandwidth_burst);
+    let idle_io = match settings.idle_io {
+        true => IdleIo::enter().inspect_err(|e| eprintln!("Non sono riuscito a impostare la priorità di I/O idle: {}", e)).ok(),
+        false => None,
+    };
+    //Con un tempo a disposizione, i file che non possono essere copiati entro la scadenza non vengono salvati.
+    //Nel formato directory senza cifratura l'hash dei file viene calcolato dopo la copia, rileggendo quelli modificati (vedi manifest::build_manifest)
+    let passes = if settings.format == OutputFormat::Directory && !settings.encryption.is_enabled() { 2 } else { 1 };
+    let mut budget = settings.time_limit.map(|limit| TimeBudget::new(limit, passes));
+
+    //In options[0] ho un elenco di tipi di file separati da virgola (,). Li estraggo e li inserisco in un vettore
+    let ext: Vec<&str> = options[0].split(',').collect();
+
+    //File che non è stato possibile copiare: il backup prosegue con gli altri
+    let mut failures = CopyFailures { best_fit: settings.best_fit, ..Default::default() };
+
+    //Metadati che la destinazione non conserva, registrati solo se preserve_metadata è attivo
+    let mut metadata = if settings.preserve_metadata { Some(MetadataSidecar::default()) } else { None };
+
+    //Se il backup deve essere cifrato, genero la chiave del backup prima di scrivere i file
+    let mut cipher = if settings.encryption.is_enabled() {
+        println!("The backup will be encrypted");
+        Some(BackupCipher::create(staging, &settings.encryption)?)
+    } else {
+        None
+    };
+
+    //Numero di file copiati in parallelo, descrizione dei dischi e strategie di copia usate, riportati nel log (solo nel formato directory senza cifratura)
+    let (mut workers, mut disks) = (1, String::new());
+    let mut strategies = CopyStrategies::default();
+
+    let mut manifest = if settings.format != OutputFormat::Directory {
+        //Scrivo tutti i file selezionati in un archivio: su una chiavetta FAT è molto più veloce che copiare migliaia di file piccoli
+        let mut files = if options[0] == "F" {
+            list_folder_files(Path::new(&options[1]), &mut failures)?
+        } else {
+            plan_files(&options[1], &ext, settings, &mut failures)?
+        };
+        fit_to_space(&mut files, Path::new(&options[1]), staging, settings, &mut failures)?;
+        let archive = if settings.format == OutputFormat::TarZst {
+            //Se il file system della destinazione non ammette file oltre una certa dimensione (FAT32), divido l'archivio in parti
+            ArchiveWriter::TarZst(Box::new(TarZstWriter::create(&staging.join(ARCHIVE_FILE), settings.compression_level, &settings.store_extensions, cipher.as_ref(), max_file_size(staging))?))
+        } else {
+            //Se il file system della destinazione non ammette file oltre una certa dimensione (FAT32), divido l'archivio in volumi
+            let volume_size = match (settings.volume_size, max_file_size(staging)) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            ArchiveWriter::Zip(Box::new(ZipVolumes::create(staging, volume_size, &settings.store_extensions)?))
+        };
+        write_archive(archive, &files, options, settings, budget.as_mut(), &mut failures)?
+    } else if let Some(cipher) = cipher.as_mut() {
+        //Cifro i file uno per uno nella cartella di staging
+        let mut files = if options[0] == "F" {
+            list_folder_files(Path::new(&options[1]), &mut failures)?
+        } else {
+            plan_files(&options[1], &ext, settings, &mut failures)?
+        };
+        fit_to_space(&mut files, Path::new(&options[1]), staging, settings, &mut failures)?;
+        encrypt_files(cipher, &files, staging, options, &settings.priority, budget.as_mut(), &mut failures)?
+    } else {
+        //I file vengono copiati da più worker in parallelo: molti per una sorgente SSD/NVMe, pochi per un disco rotativo o una destinazione USB
+        (workers, disks) = match settings.workers {
+            Some(n) => (n, "workers setting".to_string()),
+            None if !settings.salvage => auto_workers(Path::new(&options[1]), staging),
+            None => (1, String::new()),
+        };
+        if workers > 1 {
+            println!("Parallel copy with {} workers ({})", workers, disks);
+        }
+
+        let mut queue = CopyQueue::new(workers, budget.take());
+
+        //Tengo traccia dei file copiati (destinazione -> sorgente) per scrivere il manifest del backup
+        let mut copied = if options[0] == "F" && !settings.priority.is_enabled() {
+            //Effettuo il backup di un'intera cartella, se c'è spazio sufficiente nella destinazione
+            check_space(get_size(&options[1]).unwrap_or(0), staging)?;
+            copy_folder(Path::new(&options[1]), staging, &mut queue, metadata.as_mut(), &mut failures)?;
+            manifest::list_copied_folder(staging, Path::new(&options[1]))?
+        } else {
+            //Con l'impostazione priority (o in modalità salvataggio) anche una cartella intera viene copiata a partire dall'elenco dei file, ordinato secondo le regole
+            let mut files = if options[0] == "F" {
+                list_folder_files(Path::new(&options[1]), &mut failures)?
+            } else {
+                plan_files(&options[1], &ext, settings, &mut failures)?
+            };
+            fit_to_space(&mut files, Path::new(&options[1]), staging, settings, &mut failures)?;
+            //Richiamo la funzione copy_files che effettua il backup dei file selezionati
+            copy_files(&options[1], &staging.to_string_lossy(), files, settings, &mut queue, metadata.as_mut(), &mut failures)?
+        };
+        strategies = queue.strategies;
+        budget = queue.budget;
+        //I collegamenti simbolici (e il contenuto delle cartelle collegate) non compaiono nel manifest
+        if let Some(metadata) = &metadata {
+            let links = metadata.symlinks();
+            copied.retain(|dest_path, _| !links.contains(&manifest::relative_path(dest_path, staging)));
+        }
+        manifest::build_manifest(staging, &copied, options)?
+    };
+
+    let cpu_time: Duration = start_backup.try_elapsed().expect("Non sono riuscito ad ottenere il tempo del backup");
+    let copy_time = start_copy.elapsed();
+    let io_priority = if idle_io.is_some() { "idle" } else { "normal (idle not available)" };
+    let size = get_size(staging).map_err(|e| io::Error::other(e.to_string()))?;
+
+    if let Some(cipher) = &cipher {
+        cipher.finish()?;
+    }
+    if !failures.not_saved.is_empty() || !failures.excluded.is_empty() {
+        manifest.set_partial(&failures.not_saved, &failures.excluded, Path::new(&options[1]));
+    }
+    manifest.save(staging, cipher.as_ref())?;
+    if let Some(metadata) = &metadata {
+        metadata.save(staging)?;
+    }
+
+    let mut backup_log = File::create(staging.join("backup_log.txt"))?;
+    backup_log.write_all((size.to_string() + " bytes\n").as_bytes())?;
+    backup_log.write_all((cpu_time.as_millis().to_string() + " millis\n").as_bytes())?;
+    backup_log.write_all(format!("Manifest: {} ({} file, {} bytes, {})\n", manifest::MANIFEST_FILE, manifest.total_files, manifest.total_size, manifest.hash_algorithm).as_bytes())?;
+    if let Some(metadata) = metadata.as_ref().filter(|m| !m.files.is_empty()) {
+        backup_log.write_all(format!("Metadata: {} ({} entries, {} symbolic links)\n", METADATA_FILE, metadata.files.len(), metadata.symlink_count()).as_bytes())?;
+    }
+    if workers > 1 {
+        backup_log.write_all(format!("Parallel copy: {} workers ({})\n", workers, disks).as_bytes())?;
+    }
+    backup_log.write_all(format!("{}\n", bandwidth::report(manifest.total_size, copy_time)).as_bytes())?;
+    if settings.idle_io {
+        backup_log.write_all(format!("I/O priority: {}\n", io_priority).as_bytes())?;
+    }
+    if let Some(budget) = &budget {
+        backup_log.write_all(format!("{}\n", budget.report()).as_bytes())?;
+    }
+    if let Some(line) = strategies.log_line() {
+        println!("{}", line);
+        backup_log.write_all(format!("{}\n", line).as_bytes())?;
+    }
+    failures.write_log(&mut backup_log)?;
+    drop(backup_log);
+
+    //I dati di parità vengono calcolati per ultimi, sui file scritti nella cartella di staging (compresi manifest e log)
+    if settings.parity > 0 {
+        println!("Computing parity data ({}% redundancy)", settings.parity);
+        let size = parity::create_parity(staging, settings.parity)?;
+        println!("{} bytes of parity data written", size);
+    }
+    Ok(failures)
+}
+
+//File copiati dai worker, con la strategia usata per ognuno
+type FilePool = CopyPool<PendingCopy, CopyStrategy>;
+
+/**
+ * File (o cartella) di cui registrare la copia al termine: con preserve_metadata ne vengono copiati i metadati, in caso di errore
+ * la copia parziale viene eliminata e l'errore registrato
+ */
+struct PendingCopy {
+    path: PathBuf,                          //Percorso sorgente
+    dest_path: PathBuf,                     //Percorso della copia
+    key: String,                            //Percorso relativo con cui vengono registrati i metadati
+    src_metadata: Option<fs::Metadata>,     //Metadati letti prima della copia (che può modificare la data di ultimo accesso), solo con preserve_metadata
+    size: u64,                              //Dimensione del file, per stimare il tempo della copia (vedi time_budget); 0 per le cartelle e nella copia di una cartella intera
+}
+
+/**
+ * Copie dei file di un backup. Ogni file viene copiato con la strategia più veloce disponibile (vedi fast_copy::copy_file),
+ * da uno dei worker se ce ne sono più di uno, altrimenti dal thread del backup
+ */
+struct CopyQueue {
+    pool: Option<FilePool>,
+    folders: Vec<PendingCopy>,      //Cartelle di cui copiare i metadati dopo aver copiato il loro contenuto
+    strategies: CopyStrategies,     //Numero di file copiati con ogni strategia
+    budget: Option<TimeBudget>,     //Tempo a disposizione del backup, se c'è un limite
+}
+
+impl CopyQueue {
+    fn new(workers: usize, budget: Option<TimeBudget>) -> Self {
+        CopyQueue { pool: if workers > 1 { Some(FilePool::new(workers)) } else { None }, folders: Vec::new(), strategies: CopyStrategies::default(), budget }
+    }
+
+    //Copia un file e ritorna le copie terminate: quella del file stesso se non ci sono worker, altrimenti quelle concluse nel frattempo dai worker
+    fn copy(&mut self, copy: PendingCopy) -> Vec<(PendingCopy, io::Result<()>)> {
+        let completed = match self.pool.as_mut() {
+            Some(pool) => {
+                let (src, dst) = (copy.path.clone(), copy.dest_path.clone());
+                pool.submit(copy, move || fast_copy::copy_file(&src, &dst))
+            }
+            None => {
+                let result = fast_copy::copy_file(&copy.path, &copy.dest_path);
+                vec![(copy, result)]
+            }
+        };
+        self.count(completed)
+    }
+
+    //Aspetta la fine delle copie dei worker e ritorna quelle non ancora restituite da copy
+    fn finish(&mut self) -> Vec<(PendingCopy, io::Result<()>)> {
+        match self.pool.take() {
+            Some(pool) => {
+                let completed = pool.finish();
+                self.count(completed)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn count(&mut self, completed: Vec<(PendingCopy, io::Result<CopyStrategy>)>) -> Vec<(PendingCopy, io::Result<()>)> {
+        completed.into_iter().map(|(copy, result)| {
+            if let Ok(strategy) = result {
+                self.strategies.add(strategy);
+            }
+            (copy, result.map(|_| ()))
+        }).collect()
+    }
+}
+
+//Copia il contenuto di una cartella (vedi copy_tree)
+fn copy_folder(root: &Path, dest: &Path, queue: &mut CopyQueue, mut metadata: Option<&mut MetadataSidecar>, failures: &mut CopyFailures) -> io::Result<()> {
+    copy_tree(root, root, dest, metadata.as_deref_mut(), failures, queue)?;
+    for (file, result) in queue.finish() {
+        finish_copy(file, result, metadata.as_deref_mut(), failures)?;
+    }
+    //Le date delle cartelle vengono copiate per ultime (dalla più interna), perché la copia dei file al loro interno le modifica
+    for folder in std::mem::take(&mut queue.folders) {
+        finish_copy(folder, Ok(()), metadata.as_deref_mut(), failures)?;
+    }
+    Ok(())
+}
+
+//Copia ricorsivamente il contenuto di una cartella e copia i collegamenti simbolici come collegamenti. Con preserve_metadata conserva anche i metadati di file e sottocartelle.
+//I file vengono copiati tramite queue, dai worker se presenti, mentre la visita prosegue; le cartelle vengono aggiunte a queue.folders, per copiarne i metadati al termine.
+//Se un file o una cartella non può essere copiato, l'errore viene registrato in failures e la copia prosegue con gli altri
+fn copy_tree(root: &Path, dir: &Path, dest: &Path, mut metadata: Option<&mut MetadataSidecar>, failures: &mut CopyFailures, queue: &mut CopyQueue) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        //Pausa se il backup supera il limite di CPU (vedi cpu_governor)
+        throttle();
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                failures.record(dir, e)?;
+                continue;
+            }
+        };
+        let path = entry.path();
+        let target = dest.join(entry.file_name());
+
+        if let Err(e) = copy_entry(root, &path, &target, metadata.as_deref_mut(), failures, queue) {
+            failures.record(&path, e)?;
+        }
+    }
+    Ok(())
+}
+
+//Copia un elemento della cartella sorgente (file, cartella o collegamento simbolico)
+fn copy_entry(root: &Path, path: &Path, target: &Path, mut metadata: Option<&mut MetadataSidecar>, failures: &mut CopyFailures, queue: &mut CopyQueue) -> io::Result<()> {
+    let file_type = fs::symlink_metadata(path)?.file_type();
+    let key = manifest::relative_path(path, root);
+    if file_type.is_symlink() {
+        let record = copy_symlink(path, target)?;
+        if let Some(metadata) = metadata.filter(|_| !record.is_empty()) {
+            metadata.files.insert(key, record);
+        }
+        return Ok(());
+    }
+
+    let src_metadata = if metadata.is_some() { Some(path.metadata()?) } else { None };
+    let copy = PendingCopy { path: path.to_path_buf(), dest_path: target.to_path_buf(), key, src_metadata, size: 0 };
+    if file_type.is_dir() {
+        fs::create_dir_all(target)?;
+        copy_tree(root, path, target, metadata.as_deref_mut(), failures, queue)?;
+        if metadata.is_some() {
+            queue.folders.push(copy);
+        }
+        return Ok(());
+    }
+    for (copy, result) in queue.copy(copy) {
+        finish_copy(copy, result, metadata.as_deref_mut(), failures)?;
+    }
+    Ok(())
+}
+
+//Registra il risultato della copia di un file o di una cartella: con preserve_metadata ne copia i metadati (dopo la scrittura del contenuto),
+//in caso di errore elimina la copia parziale e registra l'errore in failures. Ritorna true se la copia è andata a buon fine
+fn finish_copy(copy: PendingCopy, result: io::Result<()>, metadata: Option<&mut MetadataSidecar>, failures: &mut CopyFailures) -> io::Result<bool> {
+    if let Err(e) = result {
+        //Non lascio nel backup la copia parziale di un file
+        if copy.dest_path.is_file() {
+            let _ = fs::remove_file(&copy.dest_path);
+        }
+        failures.record(&copy.path, e)?;
+        return Ok(false);
+    }
+    if let (Some(sidecar), Some(src_metadata)) = (metadata, &copy.src_metadata) {
+        let record = copy_metadata(&copy.path, src_metadata, &copy.dest_path);
+        if !record.is_empty() {
+            sidecar.files.insert(copy.key, record);
+        }
+    }
+    Ok(true)
+}
+
 fn is_vertical(start: (i32, i32), end: (i32, i32)) -> bool {
     start.0 >= end.0-50 && start.0 <= end.0+50
 }
@@ -209,29 +593,460 @@ fn play_sound(dur: u64) {
     sink.sleep_until_end();
 }
 
-fn copy_files(src: &str, dest: &str, extensions: &[&str]) -> io::Result<()> {
+/**
+ * Copia i file indicati dalla cartella sorgente alla cartella destinazione, secondo quanto calcolato da plan_files (o da list_folder_files).
+ * In modalità salvataggio i file piccoli e i documenti vengono copiati per primi. Con un tempo a disposizione, i file la cui copia
+ * non terminerebbe entro la scadenza vengono registrati in failures.not_saved
+ *
+ * @param src:        percorso della cartella sorgente
+ * @param dest:       percorso della cartella destinazione
+ * @param files:      file da copiare (percorso relativo di destinazione -> percorso sorgente)
+ * @param settings:   impostazioni avanzate del backup
+ * @param queue:      copie dei file, eseguite dai worker se ce ne sono più di uno (con il tempo a disposizione del backup)
+ * @param metadata:   se presente, i metadati dei file vengono conservati e quelli che la destinazione non può rappresentare vengono registrati qui
+ * @param failures:   file che non è stato possibile copiare (la copia prosegue con gli altri)
+ * @return            i file copiati (percorso di destinazione -> percorso sorgente)
+ */
+fn copy_files(src: &str, dest: &str, files: HashMap<PathBuf, PathBuf>, settings: &BackupSettings, queue: &mut CopyQueue, mut metadata: Option<&mut MetadataSidecar>, failures: &mut CopyFailures) -> io::Result<HashMap<PathBuf, PathBuf>> {
     fs::create_dir_all(dest)?;
 
+    let mut files: Vec<(PathBuf, PathBuf)> = files.into_iter().collect();
+    //I file più utili (vedi PriorityRules) vengono copiati per primi, così che un backup interrotto li contenga
+    if settings.priority.is_enabled() {
+        settings.priority.order(&mut files, Path::new(src));
+    }
+
+    let mut copied: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut folders: BTreeSet<PathBuf> = BTreeSet::new();
+    for (relative, path) in files {
+        throttle();
+        let dest_path = Path::new(dest).join(&relative);
+        //glob entra anche nelle cartelle collegate: registro il collegamento alla cartella, invece di copiarne il contenuto
+        if let Some(sidecar) = metadata.as_deref_mut().filter(|_| !settings.flatten) {
+            if let Some(link) = linked_folder(Path::new(src), &relative) {
+                if let Entry::Vacant(entry) = sidecar.files.entry(manifest::relative_path(&link, Path::new(""))) {
+                    if let Some(parent) = Path::new(dest).join(&link).parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    match copy_symlink(&Path::new(src).join(&link), &Path::new(dest).join(&link)) {
+                        Ok(record) => {
+                            entry.insert(record);
+                            println!("Copied link {:?} to {:?}", Path::new(src).join(&link), Path::new(dest).join(&link));
+                        }
+                        Err(e) => failures.record(&Path::new(src).join(&link), e)?,
+                    }
+                }
+                folders.extend(link.ancestors().skip(1).filter(|a| !a.as_os_str().is_empty()).map(Path::to_path_buf));
+                continue;
+            }
+        }
+        let size = path.metadata().map(|m| m.len()).unwrap_or(0);
+        if queue.budget.as_mut().is_some_and(|b| !b.try_start(size)) {
+            failures.not_saved.push((path, size));
+            continue;
+        }
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        folders.extend(relative.ancestors().skip(1).filter(|a| !a.as_os_str().is_empty()).map(Path::to_path_buf));
+
+        let key = manifest::relative_path(&dest_path, Path::new(dest));
+        //In modalità flatten i collegamenti vengono seguiti: senza le sottocartelle, un collegamento relativo non punterebbe più al file giusto
+        if let Some(sidecar) = metadata.as_deref_mut().filter(|_| !settings.flatten && path.is_symlink()) {
+            match copy_symlink(&path, &dest_path) {
+                Ok(record) => {
+                    sidecar.files.insert(key, record);
+                    println!("Copied link {:?} to {:?}", path, dest_path);
+                }
+                Err(e) => failures.record(&path, e)?,
+            }
+            continue;
+        }
+        let src_metadata = match metadata.as_ref().map(|_| path.metadata()).transpose() {
+            Ok(m) => m,
+            Err(e) => {
+                failures.record(&path, e)?;
+                continue;
+            }
+        };
+        let copy = PendingCopy { path, dest_path, key, src_metadata, size };
+        let completed = if settings.salvage {
+            let result = copy_salvage(&copy.path, &copy.dest_path, failures);
+            vec![(copy, result)]
+        } else {
+            queue.copy(copy)
+        };
+        for (copy, result) in completed {
+            if let Some(budget) = queue.budget.as_mut() {
+                budget.finish(copy.size);
+            }
+            record_copied(copy, result, &mut copied, metadata.as_deref_mut(), failures)?;
+        }
+    }
+    for (copy, result) in queue.finish() {
+        if let Some(budget) = queue.budget.as_mut() {
+            budget.finish(copy.size);
+        }
+        record_copied(copy, result, &mut copied, metadata.as_deref_mut(), failures)?;
+    }
+
+    //Le date delle cartelle vengono copiate per ultime (dalla più interna), perché la copia dei file al loro interno le modifica
+    if let Some(sidecar) = metadata {
+        for folder in folders.iter().rev() {
+            let src_folder = Path::new(src).join(folder);
+            let src_metadata = match src_folder.metadata() {
+                Ok(m) => m,
+                Err(e) => {
+                    failures.record(&src_folder, e)?;
+                    continue;
+                }
+            };
+            let record = copy_metadata(&src_folder, &src_metadata, &Path::new(dest).join(folder));
+            if !record.is_empty() {
+                sidecar.files.insert(manifest::relative_path(folder, Path::new("")), record);
+            }
+        }
+    }
+
+    Ok(copied)
+}
+
+//Registra il risultato della copia di un file (vedi finish_copy) e, se è andata a buon fine, lo aggiunge ai file copiati (destinazione -> sorgente)
+fn record_copied(copy: PendingCopy, result: io::Result<()>, copied: &mut HashMap<PathBuf, PathBuf>, metadata: Option<&mut MetadataSidecar>, failures: &mut CopyFailures) -> io::Result<()> {
+    let (path, dest_path) = (copy.path.clone(), copy.dest_path.clone());
+    if finish_copy(copy, result, metadata, failures)? {
+        println!("Copied {:?} to {:?}", path, dest_path);
+        copied.insert(dest_path, path);
+    }
+    Ok(())
+}
+
+//Copia il contenuto di un file in modalità salvataggio: le letture non riuscite vengono ripetute e le zone illeggibili riempite di zeri, registrandole in failures
+fn copy_salvage(path: &Path, dest_path: &Path, failures: &mut CopyFailures) -> io::Result<()> {
+    let lost = salvage::copy_file_salvage(path, dest_path)?;
+    if !lost.is_empty() {
+        failures.salvaged.push((path.to_path_buf(), lost));
+    }
+    Ok(())
+}
+
+//Ritorna la prima cartella (percorso relativo) che contiene il file ed è un collegamento simbolico, se c'è
+fn linked_folder(src: &Path, relative: &Path) -> Option<PathBuf> {
+    let mut ancestors: Vec<&Path> = relative.ancestors().skip(1).filter(|a| !a.as_os_str().is_empty()).collect();
+    ancestors.reverse();
+    ancestors.into_iter().find(|a| src.join(a).is_symlink()).map(Path::to_path_buf)
+}
+
+/**
+ * Calcola quali file con le estensioni indicate devono essere copiati e con quale percorso, relativo alla destinazione.
+ * Di default viene mantenuto il percorso relativo di ogni file, così che file con lo stesso nome in sottocartelle diverse non si sovrascrivano.
+ * Se settings.flatten è true, tutti i file finiscono nella radice della destinazione e i nomi duplicati vengono gestiti secondo settings.collision_policy
+ *
+ * @param src:        percorso della cartella sorgente
+ * @param extensions: estensioni dei file da copiare
+ * @param settings:   impostazioni avanzate del backup
+ * @param failures:   file e cartelle che non è stato possibile leggere
+ * @return            i file da copiare (percorso relativo di destinazione -> percorso sorgente)
+ */
+fn plan_files(src: &str, extensions: &[&str], settings: &BackupSettings, failures: &mut CopyFailures) -> io::Result<HashMap<PathBuf, PathBuf>> {
+    //Per ogni nome di destinazione già assegnato, tengo traccia del file sorgente da cui proviene. Serve per gestire i nomi duplicati in modalità flatten
+    let mut planned: HashMap<PathBuf, PathBuf> = HashMap::new();
+
     for ext in extensions {
         //Tramite Glob, trovo i file con l'estensione desiderata nella cartella sorgente
         let pattern = format!("{}/**/*.{}", src, ext);
 
-        //Itero i file trovati, e li copio nella cartella di destinazione
         for entry in glob(&pattern).expect("Failed to read glob pattern") {
             match entry {
                 Ok(path) => {
                     if path.is_file() {
-                        let file_name = path.file_name().unwrap();
-                        let dest_path = Path::new(dest).join(file_name);
+                        let relative = if settings.flatten {
+                            let file_name = Path::new(path.file_name().unwrap());
+                            match resolve_collision(&path, file_name, settings.collision_policy, &planned) {
+                                Ok(Some(p)) => p,
+                                Ok(None) => {
+                                    println!("Skipped {:?}: a file with the same name was already copied", path);
+                                    continue;
+                                }
+                                Err(e) => {
+                                    failures.record(&path, e)?;
+                                    continue;
+                                }
+                            }
+                        } else {
+                            //Mantengo il percorso relativo del file rispetto alla sorgente
+                            match path.strip_prefix(src) {
+                                Ok(r) => r.to_path_buf(),
+                                Err(_) => PathBuf::from(path.file_name().unwrap()),
+                            }
+                        };
 
-                        fs::copy(&path, &dest_path)?;
-                        println!("Copied {:?} to {:?}", path, dest_path);
+                        planned.insert(relative, path);
                     }
                 }
-                Err(e) => println!("{:?}", e),
+                Err(e) => {
+                    let path = e.path().to_path_buf();
+                    failures.record(&path, e.into())?;
+                }
             }
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(planned)
+}
+
+/**
+ * Decide con quale nome copiare un file in modalità flatten, quando lo stesso nome potrebbe essere già stato assegnato a un altro file.
+ * Ritorna None se il file non deve essere copiato
+ *
+ * @param src_path: percorso del file sorgente
+ * @param name:     nome "naturale" del file nella radice della destinazione
+ * @param policy:   politica da applicare in caso di collisione
+ * @param planned:  nomi già assegnati durante questo backup (destinazione -> sorgente)
+ */
+fn resolve_collision(src_path: &Path, name: &Path, policy: CollisionPolicy, planned: &HashMap<PathBuf, PathBuf>) -> io::Result<Option<PathBuf>> {
+    let existing = match planned.get(name) {
+        Some(p) => p,
+        None => return Ok(Some(name.to_path_buf())),
+    };
+
+    match policy {
+        CollisionPolicy::Overwrite => Ok(Some(name.to_path_buf())),
+        CollisionPolicy::Skip => Ok(None),
+        CollisionPolicy::KeepBoth => {
+            //Aggiungo un suffisso " (n)" al nome del file, finché non trovo un nome libero
+            let stem = name.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let extension = name.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+            let mut n = 1;
+            loop {
+                let candidate = name.with_file_name(format!("{} ({}){}", stem, n, extension));
+                if !planned.contains_key(&candidate) {
+                    return Ok(Some(candidate));
+                }
+                n += 1;
+            }
+        }
+        CollisionPolicy::NewerWins => {
+            //Confronto la data di modifica del file sorgente con quella del file sorgente a cui è già stato assegnato il nome
+            if src_path.metadata()?.modified()? > existing.metadata()?.modified()? {
+                Ok(Some(name.to_path_buf()))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+}
+
+//Elenca ricorsivamente tutti i file della cartella sorgente (percorso relativo -> percorso sorgente), usata in modalità "Folder" se il backup è un archivio o è cifrato.
+//Le sottocartelle che non è possibile leggere vengono registrate in failures
+fn list_folder_files(src: &Path, failures: &mut CopyFailures) -> io::Result<HashMap<PathBuf, PathBuf>> {
+    let mut files = HashMap::new();
+    let mut stack = vec![src.to_path_buf()];
+
+    while let Some(dir) = stack.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if dir != src => {
+                failures.record(&dir, e)?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    failures.record(&dir, e)?;
+                    continue;
+                }
+            };
+            if path.is_dir() {
+                stack.push(path);
+            } else {
+                files.insert(path.strip_prefix(src).unwrap().to_path_buf(), path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/**
+ * Scrive i file indicati nell'archivio e ritorna il manifest del backup.
+ * L'hash di ogni file viene calcolato durante la scrittura nell'archivio
+ *
+ * @param archive: archivio (tar.zst o zip) appena creato nella cartella di staging
+ * @param files:   file da scrivere (percorso all'interno dell'archivio -> percorso sorgente)
+ * @param options: opzioni del backup (vedi start_backup)
+ * @param settings: impostazioni avanzate del backup (formato dell'archivio, riportato nel manifest, e ordine dei file)
+ * @param budget:   tempo a disposizione del backup, se c'è un limite (i file che non possono essere scritti entro la scadenza vengono registrati in failures.not_saved)
+ * @param failures: file che non è stato possibile leggere, non scritti nell'archivio
+ */
+fn write_archive(mut archive: ArchiveWriter, files: &HashMap<PathBuf, PathBuf>, options: &[String], settings: &BackupSettings, mut budget: Option<&mut TimeBudget>, failures: &mut CopyFailures) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new(options, settings.format.name());
+
+    //Scrivo i file in ordine di percorso, così che archivi di backup diversi siano confrontabili, oppure secondo l'impostazione priority
+    let mut entries: Vec<(&PathBuf, &PathBuf)> = files.iter().collect();
+    entries.sort();
+    if settings.priority.is_enabled() {
+        settings.priority.order(&mut entries, Path::new(&options[1]));
+    }
+
+    for (name, path) in entries {
+        //Controllo che il file sia leggibile prima di scriverlo: un errore a metà della scrittura lascerebbe l'archivio incompleto, quindi interrompe il backup
+        let metadata = match path.metadata().and_then(|m| File::open(path).map(|_| m)) {
+            Ok(m) => m,
+            Err(e) => {
+                failures.record(path, e)?;
+                continue;
+            }
+        };
+        if budget.as_deref_mut().is_some_and(|b| !b.try_start(metadata.len())) {
+            failures.not_saved.push((path.clone(), metadata.len()));
+            continue;
+        }
+        let name = manifest::relative_path(name, Path::new(""));
+        let result = archive.append_file(path, &name, &metadata);
+        if let Some(budget) = budget.as_deref_mut() {
+            budget.finish(metadata.len());
+        }
+        let hash = match result {
+            Ok(hash) => hash,
+            Err(e) => {
+                //Solo un file accorciato durante la scrittura lascia l'archivio utilizzabile: gli altri errori sono bloccanti
+                failures.record(path, e)?;
+                continue;
+            }
+        };
+        println!("Archived {:?} as {}", path, name);
+        manifest.add(name, manifest::relative_path(path, Path::new(&options[1])), &metadata, metadata.len(), hash);
+    }
+
+    archive.finish()?;
+    Ok(manifest)
+}
+
+/**
+ * Cifra i file indicati nella cartella di staging e ritorna il manifest del backup.
+ * L'hash di ogni file viene calcolato sul contenuto in chiaro, durante la cifratura
+ *
+ * @param cipher:  chiave del backup
+ * @param files:   file da cifrare (percorso relativo nel backup -> percorso sorgente)
+ * @param staging: cartella di staging
+ * @param options: opzioni del backup (vedi start_backup)
+ * @param priority: ordine di cifratura dei file
+ * @param budget:   tempo a disposizione del backup, se c'è un limite (i file che non possono essere cifrati entro la scadenza vengono registrati in failures.not_saved)
+ * @param failures: file che non è stato possibile cifrare
+ */
+fn encrypt_files(cipher: &mut BackupCipher, files: &HashMap<PathBuf, PathBuf>, staging: &Path, options: &[String], priority: &PriorityRules, mut budget: Option<&mut TimeBudget>, failures: &mut CopyFailures) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new(options, OutputFormat::Directory.name());
+
+    let mut entries: Vec<(&PathBuf, &PathBuf)> = files.iter().collect();
+    entries.sort();
+    if priority.is_enabled() {
+        priority.order(&mut entries, Path::new(&options[1]));
+    }
+
+    for (relative, path) in entries {
+        let size = path.metadata().map(|m| m.len()).unwrap_or(0);
+        if budget.as_deref_mut().is_some_and(|b| !b.try_start(size)) {
+            failures.not_saved.push((path.clone(), size));
+            continue;
+        }
+        let dest_path = staging.join(relative);
+        //Con i nomi cifrati tutti i file finiscono nella radice del backup, quindi non creo le sottocartelle
+        if !cipher.encrypts_names() {
+            if let Some(parent) = dest_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let name = manifest::relative_path(relative, Path::new(""));
+        let result = path.metadata().and_then(|m| cipher.encrypt_file(path, &name, &dest_path).map(|h| (m, h)));
+        if let Some(budget) = budget.as_deref_mut() {
+            budget.finish(size);
+        }
+        let (metadata, hash) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                failures.record(path, e)?;
+                continue;
+            }
+        };
+        println!("Encrypted {:?}", path);
+        manifest.add(name, manifest::relative_path(path, Path::new(&options[1])), &metadata, metadata.len(), hash);
+    }
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    //Sorgente con due file con lo stesso nome in sottocartelle diverse, un file con il nome che KeepBoth darebbe al secondo
+    //e una cartella con il nome di un file da copiare
+    fn source() -> tempfile::TempDir {
+        let src = tempfile::tempdir().unwrap();
+        for (path, content) in [("a/doc.txt", "a"), ("b/doc.txt", "b"), ("c/doc (1).txt", "c"), ("note.txt/interno.txt", "d"), ("a/foto.jpg", "e")] {
+            let path = src.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        src
+    }
+
+    fn plan(src: &Path, flatten: bool, collision_policy: CollisionPolicy) -> HashMap<PathBuf, PathBuf> {
+        let settings = BackupSettings { flatten, collision_policy, ..Default::default() };
+        let mut failures = CopyFailures::default();
+        let planned = plan_files(&src.to_string_lossy(), &["txt"], &settings, &mut failures).unwrap();
+        assert!(failures.list.is_empty());
+        planned
+    }
+
+    fn names(planned: &HashMap<PathBuf, PathBuf>) -> Vec<String> {
+        let mut names: Vec<String> = planned.keys().map(|p| p.to_string_lossy().replace('\\', "/")).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn single_files_keep_the_folder_structure() {
+        let src = source();
+        let planned = plan(src.path(), false, CollisionPolicy::KeepBoth);
+        assert_eq!(names(&planned), vec!["a/doc.txt", "b/doc.txt", "c/doc (1).txt", "note.txt/interno.txt"]);
+        assert_eq!(planned[Path::new("b/doc.txt")], src.path().join("b/doc.txt"));
+    }
+
+    #[test]
+    fn same_name_from_different_folders() {
+        let src = source();
+
+        //KeepBoth: nessun file viene perso, nemmeno quello che ha già il nome con il suffisso
+        let planned = plan(src.path(), true, CollisionPolicy::KeepBoth);
+        assert_eq!(planned.len(), 4);
+        let sources: Vec<&PathBuf> = planned.values().collect();
+        for file in ["a/doc.txt", "b/doc.txt", "c/doc (1).txt", "note.txt/interno.txt"] {
+            assert!(sources.contains(&&src.path().join(file)), "{} non pianificato", file);
+        }
+        //La cartella "note.txt" non è un file: non occupa il nome e il file al suo interno viene copiato
+        assert!(planned.contains_key(Path::new("interno.txt")));
+        assert!(!planned.contains_key(Path::new("note.txt")));
+
+        //Skip e Overwrite: un solo file con il nome "doc.txt"
+        for policy in [CollisionPolicy::Skip, CollisionPolicy::Overwrite] {
+            let planned = plan(src.path(), true, policy);
+            assert_eq!(names(&planned), vec!["doc (1).txt", "doc.txt", "interno.txt"]);
+        }
+
+        //NewerWins: viene copiato il file modificato più di recente
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        File::options().write(true).open(src.path().join("a/doc.txt")).unwrap().set_modified(old).unwrap();
+        let planned = plan(src.path(), true, CollisionPolicy::NewerWins);
+        assert_eq!(planned[Path::new("doc.txt")], src.path().join("b/doc.txt"));
+        File::options().write(true).open(src.path().join("b/doc.txt")).unwrap().set_modified(old - Duration::from_secs(3600)).unwrap();
+        let planned = plan(src.path(), true, CollisionPolicy::NewerWins);
+        assert_eq!(planned[Path::new("doc.txt")], src.path().join("a/doc.txt"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use sysinfo::{Disk, DiskKind, Disks};
+use crate::cpu_governor::throttle;
+use crate::destination::disk_for;
+
+pub const MAX_WORKERS: usize = 64;      //Numero massimo di worker impostabile con workers=N
+
+const SSD_WORKERS: usize = 8;           //Worker per una sorgente SSD/NVMe: molte letture contemporanee nascondono la latenza dei file piccoli
+const HDD_WORKERS: usize = 2;           //Worker per una sorgente o una destinazione su disco rotativo o rimovibile (USB), dove più letture contemporanee sposterebbero solo la testina
+const QUEUED_PER_WORKER: usize = 4;     //File in attesa o in copia per ogni worker: limita la memoria usata mentre la visita della sorgente prosegue
+
+type Job<R> = Box<dyn FnOnce() -> io::Result<R> + Send>;
+
+/**
+ * Insieme di thread (worker) che copiano i file in parallelo, mentre il thread del backup continua a visitare la sorgente.
+ * Ogni file viene inviato ai worker insieme a un'etichetta, restituita con il risultato della copia: manifest, metadati ed errori
+ * vengono aggiornati solo dal thread del backup. I file in attesa o in copia sono al massimo QUEUED_PER_WORKER per worker,
+ * quindi la memoria usata non dipende dal numero di file della sorgente
+ */
+pub struct CopyPool<T, R> {
+    jobs: Option<SyncSender<(T, Job<R>)>>,  //Coda dei file da copiare (None dopo finish)
+    results: Receiver<(T, io::Result<R>)>,  //Risultati delle copie terminate
+    workers: Vec<JoinHandle<()>>,
+    pending: usize,                         //File inviati ai worker di cui non è ancora stato restituito il risultato
+    limit: usize,                           //Numero massimo di file in attesa o in copia
+}
+
+impl<T: Send + 'static, R: Send + 'static> CopyPool<T, R> {
+    /**
+     * Avvia i worker
+     *
+     * @param workers: numero di worker (almeno 1)
+     */
+    pub fn new(workers: usize) -> Self {
+        let workers = workers.max(1);
+        let (jobs, queue) = mpsc::sync_channel::<(T, Job<R>)>(workers);
+        let (done, results) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..workers).map(|_| {
+            let (queue, done) = (Arc::clone(&queue), done.clone());
+            thread::spawn(move || loop {
+                //Il lock viene rilasciato prima di copiare il file
+                let next = queue.lock().map(|q| q.recv());
+                let (tag, job) = match next {
+                    Ok(Ok(next)) => next,
+                    _ => break,
+                };
+                if done.send((tag, job())).is_err() {
+                    break;
+                }
+                //Pausa se il backup supera il limite di CPU
+                throttle();
+            })
+        }).collect::<Vec<_>>();
+        let limit = workers.len() * QUEUED_PER_WORKER;
+        CopyPool { jobs: Some(jobs), results, workers, pending: 0, limit }
+    }
+
+    /**
+     * Invia un file da copiare ai worker. Se i file in attesa sono già troppi, aspetta che termini almeno una copia.
+     * Ritorna i risultati delle copie terminate nel frattempo (anche di file inviati in precedenza)
+     *
+     * @param tag: etichetta restituita insieme al risultato della copia
+     * @param job: funzione che copia il file
+     */
+    pub fn submit(&mut self, tag: T, job: impl FnOnce() -> io::Result<R> + Send + 'static) -> Vec<(T, io::Result<R>)> {
+        let mut completed = Vec::new();
+        if self.pending >= self.limit {
+            if let Ok(result) = self.results.recv() {
+                self.pending -= 1;
+                completed.push(result);
+            }
+        }
+        if let Some(jobs) = &self.jobs {
+            if jobs.send((tag, Box::new(job))).is_ok() {
+                self.pending += 1;
+            }
+        }
+        while let Ok(result) = self.results.try_recv() {
+            self.pending -= 1;
+            completed.push(result);
+        }
+        completed
+    }
+
+    //Aspetta la fine di tutte le copie, termina i worker e ritorna i risultati non ancora restituiti da submit
+    pub fn finish(mut self) -> Vec<(T, io::Result<R>)> {
+        //Chiudendo la coda, i worker terminano dopo l'ultimo file
+        self.jobs = None;
+        let completed: Vec<_> = self.results.iter().take(self.pending).collect();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        completed
+    }
+}
+
+/**
+ * Sceglie il numero di worker in base ai dischi della sorgente e della destinazione: molti per una sorgente SSD/NVMe,
+ * pochi per una sorgente su disco rotativo o per una destinazione rotativa o rimovibile (chiavetta USB)
+ *
+ * @param src:  percorso della sorgente
+ * @param dest: percorso della destinazione
+ * @return      il numero di worker e la descrizione dei due dischi (es. "source SSD, destination HDD removable")
+ */
+pub fn auto_workers(src: &Path, dest: &Path) -> (usize, String) {
+    let disks = Disks::new_with_refreshed_list();
+    let describe = |disk: Option<&Disk>| match disk {
+        Some(d) if d.is_removable() => format!("{} removable", d.kind()),
+        Some(d) => d.kind().to_string(),
+        None => "unknown".to_string(),
+    };
+    let (source, destination) = (disk_for(&disks, src), disk_for(&disks, dest));
+
+    let workers = workers_for(source.map(|d| d.kind()), destination.map(|d| (d.kind(), d.is_removable())));
+    (workers, format!("source {}, destination {}", describe(source), describe(destination)))
+}
+
+/**
+ * Sceglie il numero di worker in base al tipo dei dischi (vedi auto_workers)
+ *
+ * @param source:      tipo del disco della sorgente (None se il disco non è stato trovato)
+ * @param destination: tipo del disco della destinazione e true se è rimovibile (None se il disco non è stato trovato)
+ */
+fn workers_for(source: Option<DiskKind>, destination: Option<(DiskKind, bool)>) -> usize {
+    let mut workers = match source {
+        Some(DiskKind::HDD) => HDD_WORKERS,
+        _ => SSD_WORKERS,
+    };
+    if destination.is_some_and(|(kind, removable)| removable || kind == DiskKind::HDD) {
+        workers = workers.min(HDD_WORKERS);
+    }
+    workers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn every_file_is_copied_and_errors_keep_their_tag() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let mut pool = CopyPool::new(3);
+        let mut results = Vec::new();
+        //Più file di quanti ne possano restare in attesa, così che submit debba aspettare i worker
+        for i in 0..50 {
+            let name = format!("file{}.txt", i);
+            if i % 10 != 7 {
+                fs::write(src.path().join(&name), name.as_bytes()).unwrap();
+            }
+            let (from, to) = (src.path().join(&name), dst.path().join(&name));
+            results.extend(pool.submit(name, move || fs::copy(from, to)));
+        }
+        results.extend(pool.finish());
+
+        assert_eq!(results.len(), 50);
+        let mut failed: Vec<String> = results.iter().filter(|(_, r)| r.is_err()).map(|(name, _)| name.clone()).collect();
+        failed.sort();
+        assert_eq!(failed, vec!["file17.txt", "file27.txt", "file37.txt", "file47.txt", "file7.txt"]);
+        for (name, result) in &results {
+            if let Ok(size) = result {
+                assert_eq!(*size, name.len() as u64);
+                assert_eq!(fs::read_to_string(dst.path().join(name)).unwrap(), *name);
+            } else {
+                assert_eq!(result.as_ref().unwrap_err().kind(), io::ErrorKind::NotFound);
+            }
+        }
+    }
+
+    #[test]
+    fn workers_follow_the_disks() {
+        assert_eq!(workers_for(Some(DiskKind::SSD), Some((DiskKind::SSD, false))), SSD_WORKERS);
+        assert_eq!(workers_for(Some(DiskKind::HDD), Some((DiskKind::SSD, false))), HDD_WORKERS);
+        //Una destinazione rotativa o rimovibile limita i worker anche con una sorgente SSD
+        assert_eq!(workers_for(Some(DiskKind::SSD), Some((DiskKind::HDD, false))), HDD_WORKERS);
+        assert_eq!(workers_for(Some(DiskKind::SSD), Some((DiskKind::SSD, true))), HDD_WORKERS);
+        //Dischi non trovati o di tipo sconosciuto: vengono trattati come SSD
+        assert_eq!(workers_for(None, None), SSD_WORKERS);
+        assert_eq!(workers_for(Some(DiskKind::Unknown(0)), None), SSD_WORKERS);
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use cpu_time::ProcessTime;
+
+pub const DEFAULT_LISTENING_LIMIT: u8 = 5;  //Limite di CPU (in % della capacità totale) mentre l'applicazione aspetta il comando di backup
+pub const DEFAULT_BACKUP_LIMIT: u8 = 40;    //Limite di CPU (in % della capacità totale) durante il backup
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(20);    //Il consumo viene misurato al massimo una volta ogni CHECK_INTERVAL
+const WINDOW: Duration = Duration::from_secs(1);               //Durata minima della finestra su cui viene calcolato il consumo
+const MAX_PAUSE: Duration = Duration::from_secs(1);            //Pausa massima imposta in una volta sola
+
+/**
+ * Fase dell'applicazione, con un limite di CPU diverso
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Phase {
+    Listening,  //L'applicazione aspetta che venga disegnato il comando di backup
+    Backup,     //L'applicazione sta copiando i file
+}
+
+impl Phase {
+    fn index(self) -> usize {
+        match self {
+            Phase::Listening => 0,
+            Phase::Backup => 1,
+        }
+    }
+}
+
+/**
+ * Stato del limitatore di CPU, condiviso da tutti i thread del processo
+ */
+struct Governor {
+    limits: [u8; 2],                                //Limite di ogni fase, in % della capacità totale (tutti i core); 100 = nessun limite
+    phase: Phase,
+    cpus: usize,
+    window_start: Instant,                          //Inizio della finestra su cui viene calcolato il consumo
+    window_cpu: Duration,                           //Tempo di CPU del processo all'inizio della finestra
+    last_check: Instant,
+    pause_until: Instant,                           //Fino a questo istante i thread che chiamano throttle restano in pausa
+    phase_start: Instant,
+    phase_cpu: Duration,                            //Tempo di CPU del processo all'inizio della fase corrente
+    totals: [(Duration, Duration, Duration); 2],    //Per ogni fase: tempo trascorso, tempo di CPU consumato e durata delle pause imposte
+}
+
+static GOVERNOR: LazyLock<Mutex<Governor>> = LazyLock::new(|| {
+    let (now, cpu) = (Instant::now(), process_cpu());
+    Mutex::new(Governor {
+        //Nessun limite finché non viene chiamata configure: i comandi verify, decrypt e repair non vengono rallentati
+        limits: [100, 100],
+        phase: Phase::Listening,
+        cpus: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        window_start: now,
+        window_cpu: cpu,
+        last_check: now,
+        pause_until: now,
+        phase_start: now,
+        phase_cpu: cpu,
+        totals: [(Duration::ZERO, Duration::ZERO, Duration::ZERO); 2],
+    })
+});
+
+//Tempo di CPU consumato dal processo (tutti i thread, in modalità utente e kernel)
+fn process_cpu() -> Duration {
+    ProcessTime::try_now().map(|t| t.as_duration()).unwrap_or_default()
+}
+
+/**
+ * Imposta i limiti di CPU (impostazioni cpu_limit_idle e cpu_limit_backup)
+ *
+ * @param listening: limite mentre l'applicazione aspetta il comando di backup, in % della capacità totale
+ * @param backup:    limite durante il backup, in % della capacità totale
+ */
+pub fn configure(listening: u8, backup: u8) {
+    if let Ok(mut governor) = GOVERNOR.lock() {
+        governor.limits = [listening, backup];
+    }
+}
+
+//Passa a un'altra fase (e al suo limite di CPU), registrando il consumo della fase precedente
+pub fn set_phase(phase: Phase) {
+    if let Ok(mut governor) = GOVERNOR.lock() {
+        let (now, cpu) = (Instant::now(), process_cpu());
+        governor.close_phase(now, cpu);
+        governor.phase = phase;
+        governor.window_start = now;
+        governor.window_cpu = cpu;
+        governor.pause_until = now;
+    }
+}
+
+/**
+ * Chiamata periodicamente dal ciclo che rileva il comando di backup e dai cicli di copia: se il processo ha consumato più CPU
+ * del limite della fase corrente, il thread viene messo in pausa finché il consumo medio della finestra non rientra nel limite.
+ * Tutti i thread che chiamano throttle durante la pausa (ad esempio i worker della copia) restano in pausa fino alla stessa scadenza
+ */
+pub fn throttle() {
+    let pause = {
+        let Ok(mut governor) = GOVERNOR.lock() else { return };
+        let now = Instant::now();
+        if governor.pause_until > now {
+            governor.pause_until - now
+        } else if governor.limits[governor.phase.index()] >= 100 || now - governor.last_check < CHECK_INTERVAL {
+            return;
+        } else {
+            governor.last_check = now;
+            let cpu = process_cpu();
+            let elapsed = now - governor.window_start;
+            //Tempo che deve trascorrere perché il consumo della finestra sia pari al limite
+            let budget = governor.limits[governor.phase.index()] as f64 / 100.0 * governor.cpus as f64;
+            let needed = Duration::from_secs_f64(cpu.saturating_sub(governor.window_cpu).as_secs_f64() / budget.max(0.01));
+            if needed <= elapsed {
+                //Consumo nel limite: inizia una nuova finestra
+                if elapsed >= WINDOW {
+                    governor.window_start = now;
+                    governor.window_cpu = cpu;
+                }
+                return;
+            }
+            let pause = (needed - elapsed).min(MAX_PAUSE);
+            let phase = governor.phase.index();
+            governor.totals[phase].2 += pause;
+            governor.pause_until = now + pause;
+            pause
+        }
+    };
+    thread::sleep(pause);
+}
+
+//Righe del log della CPU con i limiti impostati e il consumo medio di ogni fase dall'avvio
+pub fn report() -> Vec<String> {
+    let Ok(mut governor) = GOVERNOR.lock() else { return Vec::new() };
+    let (now, cpu) = (Instant::now(), process_cpu());
+    governor.close_phase(now, cpu);
+    let mut lines = vec![format!("CPU limits: {}% while listening, {}% during backup (current phase: {:?})", governor.limits[0], governor.limits[1], governor.phase)];
+    for (phase, name) in [(Phase::Listening, "while listening"), (Phase::Backup, "during backup")] {
+        let (wall, used, paused) = governor.totals[phase.index()];
+        if wall.is_zero() {
+            continue;
+        }
+        let average = used.as_secs_f64() / wall.as_secs_f64() / governor.cpus as f64 * 100.0;
+        lines.push(format!("Average CPU usage {}: {:.2}% over {} s (limit {}%, throttled for {:.1} s)", name, average, wall.as_secs(), governor.limits[phase.index()], paused.as_secs_f64()));
+    }
+    lines
+}
+
+impl Governor {
+    //Aggiunge ai totali della fase corrente il tempo e la CPU consumata dall'inizio della fase (che riparte da adesso)
+    fn close_phase(&mut self, now: Instant, cpu: Duration) {
+        let phase = self.phase.index();
+        self.totals[phase].0 += now - self.phase_start;
+        self.totals[phase].1 += cpu.saturating_sub(self.phase_cpu);
+        self.phase_start = now;
+        self.phase_cpu = cpu;
+    }
+}
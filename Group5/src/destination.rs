@@ -0,0 +1,269 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use sysinfo::{Disk, Disks};
+
+//Nome del file "marcatore" che il programma scrive in ogni cartella che gestisce. Il programma non elimina mai una cartella che non contiene questo file
+pub const MARKER_FILE: &str = ".group5_backup";
+
+//File presente nei backup parziali (tempo a disposizione terminato o file esclusi con best_fit), che non prendono mai il posto di un backup completo
+pub const PARTIAL_FILE: &str = ".group5_partial";
+
+//Unità di allocazione usata se non è possibile leggere quella del file system
+const DEFAULT_ALLOCATION_UNIT: u64 = 4096;
+
+/**
+ * Ritorna il disco che contiene il percorso: quello con il punto di montaggio più lungo che lo contiene (es. "/media/usb" invece di "/")
+ *
+ * @param disks: elenco dei dischi
+ * @param path:  percorso sul disco
+ */
+pub fn disk_for<'a>(disks: &'a Disks, path: &Path) -> Option<&'a Disk> {
+    disks.list().iter().filter(|d| path.starts_with(d.mount_point())).max_by_key(|d| d.mount_point().as_os_str().len())
+}
+
+/**
+ * Ritorna l'unità di allocazione (cluster) del file system che contiene il percorso: ogni file occupa un numero intero di unità,
+ * da 4 KB su ext4 e NTFS fino a 128 KB sulle chiavette FAT32 ed exFAT (DEFAULT_ALLOCATION_UNIT se non è possibile leggerla)
+ *
+ * @param path: percorso sul disco (deve esistere)
+ */
+pub fn allocation_unit(path: &Path) -> u64 {
+    cluster_size(path).ok().filter(|&size| size > 0).unwrap_or(DEFAULT_ALLOCATION_UNIT)
+}
+
+#[cfg(unix)]
+fn cluster_size(path: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn cluster_size(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Component;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceW(root: *const u16, sectors_per_cluster: *mut u32, bytes_per_sector: *mut u32, free_clusters: *mut u32, total_clusters: *mut u32) -> i32;
+    }
+    //GetDiskFreeSpaceW accetta solo la radice del disco (es. "E:\")
+    let root: PathBuf = path.components().take_while(|c| matches!(c, Component::Prefix(_) | Component::RootDir)).collect();
+    let wide: Vec<u16> = root.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let (mut sectors, mut bytes, mut free, mut total) = (0u32, 0u32, 0u32, 0u32);
+    if unsafe { GetDiskFreeSpaceW(wide.as_ptr(), &mut sectors, &mut bytes, &mut free, &mut total) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sectors as u64 * bytes as u64)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn cluster_size(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "unità di allocazione non disponibile"))
+}
+
+/**
+ * Ritorna true se la cartella è stata creata da questo programma (contiene il file marcatore)
+ *
+ * @param path: percorso della cartella
+ */
+pub fn is_managed(path: &Path) -> bool {
+    path.join(MARKER_FILE).is_file()
+}
+
+/**
+ * Scrive il file marcatore nella cartella indicata
+ *
+ * @param path: percorso della cartella
+ */
+pub fn mark(path: &Path) -> io::Result<()> {
+    let mut marker = File::create(path.join(MARKER_FILE))?;
+    writeln!(marker, "Cartella gestita da Group5. Il contenuto viene sostituito ad ogni backup.")?;
+    Ok(())
+}
+
+/**
+ * Prepara la cartella temporanea (staging) in cui viene costruito il nuovo backup.
+ * La cartella di staging si trova accanto alla destinazione, così che la rinomina finale avvenga sullo stesso file system.
+ * Ritorna errore, senza eliminare nulla, se la destinazione esiste, non è vuota e non è gestita da questo programma.
+ *
+ * @param dest: percorso della cartella destinazione del backup
+ */
+pub fn prepare_staging(dest: &Path) -> io::Result<PathBuf> {
+    recover_interrupted_commit(dest)?;
+    recover_interrupted_commit(&sibling(dest, "partial"))?;
+
+    if dest.exists() && !is_managed(dest) && !is_empty_dir(dest)? {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("La cartella di destinazione {:?} non è vuota e non è stata creata da Group5: non verrà modificata", dest)));
+    }
+
+    let staging = sibling(dest, "staging");
+    if staging.exists() {
+        //Residuo di un backup interrotto: lo elimino solo se è stato creato da questo programma
+        if !is_managed(&staging) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("La cartella {:?} esiste già e non è stata creata da Group5", staging)));
+        }
+        fs::remove_dir_all(&staging)?;
+    }
+
+    fs::create_dir_all(&staging)?;
+    mark(&staging)?;
+    Ok(staging)
+}
+
+/**
+ * Sostituisce il backup precedente con quello appena costruito nella cartella di staging.
+ * Il backup precedente viene prima rinominato, poi la cartella di staging prende il posto della destinazione e solo alla fine il backup precedente viene eliminato.
+ * Se il programma si interrompe a metà, recover_interrupted_commit ripristina uno stato coerente al backup successivo
+ *
+ * @param staging: cartella di staging contenente il nuovo backup completo
+ * @param dest:    percorso della cartella destinazione del backup
+ */
+pub fn commit_staging(staging: &Path, dest: &Path) -> io::Result<()> {
+    let old = sibling(dest, "old");
+
+    if dest.exists() {
+        if is_managed(dest) {
+            fs::rename(dest, &old)?;
+        } else {
+            //prepare_staging ha già verificato che una destinazione non gestita sia vuota
+            fs::remove_dir(dest)?;
+        }
+    }
+
+    fs::rename(staging, dest)?;
+
+    if old.exists() && is_managed(&old) {
+        fs::remove_dir_all(&old)?;
+    }
+
+    //Un backup parziale salvato accanto alla destinazione è più vecchio del backup appena salvato
+    let partial = sibling(dest, "partial");
+    if partial.exists() && is_managed(&partial) {
+        fs::remove_dir_all(&partial)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Salva un backup parziale senza perdere il backup completo precedente: se la destinazione contiene un backup completo,
+ * il backup parziale viene salvato nella cartella "<dest>.partial" accanto alla destinazione (sostituendo un backup parziale precedente);
+ * altrimenti prende il posto della destinazione, come un backup completo. Ritorna la cartella in cui è stato salvato il backup parziale
+ *
+ * @param staging: cartella di staging contenente il backup parziale
+ * @param dest:    percorso della cartella destinazione del backup
+ */
+pub fn commit_partial(staging: &Path, dest: &Path) -> io::Result<PathBuf> {
+    let mut marker = File::create(staging.join(PARTIAL_FILE))?;
+    writeln!(marker, "Backup parziale: non contiene tutti i file della sorgente (elenco in backup_log.txt).")?;
+
+    if is_managed(dest) && !dest.join(PARTIAL_FILE).exists() {
+        let partial = sibling(dest, "partial");
+        if partial.exists() && !is_managed(&partial) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("La cartella {:?} esiste già e non è stata creata da Group5", partial)));
+        }
+        commit_staging(staging, &partial)?;
+        Ok(partial)
+    } else {
+        commit_staging(staging, dest)?;
+        Ok(dest.to_path_buf())
+    }
+}
+
+/**
+ * Elimina la cartella di staging dopo un backup fallito, se è stata creata da questo programma
+ *
+ * @param staging: cartella di staging
+ */
+pub fn discard_staging(staging: &Path) -> io::Result<()> {
+    if staging.exists() && is_managed(staging) {
+        fs::remove_dir_all(staging)?;
+    }
+    Ok(())
+}
+
+//Se un commit precedente si è interrotto dopo aver rinominato il vecchio backup, lo ripristino (o lo elimino se il nuovo backup è già al suo posto)
+fn recover_interrupted_commit(dest: &Path) -> io::Result<()> {
+    let old = sibling(dest, "old");
+    if old.exists() && is_managed(&old) {
+        if dest.exists() {
+            fs::remove_dir_all(&old)?;
+        } else {
+            fs::rename(&old, dest)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_empty_dir(path: &Path) -> io::Result<bool> {
+    if !path.is_dir() {
+        return Ok(false);
+    }
+    Ok(fs::read_dir(path)?.next().is_none())
+}
+
+/**
+ * Ritorna il percorso del resoconto del comando verify, "<dest>.verify.txt" accanto alla destinazione: fuori dalla cartella del backup,
+ * così che non venga confrontato con la sorgente né sostituito dal backup successivo
+ *
+ * @param dest: percorso della cartella destinazione del backup
+ */
+pub fn verify_report_path(dest: &Path) -> PathBuf {
+    sibling(dest, "verify.txt")
+}
+
+//Costruisce il percorso "<dest>.<suffix>" accanto alla destinazione
+fn sibling(dest: &Path, suffix: &str) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", suffix));
+    dest.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Costruisce un backup nella cartella di staging, con un solo file
+    fn build(dest: &Path, content: &str) -> PathBuf {
+        let staging = prepare_staging(dest).unwrap();
+        fs::write(staging.join("file.txt"), content).unwrap();
+        staging
+    }
+
+    fn read(dir: &Path) -> String {
+        fs::read_to_string(dir.join("file.txt")).unwrap()
+    }
+
+    #[test]
+    fn partial_backup_keeps_complete_backup() {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("backup");
+        let partial = root.path().join("backup.partial");
+
+        //Senza un backup completo, il backup parziale prende il posto della destinazione
+        assert_eq!(commit_partial(&build(&dest, "parziale 1"), &dest).unwrap(), dest);
+        assert!(dest.join(PARTIAL_FILE).exists());
+
+        //Un backup completo sostituisce quello parziale
+        commit_staging(&build(&dest, "completo"), &dest).unwrap();
+        assert_eq!(read(&dest), "completo");
+        assert!(!dest.join(PARTIAL_FILE).exists());
+
+        //I backup parziali successivi vengono salvati accanto al backup completo, che resta intatto
+        assert_eq!(commit_partial(&build(&dest, "parziale 2"), &dest).unwrap(), partial);
+        assert_eq!(commit_partial(&build(&dest, "parziale 3"), &dest).unwrap(), partial);
+        assert_eq!((read(&dest).as_str(), read(&partial).as_str()), ("completo", "parziale 3"));
+
+        //Il backup completo successivo elimina il backup parziale, più vecchio
+        commit_staging(&build(&dest, "completo 2"), &dest).unwrap();
+        assert_eq!(read(&dest), "completo 2");
+        assert!(!partial.exists());
+    }
+}
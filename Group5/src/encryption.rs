@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use age::secrecy::{ExposeSecret, SecretString};
+use age::stream::{StreamReader, StreamWriter};
+use age::{x25519, Decryptor, Encryptor, Identity, IdentityFile, Recipient};
+use crate::archive::{archive_parts, open_archive, ARCHIVE_FILE};
+use crate::manifest::{check_relative_path, relative_path, MANIFEST_FILE};
+use crate::settings::{self, EncryptionSettings};
+use crate::bandwidth::limit;
+use crate::cpu_governor::throttle;
+
+//Chiave del backup, cifrata con la passphrase o con le chiavi pubbliche della configurazione
+pub const KEY_FILE: &str = "backup_key.age";
+
+//Corrispondenza tra i nomi cifrati e i percorsi originali dei file (impostazione encrypt_names)
+pub const NAMES_FILE: &str = "backup_names.age";
+
+//Estensione dei file cifrati
+pub const ENCRYPTED_SUFFIX: &str = ".age";
+
+/**
+ * Cifratura dei file di un backup (formato age: X25519 o scrypt, ChaCha20-Poly1305).
+ * Per ogni backup viene generata una chiave X25519 casuale: i file sono cifrati con questa chiave (operazione veloce anche per migliaia di file),
+ * mentre la chiave stessa viene salvata nel file KEY_FILE cifrata con la passphrase o con le chiavi pubbliche della configurazione.
+ * Tutti i file sono nel formato age, quindi il backup si può decifrare anche con il programma "age"
+ */
+pub struct BackupCipher {
+    identity: x25519::Identity,
+    dir: PathBuf,                   //Cartella del backup
+    encrypt_names: bool,            //Se true i file vengono salvati come "00000001.age", "00000002.age", ... nella radice del backup
+    names: Vec<(String, String)>,   //Corrispondenza nome cifrato -> percorso originale, salvata in NAMES_FILE
+}
+
+impl BackupCipher {
+    /**
+     * Genera la chiave di un nuovo backup e la salva, cifrata, nella cartella del backup
+     *
+     * @param dir:      cartella del backup
+     * @param settings: impostazioni di cifratura
+     */
+    pub fn create(dir: &Path, settings: &EncryptionSettings) -> io::Result<Self> {
+        let identity = x25519::Identity::generate();
+
+        let encryptor = if !settings.recipients.is_empty() {
+            let mut recipients: Vec<x25519::Recipient> = Vec::new();
+            for r in &settings.recipients {
+                recipients.push(r.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Chiave pubblica non valida {}: {}", r, e)))?);
+            }
+            Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn Recipient)).map_err(io::Error::other)?
+        } else {
+            let passphrase = settings.passphrase.clone().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Nessuna chiave di cifratura configurata"))?;
+            Encryptor::with_user_passphrase(SecretString::from(passphrase))
+        };
+
+        let mut writer = encryptor.wrap_output(BufWriter::new(File::create(dir.join(KEY_FILE))?))?;
+        writer.write_all(identity.to_string().expose_secret().as_bytes())?;
+        writer.finish()?.flush()?;
+
+        Ok(BackupCipher { identity, dir: dir.to_path_buf(), encrypt_names: settings.encrypt_names, names: Vec::new() })
+    }
+
+    /**
+     * Legge la chiave di un backup esistente
+     *
+     * @param dir:        cartella del backup
+     * @param identities: passphrase o chiavi private con cui è stata cifrata la chiave del backup
+     */
+    pub fn open(dir: &Path, identities: &[Box<dyn Identity>]) -> io::Result<Self> {
+        let decryptor = Decryptor::new_buffered(io::BufReader::new(File::open(dir.join(KEY_FILE))?)).map_err(io::Error::other)?;
+        let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref())).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, format!("Impossibile decifrare la chiave del backup: {}", e)))?;
+        let mut key = String::new();
+        reader.read_to_string(&mut key)?;
+        let identity = key.trim().parse().map_err(|e: &str| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(BackupCipher { identity, dir: dir.to_path_buf(), encrypt_names: false, names: Vec::new() })
+    }
+
+    //Ritorna true se i file vengono salvati con nomi cifrati nella radice del backup
+    pub fn encrypts_names(&self) -> bool {
+        self.encrypt_names
+    }
+
+    /**
+     * Cifra tutto ciò che viene scritto su un writer (usata per l'archivio tar.zst). Il writer ritornato deve essere chiuso con finish
+     *
+     * @param output: writer di destinazione
+     */
+    pub fn wrap<W: Write>(&self, output: W) -> io::Result<StreamWriter<W>> {
+        let recipient = self.identity.to_public();
+        Encryptor::with_recipients(iter::once(&recipient as &dyn Recipient)).map_err(io::Error::other)?.wrap_output(output)
+    }
+
+    //Decifra un file cifrato con la chiave del backup
+    fn decrypt_reader<R: Read>(&self, input: R) -> io::Result<StreamReader<R>> {
+        let decryptor = Decryptor::new(input).map_err(io::Error::other)?;
+        decryptor.decrypt(iter::once(&self.identity as &dyn Identity)).map_err(io::Error::other)
+    }
+
+    /**
+     * Cifra un file della sorgente nella cartella del backup e ritorna l'hash BLAKE3 del contenuto in chiaro
+     *
+     * @param src:      percorso del file sorgente
+     * @param relative: percorso del file nel backup (separatore '/'), usato se encrypt_names è true
+     * @param dst:      percorso di destinazione del file in chiaro (al nome viene aggiunto ENCRYPTED_SUFFIX; se encrypt_names è true viene ignorato)
+     */
+    pub fn encrypt_file(&mut self, src: &Path, relative: &str, dst: &Path) -> io::Result<String> {
+        //Apro il file sorgente prima di scegliere il nome cifrato: un file illeggibile non deve lasciare un nome senza file
+        let mut input = File::open(src)?;
+        let stored = format!("{:08}{}", self.names.len() + 1, ENCRYPTED_SUFFIX);
+        let target = if self.encrypt_names {
+            self.dir.join(&stored)
+        } else {
+            let mut name = dst.as_os_str().to_os_string();
+            name.push(ENCRYPTED_SUFFIX);
+            PathBuf::from(name)
+        };
+
+        match self.encrypt_to(&mut input, &target) {
+            Ok(hash) => {
+                if self.encrypt_names {
+                    self.names.push((stored, relative.to_string()));
+                }
+                Ok(hash)
+            }
+            Err(e) => {
+                //Elimino il file cifrato incompleto, così che il backup non contenga file a metà
+                let _ = fs::remove_file(&target);
+                Err(e)
+            }
+        }
+    }
+
+    //Cifra il contenuto di input nel file target e ritorna l'hash BLAKE3 del contenuto in chiaro
+    fn encrypt_to(&self, input: &mut File, target: &Path) -> io::Result<String> {
+        let mut output = self.wrap(BufWriter::new(File::create(target)?))?;
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = input.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            output.write_all(&buffer[..n])?;
+            throttle();
+            limit(n as u64);
+        }
+        output.finish()?.flush()?;
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+
+    /**
+     * Scrive un file di servizio cifrato nella cartella del backup (ad esempio il manifest)
+     *
+     * @param name: nome del file, senza ENCRYPTED_SUFFIX
+     * @param data: contenuto in chiaro
+     */
+    pub fn write_encrypted(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let mut output = self.wrap(BufWriter::new(File::create(self.dir.join(format!("{}{}", name, ENCRYPTED_SUFFIX)))?))?;
+        output.write_all(data)?;
+        output.finish()?.flush()
+    }
+
+    //Salva, cifrata, la corrispondenza tra i nomi cifrati e i percorsi originali (solo se encrypt_names è true)
+    pub fn finish(&self) -> io::Result<()> {
+        if self.names.is_empty() {
+            return Ok(());
+        }
+        let content: String = self.names.iter().map(|(stored, original)| format!("{}\t{}\n", stored, original)).collect();
+        let mut output = self.wrap(BufWriter::new(File::create(self.dir.join(NAMES_FILE))?))?;
+        output.write_all(content.as_bytes())?;
+        output.finish()?.flush()
+    }
+}
+
+//Ritorna true se il backup contenuto nella cartella indicata è cifrato
+pub fn is_encrypted(dir: &Path) -> bool {
+    dir.join(KEY_FILE).is_file()
+}
+
+/**
+ * Decifra un backup cifrato, ricreando i file originali. Ritorna il numero di file decifrati
+ *
+ * @param backup:     cartella del backup
+ * @param output:     cartella in cui vengono scritti i file decifrati
+ * @param identities: passphrase o chiavi private con cui è stata cifrata la chiave del backup
+ */
+pub fn decrypt_backup(backup: &Path, output: &Path, identities: &[Box<dyn Identity>]) -> io::Result<usize> {
+    let cipher = BackupCipher::open(backup, identities)?;
+    fs::create_dir_all(output)?;
+
+    //Backup in formato tar.zst: l'intero archivio è cifrato
+    let archive = backup.join(format!("{}{}", ARCHIVE_FILE, ENCRYPTED_SUFFIX));
+    if !archive_parts(&archive).is_empty() {
+        let decoder = zstd::stream::read::Decoder::new(cipher.decrypt_reader(open_archive(&archive)?)?)?;
+        let mut archive = tar::Archive::new(decoder);
+        let mut count = 0;
+        for entry in archive.entries()? {
+            entry?.unpack_in(output)?;
+            count += 1;
+        }
+        return Ok(count);
+    }
+
+    //Corrispondenza nome cifrato -> percorso originale (se i nomi sono stati cifrati)
+    let mut names = HashMap::new();
+    if backup.join(NAMES_FILE).is_file() {
+        let reader = io::BufReader::new(cipher.decrypt_reader(File::open(backup.join(NAMES_FILE))?)?);
+        for line in reader.lines() {
+            if let Some((stored, original)) = line?.split_once('\t') {
+                names.insert(stored.to_string(), original.to_string());
+            }
+        }
+    }
+
+    let service_files = [KEY_FILE.to_string(), NAMES_FILE.to_string(), format!("{}{}", MANIFEST_FILE, ENCRYPTED_SUFFIX)];
+    let mut count = 0;
+    let mut stack = vec![backup.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                stack.push(path);
+                continue;
+            }
+            let key = relative_path(&path, backup);
+            if service_files.contains(&key) {
+                continue;
+            }
+            let original = match (names.get(&key), key.strip_suffix(ENCRYPTED_SUFFIX)) {
+                (Some(original), _) => original.clone(),
+                (None, Some(original)) => original.to_string(),
+                (None, None) => continue, //File non cifrati (log, marcatore della destinazione)
+            };
+            //Un backup manomesso non deve poter scrivere fuori dalla cartella di destinazione
+            check_relative_path(&original)?;
+
+            let destination = output.join(&original);
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut reader = cipher.decrypt_reader(io::BufReader::new(File::open(&path)?))?;
+            let mut writer = BufWriter::new(File::create(&destination)?);
+            io::copy(&mut reader, &mut writer)?;
+            writer.flush()?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/**
+ * Comando "decrypt": decifra il backup indicato in configuration.txt nella cartella indicata, senza avviare la GUI.
+ * Senza --identity viene usata la passphrase indicata in configuration.txt.
+ * Ritorna il codice di uscita del programma: 0 se il backup è stato decifrato, 2 in caso di errore
+ *
+ * @param args: argomenti successivi a "decrypt": <cartella di destinazione> [--identity <file delle chiavi private>]
+ */
+pub fn decrypt_from_configuration(args: &[String]) -> i32 {
+    let mut output = None;
+    let mut identity_file = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--identity" {
+            identity_file = iter.next().cloned();
+        } else {
+            output = Some(arg);
+        }
+    }
+    let output = match output {
+        Some(o) => Path::new(o),
+        None => {
+            eprintln!("Usage: Group5 decrypt <output folder> [--identity <file>]");
+            return 2;
+        }
+    };
+
+    let backup = match fs::read_to_string("configuration.txt").ok().and_then(|c| c.lines().nth(1).and_then(|l| l.split(';').nth(2).map(String::from))) {
+        Some(dest) => PathBuf::from(dest),
+        None => {
+            eprintln!("Invalid configuration file");
+            return 2;
+        }
+    };
+    if !is_encrypted(&backup) {
+        eprintln!("The backup in {:?} is not encrypted", backup);
+        return 2;
+    }
+
+    let identities: Vec<Box<dyn Identity>> = match identity_file {
+        Some(file) => match IdentityFile::from_file(file.clone()).map_err(io::Error::other).and_then(|f| f.into_identities().map_err(io::Error::other)) {
+            Ok(identities) => identities,
+            Err(e) => {
+                eprintln!("Error reading identity file {}: {}", file, e);
+                return 2;
+            }
+        },
+        None => match settings::load_settings("configuration.txt").encryption.passphrase {
+            Some(passphrase) => vec![Box::new(age::scrypt::Identity::new(SecretString::from(passphrase)))],
+            None => {
+                eprintln!("No passphrase in configuration.txt: use --identity <file> to decrypt a backup encrypted with a public key");
+                return 2;
+            }
+        },
+    };
+
+    match decrypt_backup(&backup, output, &identities) {
+        Ok(count) => {
+            println!("Decrypted {} files into {:?}", count, output);
+            0
+        }
+        Err(e) => {
+            eprintln!("Error decrypting backup: {}", e);
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::content;
+
+    //Chiave di prova: il backup viene cifrato per la sua chiave pubblica e decifrato con la chiave privata
+    fn test_key() -> (EncryptionSettings, Vec<Box<dyn Identity>>) {
+        let identity = x25519::Identity::generate();
+        let settings = EncryptionSettings { recipients: vec![identity.to_public().to_string()], ..EncryptionSettings::default() };
+        (settings, vec![Box::new(identity)])
+    }
+
+    //Cifra i file indicati (percorso nel backup, contenuto), li decifra e verifica il contenuto
+    fn round_trip(encrypt_names: bool) {
+        let (src, backup, output) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (mut settings, identities) = test_key();
+        settings.encrypt_names = encrypt_names;
+        let mut cipher = BackupCipher::create(backup.path(), &settings).unwrap();
+
+        let files = [("documenti/segreto.txt", vec![7u8; 1000]), ("foto.jpg", content(1, 70_000))];
+        for (i, (relative, data)) in files.iter().enumerate() {
+            let path = src.path().join(i.to_string());
+            fs::write(&path, data).unwrap();
+            let dst = backup.path().join(relative);
+            fs::create_dir_all(dst.parent().unwrap()).unwrap();
+            assert_eq!(cipher.encrypt_file(&path, relative, &dst).unwrap(), blake3::hash(data).to_hex().to_string());
+        }
+        cipher.finish().unwrap();
+        assert_eq!(backup.path().join("00000001.age").is_file(), encrypt_names);
+
+        assert_eq!(decrypt_backup(backup.path(), output.path(), &identities).unwrap(), 2);
+        for (relative, data) in &files {
+            assert_eq!(&fs::read(output.path().join(relative)).unwrap(), data, "{}", relative);
+        }
+    }
+
+    #[test]
+    fn round_trip_with_plain_names() {
+        round_trip(false);
+    }
+
+    #[test]
+    fn round_trip_with_encrypted_names() {
+        round_trip(true);
+    }
+
+    #[test]
+    fn tampered_names_are_rejected() {
+        let (src, backup, output) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (settings, identities) = test_key();
+        let mut cipher = BackupCipher::create(backup.path(), &settings).unwrap();
+        fs::write(src.path().join("file.txt"), "contenuto").unwrap();
+        cipher.encrypt_file(&src.path().join("file.txt"), "file.txt", &backup.path().join("00000001")).unwrap();
+        //Chi conosce la chiave pubblica può scrivere un elenco dei nomi che punta fuori dalla cartella di destinazione
+        cipher.write_encrypted(NAMES_FILE.strip_suffix(ENCRYPTED_SUFFIX).unwrap(), b"00000001.age\t../fuori.txt\n").unwrap();
+
+        let error = decrypt_backup(backup.path(), &output.path().join("decifrato"), &identities).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!output.path().join("fuori.txt").exists());
+    }
+}
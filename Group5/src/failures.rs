@@ -0,0 +1,145 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::salvage::{format_ranges, lost_bytes};
+
+/**
+ * Tipo di errore incontrato copiando un file
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FailureKind {
+    PermissionDenied,   //Il programma non ha i permessi per leggere il file
+    Vanished,           //Il file è stato eliminato o spostato durante il backup
+    Io,                 //Errore di lettura o scrittura (es. settore danneggiato)
+}
+
+impl FailureKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailureKind::PermissionDenied => "permission denied",
+            FailureKind::Vanished => "vanished",
+            FailureKind::Io => "I/O error",
+        }
+    }
+}
+
+/**
+ * File che non è stato possibile copiare
+ */
+#[derive(Debug)]
+pub struct CopyFailure {
+    pub path: PathBuf,          //Percorso del file (o della cartella) nella sorgente
+    pub kind: FailureKind,
+    pub message: String,        //Messaggio dell'errore
+}
+
+/**
+ * Elenco dei file che non è stato possibile copiare. Il backup prosegue con gli altri file
+ */
+#[derive(Default, Debug)]
+pub struct CopyFailures {
+    pub list: Vec<CopyFailure>,
+    pub salvaged: Vec<(PathBuf, Vec<(u64, u64)>)>,  //File copiati in parte in modalità salvataggio, con le zone illeggibili riempite di zeri
+    pub not_saved: Vec<(PathBuf, u64)>,             //File non copiati perché la copia non sarebbe terminata entro il tempo a disposizione, con la loro dimensione
+    pub excluded: Vec<(PathBuf, u64)>,              //File esclusi perché non c'era spazio sufficiente nella destinazione (best_fit), con la loro dimensione
+    pub best_fit: bool,                             //Se true un file che non sta nella destinazione piena viene escluso invece di interrompere il backup
+}
+
+impl CopyFailures {
+    /**
+     * Registra l'errore incontrato copiando un file. Ritorna l'errore stesso se non ha senso proseguire il backup
+     * (destinazione piena o in sola lettura, archivio rimasto incompleto), così che il chiamante possa interromperlo con ?
+     * Con best_fit la destinazione piena non interrompe il backup: lo spazio stimato non bastava e il file viene registrato tra gli esclusi
+     *
+     * @param path:  percorso del file sorgente
+     * @param error: errore incontrato
+     */
+    pub fn record(&mut self, path: &Path, error: io::Error) -> io::Result<()> {
+        if self.best_fit && is_space_exhausted(&error) {
+            println!("Escluso {:?}: spazio insufficiente ({})", path, error);
+            self.excluded.push((path.to_path_buf(), path.metadata().map(|m| m.len()).unwrap_or(0)));
+            return Ok(());
+        }
+        if is_fatal(&error) {
+            return Err(error);
+        }
+        let kind = match error.kind() {
+            io::ErrorKind::PermissionDenied => FailureKind::PermissionDenied,
+            io::ErrorKind::NotFound => FailureKind::Vanished,
+            _ => FailureKind::Io,
+        };
+        eprintln!("Non sono riuscito a copiare {:?} ({}): {}", path, kind.name(), error);
+        self.list.push(CopyFailure { path: path.to_path_buf(), kind, message: error.to_string() });
+        Ok(())
+    }
+
+    //Numero di file non copiati o copiati in parte
+    pub fn len(&self) -> usize {
+        self.list.len() + self.salvaged.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty() && self.salvaged.is_empty()
+    }
+
+    /**
+     * Scrive l'elenco degli errori, dei file non salvati per il limite di tempo e di quelli esclusi per lo spazio insufficiente nel log del backup, una riga per file
+     *
+     * @param log: file di log del backup
+     */
+    pub fn write_log(&self, log: &mut impl Write) -> io::Result<()> {
+        if !self.is_empty() {
+            writeln!(log, "Completed with {} errors:", self.len())?;
+            for failure in &self.list {
+                writeln!(log, "FAILED\t{}\t{}\t{}", failure.kind.name(), failure.path.display(), failure.message)?;
+            }
+            for (path, lost) in &self.salvaged {
+                writeln!(log, "SALVAGED\t{}\t{} bytes lost\t{}", path.display(), lost_bytes(lost), format_ranges(lost))?;
+            }
+        }
+        if !self.not_saved.is_empty() {
+            writeln!(log, "Not saved (time limit reached): {} files, {} bytes", self.not_saved.len(), self.not_saved.iter().map(|(_, size)| size).sum::<u64>())?;
+            for (path, size) in &self.not_saved {
+                writeln!(log, "NOT SAVED\t{}\t{} bytes", path.display(), size)?;
+            }
+        }
+        if !self.excluded.is_empty() {
+            writeln!(log, "Excluded (not enough space on the destination): {} files, {} bytes", self.excluded.len(), self.excluded.iter().map(|(_, size)| size).sum::<u64>())?;
+            for (path, size) in &self.excluded {
+                writeln!(log, "EXCLUDED\t{}\t{} bytes", path.display(), size)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Errore dopo il quale il backup non può proseguire, anche se riguarda un solo file (es. l'archivio è rimasto a metà di un file)
+ */
+#[derive(Debug)]
+pub struct FatalCopyError(pub io::Error);
+
+impl fmt::Display for FatalCopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for FatalCopyError {}
+
+//Segna un errore come bloccante per tutto il backup
+pub fn fatal(error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), FatalCopyError(error))
+}
+
+//Ritorna true se la destinazione è piena e l'errore non riguarda un archivio rimasto a metà (segnato con fatal)
+pub fn is_space_exhausted(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::StorageFull && !error.get_ref().is_some_and(|e| e.is::<FatalCopyError>())
+}
+
+//Ritorna true se dopo l'errore il backup non può proseguire: destinazione piena o in sola lettura, oppure errore segnato con fatal
+pub fn is_fatal(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::StorageFull | io::ErrorKind::ReadOnlyFilesystem)
+        || error.get_ref().is_some_and(|e| e.is::<FatalCopyError>())
+}
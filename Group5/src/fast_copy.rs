@@ -0,0 +1,139 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use crate::bandwidth::{chunk_size, limit};
+use crate::cpu_governor::throttle;
+
+/**
+ * Modo in cui un file viene copiato nella destinazione
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CopyStrategy {
+    Reflink,        //Il file system (btrfs, XFS) condivide i blocchi della sorgente con la copia, senza scrivere i dati (ioctl FICLONE)
+    CopyFileRange,  //I dati vengono copiati dal kernel senza passare dalla memoria del programma (copy_file_range)
+    Buffered,       //I dati vengono letti e scritti dal programma
+}
+
+/**
+ * Numero di file copiati con ogni strategia durante un backup, riportato in backup_log.txt
+ */
+#[derive(Default, Debug)]
+pub struct CopyStrategies {
+    pub reflink: usize,
+    pub copy_file_range: usize,
+    pub buffered: usize,
+}
+
+impl CopyStrategies {
+    pub fn add(&mut self, strategy: CopyStrategy) {
+        match strategy {
+            CopyStrategy::Reflink => self.reflink += 1,
+            CopyStrategy::CopyFileRange => self.copy_file_range += 1,
+            CopyStrategy::Buffered => self.buffered += 1,
+        }
+    }
+
+    //Riga di backup_log.txt, None se non è stato copiato nessun file
+    pub fn log_line(&self) -> Option<String> {
+        if self.reflink + self.copy_file_range + self.buffered == 0 {
+            return None;
+        }
+        Some(format!("Copy strategy: {} reflink, {} copy_file_range, {} buffered", self.reflink, self.copy_file_range, self.buffered))
+    }
+}
+
+/**
+ * Copia un file con la strategia più veloce disponibile: prima prova a clonarlo (reflink, immediato sullo stesso file system btrfs o XFS),
+ * poi a copiarlo con copy_file_range e infine lo copia leggendolo e scrivendolo. Come fs::copy, copia anche i permessi
+ *
+ * @param src: percorso del file sorgente
+ * @param dst: percorso della copia (viene creata o sovrascritta)
+ * @return     la strategia usata
+ */
+pub fn copy_file(src: &Path, dst: &Path) -> io::Result<CopyStrategy> {
+    let mut input = File::open(src)?;
+    let metadata = input.metadata()?;
+    let mut output = File::create(dst)?;
+
+    let strategy = if reflink(&input, &output)? {
+        CopyStrategy::Reflink
+    } else if copy_range(&input, &output, metadata.len())? {
+        CopyStrategy::CopyFileRange
+    } else {
+        let mut buffer = vec![0u8; 256 * 1024];
+        loop {
+            let n = match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            output.write_all(&buffer[..n])?;
+            throttle();
+            limit(n as u64);
+        }
+        CopyStrategy::Buffered
+    };
+    output.set_permissions(metadata.permissions())?;
+    Ok(strategy)
+}
+
+//Clona il contenuto di input in output. Ritorna false se il file system (o il sistema operativo) non supporta i reflink tra i due file
+#[allow(unused_variables)]
+fn reflink(input: &File, output: &File) -> io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd()) } == 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        if !unsupported(&error) {
+            return Err(error);
+        }
+    }
+    Ok(false)
+}
+
+//Copia len byte da input a output con copy_file_range. Ritorna false se non è supportato tra i due file (in quel caso non è stato copiato nessun byte)
+#[allow(unused_variables)]
+fn copy_range(input: &File, output: &File, len: u64) -> io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let mut remaining = len;
+        let mut copied = 0;
+        while remaining > 0 {
+            //Blocchi da 64 MB (più piccoli con la banda limitata), per poter rispettare i limiti di CPU e di banda anche sui file grandi
+            let chunk = remaining.min(chunk_size(64 << 20) as u64) as usize;
+            let n = unsafe { libc::copy_file_range(input.as_raw_fd(), std::ptr::null_mut(), output.as_raw_fd(), std::ptr::null_mut(), chunk, 0) };
+            if n < 0 {
+                let error = io::Error::last_os_error();
+                match error.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    _ if copied == 0 && unsupported(&error) => return Ok(false),
+                    _ => return Err(error),
+                }
+            }
+            //Il file si è accorciato durante la copia: la copia incompleta non deve risultare riuscita
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Il file è stato accorciato durante la copia: mancano {} byte", remaining)));
+            }
+            remaining -= n as u64;
+            copied += n as u64;
+            throttle();
+            limit(n as u64);
+        }
+        Ok(true)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Ok(false)
+    }
+}
+
+//Ritorna true se l'errore indica che la strategia non è disponibile tra i due file (file system diversi o senza supporto)
+#[cfg(target_os = "linux")]
+fn unsupported(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL | libc::ENOSYS | libc::ENOTTY | libc::EBADF | libc::EPERM))
+}
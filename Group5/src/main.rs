@@ -1,7 +1,26 @@
 //Nasconde la console del terminale in Windows
 #![windows_subsystem = "windows"]
 
+mod archive;
 mod backup;
+mod bandwidth;
+mod copy_pool;
+mod cpu_governor;
+mod destination;
+mod encryption;
+mod failures;
+mod fast_copy;
+mod manifest;
+mod metadata;
+mod parity;
+mod priority;
+mod salvage;
+mod settings;
+mod space_budget;
+mod time_budget;
+mod verify;
+#[cfg(test)]
+mod test_data;
 
 slint::include_modules!();
 use std::{env, io, thread};
@@ -9,20 +28,37 @@ use std::cell::{Ref, RefCell};
 use auto_launch::{AutoLaunchBuilder};
 use std::fs::{File, read_to_string};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command};  //La import di command serve per MacOS
 use std::rc::Rc;
 use std::sync::mpsc;
 use slint::{ SharedString};
 use rfd::FileDialog;
 
+const EXCLUDED_SHOWN: usize = 5;    //Numero di file esclusi per lo spazio insufficiente mostrati nel messaggio di fine backup
+
 enum MainThreadMessage {
     ShowConfirmMessage,
     ShowBackupCompleteMessage,
+    ShowBackupCompleteWithErrorsMessage(usize),     //Backup salvato, ma con il numero indicato di file non copiati
+    ShowBackupPartialMessage(usize, Vec<PathBuf>, PathBuf, bool),  //Backup salvato senza alcuni file: il numero di file non copiati allo scadere del tempo a disposizione, i file esclusi per lo spazio insufficiente, la cartella del backup parziale e true se il backup completo precedente è stato mantenuto
     ShowBackupErrorMessage
 }
 
 fn main() {
+    //Comando "verify": confronta il backup con la sorgente ed esce senza avviare la GUI
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "verify" {
+        exit(verify::verify_from_configuration(args.iter().any(|a| a == "--full")));
+    }
+    //Comando "decrypt": decifra il backup in una cartella ed esce senza avviare la GUI
+    if args.len() > 1 && args[1] == "decrypt" {
+        exit(encryption::decrypt_from_configuration(&args[2..]));
+    }
+    //Comando "repair": ripara il backup con i dati di parità ed esce senza avviare la GUI
+    if args.len() > 1 && args[1] == "repair" {
+        exit(parity::repair_from_configuration());
+    }
 
     /*
 Queste due righe vengono utilizzate per ottenere il percorso
@@ -80,7 +116,7 @@ dell'eseguibile corrente e il percorso della directory che lo contiene
     let content: Vec<String>;
     if Path::new("configuration.txt").exists() {
         content = read_to_string("configuration.txt").unwrap().lines().map(String::from).collect();
-        if content.len() == 2 {
+        if content.len() >= 2 {    //Le righe successive alla seconda contengono le impostazioni avanzate (vedi settings.rs)
             let options: Vec<&str>;
             options = content[1].split(";").collect();
 
@@ -197,7 +233,7 @@ dell'eseguibile corrente e il percorso della directory che lo contiene
                     } else {
                         //Creo questo vettore di stringhe contenente le informazioni di configurazione del backup, che viene passato alla funzione che si occupa di effettuare il backup
                         let options: Vec<String> = Vec::from([formats.to_string(), source.to_string(), destination.to_string()]);
-                        backup::start_backup(tx.clone(), tx_close.clone(), options);
+                        backup::start_backup(tx.clone(), tx_close.clone(), options, settings::load_settings("configuration.txt"));
                     }
                 }
 
@@ -281,6 +317,32 @@ dell'eseguibile corrente e il percorso della directory che lo contiene
                     let _ = confirm_mess.run();
                 }
                 MainThreadMessage::ShowBackupCompleteMessage => {
+                    backup_compl_mess.set_message(SharedString::from("Backup correctly executed"));
+                    let _ = backup_compl_mess.run();
+                }
+                MainThreadMessage::ShowBackupCompleteWithErrorsMessage(errors) => {
+                    backup_compl_mess.set_message(SharedString::from(format!("Backup completed with {} errors: some files could not be copied (see backup_log.txt)", errors)));
+                    let _ = backup_compl_mess.run();
+                }
+                MainThreadMessage::ShowBackupPartialMessage(not_saved, excluded, saved, kept) => {
+                    let mut message = Vec::new();
+                    if not_saved > 0 {
+                        message.push(format!("Time limit reached: backup saved, but {} files could not be copied in time", not_saved));
+                    }
+                    if !excluded.is_empty() {
+                        //Mostro i nomi dei primi file esclusi, l'elenco completo è nel log del backup
+                        let mut names: Vec<String> = excluded.iter().take(EXCLUDED_SHOWN).map(|path| path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().to_string()).collect();
+                        if excluded.len() > EXCLUDED_SHOWN {
+                            names.push("...".to_string());
+                        }
+                        message.push(format!("Not enough space on the destination: {} files were left out ({})", excluded.len(), names.join(", ")));
+                    }
+                    if kept {
+                        message.push(format!("The partial backup was saved in {:?}, the previous complete backup was kept", saved));
+                    } else {
+                        message.push(format!("The partial backup was saved in {:?}", saved));
+                    }
+                    backup_compl_mess.set_message(SharedString::from(format!("{} (see backup_log.txt)", message.join(". "))));
                     let _ = backup_compl_mess.run();
                 }
                 MainThreadMessage::ShowBackupErrorMessage => {
@@ -308,6 +370,12 @@ fn convert_file_formats(file_formats: Ref<Vec<String>>) -> String {
 
 fn create_file_configuration(file_formats: &String, path_source: &String, path_destination: &String) -> io::Result<()> {
     let configuration_file = "configuration.txt";
+
+    //Conservo le impostazioni avanzate già presenti (righe successive alla seconda), che la GUI non gestisce
+    let settings_lines: Vec<String> = read_to_string(configuration_file)
+        .map(|c| c.lines().skip(2).map(String::from).collect())
+        .unwrap_or_default();
+
     let mut file = File::create(configuration_file)?;
 
 
@@ -318,6 +386,10 @@ fn create_file_configuration(file_formats: &String, path_source: &String, path_d
     // Scrivere i dati nel file
     writeln!(file, "{};{};{}", file_formats, path_source, path_destination)?;
 
+    for line in settings_lines {
+        writeln!(file, "{}", line)?;
+    }
+
     println!("Configuration file written successfully.");
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::encryption::BackupCipher;
+use crate::settings::OutputFormat;
+use crate::bandwidth::limit;
+use crate::cpu_governor::throttle;
+
+//Nome del manifest scritto in ogni backup, accanto a backup_log.txt
+pub const MANIFEST_FILE: &str = "backup_manifest.json";
+
+/**
+ * Descrizione di un file copiato nel backup
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestEntry {
+    pub path: String,           //Percorso relativo alla cartella del backup, o all'interno dell'archivio se format è "tar.zst" o "zip" (separatore '/')
+    pub source: String,         //Percorso relativo alla cartella sorgente (diverso da path in modalità flatten)
+    pub size: u64,              //Dimensione in byte
+    pub mtime: String,          //Data di ultima modifica del file sorgente (RFC 3339, UTC)
+    pub permissions: String,    //Permessi del file sorgente (modo ottale su Unix, "readonly"/"readwrite" sugli altri sistemi)
+    pub hash: String,           //Hash BLAKE3 del file scritto nella destinazione
+}
+
+/**
+ * File della sorgente che non è stato salvato nel backup
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MissingFile {
+    pub path: String,           //Percorso relativo alla cartella sorgente (separatore '/')
+    pub size: u64,              //Dimensione in byte
+}
+
+/**
+ * Manifest del backup: elenco leggibile da programma dei file copiati, con i totali e la configurazione usata.
+ * Permette di dimostrare che il backup è completo e integro
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub source: String,
+    pub destination: String,
+    pub profile: String,        //Tipo di backup: "Folder" oppure "Single files" con l'elenco delle estensioni
+    #[serde(default)]
+    pub format: String,         //Formato del backup: "directory", "tar.zst" oppure "zip"
+    pub created: String,
+    pub hash_algorithm: String,
+    pub total_files: usize,
+    pub total_size: u64,
+    #[serde(default = "default_complete")]
+    pub complete: bool,         //false se il backup contiene solo una parte dei file della sorgente (backup parziale)
+    #[serde(default)]
+    pub not_saved: Vec<MissingFile>,    //File non salvati perché il tempo a disposizione (time_limit) non bastava
+    #[serde(default)]
+    pub excluded: Vec<MissingFile>,     //File esclusi perché lo spazio della destinazione non bastava (best_fit)
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /**
+     * Crea un manifest vuoto
+     *
+     * @param options: opzioni del backup (vedi start_backup)
+     * @param format:  formato del backup ("directory" oppure "tar.zst")
+     */
+    pub fn new(options: &[String], format: &str) -> Self {
+        let profile = if options[0] == "F" { "Folder".to_string() } else { format!("Single files: {}", options[0]) };
+        Manifest {
+            source: options[1].clone(),
+            destination: options[2].clone(),
+            profile,
+            format: format.to_string(),
+            created: Utc::now().to_rfc3339(),
+            hash_algorithm: "blake3".to_string(),
+            total_files: 0,
+            total_size: 0,
+            complete: true,
+            not_saved: Vec::new(),
+            excluded: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /**
+     * Aggiunge un file al manifest e aggiorna i totali
+     *
+     * @param path:     percorso del file nel backup
+     * @param source:   percorso del file nella sorgente
+     * @param metadata: metadati del file sorgente
+     * @param size:     dimensione del file scritto nel backup
+     * @param hash:     hash BLAKE3 del file scritto nel backup
+     */
+    pub fn add(&mut self, path: String, source: String, metadata: &fs::Metadata, size: u64, hash: String) {
+        self.total_files += 1;
+        self.total_size += size;
+        self.files.push(ManifestEntry {
+            path,
+            source,
+            size,
+            mtime: metadata.modified().map(|t| DateTime::<Utc>::from(t).to_rfc3339()).unwrap_or_default(),
+            permissions: permissions_string(metadata),
+            hash,
+        });
+    }
+
+    /**
+     * Segna il backup come parziale
+     *
+     * @param not_saved: file non salvati per il limite di tempo, con la loro dimensione
+     * @param excluded:  file esclusi per lo spazio insufficiente della destinazione, con la loro dimensione
+     * @param source:    cartella sorgente del backup
+     */
+    pub fn set_partial(&mut self, not_saved: &[(PathBuf, u64)], excluded: &[(PathBuf, u64)], source: &Path) {
+        self.complete = false;
+        self.not_saved = missing_files(not_saved, source);
+        self.excluded = missing_files(excluded, source);
+    }
+
+    /**
+     * Salva il manifest in formato JSON nella cartella del backup
+     *
+     * Se il backup è cifrato, anche il manifest viene salvato cifrato, perché contiene i nomi dei file
+     *
+     * @param dir:    cartella del backup
+     * @param cipher: chiave del backup, se il backup è cifrato
+     */
+    pub fn save(&self, dir: &Path, cipher: Option<&BackupCipher>) -> io::Result<()> {
+        if let Some(cipher) = cipher {
+            return cipher.write_encrypted(MANIFEST_FILE, &serde_json::to_vec_pretty(self)?);
+        }
+        let mut writer = BufWriter::new(File::create(dir.join(MANIFEST_FILE))?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+}
+
+/**
+ * Costruisce il manifest del backup appena copiato nella cartella di staging.
+ * L'hash viene calcolato rileggendo i file dalla destinazione, così da descrivere ciò che è stato effettivamente scritto.
+ * Per i file invariati rispetto al backup precedente della stessa sorgente (stessa dimensione e data di modifica) l'hash
+ * viene preso dal suo manifest: i file clonati o copiati dal kernel non vengono riletti
+ *
+ * @param staging: cartella di staging che contiene il backup
+ * @param copied:  file copiati (percorso di destinazione -> percorso sorgente)
+ * @param options: opzioni del backup (vedi start_backup)
+ */
+pub fn build_manifest(staging: &Path, copied: &HashMap<PathBuf, PathBuf>, options: &[String]) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new(options, OutputFormat::Directory.name());
+
+    //Ordino i file per percorso, così che manifest di backup diversi siano confrontabili
+    let mut entries: Vec<(&PathBuf, &PathBuf)> = copied.iter().collect();
+    entries.sort();
+
+    //Hash dei file nel backup precedente, indicizzati per percorso nella sorgente
+    let previous: HashMap<String, ManifestEntry> = match load_manifest(Path::new(&options[2])) {
+        Ok(previous) if previous.source == options[1] && previous.format == OutputFormat::Directory.name() => {
+            previous.files.into_iter().map(|entry| (entry.source.clone(), entry)).collect()
+        }
+        _ => HashMap::new(),
+    };
+
+    for (dest_path, src_path) in entries {
+        let metadata = src_path.metadata()?;
+        let size = dest_path.metadata()?.len();
+        let source = relative_path(src_path, Path::new(&options[1]));
+        let mtime = metadata.modified().map(|t| DateTime::<Utc>::from(t).to_rfc3339()).unwrap_or_default();
+        let hash = match previous.get(&source) {
+            Some(entry) if entry.size == size && entry.mtime == mtime && !mtime.is_empty() => entry.hash.clone(),
+            _ => hash_file(dest_path)?,
+        };
+        manifest.add(relative_path(dest_path, staging), source, &metadata, size, hash);
+    }
+
+    Ok(manifest)
+}
+
+/**
+ * Elenca i file copiati in modalità "Folder": ogni file della cartella di staging corrisponde al file con lo stesso percorso relativo nella sorgente
+ *
+ * @param staging: cartella di staging che contiene il backup
+ * @param src:     cartella sorgente del backup
+ */
+pub fn list_copied_folder(staging: &Path, src: &Path) -> io::Result<HashMap<PathBuf, PathBuf>> {
+    let mut copied = HashMap::new();
+    let mut stack = vec![staging.to_path_buf()];
+
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {    //Le cartelle collegate (preserve_metadata) non vengono seguite
+                stack.push(path);
+            } else if !file_type.is_symlink() {    //I collegamenti simbolici sono copiati come collegamenti e non hanno un contenuto proprio
+                let relative = path.strip_prefix(staging).unwrap().to_path_buf();
+                let source = src.join(&relative);
+                if source.is_file() {   //Esclude i file scritti dal programma (marcatore, log)
+                    copied.insert(path, source);
+                }
+            }
+        }
+    }
+
+    Ok(copied)
+}
+
+/**
+ * Calcola l'hash BLAKE3 del contenuto di un file, in formato esadecimale
+ *
+ * @param path: percorso del file
+ */
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        throttle();
+        limit(n as u64);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/**
+ * Legge il manifest di un backup, se presente
+ *
+ * @param dir: cartella del backup
+ */
+pub fn load_manifest(dir: &Path) -> io::Result<Manifest> {
+    let file = File::open(dir.join(MANIFEST_FILE))?;
+    Ok(serde_json::from_reader(io::BufReader::new(file))?)
+}
+
+/**
+ * Percorso relativo con separatore '/', usato nel manifest
+ *
+ * @param path: percorso del file
+ * @param base: cartella rispetto a cui calcolare il percorso relativo
+ */
+//I manifest scritti prima dell'introduzione di complete descrivono backup completi
+fn default_complete() -> bool {
+    true
+}
+
+//Voci del manifest dei file non salvati, con il percorso relativo alla sorgente
+fn missing_files(files: &[(PathBuf, u64)], source: &Path) -> Vec<MissingFile> {
+    files.iter().map(|(path, size)| MissingFile { path: relative_path(path, source), size: *size }).collect()
+}
+
+pub fn relative_path(path: &Path, base: &Path) -> String {
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/**
+ * Verifica un percorso relativo letto da un file del backup (es. i nomi cifrati) prima di unirlo a una cartella: un backup manomesso
+ * non deve poter scrivere fuori dalla cartella. Sono ammessi solo nomi normali separati da '/' (niente "..", ".", radice o lettera di unità);
+ * su Windows, dove sono separatori o indicano un'unità, anche '\' e ':' non sono ammessi
+ *
+ * @param path: percorso relativo
+ * @return      Err(InvalidData) se il percorso non è valido
+ */
+pub fn check_relative_path(path: &str) -> io::Result<()> {
+    let valid = path.split('/').all(|c| !c.is_empty() && c != ".")
+        && !(cfg!(windows) && path.contains(['\\', ':']))
+        && Path::new(path).components().all(|c| matches!(c, Component::Normal(_)));
+    match valid {
+        true => Ok(()),
+        false => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Percorso non valido nel backup: {}", path))),
+    }
+}
+
+fn permissions_string(metadata: &fs::Metadata) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        format!("{:o}", metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.permissions().readonly() { "readonly".to_string() } else { "readwrite".to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_backups_list_the_files_not_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = vec!["F".to_string(), "/home/utente/Documenti".to_string(), dir.path().to_string_lossy().to_string()];
+        let mut manifest = Manifest::new(&options, "directory");
+        let excluded = [(PathBuf::from("/home/utente/Documenti/iso/disco.iso"), 700_000_000)];
+        manifest.set_partial(&[(PathBuf::from("/home/utente/Documenti/video/grande.mkv"), 5_000_000)], &excluded, Path::new(&options[1]));
+        manifest.save(dir.path(), None).unwrap();
+
+        let saved: Manifest = serde_json::from_slice(&fs::read(dir.path().join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert!(!saved.complete);
+        assert_eq!(saved.not_saved, vec![MissingFile { path: "video/grande.mkv".to_string(), size: 5_000_000 }]);
+        assert_eq!(saved.excluded, vec![MissingFile { path: "iso/disco.iso".to_string(), size: 700_000_000 }]);
+
+        //Un manifest senza i nuovi campi descrive un backup completo
+        let old: Manifest = serde_json::from_str(r#"{"source": "/s", "destination": "/d", "profile": "Folder", "created": "",
+            "hash_algorithm": "blake3", "total_files": 0, "total_size": 0, "files": []}"#).unwrap();
+        assert!(old.complete && old.not_saved.is_empty() && old.excluded.is_empty());
+    }
+
+    #[test]
+    fn unchanged_files_reuse_the_previous_hash() {
+        let src = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let staging = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("a.txt"), b"contenuto").unwrap();
+        fs::write(staging.path().join("a.txt"), b"contenuto").unwrap();
+        let options = vec!["F".to_string(), src.path().to_string_lossy().to_string(), dest.path().to_string_lossy().to_string()];
+        let copied = HashMap::from([(staging.path().join("a.txt"), src.path().join("a.txt"))]);
+
+        let mut previous = build_manifest(staging.path(), &copied, &options).unwrap();
+        assert_eq!(previous.files[0].hash, hash_file(&staging.path().join("a.txt")).unwrap());
+        //Un hash diverso nel manifest precedente dimostra che il file non è stato riletto
+        previous.files[0].hash = "precedente".to_string();
+        previous.save(dest.path(), None).unwrap();
+        assert_eq!(build_manifest(staging.path(), &copied, &options).unwrap().files[0].hash, "precedente");
+
+        //Se il file cambia dimensione l'hash viene ricalcolato
+        fs::write(src.path().join("a.txt"), b"contenuto modificato").unwrap();
+        fs::write(staging.path().join("a.txt"), b"contenuto modificato").unwrap();
+        let manifest = build_manifest(staging.path(), &copied, &options).unwrap();
+        assert_eq!(manifest.files[0].hash, hash_file(&staging.path().join("a.txt")).unwrap());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+//Metadati che la destinazione non può rappresentare (es. permessi e date precise su FAT32) e collegamenti simbolici, scritti accanto al manifest
+pub const METADATA_FILE: &str = "backup_metadata.json";
+
+/**
+ * Metadati di un file o di una cartella che la destinazione non ha conservato. Sono presenti solo i campi persi
+ */
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct MetadataRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,                //Destinazione del collegamento simbolico (i collegamenti sono registrati sempre)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,                      //Permessi Unix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,                       //Proprietario
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,                       //Gruppo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<(i64, u32)>,              //Data di ultima modifica (secondi e nanosecondi dal 1/1/1970)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<(i64, u32)>,              //Data di ultimo accesso
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,       //Attributi estesi (comprese le ACL POSIX), con il valore in esadecimale
+}
+
+impl MetadataRecord {
+    pub fn is_empty(&self) -> bool {
+        *self == MetadataRecord::default()
+    }
+}
+
+/**
+ * Contenuto del file METADATA_FILE: percorso relativo alla cartella del backup (separatore '/') -> metadati
+ */
+#[derive(Serialize, Deserialize, Default)]
+pub struct MetadataSidecar {
+    pub files: BTreeMap<String, MetadataRecord>,
+}
+
+impl MetadataSidecar {
+    /**
+     * Legge i metadati di un backup. Se il backup non contiene METADATA_FILE ritorna un elenco vuoto
+     *
+     * @param dir: cartella del backup
+     */
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        match File::open(dir.join(METADATA_FILE)) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file)).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(MetadataSidecar::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+     * Scrive i metadati nella cartella del backup, solo se ce ne sono
+     *
+     * @param dir: cartella del backup
+     */
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+        let mut writer = BufWriter::new(File::create(dir.join(METADATA_FILE))?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+
+    pub fn symlink_count(&self) -> usize {
+        self.files.values().filter(|r| r.symlink.is_some()).count()
+    }
+
+    //Ritorna i percorsi dei collegamenti simbolici registrati, da creare una volta sola prima di verificare molti percorsi
+    pub fn symlinks(&self) -> Symlinks {
+        Symlinks(self.files.iter().filter(|(_, r)| r.symlink.is_some()).map(|(k, _)| k.clone()).collect())
+    }
+}
+
+/**
+ * Percorsi relativi dei collegamenti simbolici registrati in un MetadataSidecar
+ */
+pub struct Symlinks(HashSet<String>);
+
+impl Symlinks {
+    //Ritorna true se il percorso (separatore '/') è un collegamento simbolico registrato, o si trova all'interno di una cartella collegata
+    pub fn contains(&self, key: &str) -> bool {
+        !self.0.is_empty() && (self.0.contains(key) || key.match_indices('/').any(|(i, _)| self.0.contains(&key[..i])))
+    }
+}
+
+/**
+ * Copia i metadati di un file o di una cartella: date, permessi, proprietario (solo se il programma ha i privilegi necessari) e attributi estesi.
+ * Dopo la copia i metadati vengono riletti dalla destinazione: ritorna quelli che la destinazione non ha conservato (vuoto se li ha conservati tutti)
+ *
+ * @param src:          percorso del file sorgente
+ * @param src_metadata: metadati del file sorgente, letti prima della copia
+ * @param dst:          percorso della copia
+ */
+#[allow(unused_variables)]
+pub fn copy_metadata(src: &Path, src_metadata: &fs::Metadata, dst: &Path) -> MetadataRecord {
+    let mut record = MetadataRecord::default();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        //Su Linux anche le ACL POSIX sono attributi estesi ("system.posix_acl_access" e "system.posix_acl_default")
+        if let Ok(names) = xattr::list(src) {
+            for name in names {
+                if let Ok(Some(value)) = xattr::get(src, &name) {
+                    if xattr::set(dst, &name, &value).is_err() {
+                        record.xattrs.insert(name.to_string_lossy().to_string(), value.iter().map(|b| format!("{:02x}", b)).collect());
+                    }
+                }
+            }
+        }
+        //Cambio il proprietario prima dei permessi, perché chown azzera i bit setuid/setgid
+        let _ = std::os::unix::fs::chown(dst, Some(src_metadata.uid()), Some(src_metadata.gid()));
+        let _ = fs::set_permissions(dst, fs::Permissions::from_mode(src_metadata.mode() & 0o7777));
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = fs::set_permissions(dst, src_metadata.permissions());
+    }
+    let atime = FileTime::from_last_access_time(src_metadata);
+    let mtime = FileTime::from_last_modification_time(src_metadata);
+    let _ = filetime::set_file_times(dst, atime, mtime);
+
+    let written = fs::metadata(dst).ok();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if written.as_ref().is_none_or(|w| w.uid() != src_metadata.uid() || w.gid() != src_metadata.gid()) {
+            record.uid = Some(src_metadata.uid());
+            record.gid = Some(src_metadata.gid());
+        }
+        if written.as_ref().is_none_or(|w| w.mode() & 0o7777 != src_metadata.mode() & 0o7777) {
+            record.mode = Some(src_metadata.mode() & 0o7777);
+        }
+    }
+    if written.as_ref().is_none_or(|w| FileTime::from_last_modification_time(w) != mtime) {
+        record.mtime = Some((mtime.unix_seconds(), mtime.nanoseconds()));
+    }
+    if written.as_ref().is_none_or(|w| FileTime::from_last_access_time(w) != atime) {
+        record.atime = Some((atime.unix_seconds(), atime.nanoseconds()));
+    }
+    record
+}
+
+/**
+ * Copia un collegamento simbolico come collegamento, senza seguirlo.
+ * Ritorna i metadati del collegamento, da registrare in METADATA_FILE anche se la destinazione non supporta i collegamenti (es. FAT32)
+ *
+ * @param src: percorso del collegamento
+ * @param dst: percorso della copia
+ */
+pub fn copy_symlink(src: &Path, dst: &Path) -> io::Result<MetadataRecord> {
+    let target = fs::read_link(src)?;
+    #[cfg(unix)]
+    let created = std::os::unix::fs::symlink(&target, dst);
+    #[cfg(windows)]
+    let created = if src.is_dir() { std::os::windows::fs::symlink_dir(&target, dst) } else { std::os::windows::fs::symlink_file(&target, dst) };
+    if created.is_ok() {
+        let metadata = fs::symlink_metadata(src)?;
+        let _ = filetime::set_symlink_file_times(dst, FileTime::from_last_access_time(&metadata), FileTime::from_last_modification_time(&metadata));
+    }
+    Ok(MetadataRecord { symlink: Some(target.to_string_lossy().to_string()), ..Default::default() })
+}
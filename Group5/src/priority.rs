@@ -0,0 +1,204 @@
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SMALL_FILE_SIZE: u64 = 1024 * 1024;  //Dimensione massima dei file della regola "small"
+pub const RECENT_DAYS: u64 = 7;                 //Giorni dall'ultima modifica dei file della regola "recent"
+
+//Estensioni dei file (documenti, fogli di calcolo, archivi di password, ...) della regola "documents"
+pub const DOCUMENT_EXTENSIONS: [&str; 20] = ["txt", "md", "rtf", "pdf", "doc", "docx", "odt", "xls", "xlsx", "ods", "csv", "ppt", "pptx", "odp", "tex", "kdbx", "pem", "key", "vcf", "ics"];
+
+/**
+ * Regola con cui vengono scelti i file copiati per primi
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PriorityRule {
+    Critical,   //File nei percorsi dell'impostazione priority_paths (nell'ordine in cui sono elencati)
+    Documents,  //Documenti (estensioni di DOCUMENT_EXTENSIONS)
+    Recent,     //File modificati negli ultimi priority_recent_days giorni (dal più recente)
+    Small,      //File fino a priority_small_size byte (dal più piccolo)
+}
+
+impl PriorityRule {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "critical" => Some(PriorityRule::Critical),
+            "documents" => Some(PriorityRule::Documents),
+            "recent" => Some(PriorityRule::Recent),
+            "small" => Some(PriorityRule::Small),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Ordine di copia dei file, scelto in modo che un backup interrotto in qualsiasi momento contenga i dati più utili.
+ * Ogni file appartiene alla classe della prima regola che soddisfa (i file che non ne soddisfano nessuna vengono copiati per ultimi);
+ * a parità di classe i file vengono ordinati secondo le regole stesse (percorsi critici nell'ordine indicato, più recenti, più piccoli)
+ */
+#[derive(Clone, Debug)]
+pub struct PriorityRules {
+    pub rules: Vec<PriorityRule>,   //Regole nell'ordine di importanza (impostazione priority). Se è vuoto i file vengono copiati nell'ordine in cui sono trovati
+    pub critical: Vec<PathBuf>,     //Percorsi critici, relativi alla sorgente (impostazione priority_paths)
+    pub recent_days: u64,           //Impostazione priority_recent_days
+    pub small_size: u64,            //Impostazione priority_small_size
+}
+
+impl Default for PriorityRules {
+    fn default() -> Self {
+        PriorityRules { rules: Vec::new(), critical: Vec::new(), recent_days: RECENT_DAYS, small_size: SMALL_FILE_SIZE }
+    }
+}
+
+impl PriorityRules {
+    //Regole della modalità salvataggio senza l'impostazione priority: prima i file piccoli (letti in fretta anche da un disco danneggiato), poi i documenti
+    pub fn salvage(&self) -> Self {
+        PriorityRules { rules: vec![PriorityRule::Small, PriorityRule::Documents], ..self.clone() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /**
+     * Ordina i file da copiare secondo le regole
+     *
+     * @param files: file da copiare (percorso relativo di destinazione, percorso sorgente)
+     * @param src:   cartella sorgente, a cui sono relativi i percorsi critici
+     */
+    pub fn order<T>(&self, files: &mut [(T, T)], src: &Path) where T: AsRef<Path> {
+        files.sort_by_cached_key(|(_, path)| {
+            let path = path.as_ref();
+            match path.metadata() {
+                Ok(metadata) => self.sort_key(path.strip_prefix(src).unwrap_or(path), &metadata),
+                Err(_) => vec![u64::MAX],
+            }
+        });
+    }
+
+    //Chiave di ordinamento di un file (classe, poi il valore di ogni regola): i file da copiare prima hanno la chiave minore
+    fn sort_key(&self, relative: &Path, metadata: &Metadata) -> Vec<u64> {
+        let class = self.rules.iter().position(|rule| self.matches(*rule, relative, metadata)).unwrap_or(self.rules.len());
+        let mut key = vec![class as u64];
+        for rule in &self.rules {
+            key.push(match rule {
+                PriorityRule::Critical => self.critical.iter().position(|p| relative.starts_with(p)).unwrap_or(self.critical.len()) as u64,
+                PriorityRule::Documents => !is_document(relative) as u64,
+                PriorityRule::Recent => u64::MAX - modified_secs(metadata),
+                PriorityRule::Small => metadata.len(),
+            });
+        }
+        key
+    }
+
+    fn matches(&self, rule: PriorityRule, relative: &Path, metadata: &Metadata) -> bool {
+        match rule {
+            PriorityRule::Critical => self.critical.iter().any(|p| relative.starts_with(p)),
+            PriorityRule::Documents => is_document(relative),
+            PriorityRule::Recent => metadata.modified().ok().and_then(|m| m.elapsed().ok()).is_some_and(|age| age <= Duration::from_secs(self.recent_days * 24 * 3600)),
+            PriorityRule::Small => metadata.len() <= self.small_size,
+        }
+    }
+}
+
+fn is_document(path: &Path) -> bool {
+    path.extension().is_some_and(|e| DOCUMENT_EXTENSIONS.iter().any(|d| e.eq_ignore_ascii_case(d)))
+}
+
+fn modified_secs(metadata: &Metadata) -> u64 {
+    metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH).duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use filetime::{set_file_mtime, FileTime};
+
+    //File di prova: percorso relativo, dimensione, secondi dall'ultima modifica (None: file del 2020)
+    const FILES: [(&str, usize, Option<u64>); 10] = [
+        ("video/vecchio.mkv", 2_000_000, None),
+        ("cache/medio.bin", 500_000, None),
+        ("cache/piccolo.bin", 10, None),
+        ("foto/recente2.jpg", 2_000_000, Some(3600)),
+        ("foto/recente.jpg", 2_000_000, Some(60)),
+        ("lettera.docx", 2_000_000, None),
+        ("progetti/tesina.txt", 50, None),
+        ("mail/inbox.mbox", 3, None),
+        ("progetti/tesi/dati.bin", 2_000_000, None),
+        ("progetti/tesi/capitolo.pdf", 100, Some(60)),
+    ];
+
+    //Crea i file nella cartella e ritorna l'elenco da ordinare (percorso relativo, percorso sorgente), in ordine alfabetico
+    fn create_files(dir: &Path) -> Vec<(PathBuf, PathBuf)> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut files = Vec::new();
+        for (name, size, age) in FILES {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, vec![0u8; size]).unwrap();
+            let mtime = age.map_or(1_577_836_800, |age| now - age); //1 gennaio 2020
+            set_file_mtime(&path, FileTime::from_unix_time(mtime as i64, 0)).unwrap();
+            files.push((PathBuf::from(name), path));
+        }
+        files.sort();
+        files
+    }
+
+    fn names(files: &[(PathBuf, PathBuf)]) -> Vec<&str> {
+        files.iter().map(|(name, _)| name.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn orders_by_class_then_rule_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = create_files(dir.path());
+        let rules = PriorityRules {
+            rules: vec![PriorityRule::Critical, PriorityRule::Documents, PriorityRule::Recent, PriorityRule::Small],
+            critical: vec![PathBuf::from("progetti/tesi"), PathBuf::from("mail")],
+            ..Default::default()
+        };
+        rules.order(&mut files, dir.path());
+
+        assert_eq!(names(&files), vec![
+            //Percorsi critici, nell'ordine di priority_paths (poi i documenti)
+            "progetti/tesi/capitolo.pdf", "progetti/tesi/dati.bin", "mail/inbox.mbox",
+            //Documenti ("progetti/tesina.txt" non è in "progetti/tesi"), poi dal più piccolo
+            "progetti/tesina.txt", "lettera.docx",
+            //File recenti, dal più recente
+            "foto/recente.jpg", "foto/recente2.jpg",
+            //File piccoli, dal più piccolo
+            "cache/piccolo.bin", "cache/medio.bin",
+            //Nessuna regola
+            "video/vecchio.mkv",
+        ]);
+    }
+
+    #[test]
+    fn class_is_the_first_matching_rule() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = create_files(dir.path());
+        let rules = PriorityRules { rules: vec![PriorityRule::Small, PriorityRule::Documents, PriorityRule::Recent], ..Default::default() };
+        rules.order(&mut files, dir.path());
+
+        //Un documento piccolo e recente appartiene alla classe della prima regola elencata (small), un documento grande alla seconda
+        assert_eq!(names(&files), vec![
+            "mail/inbox.mbox", "cache/piccolo.bin", "progetti/tesina.txt", "progetti/tesi/capitolo.pdf", "cache/medio.bin",
+            "lettera.docx",
+            "foto/recente.jpg", "foto/recente2.jpg",
+            "progetti/tesi/dati.bin", "video/vecchio.mkv",
+        ]);
+
+        //Senza regole l'ordine non cambia
+        let mut unordered = create_files(dir.path());
+        PriorityRules::default().order(&mut unordered, dir.path());
+        assert_eq!(unordered, create_files(dir.path()));
+
+        //I file che non è più possibile leggere vengono copiati per ultimi
+        let mut files = create_files(dir.path());
+        fs::remove_file(dir.path().join("mail/inbox.mbox")).unwrap();
+        rules.order(&mut files, dir.path());
+        assert_eq!(names(&files)[..2], ["cache/piccolo.bin", "progetti/tesina.txt"]);
+        assert_eq!(names(&files).last(), Some(&"mail/inbox.mbox"));
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use crate::bandwidth::limit;
+use crate::cpu_governor::throttle;
+
+const BLOCK_SIZE: u64 = 4096;                       //Granularità con cui vengono letti i dati attorno a una zona illeggibile
+const RETRIES: u32 = 3;                             //Tentativi ripetuti dopo una lettura non riuscita, con attesa crescente
+const BACKOFF: Duration = Duration::from_millis(50); //Attesa prima del primo tentativo ripetuto (raddoppiata a ogni tentativo)
+const CAREFUL_SPAN: u64 = 1024 * 1024;              //Dopo un errore la zona successiva viene letta un blocco alla volta (ed è il salto massimo in una zona illeggibile)
+const GIVE_UP_SPAN: u64 = 16 * 1024 * 1024;         //Dopo questi byte consecutivi illeggibili il resto del file non viene più letto (il disco non risponde più)
+
+/**
+ * Lettore di un file su un disco danneggiato. Le letture non riuscite vengono ripetute con attesa crescente;
+ * se continuano a non riuscire la zona viene letta un blocco alla volta e i blocchi illeggibili vengono sostituiti da zeri.
+ * Legge da qualsiasi sorgente Read + Seek: nei test una sorgente in memoria simula il disco danneggiato
+ */
+pub struct SalvageReader<R: Read + Seek = File> {
+    file: R,
+    len: u64,                   //Dimensione del file all'apertura
+    pos: u64,                   //Posizione di lettura
+    careful_until: u64,         //Fino a questa posizione il file viene letto un blocco alla volta
+    skip_until: u64,            //Fino a questa posizione il file non viene letto, ma riempito di zeri (zona illeggibile estesa)
+    unreadable: u64,            //Byte illeggibili consecutivi
+    seek: bool,                 //Se true, prima della prossima lettura va ripristinata la posizione (dopo un errore non è definita)
+    pub lost: Vec<(u64, u64)>,  //Zone illeggibili sostituite da zeri (inizio, fine), in byte
+}
+
+impl SalvageReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(SalvageReader::new(file, len))
+    }
+}
+
+impl<R: Read + Seek> SalvageReader<R> {
+    pub fn new(file: R, len: u64) -> Self {
+        SalvageReader { file, len, pos: 0, careful_until: 0, skip_until: 0, unreadable: 0, seek: false, lost: Vec::new() }
+    }
+
+    //Legge dalla posizione corrente, ripetendo la lettura fino a attempts volte
+    fn read_at(&mut self, buf: &mut [u8], attempts: u32) -> io::Result<usize> {
+        let mut delay = BACKOFF;
+        let mut attempt = 1;
+        loop {
+            if self.seek {
+                self.file.seek(SeekFrom::Start(self.pos))?;
+                self.seek = false;
+            }
+            match self.file.read(buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if attempt >= attempts || e.kind() == io::ErrorKind::NotFound => {
+                    self.seek = true;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.seek = true;
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    //Sostituisce con zeri i byte di buf (dalla posizione corrente), registrandoli come persi
+    fn lose(&mut self, buf: &mut [u8]) -> usize {
+        buf.fill(0);
+        let n = buf.len() as u64;
+        match self.lost.last_mut() {
+            Some(last) if last.1 == self.pos => last.1 += n,
+            _ => self.lost.push((self.pos, self.pos + n)),
+        }
+        self.pos += n;
+        self.unreadable += n;
+        self.seek = true;
+        buf.len()
+    }
+}
+
+impl<R: Read + Seek> Read for SalvageReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let want = buf.len().min((self.len - self.pos).min(usize::MAX as u64) as usize);
+        //Il disco non risponde più: riempio di zeri il resto del file senza leggerlo
+        if self.unreadable >= GIVE_UP_SPAN {
+            self.skip_until = self.len;
+        }
+        if self.pos < self.skip_until {
+            let size = want.min((self.skip_until - self.pos).min(usize::MAX as u64) as usize);
+            return Ok(self.lose(&mut buf[..size]));
+        }
+        let careful = self.pos < self.careful_until;
+        let size = if careful { want.min((BLOCK_SIZE - self.pos % BLOCK_SIZE) as usize) } else { want };
+        match self.read_at(&mut buf[..size], if careful { 2 } else { 1 + RETRIES }) {
+            Ok(n) => {
+                self.pos += n as u64;   //0 se il file si è accorciato: la lettura termina
+                self.unreadable = 0;
+                Ok(n)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(e),
+            Err(_) if !careful => {
+                self.careful_until = self.pos + CAREFUL_SPAN;
+                self.read(buf)
+            }
+            //Ogni nuovo blocco illeggibile fa saltare una zona grande quanto quella già persa (fino a CAREFUL_SPAN):
+            //un'area danneggiata estesa viene superata con poche letture, invece di bloccare il backup su ogni blocco
+            Err(_) => {
+                let skip = self.unreadable.min(CAREFUL_SPAN) / BLOCK_SIZE * BLOCK_SIZE;
+                let n = self.lose(&mut buf[..size]);
+                self.skip_until = (self.pos + skip).min(self.len);
+                self.careful_until = self.skip_until + CAREFUL_SPAN;
+                Ok(n)
+            }
+        }
+    }
+}
+
+/**
+ * Copia un file da un disco danneggiato (vedi SalvageReader)
+ *
+ * @param src: percorso del file sorgente
+ * @param dst: percorso della copia
+ * @return     le zone illeggibili riempite di zeri (inizio, fine), vuoto se il file è stato copiato per intero
+ */
+pub fn copy_file_salvage(src: &Path, dst: &Path) -> io::Result<Vec<(u64, u64)>> {
+    let mut input = SalvageReader::open(src)?;
+    let permissions = input.file.metadata()?.permissions();
+    let mut output = BufWriter::new(File::create(dst)?);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        output.write_all(&buffer[..n])?;
+        throttle();
+        limit(n as u64);
+    }
+    output.flush()?;
+    output.get_ref().set_permissions(permissions)?;
+    if !input.lost.is_empty() {
+        eprintln!("File copiato in parte: {:?} ({} byte illeggibili)", src, lost_bytes(&input.lost));
+    }
+    Ok(input.lost)
+}
+
+//Numero totale di byte delle zone illeggibili
+pub fn lost_bytes(lost: &[(u64, u64)]) -> u64 {
+    lost.iter().map(|(start, end)| end - start).sum()
+}
+
+//Descrizione delle zone illeggibili, riportata nel log del backup (es. "4096-8192, 1048576-1052672")
+pub fn format_ranges(lost: &[(u64, u64)]) -> String {
+    lost.iter().map(|(start, end)| format!("{}-{}", start, end)).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::test_data::content;
+
+    //Sorgente in memoria le cui letture non riescono se toccano una delle zone danneggiate
+    struct FaultyReader {
+        data: Cursor<Vec<u8>>,
+        bad: Vec<(u64, u64)>,
+    }
+
+    impl Read for FaultyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let start = self.data.position();
+            let end = start + buf.len() as u64;
+            if self.bad.iter().any(|&(bad_start, bad_end)| start < bad_end && bad_start < end) {
+                return Err(io::Error::other("settore illeggibile"));
+            }
+            self.data.read(buf)
+        }
+    }
+
+    impl Seek for FaultyReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    //Legge tutto il file a blocchi da 64 KiB, come copy_file_salvage
+    fn salvage(data: &[u8], bad: Vec<(u64, u64)>) -> (Vec<u8>, Vec<(u64, u64)>) {
+        let mut reader = SalvageReader::new(FaultyReader { data: Cursor::new(data.to_vec()), bad }, data.len() as u64);
+        let mut output = Vec::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buffer).unwrap();
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&buffer[..n]);
+        }
+        (output, reader.lost)
+    }
+
+    #[test]
+    fn unreadable_block_is_zero_filled() {
+        let data = content(1, 128 * 1024);
+        let (output, lost) = salvage(&data, vec![(8192, 12288)]);
+        assert_eq!(lost, vec![(8192, 12288)]);
+        assert_eq!(lost_bytes(&lost), 4096);
+        assert_eq!(output.len(), data.len());
+        assert!(output[8192..12288].iter().all(|&b| b == 0));
+        assert_eq!(output[..8192], data[..8192]);
+        assert_eq!(output[12288..], data[12288..]);
+    }
+
+    #[test]
+    fn unreadable_area_is_skipped_with_growing_jumps() {
+        let data = content(1, 128 * 1024);
+        //Quattro blocchi illeggibili: dopo il secondo la zona saltata cresce con quella già persa e va oltre la fine del danno
+        let (output, lost) = salvage(&data, vec![(8192, 24576)]);
+        assert_eq!(lost, vec![(8192, 36864)]);
+        assert_eq!(lost_bytes(&lost), 28672);
+        assert_eq!(format_ranges(&lost), "8192-36864");
+        assert!(output[8192..36864].iter().all(|&b| b == 0));
+        assert_eq!(output[..8192], data[..8192]);
+        assert_eq!(output[36864..], data[36864..]);
+    }
+
+    #[test]
+    fn readable_file_is_copied_whole() {
+        let data = content(1, 100_000);
+        let (output, lost) = salvage(&data, Vec::new());
+        assert!(lost.is_empty());
+        assert_eq!(output, data);
+    }
+}
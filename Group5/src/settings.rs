@@ -0,0 +1,351 @@
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::archive::DEFAULT_STORE_EXTENSIONS;
+use crate::copy_pool::MAX_WORKERS;
+use crate::cpu_governor::{DEFAULT_BACKUP_LIMIT, DEFAULT_LISTENING_LIMIT};
+use crate::priority::{PriorityRule, PriorityRules};
+
+/**
+ * Politica da applicare quando, in modalità "appiattita", due file sorgente hanno lo stesso nome
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CollisionPolicy {
+    Overwrite,  //L'ultimo file copiato sovrascrive quello già presente
+    Skip,       //Il file già presente viene mantenuto, quello nuovo viene ignorato
+    KeepBoth,   //Vengono mantenuti entrambi, aggiungendo un suffisso numerico al nome del nuovo file
+    NewerWins,  //Viene mantenuto il file con la data di modifica più recente
+}
+
+/**
+ * Formato in cui viene scritto il backup nella destinazione
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputFormat {
+    Directory,  //I file vengono copiati uno per uno nella destinazione
+    TarZst,     //I file vengono scritti in un unico archivio tar compresso con zstd (archive::ARCHIVE_FILE)
+    Zip,        //I file vengono scritti in un archivio zip (archive::ZIP_FILE), eventualmente diviso in volumi, apribile su qualsiasi computer
+}
+
+impl OutputFormat {
+    //Nome del formato, usato nel file di configurazione e nel manifest del backup
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Directory => "directory",
+            OutputFormat::TarZst => "tar.zst",
+            OutputFormat::Zip => "zip",
+        }
+    }
+}
+
+/**
+ * Impostazioni di cifratura del backup (formato age). Il backup è cifrato se è presente la passphrase o almeno una chiave pubblica
+ */
+#[derive(Clone, Default)]
+pub struct EncryptionSettings {
+    pub passphrase: Option<String>, //Passphrase da cui viene derivata la chiave
+    pub recipients: Vec<String>,    //Chiavi pubbliche age ("age1..."); se presenti, la passphrase viene ignorata
+    pub encrypt_names: bool,        //Se true vengono nascosti anche i nomi dei file
+}
+
+impl EncryptionSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.passphrase.is_some() || !self.recipients.is_empty()
+    }
+}
+
+//La passphrase non deve comparire nei messaggi stampati dal programma
+impl fmt::Debug for EncryptionSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionSettings")
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "***"))
+            .field("recipients", &self.recipients)
+            .field("encrypt_names", &self.encrypt_names)
+            .finish()
+    }
+}
+
+/**
+ * Impostazioni avanzate del backup.
+ * Sono lette dal file di configurazione, nelle righe successive alla seconda, nel formato "chiave=valore".
+ * La GUI non le modifica, ma le mantiene quando viene salvata la configurazione.
+ */
+#[derive(Clone, Debug)]
+pub struct BackupSettings {
+    pub flatten: bool,                      //Se true, in modalità "Single files" tutti i file vengono copiati nella radice della destinazione, senza ricreare le sottocartelle
+    pub collision_policy: CollisionPolicy,  //Usata solo se flatten è true
+    pub format: OutputFormat,
+    pub compression_level: i32,             //Livello di compressione zstd, usato solo se format è TarZst
+    pub store_extensions: Vec<String>,      //Estensioni dei file già compressi, inseriti nell'archivio senza comprimerli
+    pub volume_size: Option<u64>,           //Dimensione massima di un volume dell'archivio zip. Su FAT32 l'archivio viene comunque diviso in volumi da 4 GB
+    pub encryption: EncryptionSettings,
+    pub parity: u8,                         //Percentuale di ridondanza dei dati di parità Reed-Solomon scritti nel backup (0 = nessun dato di parità)
+    pub preserve_metadata: bool,            //Se true conserva date, permessi, proprietario, attributi estesi e collegamenti simbolici (solo nel formato directory)
+    pub salvage: bool,                      //Modalità salvataggio per una sorgente danneggiata: letture ripetute, zone illeggibili riempite di zeri, file piccoli e documenti copiati per primi
+    pub workers: Option<usize>,             //Numero di file copiati in parallelo (da 1 a MAX_WORKERS). None ("auto"): scelto in base ai dischi di sorgente e destinazione
+    pub cpu_limit_idle: u8,                 //Limite di CPU mentre l'applicazione aspetta il comando di backup, in % della capacità totale (100 = nessun limite)
+    pub cpu_limit_backup: u8,               //Limite di CPU durante il backup, in % della capacità totale (100 = nessun limite)
+    pub bandwidth_limit: Option<u64>,       //Banda massima della copia in byte al secondo (in MB/s nel file di configurazione). None: nessun limite
+    pub bandwidth_burst: Option<u64>,       //Byte copiati senza pause dopo un periodo di inattività (in MB nel file di configurazione). None: quelli di un secondo
+    pub idle_io: bool,                      //Se true, su Linux il backup usa la classe di I/O idle: accede al disco solo quando nessun altro processo lo usa
+    pub priority: PriorityRules,            //Ordine di copia dei file (impostazioni priority, priority_paths, priority_recent_days, priority_small_size)
+    pub time_limit: Option<Duration>,       //Tempo a disposizione per un backup d'emergenza (es. "3m", "90s"; senza suffisso in minuti), vedi time_budget. None: nessun limite
+    pub best_fit: bool,                     //Se true e lo spazio della destinazione non basta, invece di annullare il backup vengono copiati i file più importanti che ci stanno (vedi space_budget)
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        BackupSettings {
+            flatten: false,
+            collision_policy: CollisionPolicy::KeepBoth,
+            format: OutputFormat::Directory,
+            compression_level: 3,
+            store_extensions: DEFAULT_STORE_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            volume_size: None,
+            encryption: EncryptionSettings::default(),
+            parity: 0,
+            preserve_metadata: false,
+            salvage: false,
+            workers: None,
+            cpu_limit_idle: DEFAULT_LISTENING_LIMIT,
+            cpu_limit_backup: DEFAULT_BACKUP_LIMIT,
+            bandwidth_limit: None,
+            bandwidth_burst: None,
+            idle_io: false,
+            priority: PriorityRules::default(),
+            time_limit: None,
+            best_fit: false,
+        }
+    }
+}
+
+/**
+ * Legge le impostazioni avanzate dal file di configurazione.
+ * Se il file non esiste, o una chiave è assente o non valida, viene usato il valore di default.
+ *
+ * @param filename: percorso del file di configurazione
+ */
+pub fn load_settings(filename: &str) -> BackupSettings {
+    let mut settings = BackupSettings::default();
+
+    let content = match read_to_string(filename) {
+        Ok(c) => c,
+        Err(_) => return settings,
+    };
+
+    //Le prime due righe contengono l'intestazione e la riga tipo;sorgente;destinazione
+    for line in content.lines().skip(2) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => {
+                eprintln!("Riga di configurazione ignorata: {}", line);
+                continue;
+            }
+        };
+
+        match key {
+            "flatten" => match parse_bool(value) {
+                Some(b) => settings.flatten = b,
+                None => eprintln!("Valore non valido per flatten: {}", value),
+            },
+            "collision" => match value {
+                "overwrite" => settings.collision_policy = CollisionPolicy::Overwrite,
+                "skip" => settings.collision_policy = CollisionPolicy::Skip,
+                "keep-both" => settings.collision_policy = CollisionPolicy::KeepBoth,
+                "newer-wins" => settings.collision_policy = CollisionPolicy::NewerWins,
+                _ => eprintln!("Valore non valido per collision: {}", value),
+            },
+            "format" => match value {
+                "directory" => settings.format = OutputFormat::Directory,
+                "tar.zst" => settings.format = OutputFormat::TarZst,
+                "zip" => settings.format = OutputFormat::Zip,
+                _ => eprintln!("Valore non valido per format: {}", value),
+            },
+            "compression_level" => match value.parse::<i32>() {
+                Ok(level) if zstd::compression_level_range().contains(&level) => settings.compression_level = level,
+                _ => eprintln!("Valore non valido per compression_level: {}", value),
+            },
+            "volume_size" => match parse_size(value) {
+                Some(size) if size > 0 => settings.volume_size = Some(size),
+                _ => eprintln!("Valore non valido per volume_size: {}", value),
+            },
+            "passphrase" if !value.is_empty() => settings.encryption.passphrase = Some(value.to_string()),
+            "recipient" if !value.is_empty() => settings.encryption.recipients.push(value.to_string()),
+            "encrypt_names" => match parse_bool(value) {
+                Some(b) => settings.encryption.encrypt_names = b,
+                None => eprintln!("Valore non valido per encrypt_names: {}", value),
+            },
+            "parity" => match value.trim_end_matches('%').parse::<u8>() {
+                Ok(p) if p <= 100 => settings.parity = p,
+                _ => eprintln!("Valore non valido per parity: {}", value),
+            },
+            "preserve_metadata" => match parse_bool(value) {
+                Some(b) => settings.preserve_metadata = b,
+                None => eprintln!("Valore non valido per preserve_metadata: {}", value),
+            },
+            "salvage" => match parse_bool(value) {
+                Some(b) => settings.salvage = b,
+                None => eprintln!("Valore non valido per salvage: {}", value),
+            },
+            "workers" => match value {
+                "auto" => settings.workers = None,
+                _ => match value.parse::<usize>() {
+                    Ok(n) if (1..=MAX_WORKERS).contains(&n) => settings.workers = Some(n),
+                    _ => eprintln!("Valore non valido per workers: {}", value),
+                },
+            },
+            "cpu_limit_idle" | "cpu_limit_backup" => match value.trim_end_matches('%').parse::<u8>() {
+                Ok(p) if (1..=100).contains(&p) => match key {
+                    "cpu_limit_idle" => settings.cpu_limit_idle = p,
+                    _ => settings.cpu_limit_backup = p,
+                },
+                _ => eprintln!("Valore non valido per {}: {}", key, value),
+            },
+            "bandwidth_limit" => match parse_rate(value) {
+                Some(0) => settings.bandwidth_limit = None,
+                Some(r) => settings.bandwidth_limit = Some(r),
+                None => eprintln!("Valore non valido per bandwidth_limit: {}", value),
+            },
+            "bandwidth_burst" => match parse_rate(value) {
+                Some(b) if b > 0 => settings.bandwidth_burst = Some(b),
+                _ => eprintln!("Valore non valido per bandwidth_burst: {}", value),
+            },
+            "idle_io" => match parse_bool(value) {
+                Some(b) => settings.idle_io = b,
+                None => eprintln!("Valore non valido per idle_io: {}", value),
+            },
+            "priority" => {
+                let rules: Vec<&str> = value.split(|c: char| c == ',' || c.is_whitespace()).filter(|r| !r.is_empty() && *r != "none").collect();
+                match rules.iter().map(|r| PriorityRule::parse(r)).collect::<Option<Vec<_>>>() {
+                    Some(rules) => settings.priority.rules = rules,
+                    None => eprintln!("Valore non valido per priority (regole: critical, documents, recent, small): {}", value),
+                }
+            }
+            "priority_paths" => {
+                settings.priority.critical = value.split(',')
+                    .map(|p| PathBuf::from(p.trim().trim_matches(|c| c == '/' || c == '\\')))
+                    .filter(|p| !p.as_os_str().is_empty())
+                    .collect();
+            }
+            "priority_recent_days" => match value.parse::<u64>() {
+                Ok(d) if d > 0 => settings.priority.recent_days = d,
+                _ => eprintln!("Valore non valido per priority_recent_days: {}", value),
+            },
+            "priority_small_size" => match parse_size(value) {
+                Some(s) => settings.priority.small_size = s,
+                None => eprintln!("Valore non valido per priority_small_size: {}", value),
+            },
+            "time_limit" => match value {
+                "none" | "0" => settings.time_limit = None,
+                _ => match parse_duration(value) {
+                    Some(d) => settings.time_limit = Some(d),
+                    None => eprintln!("Valore non valido per time_limit: {}", value),
+                },
+            },
+            "best_fit" => match parse_bool(value) {
+                Some(b) => settings.best_fit = b,
+                None => eprintln!("Valore non valido per best_fit: {}", value),
+            },
+            "store_extensions" => {
+                settings.store_extensions = value.split(|c: char| c == ',' || c.is_whitespace())
+                    .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                    .filter(|e| !e.is_empty())
+                    .collect();
+            }
+            _ => eprintln!("Impostazione sconosciuta: {}", key),
+        }
+    }
+
+    //L'archivio zip non supporta la cifratura age: un backup cifrato in formato zip viene scritto come tar.zst
+    if settings.encryption.is_enabled() && settings.format == OutputFormat::Zip {
+        eprintln!("Il formato zip non supporta la cifratura, verrà usato il formato tar.zst");
+        settings.format = OutputFormat::TarZst;
+    }
+
+    //Negli archivi date e permessi sono già salvati nell'intestazione di ogni file; i file cifrati non conservano i metadati
+    if settings.preserve_metadata && (settings.format != OutputFormat::Directory || settings.encryption.is_enabled()) {
+        eprintln!("preserve_metadata è supportato solo nel formato directory senza cifratura, verrà ignorato");
+        settings.preserve_metadata = false;
+    }
+
+    //Gli archivi e i file cifrati vengono letti dalle rispettive librerie, senza ripetere le letture non riuscite
+    if settings.salvage && (settings.format != OutputFormat::Directory || settings.encryption.is_enabled()) {
+        eprintln!("salvage è supportato solo nel formato directory senza cifratura, verrà ignorato");
+        settings.salvage = false;
+    }
+
+    //In modalità salvataggio, senza l'impostazione priority, vengono copiati per primi i file piccoli e poi i documenti
+    if settings.salvage && !settings.priority.is_enabled() {
+        settings.priority = settings.priority.salvage();
+    }
+    //Con un tempo a disposizione o con best_fit, senza l'impostazione priority, vengono copiati per primi i file più utili
+    if (settings.time_limit.is_some() || settings.best_fit) && !settings.priority.is_enabled() {
+        settings.priority.rules = vec![PriorityRule::Critical, PriorityRule::Documents, PriorityRule::Recent, PriorityRule::Small];
+    }
+    //Il calcolo della parità, eseguito dopo la copia, non rispetterebbe la scadenza
+    if settings.time_limit.is_some() && settings.parity > 0 {
+        eprintln!("parity non è supportato con time_limit, verrà ignorato");
+        settings.parity = 0;
+    }
+    if !settings.priority.critical.is_empty() && !settings.priority.rules.contains(&PriorityRule::Critical) {
+        eprintln!("priority_paths viene usato solo se priority contiene la regola critical, verrà ignorato");
+    }
+
+    //Gli archivi e i file cifrati vengono scritti in sequenza; su un disco danneggiato più letture contemporanee peggiorerebbero la situazione
+    if settings.workers.is_some_and(|n| n > 1) && (settings.format != OutputFormat::Directory || settings.encryption.is_enabled() || settings.salvage) {
+        eprintln!("workers è supportato solo nel formato directory senza cifratura e senza salvage, i file verranno copiati uno alla volta");
+        settings.workers = Some(1);
+    }
+
+    settings
+}
+
+//Legge una dimensione in byte, con suffisso opzionale K, M, G o T (multipli di 1024), ad esempio "650M" o "4G"
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (number, multiplier) = match value.chars().last()? {
+        'K' => (&value[..value.len() - 1], 1u64 << 10),
+        'M' => (&value[..value.len() - 1], 1u64 << 20),
+        'G' => (&value[..value.len() - 1], 1u64 << 30),
+        'T' => (&value[..value.len() - 1], 1u64 << 40),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+//Legge una banda (o una dimensione) in MB, ad esempio "20" o "20MB/s"; accetta anche i suffissi di parse_size, ad esempio "512K"
+fn parse_rate(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let value = value.strip_suffix("/s").unwrap_or(value);
+    match value.parse::<u64>() {
+        Ok(mb) => mb.checked_mul(1 << 20),
+        Err(_) => parse_size(value),
+    }
+}
+
+//Legge una durata con suffisso opzionale s, m o h (senza suffisso in minuti), ad esempio "3", "90s" o "1h"
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim().to_lowercase();
+    let (number, unit) = match value.chars().last()? {
+        's' => (&value[..value.len() - 1], 1),
+        'm' => (&value[..value.len() - 1], 60),
+        'h' => (&value[..value.len() - 1], 3600),
+        _ => (value.as_str(), 60),
+    };
+    number.trim().parse::<u64>().ok().filter(|n| *n > 0).map(|n| Duration::from_secs(n * unit))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use sysinfo::Disks;
+use crate::archive::archive_size_estimate;
+use crate::destination::{allocation_unit, disk_for};
+use crate::failures::CopyFailures;
+use crate::settings::{BackupSettings, OutputFormat};
+
+const RESERVE: u64 = 16 * 1024 * 1024;          //Spazio lasciato libero per manifest, log, metadati e chiave del backup
+const ENTRY_SIZE: u64 = 1024;                   //Spazio stimato per le righe di un file nel manifest e nei metadati
+
+/**
+ * Sceglie i file del backup in modo che stiano nello spazio libero della destinazione. Se lo spazio basta per tutti i file non cambia nulla;
+ * altrimenti, con l'impostazione best_fit, i file vengono esaminati nell'ordine di PriorityRules e un file che non sta nello spazio rimasto
+ * viene escluso (e registrato in failures.excluded), proseguendo con i successivi, più piccoli o meno importanti. Senza best_fit il backup viene annullato.
+ * Lo spazio occupato dal backup precedente non viene considerato libero: il nuovo backup viene costruito accanto a quello precedente
+ * e un backup con dei file esclusi non prende mai il posto di un backup completo (vedi destination::commit_partial)
+ *
+ * @param files:    file da copiare (percorso relativo di destinazione -> percorso sorgente), da cui vengono tolti i file esclusi
+ * @param src:      cartella sorgente, a cui sono relativi i percorsi critici
+ * @param staging:  cartella di staging, sul disco di destinazione
+ * @param settings: impostazioni avanzate del backup (formato, cifratura, parità, regole di priorità)
+ * @param failures: file esclusi per lo spazio insufficiente
+ * @return          Err se lo spazio non basta e best_fit non è attivo
+ */
+pub fn fit_to_space(files: &mut HashMap<PathBuf, PathBuf>, src: &Path, staging: &Path, settings: &BackupSettings, failures: &mut CopyFailures) -> io::Result<()> {
+    let Some(available) = available_space(staging) else { return Ok(()) };
+    //Nel formato directory ogni file occupa un numero intero di unità di allocazione della destinazione (fino a 128 KB su FAT32/exFAT)
+    let block_size = allocation_unit(staging);
+    let needed: u64 = files.values().map(|path| backup_size(file_size(path), block_size, settings)).sum();
+    if needed + RESERVE <= available {
+        return Ok(());
+    }
+    if !settings.best_fit {
+        return Err(insufficient_space(needed + RESERVE, available));
+    }
+
+    println!("Spazio insufficiente nella destinazione ({} byte liberi, {} byte richiesti): verranno copiati solo i file più importanti", available, needed + RESERVE);
+    let mut entries: Vec<(PathBuf, PathBuf)> = files.iter().map(|(name, path)| (name.clone(), path.clone())).collect();
+    entries.sort();
+    settings.priority.order(&mut entries, src);
+    let mut used = RESERVE;
+    for (name, path) in entries {
+        let size = file_size(&path);
+        if used + backup_size(size, block_size, settings) <= available {
+            used += backup_size(size, block_size, settings);
+        } else {
+            println!("Escluso {:?} ({} byte): spazio insufficiente", path, size);
+            files.remove(&name);
+            failures.excluded.push((path, size));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Verifica che lo spazio libero della destinazione basti per il backup di un'intera cartella (copiata senza elenco dei file)
+ *
+ * @param needed:  dimensione della cartella sorgente
+ * @param staging: cartella di staging, sul disco di destinazione
+ */
+pub fn check_space(needed: u64, staging: &Path) -> io::Result<()> {
+    match available_space(staging) {
+        Some(available) if available < needed + RESERVE => Err(insufficient_space(needed + RESERVE, available)),
+        _ => Ok(()),
+    }
+}
+
+fn insufficient_space(needed: u64, available: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::StorageFull, format!("Spazio insufficiente nella destinazione: {} byte liberi, {} byte richiesti (con best_fit=true vengono copiati solo i file più importanti)", available, needed))
+}
+
+//Spazio occupato nel backup da un file di dimensione size, stimato per eccesso (block_size: unità di allocazione della destinazione)
+fn backup_size(size: u64, block_size: u64, settings: &BackupSettings) -> u64 {
+    let mut needed = match settings.format {
+        OutputFormat::Directory => size.div_ceil(block_size) * block_size,
+        format => archive_size_estimate(format, size),
+    };
+    if settings.encryption.is_enabled() {
+        needed += needed / 4096 + 256;  //16 byte ogni 64 KB e l'intestazione del file cifrato
+    }
+    needed + needed * settings.parity as u64 / 100 + ENTRY_SIZE
+}
+
+//Un file che non è possibile leggere viene lasciato nell'elenco: l'errore viene registrato dalla copia
+fn file_size(path: &Path) -> u64 {
+    path.metadata().map(|m| m.len()).unwrap_or(0)
+}
+
+//Spazio libero del disco che contiene il percorso indicato (None se il disco non viene trovato)
+fn available_space(path: &Path) -> Option<u64> {
+    let disks = Disks::new_with_refreshed_list();
+    disk_for(&disks, path).map(|disk| disk.available_space())
+}
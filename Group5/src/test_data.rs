@@ -0,0 +1,9 @@
+/**
+ * Genera il contenuto dei file usati nei test: byte tutti diversi da zero, così che le zone riempite di zeri siano riconoscibili
+ *
+ * @param seed: distingue il contenuto di file diversi
+ * @param len:  lunghezza in byte
+ */
+pub fn content(seed: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 31 + seed as usize) % 251 + 1) as u8).collect()
+}
@@ -0,0 +1,128 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::bandwidth::transferred;
+
+const SAFETY: f64 = 0.9;                                //Un file viene copiato solo se la copia stimata termina entro questa frazione del tempo rimasto
+const WARMUP: Duration = Duration::from_secs(1);        //Durata della copia dopo la quale la velocità viene stimata
+
+/**
+ * Tempo a disposizione del backup (impostazione time_limit), per un backup d'emergenza (batteria quasi scarica, computer che si surriscalda).
+ * Durante la copia viene stimata la velocità e un file viene copiato solo se la sua copia, dopo quella dei file già avviati,
+ * può terminare entro la scadenza; i file vengono copiati a partire dai più utili (vedi PriorityRules)
+ */
+pub struct TimeBudget {
+    start: Instant,                         //Inizio del backup (il tempo a disposizione comprende anche la preparazione)
+    limit: Duration,                        //Tempo a disposizione
+    passes: u64,                            //Numero di volte in cui ogni file viene letto: 2 se il manifest viene calcolato rileggendo i file copiati
+    copy_start: Option<(Instant, u64)>,     //Inizio della copia del primo file e byte trasferiti fino ad allora dai cicli di copia (vedi bandwidth::transferred)
+    started: u64,                           //Byte dei file di cui è stata avviata la copia
+    completed: u64,                         //Byte dei file di cui è terminata la copia
+    expired: bool,                          //True dopo la scadenza
+}
+
+impl TimeBudget {
+    pub fn new(limit: Duration, passes: u64) -> Self {
+        TimeBudget { start: Instant::now(), limit, passes, copy_start: None, started: 0, completed: 0, expired: false }
+    }
+
+    /**
+     * Chiamata prima di copiare un file: ritorna true se il file va copiato (la copia stimata termina prima della scadenza),
+     * false se non viene salvato
+     *
+     * @param size: dimensione del file
+     */
+    pub fn try_start(&mut self, size: u64) -> bool {
+        if self.is_expired() {
+            return false;
+        }
+        let (copy_start, _) = *self.copy_start.get_or_insert_with(|| (Instant::now(), transferred()));
+        if self.started > self.completed && copy_start.elapsed() < WARMUP {
+            //I worker stanno ancora copiando i primi file: prima di avviarne altri aspetto di poter stimare la velocità
+            thread::sleep(WARMUP - copy_start.elapsed());
+        }
+        if let Some(speed) = self.throughput() {
+            //Byte ancora da leggere: quelli dei file avviati (es. in coda ai worker) e quelli del file, più quelli da rileggere per il manifest
+            //(anche dei file già copiati)
+            let pending = self.started.saturating_sub(self.completed) + size + (self.passes - 1) * (self.started + size);
+            let remaining = self.limit.saturating_sub(self.start.elapsed()).as_secs_f64() * SAFETY;
+            if pending as f64 / speed > remaining {
+                return false;   //Un file più piccolo, più avanti nell'elenco, può ancora essere copiato
+            }
+        }
+        self.started += size;
+        true
+    }
+
+    //Chiamata quando termina la copia (anche non riuscita) di un file avviato con try_start
+    pub fn finish(&mut self, size: u64) {
+        self.completed += size;
+    }
+
+    pub fn is_expired(&mut self) -> bool {
+        self.expired = self.expired || self.start.elapsed() >= self.limit;
+        self.expired
+    }
+
+    //Velocità della copia in byte dei file copiati al secondo, None finché la copia non dura da almeno WARMUP
+    fn throughput(&self) -> Option<f64> {
+        let (copy_start, transferred_start) = self.copy_start?;
+        let elapsed = copy_start.elapsed();
+        if elapsed < WARMUP {
+            return None;
+        }
+        Some(self.speed(transferred().saturating_sub(transferred_start), elapsed))
+    }
+
+    /**
+     * Velocità della copia (almeno 1 byte al secondo) calcolata dai byte dei file copiati. Ai file già copiati si aggiungono i byte
+     * trasferiti per quelli in corso, al massimo la loro dimensione: un file può essere letto più volte e un clone (reflink) non passa dai cicli di copia
+     *
+     * @param transferred: byte letti e scritti dai cicli di copia dall'inizio della copia
+     * @param elapsed:     durata della copia
+     */
+    fn speed(&self, transferred: u64, elapsed: Duration) -> f64 {
+        let in_flight = self.started.saturating_sub(self.completed);
+        let copied = self.completed + transferred.saturating_sub(self.completed).min(in_flight);
+        (copied as f64 / elapsed.as_secs_f64()).max(1.0)
+    }
+
+    //Riga del log del backup con il tempo a disposizione e la velocità stimata
+    pub fn report(&self) -> String {
+        let speed = self.throughput().map(|s| format!("{:.2} MB/s", s / (1024.0 * 1024.0))).unwrap_or_else(|| "non stimata".to_string());
+        format!("Tempo a disposizione: {} s, backup scritto in {} s (velocità stimata {}){}", self.limit.as_secs(), self.start.elapsed().as_secs(), speed,
+            if self.expired { ", tempo a disposizione terminato" } else { "" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_is_bounded_by_the_files_in_flight() {
+        let mut budget = TimeBudget::new(Duration::from_secs(60), 1);
+        budget.started = 20_000_000;
+        budget.completed = 10_000_000;
+        assert_eq!(budget.speed(0, Duration::from_secs(2)), 5_000_000.0);            //File clonati
+        assert_eq!(budget.speed(50_000_000, Duration::from_secs(2)), 10_000_000.0);   //File letti più volte
+        assert_eq!(budget.speed(14_000_000, Duration::from_secs(2)), 7_000_000.0);
+    }
+
+    #[test]
+    fn try_start_follows_the_time_left() {
+        //Un file clonato non passa dai cicli di copia, ma non deve fermare i successivi
+        let mut budget = TimeBudget::new(Duration::from_secs(60), 1);
+        assert!(budget.try_start(100_000_000));
+        budget.finish(100_000_000);
+        thread::sleep(WARMUP);
+        assert!(budget.try_start(100_000_000));
+
+        //Dopo la scadenza nessun file viene più avviato
+        let mut budget = TimeBudget::new(Duration::from_millis(50), 1);
+        assert!(budget.try_start(10));
+        budget.finish(10);
+        thread::sleep(Duration::from_millis(60));
+        assert!(budget.is_expired());
+        assert!(!budget.try_start(1));
+    }
+}
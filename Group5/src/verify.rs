@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use glob::glob;
+use crate::archive::{list_archive, list_zip_volumes, ARCHIVE_FILE};
+use crate::destination::{verify_report_path, MARKER_FILE, PARTIAL_FILE};
+use crate::encryption::is_encrypted;
+use crate::parity::{PARITY_FILE, PARITY_INDEX_FILE};
+use crate::manifest::{hash_file, load_manifest, relative_path, Manifest, MANIFEST_FILE};
+use crate::metadata::{MetadataSidecar, METADATA_FILE};
+
+//File scritti dal programma nella cartella del backup, esclusi dal confronto
+const TOOL_FILES: [&str; 7] = [MARKER_FILE, PARTIAL_FILE, "backup_log.txt", MANIFEST_FILE, PARITY_FILE, PARITY_INDEX_FILE, METADATA_FILE];
+
+/**
+ * Risultato del confronto tra la sorgente e il backup
+ */
+#[derive(Default, Debug)]
+pub struct VerifyReport {
+    pub checked: usize,                 //File della sorgente confrontati
+    pub missing: Vec<String>,           //File della sorgente assenti nel backup
+    pub extra: Vec<String>,             //File del backup che non corrispondono a nessun file della sorgente
+    pub size_mismatch: Vec<String>,     //File con dimensione diversa
+    pub content_mismatch: Vec<String>,  //File con contenuto diverso (solo nel confronto completo)
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.size_mismatch.is_empty() && self.content_mismatch.is_empty()
+    }
+
+    //Righe del resoconto: le differenze trovate e i totali
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (title, list) in [("Missing", &self.missing), ("Extra", &self.extra), ("Size mismatch", &self.size_mismatch), ("Content mismatch", &self.content_mismatch)] {
+            for path in list {
+                lines.push(format!("{}: {}", title, path));
+            }
+        }
+        lines.push(format!("Checked {} files: {} missing, {} extra, {} size mismatches, {} content mismatches",
+                           self.checked, self.missing.len(), self.extra.len(), self.size_mismatch.len(), self.content_mismatch.len()));
+        lines
+    }
+}
+
+/**
+ * Confronta il backup con la sorgente, usando gli stessi filtri sulle estensioni usati da copy_files.
+ * Se il backup contiene il manifest, viene usato per associare i file della sorgente a quelli del backup (necessario in modalità flatten)
+ *
+ * @param options: opzioni del backup (vedi start_backup)
+ * @param full:    se true confronta anche il contenuto dei file tramite hash, altrimenti solo la dimensione
+ */
+pub fn verify_backup(options: &[String], full: bool) -> io::Result<VerifyReport> {
+    let src = Path::new(&options[1]);
+    let dest = Path::new(&options[2]);
+    let mut report = VerifyReport::default();
+
+    //Se il backup contiene collegamenti simbolici copiati come collegamenti (preserve_metadata), non li seguo e non li confronto
+    let metadata = MetadataSidecar::load(dest)?;
+    let follow_links = metadata.symlink_count() == 0;
+
+    //File della sorgente: percorso relativo -> percorso completo
+    let mut source_files: BTreeMap<String, PathBuf> = BTreeMap::new();
+    if options[0] == "F" {
+        walk_files(src, src, &mut source_files, follow_links)?;
+    } else {
+        for ext in options[0].split(',') {
+            for path in glob(&format!("{}/**/*.{}", options[1], ext)).expect("Failed to read glob pattern").flatten() {
+                if path.is_file() {
+                    source_files.insert(relative_path(&path, src), path);
+                }
+            }
+        }
+    }
+
+    //Il manifest indica se il backup è un archivio (tar.zst o zip): in questo caso confronto i file contenuti nell'archivio
+    let manifest = load_manifest(dest).ok();
+    if let Some(m) = &manifest {
+        match m.format.as_str() {
+            "tar.zst" => return verify_archive(&source_files, list_archive(&dest.join(ARCHIVE_FILE), full)?, m, full),
+            "zip" => return verify_archive(&source_files, list_zip_volumes(dest, full)?, m, full),
+            _ => {}
+        }
+    }
+
+    //File del backup, esclusi quelli scritti dal programma
+    let mut backup_files: BTreeMap<String, PathBuf> = BTreeMap::new();
+    walk_files(dest, dest, &mut backup_files, follow_links)?;
+    for tool_file in TOOL_FILES {
+        backup_files.remove(tool_file);
+    }
+    let links = metadata.symlinks();
+    source_files.retain(|k, _| !links.contains(k));
+    backup_files.retain(|k, _| !links.contains(k));
+
+    //Percorso relativo nella sorgente -> percorso relativo nel backup
+    let mapping: HashMap<String, String> = match manifest {
+        Some(manifest) => manifest.files.into_iter().map(|e| (e.source, e.path)).collect(),
+        None => HashMap::new(),
+    };
+
+    let mut matched: Vec<String> = Vec::new();
+    for (key, src_file) in &source_files {
+        report.checked += 1;
+        let backup_key = mapping.get(key).unwrap_or(key);
+        let backup_file = match backup_files.get(backup_key) {
+            Some(p) => p,
+            None => {
+                report.missing.push(key.clone());
+                continue;
+            }
+        };
+        matched.push(backup_key.clone());
+
+        if src_file.metadata()?.len() != backup_file.metadata()?.len() {
+            report.size_mismatch.push(key.clone());
+        } else if full && hash_file(src_file)? != hash_file(backup_file)? {
+            report.content_mismatch.push(key.clone());
+        }
+    }
+
+    for key in matched {
+        backup_files.remove(&key);
+    }
+    report.extra = backup_files.into_keys().collect();
+
+    Ok(report)
+}
+
+/**
+ * Confronta un backup in formato tar.zst o zip con la sorgente, usando l'elenco dei file contenuti nell'archivio
+ *
+ * @param source_files: file della sorgente (percorso relativo -> percorso completo)
+ * @param archived:     file contenuti nell'archivio (percorso -> dimensione e, se full, hash)
+ * @param manifest:     manifest del backup, usato per associare i file della sorgente a quelli dell'archivio
+ * @param full:         se true confronta anche il contenuto dei file tramite hash
+ */
+fn verify_archive(source_files: &BTreeMap<String, PathBuf>, mut archived: BTreeMap<String, (u64, Option<String>)>, manifest: &Manifest, full: bool) -> io::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mapping: HashMap<&String, &String> = manifest.files.iter().map(|e| (&e.source, &e.path)).collect();
+
+    for (key, src_file) in source_files {
+        report.checked += 1;
+        let archive_key = mapping.get(key).copied().unwrap_or(key);
+        let (size, hash) = match archived.remove(archive_key) {
+            Some(entry) => entry,
+            None => {
+                report.missing.push(key.clone());
+                continue;
+            }
+        };
+
+        if src_file.metadata()?.len() != size {
+            report.size_mismatch.push(key.clone());
+        } else if full && hash != Some(hash_file(src_file)?) {
+            report.content_mismatch.push(key.clone());
+        }
+    }
+
+    report.extra = archived.into_keys().collect();
+
+    Ok(report)
+}
+
+/**
+ * Punto di ingresso del comando "verify" (Group5 verify [--full]): confronta il backup con la sorgente indicati nel file di configurazione.
+ * Ritorna il codice di uscita del programma: 0 se il backup corrisponde alla sorgente, 1 se ci sono differenze, 2 in caso di errore.
+ * Su Windows il programma non ha una console, quindi il resoconto viene anche salvato in "<destinazione>.verify.txt" (vedi verify_report_path)
+ *
+ * @param full: se true confronta anche il contenuto dei file
+ */
+pub fn verify_from_configuration(full: bool) -> i32 {
+    let content = match read_to_string("configuration.txt") {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error reading configuration file: {}", e);
+            return 2;
+        }
+    };
+    let options: Vec<String> = match content.lines().nth(1) {
+        Some(line) if line.split(';').count() >= 3 => line.split(';').map(String::from).collect(),
+        _ => {
+            eprintln!("Invalid configuration file");
+            return 2;
+        }
+    };
+
+    let dest = Path::new(&options[2]);
+    if is_encrypted(dest) {
+        return save_report(dest, vec!["The backup is encrypted: restore it with 'Group5 decrypt <folder>' and compare the restored folder with the source".to_string()], 2);
+    }
+
+    let mut lines = vec![format!("Verifying {} against {} ({})", options[2], options[1], if full { "full" } else { "metadata only" })];
+    match verify_backup(&options, full) {
+        Ok(report) => {
+            lines.extend(report.lines());
+            save_report(dest, lines, if report.is_ok() { 0 } else { 1 })
+        }
+        Err(e) => {
+            lines.push(format!("Error verifying backup: {}", e));
+            save_report(dest, lines, 2)
+        }
+    }
+}
+
+//Stampa il resoconto e lo salva accanto al backup. Ritorna il codice di uscita indicato, o 2 se non è stato possibile salvare il resoconto
+fn save_report(dest: &Path, lines: Vec<String>, code: i32) -> i32 {
+    for line in &lines {
+        if code == 2 { eprintln!("{}", line) } else { println!("{}", line) }
+    }
+    let path = verify_report_path(dest);
+    match write(&path, lines.join("\n") + "\n") {
+        Ok(()) => code,
+        Err(e) => {
+            eprintln!("Error writing verify report {:?}: {}", path, e);
+            2
+        }
+    }
+}
+
+//Elenca ricorsivamente i file di una cartella (percorso relativo -> percorso completo). Se follow_links è false, i collegamenti a cartelle non vengono seguiti
+fn walk_files(dir: &Path, root: &Path, files: &mut BTreeMap<String, PathBuf>, follow_links: bool) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && (follow_links || !path.is_symlink()) {
+            walk_files(&path, root, files, follow_links)?;
+        } else {
+            files.insert(relative_path(&path, root), path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_is_saved_next_to_the_backup() {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("backup");
+        std::fs::create_dir(&dest).unwrap();
+        let report = VerifyReport { checked: 2, missing: vec!["a.txt".to_string()], ..Default::default() };
+
+        assert_eq!(save_report(&dest, report.lines(), 1), 1);
+        let saved = read_to_string(root.path().join("backup.verify.txt")).unwrap();
+        assert_eq!(saved, "Missing: a.txt\nChecked 2 files: 1 missing, 0 extra, 0 size mismatches, 0 content mismatches\n");
+        //Il resoconto non finisce nella cartella del backup, che verrebbe confrontata con la sorgente
+        assert!(std::fs::read_dir(&dest).unwrap().next().is_none());
+    }
+}